@@ -39,6 +39,8 @@ fn main() {
         "SystemInfo",
         "ContractInfo",
         "SidevmInfo",
+        "SidevmResourceUsage",
+        "SidevmRestart",
         "ClusterInfo",
     ] {
         builder = builder.type_attribute(
@@ -1,15 +1,18 @@
+use std::collections::BTreeMap;
 use std::time::Duration;
 
 use crate::contracts;
 use crate::system::{TransactionError, TransactionResult};
 use anyhow::{anyhow, Result};
 use parity_scale_codec::{Decode, Encode};
+use phala_mq::traits::MessageChannel;
 use phala_mq::{ContractClusterId, ContractId, MessageOrigin};
-use phala_types::contract::ConvertTo;
+use phala_types::contract::{command_topic, ConvertTo};
 use pink::predefined_accounts::pallet_account;
 use pink::runtime::{BoxedEventCallbacks, ExecSideEffects};
 use runtime::{AccountId, BlockNumber, Hash};
 use sidevm::service::{Command as SidevmCommand, CommandSender, SystemMessage};
+use sp_core::{sr25519, Pair as _};
 use sp_runtime::{traits::ConstU32, BoundedVec};
 
 #[derive(Debug, Encode, Decode)]
@@ -18,6 +21,30 @@ pub enum Command {
         nonce: BoundedVec<u8, ConstU32<32>>,
         message: Vec<u8>,
     },
+    /// An ink message sent by another contract, possibly from another cluster.
+    CrossClusterMessage { nonce: u64, message: Vec<u8> },
+    /// The output of a `CrossClusterMessage` sent by this contract.
+    CrossClusterReply { nonce: u64, output: Vec<u8> },
+    /// An ink message queued by the sidevm of this contract.
+    ///
+    /// Each worker running the sidevm sends its own copy via its egress, signed by the cluster key.
+    /// Only the first copy of each nonce is executed, and none after the `expiry` block.
+    SidevmMessage {
+        nonce: BoundedVec<u8, ConstU32<32>>,
+        expiry: BlockNumber,
+        message: Vec<u8>,
+        signature: [u8; 64],
+    },
+}
+
+/// The payload signed by the cluster key in a `Command::SidevmMessage`.
+pub(crate) fn sidevm_message_signing_payload(
+    contract: &ContractId,
+    nonce: &[u8],
+    expiry: BlockNumber,
+    message: &[u8],
+) -> Vec<u8> {
+    (b"phala/sidevm_message", contract, nonce, expiry, message).encode()
 }
 
 #[derive(Debug, Encode, Decode)]
@@ -41,6 +68,70 @@ pub enum QueryError {
     Timeout,
 }
 
+/// Nonces of cross-cluster messages, tracked per peer contract.
+#[derive(Encode, Decode, Clone, Default)]
+pub struct CrossMessageNonces {
+    /// The nonce of the next message sent to each destination.
+    outgoing: BTreeMap<ContractId, u64>,
+    /// The lowest nonce accepted for the next message from each sender.
+    incoming: BTreeMap<ContractId, u64>,
+    /// The lowest nonce accepted for the next reply from each destination.
+    replies: BTreeMap<ContractId, u64>,
+}
+
+impl CrossMessageNonces {
+    pub(crate) fn next_outgoing(&mut self, to: ContractId) -> u64 {
+        let nonce = self.outgoing.entry(to).or_default();
+        let current = *nonce;
+        *nonce += 1;
+        current
+    }
+
+    fn accept_incoming(&mut self, from: ContractId, nonce: u64) -> bool {
+        let expected = self.incoming.entry(from).or_default();
+        if nonce < *expected {
+            return false;
+        }
+        *expected = nonce + 1;
+        true
+    }
+
+    fn accept_reply(&mut self, from: ContractId, nonce: u64) -> bool {
+        let sent = self.outgoing.get(&from).cloned().unwrap_or_default();
+        let expected = self.replies.entry(from).or_default();
+        if nonce < *expected || nonce >= sent {
+            return false;
+        }
+        *expected = nonce + 1;
+        true
+    }
+}
+
+/// Number of blocks a sidevm message stays valid after it is signed.
+pub(crate) const SIDEVM_MESSAGE_TTL: BlockNumber = 150;
+
+/// Nonces of the executed sidevm messages that have not expired yet, to drop the copies from the
+/// other workers.
+#[derive(Encode, Decode, Clone, Default)]
+pub struct SidevmMessageNonces {
+    expiries: BTreeMap<Vec<u8>, BlockNumber>,
+}
+
+impl SidevmMessageNonces {
+    fn accept(&mut self, nonce: &[u8], expiry: BlockNumber, now: BlockNumber) -> bool {
+        // A nonce can be forgotten once it expires since the message would be rejected anyway.
+        self.expiries.retain(|_, expiry| *expiry >= now);
+        if expiry < now || expiry > now.saturating_add(SIDEVM_MESSAGE_TTL) {
+            return false;
+        }
+        if self.expiries.contains_key(nonce) {
+            return false;
+        }
+        self.expiries.insert(nonce.to_vec(), expiry);
+        true
+    }
+}
+
 #[derive(Encode, Decode, Clone)]
 pub struct Pink {
     pub(crate) instance: pink::Contract,
@@ -133,11 +224,17 @@ impl Pink {
                         context.block_number,
                     ),
                 );
+                let mut effects = effects.into_query_only_effects();
                 if ink_result.result.is_err() {
                     log::error!("Pink [{:?}] query exec error: {:?}", self.id(), ink_result);
-                } else {
-                    *side_effects = effects.into_query_only_effects();
+                    effects.pink_events.clear();
                 }
+                effects
+                    .resource_usage
+                    .entry(self.address())
+                    .or_default()
+                    .gas += ink_result.gas_consumed;
+                *side_effects = effects;
                 Ok(Response::Payload(ink_result.encode()))
             }
             Query::SidevmQuery(payload) => {
@@ -187,41 +284,163 @@ impl Pink {
                     MessageOrigin::Pallet(_) => pallet_account(),
                     _ => return Err(TransactionError::BadOrigin),
                 };
-
-                let storage = cluster_storage(context.contract_clusters, &self.cluster_id)
+                let (output, effects) = self.call_in_command(origin.clone(), message, context);
+                self.emit_message_output(
+                    context,
+                    origin,
+                    self.address(),
+                    nonce.into_inner(),
+                    output,
+                );
+                effects
+            }
+            Command::CrossClusterMessage { nonce, message } => {
+                let sender = match origin {
+                    MessageOrigin::Contract(sender) => sender,
+                    _ => return Err(TransactionError::BadOrigin),
+                };
+                if !context.cross_message_nonces.accept_incoming(sender, nonce) {
+                    log::error!(
+                        "Pink [{:?}] rejected replayed message from {:?}, nonce={}",
+                        self.id(),
+                        sender,
+                        nonce
+                    );
+                    return Err(TransactionError::ReplayedMessage);
+                }
+                let caller: AccountId = sender.0.into();
+                let (output, effects) = self.call_in_command(caller.clone(), message, context);
+                let reply = Command::CrossClusterReply {
+                    nonce,
+                    output: output.clone(),
+                };
+                context
+                    .secret_mq
+                    .bind_remote_key(None)
+                    .push_data(reply.encode(), command_topic(sender));
+                self.emit_message_output(context, caller, self.address(), nonce.encode(), output);
+                effects
+            }
+            Command::CrossClusterReply { nonce, output } => {
+                let responder = match origin {
+                    MessageOrigin::Contract(responder) => responder,
+                    _ => return Err(TransactionError::BadOrigin),
+                };
+                if !context.cross_message_nonces.accept_reply(responder, nonce) {
+                    log::error!(
+                        "Pink [{:?}] rejected unexpected reply from {:?}, nonce={}",
+                        self.id(),
+                        responder,
+                        nonce
+                    );
+                    return Err(TransactionError::ReplayedMessage);
+                }
+                self.emit_message_output(
+                    context,
+                    self.address(),
+                    responder.0.into(),
+                    nonce.encode(),
+                    output,
+                );
+                Ok(Default::default())
+            }
+            Command::SidevmMessage {
+                nonce,
+                expiry,
+                message,
+                signature,
+            } => {
+                if !matches!(origin, MessageOrigin::Worker(_)) {
+                    return Err(TransactionError::BadOrigin);
+                }
+                let cluster = context
+                    .contract_clusters
+                    .get_cluster_mut(&self.cluster_id)
                     .expect("Pink cluster should always exists!");
-
-                let (result, effects) = self.instance.bare_call(
-                    storage,
-                    origin.clone(),
-                    message,
-                    false,
-                    context.block.block_number,
-                    context.block.now_ms,
-                    ContractEventCallback::from_log_sender(
-                        &context.log_handler,
-                        context.block.block_number,
-                    ),
+                let payload = sidevm_message_signing_payload(&self.id(), &nonce, expiry, &message);
+                let signature = sr25519::Signature::from_raw(signature);
+                if !sr25519::Pair::verify(&signature, payload, &cluster.key().public()) {
+                    log::error!(
+                        "Pink [{:?}] rejected badly signed sidevm message",
+                        self.id()
+                    );
+                    return Err(TransactionError::BadOrigin);
+                }
+                let now = context.block.block_number;
+                if !context.sidevm_message_nonces.accept(&nonce, expiry, now) {
+                    log::info!(
+                        "Pink [{:?}] dropped duplicated or expired sidevm message from {:?}",
+                        self.id(),
+                        origin
+                    );
+                    return Ok(Default::default());
+                }
+                let (output, effects) = self.call_in_command(self.address(), message, context);
+                self.emit_message_output(
+                    context,
+                    self.address(),
+                    self.address(),
+                    nonce.into_inner(),
+                    output,
                 );
+                effects
+            }
+        }
+    }
 
-                if let Some(log_handler) = &context.log_handler {
-                    let msg = SidevmCommand::PushSystemMessage(SystemMessage::PinkMessageOutput {
-                        origin: origin.into(),
-                        contract: self.instance.address.clone().into(),
-                        block_number: context.block.block_number,
-                        nonce: nonce.into_inner(),
-                        output: result.result.encode(),
-                    });
-                    if log_handler.try_send(msg).is_err() {
-                        error!("Pink emit message output to log handler failed");
-                    }
-                }
+    /// Execute an ink message in a transaction.
+    ///
+    /// Returns the encoded execution result together with the side effects, which are an `Err` if
+    /// the call failed.
+    fn call_in_command(
+        &mut self,
+        origin: AccountId,
+        message: Vec<u8>,
+        context: &mut contracts::TransactionContext,
+    ) -> (Vec<u8>, TransactionResult) {
+        let storage = cluster_storage(context.contract_clusters, &self.cluster_id)
+            .expect("Pink cluster should always exists!");
 
-                let _ = pink::transpose_contract_result(&result).map_err(|err| {
-                    log::error!("Pink [{:?}] command exec error: {:?}", self.id(), err);
-                    TransactionError::Other(format!("Call contract method failed: {:?}", err))
-                })?;
-                Ok(effects)
+        let (result, effects) = self.instance.bare_call(
+            storage,
+            origin,
+            message,
+            false,
+            context.block.block_number,
+            context.block.now_ms,
+            ContractEventCallback::from_log_sender(
+                &context.log_handler,
+                context.block.block_number,
+            ),
+        );
+        let output = result.result.encode();
+        let effects = pink::transpose_contract_result(&result)
+            .map_err(|err| {
+                log::error!("Pink [{:?}] command exec error: {:?}", self.id(), err);
+                TransactionError::Other(format!("Call contract method failed: {:?}", err))
+            })
+            .map(|_| effects);
+        (output, effects)
+    }
+
+    fn emit_message_output(
+        &self,
+        context: &contracts::TransactionContext,
+        origin: AccountId,
+        contract: AccountId,
+        nonce: Vec<u8>,
+        output: Vec<u8>,
+    ) {
+        if let Some(log_handler) = &context.log_handler {
+            let msg = SidevmCommand::PushSystemMessage(SystemMessage::PinkMessageOutput {
+                origin: origin.into(),
+                contract: contract.into(),
+                block_number: context.block.block_number,
+                nonce,
+                output,
+            });
+            if log_handler.try_send(msg).is_err() {
+                error!("Pink emit message output to log handler failed");
             }
         }
     }
@@ -479,3 +698,63 @@ impl pink::runtime::EventCallbacks for ContractEventCallback {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CrossMessageNonces, SidevmMessageNonces, SIDEVM_MESSAGE_TTL};
+    use phala_mq::ContractId;
+
+    #[test]
+    fn incoming_replay_is_rejected() {
+        let peer = ContractId::repeat_byte(1);
+        let mut nonces = CrossMessageNonces::default();
+        assert!(nonces.accept_incoming(peer, 0));
+        assert!(!nonces.accept_incoming(peer, 0));
+        assert!(nonces.accept_incoming(peer, 1));
+        assert!(!nonces.accept_incoming(peer, 0));
+        // Nonces are tracked per sender.
+        assert!(nonces.accept_incoming(ContractId::repeat_byte(2), 0));
+    }
+
+    #[test]
+    fn incoming_gap_is_accepted_and_skipped_nonces_are_rejected() {
+        let peer = ContractId::repeat_byte(1);
+        let mut nonces = CrossMessageNonces::default();
+        assert!(nonces.accept_incoming(peer, 5));
+        assert!(!nonces.accept_incoming(peer, 3));
+        assert!(!nonces.accept_incoming(peer, 5));
+        assert!(nonces.accept_incoming(peer, 6));
+    }
+
+    #[test]
+    fn unsolicited_reply_is_rejected() {
+        let peer = ContractId::repeat_byte(1);
+        let mut nonces = CrossMessageNonces::default();
+        assert!(!nonces.accept_reply(peer, 0));
+        assert_eq!(nonces.next_outgoing(peer), 0);
+        assert_eq!(nonces.next_outgoing(peer), 1);
+        // Replies to messages sent to another contract are not accepted.
+        assert!(!nonces.accept_reply(ContractId::repeat_byte(2), 0));
+        assert!(!nonces.accept_reply(peer, 2));
+        assert!(nonces.accept_reply(peer, 0));
+        assert!(!nonces.accept_reply(peer, 0));
+        assert!(nonces.accept_reply(peer, 1));
+        assert!(!nonces.accept_reply(peer, 1));
+    }
+
+    #[test]
+    fn sidevm_message_copies_are_dropped() {
+        let mut nonces = SidevmMessageNonces::default();
+        assert!(nonces.accept(b"a", 110, 100));
+        assert!(!nonces.accept(b"a", 110, 100));
+        assert!(!nonces.accept(b"a", 110, 110));
+        assert!(nonces.accept(b"b", 120, 100));
+        // Expired messages are rejected, so their nonces are no longer needed.
+        assert!(!nonces.accept(b"a", 110, 111));
+        assert!(!nonces.expiries.contains_key(&b"a"[..]));
+        assert!(!nonces.accept(b"c", 110, 111));
+        // So are messages that would pin their nonces for too long.
+        assert!(!nonces.accept(b"d", 112 + SIDEVM_MESSAGE_TTL, 111));
+        assert!(nonces.accept(b"d", 111 + SIDEVM_MESSAGE_TTL, 111));
+    }
+}
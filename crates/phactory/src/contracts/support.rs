@@ -1,18 +1,26 @@
 use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use parity_scale_codec::Decode;
+use parity_scale_codec::{Decode, Encode};
 use phala_crypto::ecdh::EcdhPublicKey;
 use phala_mq::{traits::MessageChannel, SignedMessageChannel};
 use phala_scheduler::RequestScheduler;
+use phala_types::contract::command_topic;
 use runtime::BlockNumber;
 use sidevm::{
-    service::{Command as SidevmCommand, CommandSender, ExitReason},
-    OcallAborted, VmId,
+    service::{Command as SidevmCommand, CommandSender, ExitReason, UpgradeResult},
+    GasMeter, OcallAborted, OcallError, ResourceLimits, ResourceUsage, VmId,
 };
 
-use super::pink::cluster::ClusterKeeper;
+use super::pink::{cluster::ClusterKeeper, CrossMessageNonces, SidevmMessageNonces};
 use crate::{
     hex,
     secret_channel::{KeyPair, SecretMessageChannel, SecretReceiver},
@@ -37,6 +45,8 @@ pub struct TransactionContext<'a, 'b> {
     pub contract_clusters: &'a mut ClusterKeeper,
     pub self_id: ContractId,
     pub log_handler: Option<CommandSender>,
+    pub cross_message_nonces: &'a mut CrossMessageNonces,
+    pub sidevm_message_nonces: &'a mut SidevmMessageNonces,
 }
 
 pub struct QueryContext {
@@ -110,6 +120,158 @@ struct SidevmInfo {
     start_time: String,
     auto_restart: bool,
     handle: Arc<Mutex<SidevmHandle>>,
+    #[serde(skip)]
+    pending_upgrade: Option<PendingUpgrade>,
+    /// The recent restarts after crashes, the oldest first.
+    #[serde(default)]
+    restarts: VecDeque<SidevmRestart>,
+    /// When the crashed instance is scheduled to restart, in unix seconds.
+    #[serde(default)]
+    next_restart_at: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct SidevmRestart {
+    /// Unix timestamp in seconds.
+    time: i64,
+    /// Why the previous instance terminated.
+    reason: ExitReason,
+}
+
+/// Policy to restart the crashed sidevm instances.
+struct RestartPolicy {
+    /// Max number of restarts within the window, after which the instance is left stopped.
+    max_restarts: usize,
+    window_secs: i64,
+    /// Delay before the first restart in the window, doubled for each subsequent one.
+    initial_backoff_secs: i64,
+    max_backoff_secs: i64,
+}
+
+impl RestartPolicy {
+    fn backoff_secs(&self, n_restarts: usize) -> i64 {
+        let factor = 1_i64 << n_restarts.min(30);
+        self.initial_backoff_secs
+            .saturating_mul(factor)
+            .min(self.max_backoff_secs)
+    }
+
+    /// Returns when to restart an instance crashed at `now` given its recent `restarts`, or
+    /// `None` if it has crashed too many times within the window.
+    fn next_restart_at(&self, restarts: &VecDeque<SidevmRestart>, now: i64) -> Option<i64> {
+        let window_start = now - self.window_secs;
+        let n_restarts = restarts
+            .iter()
+            .filter(|restart| restart.time > window_start)
+            .count();
+        if n_restarts >= self.max_restarts {
+            return None;
+        }
+        Some(now + self.backoff_secs(n_restarts))
+    }
+}
+
+/// Whether the instance terminated with `reason` is considered crashed and restarted.
+fn is_crash(reason: ExitReason) -> bool {
+    match reason {
+        ExitReason::Exited(_) => false,
+        ExitReason::Stopped => false,
+        ExitReason::InputClosed => false,
+        ExitReason::Panicked => true,
+        ExitReason::Cancelled => false,
+        // TODO.kevin: Allow to charge new gas? How to charge gas or weather the gas
+        // system works or not is not clear ATM.
+        ExitReason::OcallAborted(OcallAborted::GasExhausted) => false,
+        ExitReason::OcallAborted(OcallAborted::Stifled) => true,
+        ExitReason::OcallAborted(OcallAborted::ResourceLimitExceeded) => false,
+        ExitReason::Restore => false,
+        ExitReason::WaitingForCode => false,
+        ExitReason::ResourceLimitExceeded => false,
+        ExitReason::RestartsExhausted => false,
+    }
+}
+
+/// The sidevm instances terminated, as reported by the sidevm service, with the unix time of the
+/// reports. Taken by `ContractsKeeper::try_restart_sidevms` to schedule the restarts.
+static TERMINATED_SIDEVMS: Mutex<Vec<(VmId, ExitReason, i64)>> = Mutex::new(Vec::new());
+
+/// Called by the sidevm service when an instance terminates.
+pub(crate) fn report_sidevm_terminated(id: VmId, reason: ExitReason) {
+    let now = chrono::Utc::now().timestamp();
+    TERMINATED_SIDEVMS.lock().unwrap().push((id, reason, now));
+}
+
+fn take_terminated_sidevms() -> Vec<(VmId, ExitReason, i64)> {
+    std::mem::take(&mut *TERMINATED_SIDEVMS.lock().unwrap())
+}
+
+const RESTART_POLICY: RestartPolicy = RestartPolicy {
+    max_restarts: 5,
+    window_secs: 3600,
+    initial_backoff_secs: 6,
+    max_backoff_secs: 600,
+};
+
+/// Max number of restarts kept in the history.
+const MAX_RESTART_HISTORY: usize = 16;
+
+/// Directory under the sealing path to cache the compiled sidevm modules in.
+const SIDEVM_MODULES_DIR: &str = "sidevm_modules";
+
+/// Max total size of the compiled sidevm modules cached on disk.
+const MAX_SIDEVM_MODULES_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Directory under the sealing path to persist the sidevm filesystems in.
+const SIDEVM_FS_DIR: &str = "sidevm_fs";
+
+/// Resources a sidevm instance can hold at a time, and the bytes it can send in its lifetime.
+const SIDEVM_RESOURCE_LIMITS: ResourceLimits = ResourceLimits {
+    max_connections: 256,
+    max_timers: 1024,
+    max_listeners: 8,
+    max_outbound_bytes: 4 * 1024 * 1024 * 1024,
+    max_cache_bytes: 10 * 1024 * 1024,
+};
+
+/// Max number of ink messages the sidevm can queue to its contract per minute.
+const SIDEVM_MESSAGES_PER_MINUTE: u32 = 30;
+
+/// Rate limit of the ink messages queued by the sidevm, in one minute windows.
+#[derive(Default)]
+struct SidevmMessageQuota {
+    window_start: Option<Instant>,
+    used: u32,
+}
+
+impl SidevmMessageQuota {
+    fn take(&mut self, now: Instant) -> bool {
+        match self.window_start {
+            Some(start) if now.duration_since(start) < Duration::from_secs(60) => {}
+            _ => {
+                self.window_start = Some(now);
+                self.used = 0;
+            }
+        }
+        if self.used >= SIDEVM_MESSAGES_PER_MINUTE {
+            return false;
+        }
+        self.used += 1;
+        true
+    }
+}
+
+/// An upgrade of the running sidevm instance waiting for the result.
+struct PendingUpgrade {
+    code: Vec<u8>,
+    code_hash: H256,
+    result_rx: tokio::sync::oneshot::Receiver<UpgradeResult>,
+}
+
+/// Route of the inbound HTTP gateway to a listener of the sidevm instance.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HttpGateway {
+    pub port: u16,
+    pub hostname: Option<String>,
 }
 
 pub(crate) enum SidevmCode {
@@ -130,6 +292,18 @@ pub struct FatContract {
     sidevm_info: Option<SidevmInfo>,
     weight: u32,
     code_hash: Option<H256>,
+    #[serde(default)]
+    sidevm_gas_meter: GasMeter,
+    #[serde(skip)]
+    sidevm_resource_usage: ResourceUsage,
+    #[serde(default)]
+    http_gateway: Option<HttpGateway>,
+    #[serde(default, with = "more::scale_bytes")]
+    cross_message_nonces: CrossMessageNonces,
+    #[serde(default, with = "more::scale_bytes")]
+    sidevm_message_nonces: SidevmMessageNonces,
+    #[serde(skip)]
+    sidevm_message_quota: SidevmMessageQuota,
 }
 
 impl FatContract {
@@ -152,6 +326,12 @@ impl FatContract {
             sidevm_info: None,
             weight: 0,
             code_hash,
+            sidevm_gas_meter: Default::default(),
+            sidevm_resource_usage: Default::default(),
+            http_gateway: None,
+            cross_message_nonces: Default::default(),
+            sidevm_message_nonces: Default::default(),
+            sidevm_message_quota: Default::default(),
         }
     }
 
@@ -185,6 +365,8 @@ impl FatContract {
             contract_clusters: env.contract_clusters,
             self_id: self.id(),
             log_handler: env.log_handler.clone(),
+            cross_message_nonces: &mut self.cross_message_nonces,
+            sidevm_message_nonces: &mut self.sidevm_message_nonces,
         };
 
         phala_mq::select! {
@@ -209,6 +391,8 @@ impl FatContract {
             contract_clusters: env.contract_clusters,
             self_id: self.id(),
             log_handler: env.log_handler.clone(),
+            cross_message_nonces: &mut self.cross_message_nonces,
+            sidevm_message_nonces: &mut self.sidevm_message_nonces,
         };
         self.contract.on_block_end(&mut context)
     }
@@ -234,6 +418,25 @@ impl FatContract {
             .push_data(payload, topic)
     }
 
+    pub(crate) fn push_cross_cluster_message(&mut self, to: ContractId, message: Vec<u8>) {
+        let nonce = self.cross_message_nonces.next_outgoing(to);
+        let command = super::pink::Command::CrossClusterMessage { nonce, message };
+        self.push_osp_message(command.encode(), command_topic(to), None);
+    }
+
+    pub(crate) fn ecdh_public_key(&self) -> EcdhPublicKey {
+        self.ecdh_key.public()
+    }
+
+    /// Take a slot of the rate limit for the sidevm to queue an ink message.
+    pub(crate) fn take_sidevm_message_quota(&mut self) -> Result<(), OcallError> {
+        if self.sidevm_message_quota.take(Instant::now()) {
+            Ok(())
+        } else {
+            Err(OcallError::ResourceLimited)
+        }
+    }
+
     pub(crate) fn start_sidevm(
         &mut self,
         spawner: &sidevm::service::Spawner,
@@ -276,7 +479,14 @@ impl FatContract {
                 ExitReason::WaitingForCode,
             )))
         } else {
-            do_start_sidevm(spawner, &code, self.contract_id.0, self.weight)?
+            do_start_sidevm(
+                spawner,
+                &code,
+                self.contract_id.0,
+                self.weight,
+                self.sidevm_gas_meter.clone(),
+                self.sidevm_resource_usage.clone(),
+            )?
         };
 
         let start_time = chrono::Utc::now().to_rfc3339();
@@ -286,41 +496,149 @@ impl FatContract {
             start_time,
             handle,
             auto_restart: true,
+            pending_upgrade: None,
+            restarts: Default::default(),
+            next_restart_at: None,
+        });
+        Ok(())
+    }
+
+    /// Upgrade the running sidevm instance to `code`, handing off its state to the new code.
+    ///
+    /// The upgrade takes effect asynchronously, see `poll_sidevm_upgrade`.
+    pub(crate) fn upgrade_sidevm(&mut self, code: Vec<u8>) -> Result<()> {
+        let sidevm_info = self
+            .sidevm_info
+            .as_mut()
+            .ok_or_else(|| anyhow!("No sidevm instance to upgrade"))?;
+        if sidevm_info.pending_upgrade.is_some() {
+            bail!("Another upgrade is in progress");
+        }
+        let tx = match &*sidevm_info.handle.lock().unwrap() {
+            SidevmHandle::Running(tx) => tx.clone(),
+            SidevmHandle::Stopped(_) => bail!("The sidevm instance is not running"),
+        };
+        let code_hash = sp_core::blake2_256(&code).into();
+        let (reply_tx, result_rx) = tokio::sync::oneshot::channel();
+        tx.try_send(SidevmCommand::Upgrade {
+            code: code.clone(),
+            reply_tx,
+        })
+        .or(Err(anyhow!("Failed to send upgrade command to sidevm")))?;
+        let vmid = sidevm::ShortId(&self.contract_id.0);
+        info!(target: "sidevm", "[{vmid}] Upgrading sidevm to {code_hash:?}...");
+        sidevm_info.pending_upgrade = Some(PendingUpgrade {
+            code,
+            code_hash,
+            result_rx,
         });
         Ok(())
     }
 
+    /// Record the new code if the pending sidevm upgrade has succeeded, so that it is used on
+    /// restarts.
+    pub(crate) fn poll_sidevm_upgrade(&mut self) {
+        let sidevm_info = match &mut self.sidevm_info {
+            Some(info) => info,
+            None => return,
+        };
+        let mut upgrade = match sidevm_info.pending_upgrade.take() {
+            Some(upgrade) => upgrade,
+            None => return,
+        };
+        let vmid = sidevm::ShortId(&self.contract_id.0);
+        use tokio::sync::oneshot::error::TryRecvError;
+        match upgrade.result_rx.try_recv() {
+            Ok(UpgradeResult::Upgraded) => {
+                info!(target: "sidevm", "[{vmid}] Sidevm upgraded to {:?}", upgrade.code_hash);
+                sidevm_info.code = upgrade.code;
+                sidevm_info.code_hash = upgrade.code_hash;
+                sidevm_info.start_time = chrono::Utc::now().to_rfc3339();
+            }
+            Ok(result) => {
+                warn!(target: "sidevm", "[{vmid}] Sidevm upgrade failed: {result}");
+            }
+            Err(TryRecvError::Empty) => {
+                sidevm_info.pending_upgrade = Some(upgrade);
+            }
+            Err(TryRecvError::Closed) => {
+                error!(target: "sidevm", "[{vmid}] Sidevm terminated during upgrade");
+            }
+        }
+    }
+
+    /// Applies the restart policy to the sidevm instance terminated with `reason` at `now`: a
+    /// crashed instance is scheduled to restart after a backoff, unless it has crashed too many
+    /// times, in which case it is left stopped by `restart_sidevm_if_needed`.
+    pub(crate) fn on_sidevm_terminated(&mut self, reason: ExitReason, now: i64) {
+        let sidevm_info = match &mut self.sidevm_info {
+            Some(info) if info.auto_restart && is_crash(reason) => info,
+            _ => return,
+        };
+        let restarted_since = matches!(sidevm_info.restarts.back(), Some(last) if last.time > now);
+        if sidevm_info.next_restart_at.is_some() || restarted_since {
+            // Already scheduled, or a late report of an instance restarted since.
+            return;
+        }
+        if let Some(restart_at) = RESTART_POLICY.next_restart_at(&sidevm_info.restarts, now) {
+            let vmid = sidevm::ShortId(&self.contract_id.0);
+            let backoff = restart_at - now;
+            info!(target: "sidevm", "[{vmid}] Sidevm crashed ({reason}), restarting in {backoff}s");
+            sidevm_info.next_restart_at = Some(restart_at);
+        }
+    }
+
     pub(crate) fn restart_sidevm_if_needed(
         &mut self,
         spawner: &sidevm::service::Spawner,
     ) -> Result<()> {
-        if let Some(sidevm_info) = &mut self.sidevm_info {
-            let guard = sidevm_info.handle.lock().unwrap();
-            let handle = if let SidevmHandle::Stopped(reason) = &*guard {
-                let need_restart = match reason {
-                    ExitReason::Exited(_) => false,
-                    ExitReason::Stopped => false,
-                    ExitReason::InputClosed => false,
-                    ExitReason::Panicked => true,
-                    ExitReason::Cancelled => false,
-                    // TODO.kevin: Allow to charge new gas? How to charge gas or weather the gas
-                    // system works or not is not clear ATM.
-                    ExitReason::OcallAborted(OcallAborted::GasExhausted) => false,
-                    ExitReason::OcallAborted(OcallAborted::Stifled) => true,
-                    ExitReason::Restore => true,
-                    ExitReason::WaitingForCode => false,
-                };
-                if !need_restart {
+        let now = chrono::Utc::now().timestamp();
+        let reason = match &self.sidevm_info {
+            Some(info) => match &*info.handle.lock().unwrap() {
+                SidevmHandle::Stopped(reason) => *reason,
+                SidevmHandle::Running(_) => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        let crashed = is_crash(reason);
+        if crashed {
+            // In case the termination hasn't been reported.
+            self.on_sidevm_terminated(reason, now);
+        }
+        let sidevm_info = self.sidevm_info.as_mut().expect("Checked above");
+        let restore = matches!(reason, ExitReason::Restore);
+        if !(restore || (crashed && sidevm_info.auto_restart)) {
+            return Ok(());
+        }
+        if crashed {
+            match sidevm_info.next_restart_at {
+                None => {
+                    let vmid = sidevm::ShortId(&self.contract_id.0);
+                    error!(target: "sidevm", "[{vmid}] Sidevm crashed too many times, giving up restarting");
+                    *sidevm_info.handle.lock().unwrap() =
+                        SidevmHandle::Stopped(ExitReason::RestartsExhausted);
                     return Ok(());
                 }
-                sidevm_info.start_time = chrono::Utc::now().to_rfc3339();
-                do_start_sidevm(spawner, &sidevm_info.code, self.contract_id.0, self.weight)?
-            } else {
-                return Ok(());
-            };
-            drop(guard);
-            sidevm_info.handle = handle;
+                Some(restart_at) if now < restart_at => return Ok(()),
+                Some(_) => {}
+            }
+            sidevm_info.next_restart_at = None;
+            sidevm_info
+                .restarts
+                .push_back(SidevmRestart { time: now, reason });
+            if sidevm_info.restarts.len() > MAX_RESTART_HISTORY {
+                sidevm_info.restarts.pop_front();
+            }
         }
+        sidevm_info.start_time = chrono::Utc::now().to_rfc3339();
+        sidevm_info.handle = do_start_sidevm(
+            spawner,
+            &sidevm_info.code,
+            self.contract_id.0,
+            self.weight,
+            self.sidevm_gas_meter.clone(),
+            self.sidevm_resource_usage.clone(),
+        )?;
         Ok(())
     }
 
@@ -394,6 +712,35 @@ impl FatContract {
         self.weight
     }
 
+    fn set_http_gateway(&mut self, gateway: Option<HttpGateway>) {
+        info!(
+            "Updated http gateway for contract {:?} to {:?}",
+            self.id(),
+            gateway
+        );
+        self.http_gateway = gateway;
+    }
+
+    pub fn http_gateway(&self) -> Option<&HttpGateway> {
+        self.http_gateway.as_ref()
+    }
+
+    /// The address of the sidevm listener to forward the inbound HTTP requests to.
+    ///
+    /// Only available while the sidevm instance is running and listening on the gateway port.
+    pub fn http_gateway_addr(&self) -> Option<SocketAddr> {
+        let gateway = self.http_gateway.as_ref()?;
+        match self.sidevm_handle()? {
+            SidevmHandle::Running(_) => sidevm::vm_listener_addr(&self.contract_id.0, gateway.port),
+            SidevmHandle::Stopped(_) => None,
+        }
+    }
+
+    /// Take the gas consumed by the sidevm instance since the last take.
+    pub fn take_sidevm_gas_consumed(&self) -> u64 {
+        self.sidevm_gas_meter.take()
+    }
+
     pub fn info(&self) -> pb::ContractInfo {
         pb::ContractInfo {
             id: hex(self.contract_id),
@@ -403,11 +750,33 @@ impl FatContract {
                 let handle = info.handle.lock().unwrap().clone();
                 let start_time = info.start_time.clone();
                 let code_hash = hex(info.code_hash);
+                let usage = self.sidevm_resource_usage.stats();
+                let resource_usage = Some(pb::SidevmResourceUsage {
+                    connections: usage.connections,
+                    timers: usage.timers,
+                    listeners: usage.listeners,
+                    outbound_bytes: usage.outbound_bytes,
+                    cache_bytes: usage.cache_bytes,
+                });
+                let restarts = info
+                    .restarts
+                    .iter()
+                    .map(|restart| pb::SidevmRestart {
+                        time: format_timestamp(restart.time),
+                        reason: format!("{}", restart.reason),
+                    })
+                    .collect();
+                let next_restart_time = info
+                    .next_restart_at
+                    .map(format_timestamp)
+                    .unwrap_or_default();
                 match handle {
                     SidevmHandle::Running(_) => pb::SidevmInfo {
                         state: "running".into(),
                         code_hash,
                         start_time,
+                        resource_usage,
+                        restarts,
                         ..Default::default()
                     },
                     SidevmHandle::Stopped(reason) => pb::SidevmInfo {
@@ -415,6 +784,9 @@ impl FatContract {
                         code_hash,
                         start_time,
                         stop_reason: format!("{}", reason),
+                        resource_usage,
+                        restarts,
+                        next_restart_time,
                     },
                 }
             }),
@@ -422,11 +794,22 @@ impl FatContract {
     }
 }
 
+fn format_timestamp(secs: i64) -> String {
+    use chrono::TimeZone;
+    chrono::Utc
+        .timestamp_opt(secs, 0)
+        .single()
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default()
+}
+
 fn do_start_sidevm(
     spawner: &sidevm::service::Spawner,
     code: &[u8],
     id: VmId,
     weight: u32,
+    gas_meter: GasMeter,
+    resource_usage: ResourceUsage,
 ) -> Result<Arc<Mutex<SidevmHandle>>> {
     let max_memory_pages: u32 = 1024; // 64MB
     let gas_per_breath = 50_000_000_000_u64; // about 20 ms bench
@@ -436,7 +819,15 @@ fn do_start_sidevm(
         id,
         gas_per_breath,
         local_cache_ops(),
+        pink_ops(),
         weight,
+        gas_meter,
+        sidevm::FsConfig {
+            quota: sidevm::DEFAULT_FS_QUOTA,
+            store: *SIDEVM_FS_STORE.lock().unwrap(),
+        },
+        SIDEVM_RESOURCE_LIMITS,
+        resource_usage,
     )?;
     let handle = Arc::new(Mutex::new(SidevmHandle::Running(sender)));
     let cloned_handle = handle.clone();
@@ -480,9 +871,337 @@ fn local_cache_ops() -> sidevm::DynCacheOps {
         fn remove(&self, contract: &[u8], key: &[u8]) -> OpResult<Option<Vec<u8>>> {
             Ok(cache::local_cache_remove(contract, key))
         }
+
+        fn size(&self, contract: &[u8]) -> OpResult<u64> {
+            Ok(cache::local_cache_size(contract) as _)
+        }
     }
     &CacheOps
 }
 
+pub(crate) type PinkQueryFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>, OcallError>> + Send>>;
+
+/// Serves the calls from the sidevm instances to the pink contracts, which need access to other
+/// contracts and the clusters rather than the single contract owning the sidevm.
+pub(crate) trait SidevmPinkHandler: Send + Sync {
+    /// Query `contract` with the SCALE-encoded ink message from the sidevm owned by `origin`.
+    fn query(&self, origin: ContractId, contract: ContractId, payload: Vec<u8>) -> PinkQueryFuture;
+
+    /// Queue a command from the sidevm to its owner contract `origin`.
+    fn push_command(
+        &self,
+        origin: ContractId,
+        nonce: Vec<u8>,
+        message: Vec<u8>,
+    ) -> Result<(), OcallError>;
+}
+
+static SIDEVM_PINK_HANDLER: Mutex<Option<Arc<dyn SidevmPinkHandler>>> = Mutex::new(None);
+
+pub(crate) fn set_sidevm_pink_handler(handler: impl SidevmPinkHandler + 'static) {
+    *SIDEVM_PINK_HANDLER.lock().unwrap() = Some(Arc::new(handler));
+}
+
+fn pink_ops() -> sidevm::DynPinkOps {
+    fn handler() -> Result<Arc<dyn SidevmPinkHandler>, OcallError> {
+        SIDEVM_PINK_HANDLER
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(OcallError::NotFound)
+    }
+
+    struct PinkOps;
+    impl sidevm::PinkOps for PinkOps {
+        fn query(&self, origin: VmId, contract: VmId, payload: Vec<u8>) -> PinkQueryFuture {
+            match handler() {
+                Ok(handler) => handler.query(origin.into(), contract.into(), payload),
+                Err(err) => Box::pin(async move { Err(err) }),
+            }
+        }
+
+        fn push_command(
+            &self,
+            origin: VmId,
+            nonce: Vec<u8>,
+            message: Vec<u8>,
+        ) -> Result<(), OcallError> {
+            handler()?.push_command(origin.into(), nonce, message)
+        }
+    }
+    &PinkOps
+}
+
+/// Keeps the compiled sidevm modules sealed on disk, one file per cache key.
+struct SealedModuleCache<Platform> {
+    platform: Mutex<Platform>,
+    dir: PathBuf,
+    /// When the modules were last loaded by this process. The files are not touched on load, so
+    /// their modification time only tells when they were saved.
+    last_loaded: Mutex<BTreeMap<PathBuf, SystemTime>>,
+}
+
+impl<Platform: pal::Platform> sidevm::ModuleCacheStore for SealedModuleCache<Platform> {
+    fn load(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let path = self.dir.join(hex(key));
+        let unsealed = self.platform.lock().unwrap().unseal_data(&path);
+        match unsealed {
+            Ok(data) => {
+                if data.is_some() {
+                    self.last_loaded
+                        .lock()
+                        .unwrap()
+                        .insert(path, SystemTime::now());
+                }
+                data
+            }
+            Err(err) => {
+                warn!(target: "sidevm", "Failed to unseal compiled module {:?}: {:?}", path, err);
+                None
+            }
+        }
+    }
+
+    fn save(&self, key: &[u8], artifact: &[u8]) {
+        let path = self.dir.join(hex(key));
+        if let Err(err) = self.platform.lock().unwrap().seal_data(&path, artifact) {
+            warn!(target: "sidevm", "Failed to seal compiled module {:?}: {:?}", path, err);
+            return;
+        }
+        self.evict(Some(&path));
+    }
+}
+
+impl<Platform> SealedModuleCache<Platform> {
+    /// Remove the least recently used compiled modules until the cache fits in
+    /// `MAX_SIDEVM_MODULES_SIZE`.
+    fn evict(&self, keep: Option<&Path>) {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!(target: "sidevm", "Failed to list the module cache dir {:?}: {}", self.dir, err);
+                return;
+            }
+        };
+        let mut last_loaded = self.last_loaded.lock().unwrap();
+        let files = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let meta = entry.metadata().ok()?;
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                let path = entry.path();
+                let last_used = match last_loaded.get(&path) {
+                    Some(loaded) => modified.max(*loaded),
+                    None => modified,
+                };
+                meta.is_file().then(|| (path, last_used, meta.len()))
+            })
+            .collect();
+        for path in modules_to_evict(files, MAX_SIDEVM_MODULES_SIZE, keep) {
+            last_loaded.remove(&path);
+            info!(target: "sidevm", "Evicting compiled module {:?}", path);
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!(target: "sidevm", "Failed to remove compiled module {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+/// Picks the least recently used modules to remove to bring the total size within `max_bytes`,
+/// except the `keep` one.
+fn modules_to_evict(
+    mut files: Vec<(PathBuf, SystemTime, u64)>,
+    max_bytes: u64,
+    keep: Option<&Path>,
+) -> Vec<PathBuf> {
+    let mut total: u64 = files.iter().map(|(_, _, len)| len).sum();
+    files.sort_by_key(|(_, last_used, _)| *last_used);
+    let mut evicted = vec![];
+    for (path, _, len) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if Some(path.as_path()) == keep {
+            continue;
+        }
+        total -= len;
+        evicted.push(path);
+    }
+    evicted
+}
+
+/// Cache the compiled sidevm modules sealed under `sealing_path`, so that restarting the sidevm
+/// instances doesn't need to compile the code again.
+pub(crate) fn install_sidevm_module_cache<Platform: pal::Platform>(
+    platform: Platform,
+    sealing_path: &str,
+) {
+    let dir = PathBuf::from(sealing_path).join(SIDEVM_MODULES_DIR);
+    if let Err(err) = std::fs::create_dir_all(&dir) {
+        warn!(target: "sidevm", "Failed to create the module cache dir {:?}: {}", dir, err);
+        return;
+    }
+    let store = Box::leak(Box::new(SealedModuleCache {
+        platform: Mutex::new(platform),
+        dir,
+        last_loaded: Default::default(),
+    }));
+    // Bring the modules left by the previous runs within the limit.
+    store.evict(None);
+    sidevm::set_module_cache(store);
+}
+
+/// Where the sidevm filesystems are persisted. They are in-memory only until installed.
+static SIDEVM_FS_STORE: Mutex<Option<sidevm::DynFsStore>> = Mutex::new(None);
+
+/// Keeps the sidevm filesystems sealed on disk, one file per VM.
+struct SealedFsStore<Platform> {
+    platform: Mutex<Platform>,
+    dir: PathBuf,
+}
+
+impl<Platform: pal::Platform> sidevm::FsStore for SealedFsStore<Platform> {
+    fn load(&self, vm_id: &[u8]) -> Option<Vec<u8>> {
+        let path = self.dir.join(hex(vm_id));
+        match self.platform.lock().unwrap().unseal_data(&path) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!(target: "sidevm", "Failed to unseal sidevm fs {:?}: {:?}", path, err);
+                None
+            }
+        }
+    }
+
+    fn save(&self, vm_id: &[u8], snapshot: &[u8]) {
+        let path = self.dir.join(hex(vm_id));
+        if let Err(err) = self.platform.lock().unwrap().seal_data(&path, snapshot) {
+            warn!(target: "sidevm", "Failed to seal sidevm fs {:?}: {:?}", path, err);
+        }
+    }
+}
+
+/// Persist the sidevm filesystems sealed under `sealing_path`, so that the files survive
+/// restarts of the sidevm instances and of the worker.
+pub(crate) fn install_sidevm_fs_store<Platform: pal::Platform>(
+    platform: Platform,
+    sealing_path: &str,
+) {
+    let dir = PathBuf::from(sealing_path).join(SIDEVM_FS_DIR);
+    if let Err(err) = std::fs::create_dir_all(&dir) {
+        warn!(target: "sidevm", "Failed to create the sidevm fs dir {:?}: {}", dir, err);
+        return;
+    }
+    let store = Box::leak(Box::new(SealedFsStore {
+        platform: Mutex::new(platform),
+        dir,
+    }));
+    *SIDEVM_FS_STORE.lock().unwrap() = Some(store);
+}
+
 pub use keeper::*;
 mod keeper;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: RestartPolicy = RestartPolicy {
+        max_restarts: 3,
+        window_secs: 100,
+        initial_backoff_secs: 5,
+        max_backoff_secs: 30,
+    };
+
+    fn restarts(times: &[i64]) -> VecDeque<SidevmRestart> {
+        times
+            .iter()
+            .map(|&time| SidevmRestart {
+                time,
+                reason: ExitReason::Panicked,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        assert_eq!(POLICY.backoff_secs(0), 5);
+        assert_eq!(POLICY.backoff_secs(1), 10);
+        assert_eq!(POLICY.backoff_secs(2), 20);
+        assert_eq!(POLICY.backoff_secs(3), 30);
+        assert_eq!(POLICY.backoff_secs(usize::MAX), 30);
+        assert_eq!(
+            RESTART_POLICY.backoff_secs(64),
+            RESTART_POLICY.max_backoff_secs
+        );
+    }
+
+    #[test]
+    fn restarts_back_off_within_the_window() {
+        let now = 1000;
+        assert_eq!(POLICY.next_restart_at(&restarts(&[]), now), Some(1005));
+        assert_eq!(POLICY.next_restart_at(&restarts(&[990]), now), Some(1010));
+        assert_eq!(
+            POLICY.next_restart_at(&restarts(&[950, 990]), now),
+            Some(1020)
+        );
+        // Restarts out of the window are forgotten.
+        assert_eq!(
+            POLICY.next_restart_at(&restarts(&[800, 900, 990]), now),
+            Some(1010)
+        );
+    }
+
+    #[test]
+    fn restarts_are_exhausted_within_the_window() {
+        let now = 1000;
+        assert_eq!(
+            POLICY.next_restart_at(&restarts(&[910, 950, 990]), now),
+            None
+        );
+        assert_eq!(
+            POLICY.next_restart_at(&restarts(&[900, 950, 990]), now),
+            Some(1020)
+        );
+        assert_eq!(
+            POLICY.next_restart_at(&restarts(&[910, 950, 990]), now + 10),
+            Some(1030)
+        );
+    }
+
+    #[test]
+    fn only_crashes_are_restarted() {
+        assert!(is_crash(ExitReason::Panicked));
+        assert!(is_crash(ExitReason::OcallAborted(OcallAborted::Stifled)));
+        assert!(!is_crash(ExitReason::Exited(0)));
+        assert!(!is_crash(ExitReason::Stopped));
+        assert!(!is_crash(ExitReason::ResourceLimitExceeded));
+        assert!(!is_crash(ExitReason::RestartsExhausted));
+    }
+
+    #[test]
+    fn least_recently_used_modules_are_evicted() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let files = || {
+            vec![
+                (PathBuf::from("c"), at(3), 30),
+                (PathBuf::from("a"), at(1), 10),
+                (PathBuf::from("b"), at(2), 20),
+            ]
+        };
+        assert!(modules_to_evict(files(), 60, None).is_empty());
+        assert_eq!(
+            modules_to_evict(files(), 50, None),
+            vec![PathBuf::from("a")]
+        );
+        assert_eq!(
+            modules_to_evict(files(), 40, None),
+            vec![PathBuf::from("a"), PathBuf::from("b")]
+        );
+        // The kept module is skipped even if it is the least recently used.
+        assert_eq!(
+            modules_to_evict(files(), 30, Some(Path::new("a"))),
+            vec![PathBuf::from("b"), PathBuf::from("c")]
+        );
+        assert!(modules_to_evict(vec![], 0, None).is_empty());
+    }
+}
@@ -1,10 +1,11 @@
 use pink::runtime::ExecSideEffects;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sidevm::service::Spawner;
 use std::collections::BTreeMap;
+use std::sync::Mutex;
 
 use crate::{
-    contracts::{pink::Pink, FatContract, TransactionContext},
+    contracts::{pink::Pink, FatContract, HttpGateway, TransactionContext},
     system::{TransactionError, TransactionResult},
     types::{deopaque_query, OpaqueError, OpaqueQuery, OpaqueReply},
 };
@@ -88,32 +89,96 @@ define_any_native_contract!(
     }
 );
 
-#[derive(Default, Serialize, Deserialize)]
-pub struct ContractsKeeper(ContractMap);
+/// The hostname index of the latest `ContractsKeeper`, readable without locking the phactory.
+static HTTP_HOSTNAMES: Mutex<Option<BTreeMap<String, ContractId>>> = Mutex::new(None);
+
+/// Finds the contract whose http gateway serves the given lowercase hostname.
+pub fn http_gateway_contract_of(hostname: &str) -> Option<ContractId> {
+    HTTP_HOSTNAMES
+        .lock()
+        .unwrap()
+        .as_ref()?
+        .get(hostname)
+        .copied()
+}
+
+#[derive(Default)]
+pub struct ContractsKeeper {
+    contracts: ContractMap,
+    /// Hostnames claimed by the http gateways of the contracts.
+    http_hostnames: BTreeMap<String, ContractId>,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "ContractsKeeper")]
+struct ContractsKeeperRef<'a>(&'a ContractMap);
+
+#[derive(Deserialize)]
+#[serde(rename = "ContractsKeeper")]
+struct ContractsKeeperOwned(ContractMap);
+
+impl Serialize for ContractsKeeper {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ContractsKeeperRef(&self.contracts).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ContractsKeeper {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ContractsKeeperOwned(contracts) = ContractsKeeperOwned::deserialize(deserializer)?;
+        let http_hostnames = contracts
+            .iter()
+            .filter_map(|(id, contract)| Some((hostname_of(contract)?.to_owned(), *id)))
+            .collect();
+        // Published by `publish_http_hostnames` once the whole state is restored.
+        Ok(Self {
+            contracts,
+            http_hostnames,
+        })
+    }
+}
+
+fn hostname_of(contract: &FatContract) -> Option<&str> {
+    contract.http_gateway()?.hostname.as_deref()
+}
 
 impl ContractsKeeper {
     pub fn insert(&mut self, contract: FatContract) {
-        self.0.insert(contract.id(), contract);
+        let id = contract.id();
+        let hostname = hostname_of(&contract).map(ToOwned::to_owned);
+        if let Some(old) = self.contracts.insert(id, contract) {
+            self.unindex_hostname(&old);
+        }
+        if let Some(hostname) = hostname {
+            self.http_hostnames.insert(hostname, id);
+            self.publish_http_hostnames();
+        }
     }
 
     pub fn keys(&self) -> impl Iterator<Item = &ContractId> {
-        self.0.keys()
+        self.contracts.keys()
     }
 
     pub fn get_mut(&mut self, id: &ContractId) -> Option<&mut FatContract> {
-        self.0.get_mut(id)
+        self.contracts.get_mut(id)
     }
 
     pub fn get(&self, id: &ContractId) -> Option<&FatContract> {
-        self.0.get(id)
+        self.contracts.get(id)
     }
 
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.contracts.len()
     }
 
     pub fn try_restart_sidevms(&mut self, spawner: &Spawner) {
-        for contract in self.0.values_mut() {
+        for (id, reason, time) in super::take_terminated_sidevms() {
+            if let Some(contract) = self.contracts.get_mut(&ContractId::from(id)) {
+                contract.on_sidevm_terminated(reason, time);
+            }
+        }
+        for contract in self.contracts.values_mut() {
+            contract.poll_sidevm_upgrade();
             if let Err(err) = contract.restart_sidevm_if_needed(spawner) {
                 error!("Failed to restart sidevm instance: {:?}", err);
             }
@@ -121,10 +186,52 @@ impl ContractsKeeper {
     }
 
     pub fn remove(&mut self, id: &ContractId) -> Option<FatContract> {
-        self.0.remove(id)
+        let contract = self.contracts.remove(id)?;
+        self.unindex_hostname(&contract);
+        Some(contract)
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (&ContractId, &FatContract)> {
-        self.0.iter()
+        self.contracts.iter()
+    }
+
+    /// Finds the contract whose http gateway serves the given hostname.
+    pub fn find_by_http_hostname(&self, hostname: &str) -> Option<&FatContract> {
+        self.contracts.get(self.http_hostnames.get(hostname)?)
+    }
+
+    /// Updates the http gateway of the contract, keeping the hostname index in sync.
+    pub fn set_http_gateway(&mut self, id: &ContractId, gateway: Option<HttpGateway>) -> bool {
+        let contract = match self.contracts.get_mut(id) {
+            Some(contract) => contract,
+            None => return false,
+        };
+        let old = hostname_of(contract).map(ToOwned::to_owned);
+        contract.set_http_gateway(gateway);
+        let new = hostname_of(contract).map(ToOwned::to_owned);
+        if old != new {
+            if let Some(old) = old {
+                self.http_hostnames.remove(&old);
+            }
+            if let Some(new) = new {
+                self.http_hostnames.insert(new, *id);
+            }
+            self.publish_http_hostnames();
+        }
+        true
+    }
+
+    fn unindex_hostname(&mut self, contract: &FatContract) {
+        if let Some(hostname) = hostname_of(contract) {
+            if self.http_hostnames.get(hostname) == Some(&contract.id()) {
+                self.http_hostnames.remove(hostname);
+                self.publish_http_hostnames();
+            }
+        }
+    }
+
+    /// Makes the hostname index readable by `http_gateway_contract_of`.
+    pub(crate) fn publish_http_hostnames(&self) {
+        *HTTP_HOSTNAMES.lock().unwrap() = Some(self.http_hostnames.clone());
     }
 }
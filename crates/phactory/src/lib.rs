@@ -55,7 +55,7 @@ use std::time::Instant;
 use types::Error;
 
 pub use chain::BlockNumber;
-pub use contracts::pink;
+pub use contracts::{http_gateway_contract_of, pink};
 pub use prpc_service::RpcService;
 pub use storage::{Storage, StorageExt};
 pub use system::gk;
@@ -66,7 +66,7 @@ pub mod benchmark;
 mod bin_api_service;
 mod contracts;
 mod cryptography;
-mod light_validation;
+pub mod light_validation;
 mod prpc_service;
 mod secret_channel;
 mod storage;
@@ -295,6 +295,8 @@ impl<Platform: pal::Platform> Phactory<Platform> {
             benchmark::resume();
         }
 
+        contracts::install_sidevm_module_cache(self.platform.clone(), &args.sealing_path);
+        contracts::install_sidevm_fs_store(self.platform.clone(), &args.sealing_path);
         self.args = args;
     }
 
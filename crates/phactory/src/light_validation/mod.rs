@@ -90,6 +90,9 @@ impl Config for chain::Runtime {
     type Block = chain::Block;
 }
 
+/// The light client of the relaychain.
+pub type RelaychainValidation = LightValidation<chain::Runtime>;
+
 #[derive(Encode, Decode, Clone, Serialize, Deserialize)]
 pub struct LightValidation<T: Config> {
     num_bridges: BridgeId,
@@ -1,17 +1,19 @@
 use std::convert::TryFrom;
 use std::future::Future;
+use std::net::SocketAddr;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
 
 use crate::benchmark::Flags;
 use crate::hex;
 use crate::system::{chain_state, System};
+use sidevm::OcallError;
 
 use super::*;
 use crate::contracts::ContractClusterId;
 use phala_pallets::utils::attestation::{validate as validate_attestation_report, IasFields};
 use ::pink::runtime::ExecSideEffects;
-use parity_scale_codec::Encode;
+use parity_scale_codec::{Decode, Encode};
 use pb::{
     phactory_api_server::{PhactoryApi, PhactoryApiServer},
     server::Error as RpcError,
@@ -776,6 +778,22 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         Ok(pb::GetContractInfoResponse { contracts })
     }
 
+    /// Resolves the sidevm listener serving the http gateway of the contract, given in hex.
+    pub fn sidevm_gateway_by_contract(&self, contract_id: &str) -> Option<SocketAddr> {
+        let raw: [u8; 32] = try_decode_hex(contract_id).ok()?.try_into().ok()?;
+        let system = self.system.as_ref()?;
+        system.contracts.get(&raw.into())?.http_gateway_addr()
+    }
+
+    /// Resolves the sidevm listener serving the http gateway of the given hostname.
+    pub fn sidevm_gateway_by_host(&self, hostname: &str) -> Option<SocketAddr> {
+        let system = self.system.as_ref()?;
+        system
+            .contracts
+            .find_by_http_hostname(&hostname.to_ascii_lowercase())?
+            .http_gateway_addr()
+    }
+
     pub fn get_cluster_info(&self) -> RpcResult<pb::GetClusterInfoResponse> {
         // TODO: use `let else`.
         let system = match &self.system {
@@ -807,6 +825,107 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
     }
 }
 
+impl<Platform: pal::Platform> Phactory<Platform> {
+    /// Makes a read-only query from the sidevm of `origin` to a pink contract in the same cluster.
+    ///
+    /// The side effects of the query are discarded. Resolves to the SCALE-encoded output of the ink
+    /// message.
+    pub(crate) fn sidevm_pink_query(
+        &mut self,
+        origin: ContractId,
+        contract: ContractId,
+        payload: Vec<u8>,
+    ) -> Result<impl Future<Output = Result<Vec<u8>, OcallError>>, OcallError> {
+        use contracts::pink::{Query, QueryError, Response};
+
+        let query_scheduler = self.query_scheduler.clone();
+        let system = self.system.as_mut().ok_or(OcallError::NotFound)?;
+        let cluster_of = |id| system.contracts.get(id).map(|c| c.cluster_id());
+        let cluster_id = cluster_of(&origin).ok_or(OcallError::NotFound)?;
+        if cluster_of(&contract) != Some(cluster_id) {
+            return Err(OcallError::NotFound);
+        }
+        let origin = chain::AccountId::new(origin.0);
+        let query = Query::InkMessage(payload).encode();
+        let query = system
+            .make_query(&contract, Some(&origin), query, query_scheduler)
+            .or(Err(OcallError::NotFound))?;
+        Ok(async move {
+            let (reply, _cluster_id, _effects) = query.await.map_err(|err| {
+                warn!("Sidevm query to {:?} failed: {:?}", contract, err);
+                OcallError::IoError
+            })?;
+            let response: Result<Response, QueryError> =
+                Decode::decode(&mut &reply[..]).or(Err(OcallError::InvalidEncoding))?;
+            let Response::Payload(output) = response.map_err(|err| {
+                warn!("Sidevm query to {:?} failed: {:?}", contract, err);
+                OcallError::IoError
+            })?;
+            let result = ::pink::ContractExecResult::decode(&mut &output[..])
+                .or(Err(OcallError::InvalidEncoding))?;
+            ::pink::transpose_contract_result(&result)
+                .map(|data| data.to_vec())
+                .map_err(|err| {
+                    warn!("Sidevm query to {:?} reverted: {:?}", contract, err);
+                    OcallError::IoError
+                })
+        })
+    }
+
+    /// Queues a command from the sidevm of `origin` to the contract itself.
+    pub(crate) fn sidevm_push_command(
+        &mut self,
+        origin: ContractId,
+        nonce: Vec<u8>,
+        message: Vec<u8>,
+    ) -> Result<(), OcallError> {
+        let system = self.system.as_mut().ok_or(OcallError::NotFound)?;
+        info!("Sidevm of {:?} pushing a command", origin);
+        system.push_sidevm_message(origin, nonce, message)
+    }
+}
+
+/// Serves the calls from the sidevm instances to the pink contracts.
+struct SidevmPinkService<Platform>(Weak<Mutex<Phactory<Platform>>>);
+
+impl<Platform: pal::Platform> SidevmPinkService<Platform> {
+    fn phactory(&self) -> Result<Arc<Mutex<Phactory<Platform>>>, OcallError> {
+        self.0.upgrade().ok_or(OcallError::NotFound)
+    }
+}
+
+impl<Platform: pal::Platform> contracts::SidevmPinkHandler for SidevmPinkService<Platform> {
+    fn query(
+        &self,
+        origin: ContractId,
+        contract: ContractId,
+        payload: Vec<u8>,
+    ) -> contracts::PinkQueryFuture {
+        let phactory = self.0.clone();
+        Box::pin(async move {
+            let query = phactory
+                .upgrade()
+                .ok_or(OcallError::NotFound)?
+                .lock()
+                .unwrap()
+                .sidevm_pink_query(origin, contract, payload)?;
+            query.await
+        })
+    }
+
+    fn push_command(
+        &self,
+        origin: ContractId,
+        nonce: Vec<u8>,
+        message: Vec<u8>,
+    ) -> Result<(), OcallError> {
+        self.phactory()?
+            .lock()
+            .unwrap()
+            .sidevm_push_command(origin, nonce, message)
+    }
+}
+
 #[derive(Clone)]
 pub struct RpcService<Platform> {
     pub(crate) phactory: Arc<Mutex<Phactory<Platform>>>,
@@ -814,9 +933,9 @@ pub struct RpcService<Platform> {
 
 impl<Platform: pal::Platform> RpcService<Platform> {
     pub fn new(platform: Platform) -> RpcService<Platform> {
-        RpcService {
-            phactory: Arc::new(Mutex::new(Phactory::new(platform))),
-        }
+        let phactory = Arc::new(Mutex::new(Phactory::new(platform)));
+        contracts::set_sidevm_pink_handler(SidevmPinkService(Arc::downgrade(&phactory)));
+        RpcService { phactory }
     }
 }
 
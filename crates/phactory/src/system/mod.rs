@@ -3,9 +3,11 @@ mod master_key;
 
 use crate::{
     benchmark,
-    contracts::{pink::cluster::Cluster, AnyContract, ContractsKeeper, ExecuteEnv, SidevmCode},
+    contracts::{
+        pink::cluster::Cluster, AnyContract, ContractsKeeper, ExecuteEnv, HttpGateway, SidevmCode,
+    },
     pink::{cluster::ClusterKeeper, ContractEventCallback, Pink},
-    secret_channel::{ecdh_serde, SecretReceiver},
+    secret_channel::{ecdh_serde, SecretMessageChannel, SecretReceiver},
     types::{BlockInfo, OpaqueError, OpaqueQuery, OpaqueReply},
     StorageExt,
 };
@@ -13,7 +15,10 @@ use anyhow::{anyhow, Context, Result};
 use core::fmt;
 use log::info;
 use phala_scheduler::RequestScheduler;
-use pink::{runtime::ExecSideEffects, types::AccountId};
+use pink::{
+    runtime::{ExecSideEffects, ResourceUsage},
+    types::AccountId,
+};
 use runtime::BlockNumber;
 
 use crate::contracts;
@@ -37,8 +42,8 @@ use phala_types::{
     contract::{
         self,
         messaging::{
-            BatchDispatchClusterKeyEvent, ClusterOperation, ContractOperation, ResourceType,
-            WorkerClusterReport,
+            BatchDispatchClusterKeyEvent, ClusterOperation, ContractOperation, ContractUsage,
+            ResourceType, WorkerClusterReport, MAX_CONTRACT_USAGES_PER_REPORT,
         },
         CodeIndex, ConvertTo,
     },
@@ -53,16 +58,21 @@ use phala_types::{
 use serde::{Deserialize, Serialize};
 use sidevm::service::{Command as SidevmCommand, CommandSender, Report, Spawner, SystemMessage};
 use sp_core::{hashing::blake2_256, sr25519, Pair, U256};
+use sp_runtime::{traits::ConstU32, BoundedVec};
 
 use pink::runtime::{HookPoint, PinkEvent};
 use std::cell::Cell;
-use std::convert::TryFrom;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
 use std::future::Future;
 
 pub type TransactionResult = Result<pink::runtime::ExecSideEffects, TransactionError>;
 
 const MAX_SUPPORTED_CONSENSUS_VERSION: u32 = 1;
 
+/// Report the resource usage of contracts to the chain every this number of blocks.
+const CONTRACT_USAGE_REPORT_INTERVAL: chain::BlockNumber = 100;
+
 #[derive(Encode, Decode, Debug, Clone, thiserror::Error)]
 #[error("TransactionError: {:?}", self)]
 pub enum TransactionError {
@@ -108,6 +118,7 @@ pub enum TransactionError {
     NoClusterOnGatekeeper,
     NoPinkSystemCode,
     BadPinkSystemVersion,
+    ReplayedMessage,
 }
 
 impl From<BadOrigin> for TransactionError {
@@ -461,6 +472,10 @@ pub struct System<Platform> {
 
     // The version flag used to coordinate the pruntime's behavior.
     pub(crate) consensus_version: u32,
+
+    // Resources consumed by contracts on this worker, not reported yet.
+    #[serde(default, with = "more::scale_bytes")]
+    contract_usage: BTreeMap<ContractId, ContractUsage>,
 }
 
 thread_local! {
@@ -480,8 +495,9 @@ fn create_sidevm_service(worker_threads: usize) -> Spawner {
     let (service, spawner) = sidevm::service::service(worker_threads);
     spawner.spawn(service.run(|report| match report {
         Report::VmTerminated { id, reason } => {
-            let id = hex_fmt::HexFmt(&id[..4]);
-            info!("Sidevm {id} terminated with reason: {reason:?}");
+            let short_id = hex_fmt::HexFmt(&id[..4]);
+            info!("Sidevm {short_id} terminated with reason: {reason:?}");
+            contracts::report_sidevm_terminated(id, reason);
         }
     }));
     spawner
@@ -535,6 +551,7 @@ impl<Platform: pal::Platform> System<Platform> {
             sidevm_spawner: create_sidevm_service(worker_threads),
             retired_versions: vec![],
             consensus_version: 0,
+            contract_usage: Default::default(),
         }
     }
 
@@ -790,10 +807,47 @@ impl<Platform: pal::Platform> System<Platform> {
         }
         self.contracts.try_restart_sidevms(&self.sidevm_spawner);
 
+        if block.block_number % CONTRACT_USAGE_REPORT_INTERVAL == 0 {
+            self.report_contract_usage();
+        }
+
         let contract_running = !self.contract_clusters.is_empty();
         benchmark::set_flag(benchmark::Flags::CONTRACT_RUNNING, contract_running);
     }
 
+    fn record_resource_usage(&mut self, usage: BTreeMap<AccountId, ResourceUsage>) {
+        for (address, usage) in usage {
+            let contract_id: ContractId = address.convert_to();
+            self.contract_usage
+                .entry(contract_id)
+                .or_default()
+                .merge(&ContractUsage {
+                    query_gas: usage.gas,
+                    http_requests: usage.http_requests,
+                    http_bytes: usage.http_bytes,
+                    sidevm_gas: 0,
+                });
+        }
+    }
+
+    /// Send the resource usage of contracts to the chain, grouped by cluster, which then would be
+    /// forwarded to the cluster's tokenomic driver to charge the contracts.
+    fn report_contract_usage(&mut self) {
+        let mut usage = std::mem::take(&mut self.contract_usage);
+        for (id, contract) in self.contracts.iter() {
+            let sidevm_gas = contract.take_sidevm_gas_consumed();
+            if sidevm_gas > 0 {
+                usage.entry(*id).or_default().sidevm_gas += sidevm_gas;
+            }
+        }
+        let contracts = &self.contracts;
+        let reports = contract_usage_reports(usage, |id| contracts.get(id).map(|c| c.cluster_id()));
+        for report in reports {
+            info!("Reporting contract resource usage: {:?}", report);
+            self.egress.push_message(&report);
+        }
+    }
+
     fn process_system_event(&mut self, block: &BlockInfo, event: &SystemEvent) {
         self.worker_state.process_event(
             block,
@@ -1581,6 +1635,7 @@ impl<Platform: pal::Platform> System<Platform> {
 
 impl<P: pal::Platform> System<P> {
     pub fn on_restored(&mut self) -> Result<()> {
+        self.contracts.publish_http_hostnames();
         self.contracts.try_restart_sidevms(&self.sidevm_spawner);
         self.check_retirement();
         Ok(())
@@ -1608,6 +1663,7 @@ impl<P: pal::Platform> System<P> {
             cluster,
             &self.sidevm_spawner,
         );
+        self.record_resource_usage(effects.resource_usage);
     }
 
     pub(crate) fn upload_sidevm_code(
@@ -1621,6 +1677,76 @@ impl<P: pal::Platform> System<P> {
             .ok_or_else(|| anyhow!("Contract not found"))?;
         contract.start_sidevm(&self.sidevm_spawner, SidevmCode::Code(code), true)
     }
+
+    /// Send an ink message queued by the sidevm of `contract_id` to the contract.
+    ///
+    /// The sidevm runs on every worker of the cluster, so the message goes via the egress of this
+    /// worker rather than the contract's, and the contract only executes the first copy of each
+    /// nonce, within `SIDEVM_MESSAGE_TTL` blocks.
+    pub(crate) fn push_sidevm_message(
+        &mut self,
+        contract_id: ContractId,
+        nonce: Vec<u8>,
+        message: Vec<u8>,
+    ) -> Result<(), sidevm::OcallError> {
+        use sidevm::OcallError;
+
+        let contract = self
+            .contracts
+            .get_mut(&contract_id)
+            .ok_or(OcallError::NotFound)?;
+        let nonce: BoundedVec<u8, ConstU32<32>> =
+            nonce.try_into().or(Err(OcallError::InvalidParameter))?;
+        contract.take_sidevm_message_quota()?;
+        let cluster = self
+            .contract_clusters
+            .get_cluster_mut(&contract.cluster_id())
+            .ok_or(OcallError::NotFound)?;
+        let expiry = self.block_number + contracts::pink::SIDEVM_MESSAGE_TTL;
+        let payload =
+            contracts::pink::sidevm_message_signing_payload(&contract_id, &nonce, expiry, &message);
+        let signature = cluster.key().sign(&payload).0;
+        let command = contracts::pink::Command::SidevmMessage {
+            nonce,
+            expiry,
+            message,
+            signature,
+        };
+        let contract_key = contract.ecdh_public_key();
+        SecretMessageChannel::new(&self.ecdh_key, &self.egress)
+            .bind_remote_key(Some(&contract_key))
+            .push_data(command.encode(), contract::command_topic(contract_id));
+        Ok(())
+    }
+}
+
+/// Group the usage of contracts into reports by cluster, each with at most
+/// `MAX_CONTRACT_USAGES_PER_REPORT` contracts.
+///
+/// Usage of contracts that have been destroyed would be dropped.
+fn contract_usage_reports(
+    usage: BTreeMap<ContractId, ContractUsage>,
+    cluster_of: impl Fn(&ContractId) -> Option<phala_mq::ContractClusterId>,
+) -> Vec<WorkerClusterReport> {
+    let mut by_cluster: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for (id, usage) in usage {
+        if usage.is_empty() {
+            continue;
+        }
+        if let Some(cluster) = cluster_of(&id) {
+            by_cluster.entry(cluster).or_default().push((id, usage));
+        }
+    }
+    let mut reports = vec![];
+    for (cluster, usages) in by_cluster {
+        for chunk in usages.chunks(MAX_CONTRACT_USAGES_PER_REPORT) {
+            reports.push(WorkerClusterReport::ContractUsageReport {
+                cluster,
+                usages: chunk.to_vec(),
+            });
+        }
+    }
+    reports
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -1853,6 +1979,63 @@ pub(crate) fn apply_pink_events(
                 let contract = get_contract!(&contract);
                 contract.set_weight(weight);
             }
+            PinkEvent::CrossClusterMessage {
+                contract: target_contract,
+                message,
+            } => {
+                let contract = get_contract!(&origin);
+                contract.push_cross_cluster_message(target_contract.convert_to(), message);
+            }
+            PinkEvent::SetHttpGateway { port, hostname } => {
+                let hostname = hostname.map(|name| name.to_ascii_lowercase());
+                if let Some(hostname) = &hostname {
+                    let valid = !hostname.is_empty()
+                        && hostname.len() <= 253
+                        && hostname
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+                    if !valid {
+                        error!("Invalid http gateway hostname {hostname:?} from {origin:?}");
+                        continue;
+                    }
+                    let origin_id: ContractId = origin.convert_to();
+                    if let Some(owner) = contracts.find_by_http_hostname(hostname) {
+                        if owner.id() != origin_id {
+                            error!(
+                                "Http gateway hostname {hostname:?} is already taken by {:?}",
+                                owner.id()
+                            );
+                            continue;
+                        }
+                    }
+                }
+                let gateway = port.map(|port| HttpGateway { port, hostname });
+                if !contracts.set_http_gateway(&origin.convert_to(), gateway) {
+                    error!(
+                        "Unknown contract sending pink event, address={:?}, cluster_id={:?}",
+                        origin, cluster_id
+                    );
+                }
+            }
+            PinkEvent::UpgradeSidevmAt {
+                contract: target_contract,
+                code_hash,
+            } => {
+                ensure_system!();
+                let vmid = sidevm::ShortId(target_contract.as_ref());
+                let target_contract = get_contract!(&target_contract);
+                let code_hash = code_hash.into();
+                let code = match cluster.get_resource(ResourceType::SidevmCode, &code_hash) {
+                    Some(code) => code,
+                    None => {
+                        error!(target: "sidevm", "[{vmid}] Upgrade sidevm failed: code {code_hash:?} not uploaded");
+                        continue;
+                    }
+                };
+                if let Err(err) = target_contract.upgrade_sidevm(code) {
+                    error!(target: "sidevm", "[{vmid}] Upgrade sidevm failed: {:?}", err);
+                }
+            }
         }
     }
 }
@@ -1962,3 +2145,49 @@ pub mod chain_state {
         chain_storage.get_decoded(&key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contract_usage_reports_are_grouped_and_chunked() {
+        let cluster_a = phala_mq::ContractClusterId::repeat_byte(0xa);
+        let cluster_b = phala_mq::ContractClusterId::repeat_byte(0xb);
+        let used = ContractUsage {
+            query_gas: 1,
+            ..Default::default()
+        };
+        let mut usage = BTreeMap::new();
+        for i in 0..(MAX_CONTRACT_USAGES_PER_REPORT + 1) as u32 {
+            let mut id = [0u8; 32];
+            id[..4].copy_from_slice(&i.to_be_bytes());
+            usage.insert(ContractId::from(id), used);
+        }
+        // Empty usages are not reported.
+        usage.insert(ContractId::repeat_byte(0xe0), ContractUsage::default());
+        usage.insert(ContractId::repeat_byte(0xe1), used);
+        // Destroyed contracts are not reported.
+        usage.insert(ContractId::repeat_byte(0xff), used);
+
+        let reports = contract_usage_reports(usage, |id| match id.0[0] {
+            0 => Some(cluster_a),
+            0xe0 | 0xe1 => Some(cluster_b),
+            _ => None,
+        });
+        let reports: Vec<_> = reports
+            .into_iter()
+            .map(|report| match report {
+                WorkerClusterReport::ContractUsageReport { cluster, usages } => (cluster, usages),
+                _ => panic!("Unexpected report"),
+            })
+            .collect();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].0, cluster_a);
+        assert_eq!(reports[0].1.len(), MAX_CONTRACT_USAGES_PER_REPORT);
+        assert_eq!(reports[1].0, cluster_a);
+        assert_eq!(reports[1].1.len(), 1);
+        assert_eq!(reports[2].0, cluster_b);
+        assert_eq!(reports[2].1, vec![(ContractId::repeat_byte(0xe1), used)]);
+    }
+}
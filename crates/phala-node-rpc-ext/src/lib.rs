@@ -2,15 +2,20 @@ use std::marker::PhantomData;
 use std::sync::Arc;
 
 use codec::Encode;
+use futures::{FutureExt, StreamExt};
 use jsonrpsee::{
     core::{async_trait, Error as JsonRpseeError, RpcResult},
     proc_macros::rpc,
     types::error::{CallError, ErrorObject},
-    RpcModule
+    types::SubscriptionResult,
+    RpcModule, SubscriptionSink,
 };
 use pallet_mq_runtime_api::MqApi;
 use sc_client_api::blockchain::{HeaderBackend, HeaderMetadata};
-use sc_client_api::{backend, Backend, BlockBackend, StorageProvider};
+use sc_client_api::{
+    backend, Backend, BlockBackend, BlockchainEvents, ProofProvider, StorageProvider,
+};
+use sc_rpc::SubscriptionTaskExecutor;
 use sc_transaction_pool_api::{InPoolTransaction, TransactionPool};
 use sp_api::{ApiExt, Core, ProvideRuntimeApi, StateBackend};
 use sp_runtime::traits::Header;
@@ -18,7 +23,9 @@ use sp_runtime::{generic::BlockId, traits::Block as BlockT};
 use std::fmt::Display;
 use storage_changes::Error as StorageChangesError;
 
-pub use storage_changes::{GetStorageChangesResponse, MakeInto, StorageChanges};
+pub use storage_changes::{
+    GetStorageChangesResponse, MakeInto, StorageChanges, StorageChangesNotification, StorageKey,
+};
 
 mod mq_seq;
 mod storage_changes;
@@ -26,16 +33,23 @@ mod storage_changes;
 /// Base code for all errors.
 const CUSTOM_RPC_ERROR: i32 = 10000;
 
+const ARCHIVE_MODE_REQUIRED: &str =
+    r#"Add "--pruning=archive" to the command line to enable this RPC"#;
+
 #[rpc(server)]
 pub trait NodeRpcExtApi<BlockHash> {
     /// Return the storage changes made by each block one by one from `from` to `to`(both inclusive).
     /// To get better performance, the client should limit the amount of requested block properly.
     /// 100 blocks for each call should be OK. REQUESTS FOR TOO LARGE NUMBER OF BLOCKS WILL BE REJECTED.
+    ///
+    /// If `prefixes` is given, only the changes of the keys starting with any of the prefixes are
+    /// returned, along with a storage proof of the returned changes at each block.
     #[method(name = "pha_getStorageChanges")]
     fn get_storage_changes(
         &self,
         from: BlockHash,
         to: BlockHash,
+        prefixes: Option<Vec<StorageKey>>,
     ) -> RpcResult<GetStorageChangesResponse>;
 
     /// Get storage changes made by given block.
@@ -43,6 +57,21 @@ pub trait NodeRpcExtApi<BlockHash> {
     #[method(name = "pha_getStorageChangesAt")]
     fn get_storage_changes_at(&self, block: BlockHash) -> RpcResult<String>;
 
+    /// Subscribe the storage changes made by each newly finalized block.
+    ///
+    /// The number of concurrent subscriptions is limited. A subscriber too slow to receive the
+    /// changes is unsubscribed.
+    ///
+    /// If `prefixes` is given, only the changes of the keys starting with any of the prefixes are
+    /// sent, along with a storage proof of the sent changes. The child storage changes are matched
+    /// by the child storage keys.
+    #[subscription(
+        name = "pha_subscribeStorageChanges" => "pha_storageChanges",
+        unsubscribe = "pha_unsubscribeStorageChanges",
+        item = StorageChangesNotification<BlockHash>
+    )]
+    fn subscribe_storage_changes(&self, prefixes: Option<Vec<StorageKey>>);
+
     /// Return the next mq sequence number for given sender which take the ready transactions in count.
     #[method(name = "pha_getMqNextSequence")]
     fn get_mq_seq(&self, sender_hex: String) -> RpcResult<u64>;
@@ -54,16 +83,26 @@ struct NodeRpcExt<BE, Block: BlockT, Client, P> {
     backend: Arc<BE>,
     is_archive_mode: bool,
     pool: Arc<P>,
+    executor: SubscriptionTaskExecutor,
+    storage_changes_hub: Arc<storage_changes::StorageChangesHub<Block::Hash>>,
     _phantom: PhantomData<Block>,
 }
 
 impl<BE, Block: BlockT, Client, P> NodeRpcExt<BE, Block, Client, P> {
-    fn new(client: Arc<Client>, backend: Arc<BE>, is_archive_mode: bool, pool: Arc<P>) -> Self {
+    fn new(
+        client: Arc<Client>,
+        backend: Arc<BE>,
+        is_archive_mode: bool,
+        pool: Arc<P>,
+        executor: SubscriptionTaskExecutor,
+    ) -> Self {
         Self {
             client,
             backend,
             is_archive_mode,
             pool,
+            executor,
+            storage_changes_hub: Arc::new(storage_changes::StorageChangesHub::new()),
             _phantom: Default::default(),
         }
     }
@@ -78,6 +117,8 @@ where
         + HeaderBackend<Block>
         + BlockBackend<Block>
         + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + BlockchainEvents<Block>
+        + ProofProvider<Block>
         + ProvideRuntimeApi<Block>,
     Client::Api:
         sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
@@ -90,10 +131,11 @@ where
         &self,
         from: Block::Hash,
         to: Block::Hash,
+        prefixes: Option<Vec<StorageKey>>,
     ) -> RpcResult<GetStorageChangesResponse> {
         if !self.is_archive_mode {
             Err(JsonRpseeError::from(StorageChangesError::Unavailable(
-                r#"Add "--pruning=archive" to the command line to enable this RPC"#.into(),
+                ARCHIVE_MODE_REQUIRED.into(),
             )))
         } else {
             let result = storage_changes::get_storage_changes(
@@ -101,6 +143,7 @@ where
                 self.backend.as_ref(),
                 from,
                 to,
+                prefixes.as_deref(),
             );
 
             Ok(result?)
@@ -108,12 +151,34 @@ where
     }
 
     fn get_storage_changes_at(&self, block: Block::Hash) -> RpcResult<String> {
-        let changes = self.get_storage_changes(block, block)?;
+        let changes = self.get_storage_changes(block, block, None)?;
         // get_storage_changes never returns empty vec without error.
         let encoded = changes[0].encode();
         Ok(impl_serde::serialize::to_hex(&encoded, false))
     }
 
+    fn subscribe_storage_changes(
+        &self,
+        mut sink: SubscriptionSink,
+        prefixes: Option<Vec<StorageKey>>,
+    ) -> SubscriptionResult {
+        if !self.is_archive_mode {
+            let _ = sink.reject(JsonRpseeError::from(StorageChangesError::Unavailable(
+                ARCHIVE_MODE_REQUIRED.into(),
+            )));
+            return Ok(());
+        }
+        storage_changes::subscribe_storage_changes::<Client, BE, Block>(
+            self.storage_changes_hub.clone(),
+            self.client.clone(),
+            self.backend.clone(),
+            self.executor.clone(),
+            sink,
+            prefixes,
+        );
+        Ok(())
+    }
+
     fn get_mq_seq(&self, sender_hex: String) -> RpcResult<u64> {
         let result = mq_seq::get_mq_seq(&*self.client, &self.pool, sender_hex);
 
@@ -127,12 +192,15 @@ pub fn extend_rpc<Client, BE, Block, P>(
     backend: Arc<BE>,
     is_archive_mode: bool,
     pool: Arc<P>,
+    executor: SubscriptionTaskExecutor,
 ) where
     BE: Backend<Block> + 'static,
     Client: StorageProvider<Block, BE>
         + HeaderBackend<Block>
         + BlockBackend<Block>
         + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + BlockchainEvents<Block>
+        + ProofProvider<Block>
         + ProvideRuntimeApi<Block>
         + 'static,
     Block: BlockT + 'static,
@@ -148,6 +216,7 @@ pub fn extend_rpc<Client, BE, Block, P>(
             backend,
             is_archive_mode,
             pool,
+            executor,
         ).into_rpc(),
     ).expect("Initialize Phala node RPC ext failed.");
 }
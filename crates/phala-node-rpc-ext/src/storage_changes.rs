@@ -1,6 +1,10 @@
 use super::*;
 pub use ext_types::*;
+use futures::channel::{mpsc, oneshot};
 use rayon::prelude::*;
+use sp_core::storage::ChildInfo;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
 
 /// State RPC errors.
 #[derive(Debug, thiserror::Error)]
@@ -52,6 +56,7 @@ pub(super) fn get_storage_changes<Client, BE, Block>(
     backend: &BE,
     from: Block::Hash,
     to: Block::Hash,
+    prefixes: Option<&[StorageKey]>,
 ) -> Result<GetStorageChangesResponse, Error>
 where
     BE: Backend<Block>,
@@ -59,6 +64,7 @@ where
         + HeaderBackend<Block>
         + BlockBackend<Block>
         + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + ProofProvider<Block>
         + ProvideRuntimeApi<Block>,
     Block: BlockT + 'static,
     Client::Api:
@@ -114,14 +120,18 @@ where
                 let state = backend
                     .state_at(id)
                     .map_err(|e| Error::invalid_block(id, e))?;
-                return Ok(StorageChanges {
-                    main_storage_changes: state
-                        .pairs()
-                        .into_iter()
-                        .map(|(k, v)| (StorageKey(k), Some(StorageKey(v))))
-                        .collect(),
-                    child_storage_changes: vec![],
-                });
+                return Ok((
+                    id,
+                    StorageChanges {
+                        main_storage_changes: state
+                            .pairs()
+                            .into_iter()
+                            .map(|(k, v)| (StorageKey(k), Some(StorageKey(v))))
+                            .collect(),
+                        child_storage_changes: vec![],
+                        proof: None,
+                    },
+                ));
             }
 
             let extrinsics = client
@@ -147,10 +157,292 @@ where
                 .into_storage_changes(&state, parent_hash)
                 .map_err(|e| Error::invalid_block(parent_id, e))?;
 
-            Ok(StorageChanges {
-                main_storage_changes: storage_changes.main_storage_changes.into_(),
-                child_storage_changes: storage_changes.child_storage_changes.into_(),
-            })
+            Ok((
+                id,
+                StorageChanges {
+                    main_storage_changes: storage_changes.main_storage_changes.into_(),
+                    child_storage_changes: storage_changes.child_storage_changes.into_(),
+                    proof: None,
+                },
+            ))
+        })
+        .map(|result| {
+            let (id, changes) = result?;
+            match prefixes {
+                Some(prefixes) => filter_changes(client, id, changes, prefixes),
+                None => Ok(changes),
+            }
         })
         .collect()
 }
+
+/// Keeps the changes of the keys starting with any of the `prefixes`, along with a proof of them.
+fn filter_changes<Client, Block>(
+    client: &Client,
+    id: BlockId<Block>,
+    mut changes: StorageChanges,
+    prefixes: &[StorageKey],
+) -> Result<StorageChanges, Error>
+where
+    Client: ProofProvider<Block>,
+    Block: BlockT,
+{
+    changes.retain_prefixes(prefixes);
+    changes.proof = Some(prove_changes(client, id, &changes)?);
+    Ok(changes)
+}
+
+/// Generates a proof of the given changes against the post state of the block.
+///
+/// The deleted keys are proved to be absent.
+fn prove_changes<Client, Block>(
+    client: &Client,
+    id: BlockId<Block>,
+    changes: &StorageChanges,
+) -> Result<Vec<StorageValue>, Error>
+where
+    Client: ProofProvider<Block>,
+    Block: BlockT,
+{
+    let mut nodes = BTreeSet::new();
+    let mut keys = changes
+        .main_storage_changes
+        .iter()
+        .map(|(key, _)| key.0.as_slice());
+    let proof = client
+        .read_proof(&id, &mut keys)
+        .map_err(|e| Error::invalid_block(id, e))?;
+    nodes.extend(proof.into_iter_nodes());
+    for (storage_key, child_changes) in changes.child_storage_changes.iter() {
+        let child_info = ChildInfo::new_default(&storage_key.0);
+        let mut keys = child_changes.iter().map(|(key, _)| key.0.as_slice());
+        // The child trie root in the main trie is included in the child proof.
+        let proof = client
+            .read_child_proof(&id, &child_info, &mut keys)
+            .map_err(|e| Error::invalid_block(id, e))?;
+        nodes.extend(proof.into_iter_nodes());
+    }
+    Ok(nodes.into_iter().map(StorageKey).collect())
+}
+
+/// Max number of concurrent storage changes subscriptions.
+const MAX_SUBSCRIBERS: usize = 16;
+/// Number of notifications buffered for a subscriber. A subscriber falling further behind is
+/// dropped.
+const SUBSCRIBER_BUFFER: usize = 64;
+
+type SharedNotification<Hash> = Result<Arc<StorageChangesNotification<Hash>>, Arc<Error>>;
+
+/// Computes the storage changes of each newly finalized block once for all the subscribers.
+///
+/// The blocks are re-executed on a blocking thread by a single task, which runs as long as there
+/// are subscribers.
+pub(super) struct StorageChangesHub<Hash> {
+    state: Mutex<HubState<Hash>>,
+}
+
+struct HubState<Hash> {
+    subscribers: Vec<mpsc::Sender<SharedNotification<Hash>>>,
+    running: bool,
+}
+
+impl<Hash> StorageChangesHub<Hash> {
+    pub(super) fn new() -> Self {
+        Self {
+            state: Mutex::new(HubState {
+                subscribers: vec![],
+                running: false,
+            }),
+        }
+    }
+
+    /// Adds a subscriber. Returns the notifications receiver and whether the caller should start
+    /// the computing task.
+    fn subscribe(&self) -> Result<(mpsc::Receiver<SharedNotification<Hash>>, bool), Error> {
+        let mut state = self.state.lock().unwrap();
+        // The slots of the closed subscriptions are released on the next notification.
+        if state.subscribers.len() >= MAX_SUBSCRIBERS {
+            return Err(Error::ResourceLimited(
+                "Too many storage changes subscriptions".into(),
+            ));
+        }
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        state.subscribers.push(tx);
+        let start = !state.running;
+        state.running = true;
+        Ok((rx, start))
+    }
+
+    /// Sends the notification to the subscribers. Returns whether the computing task should keep
+    /// running.
+    fn broadcast(&self, notification: SharedNotification<Hash>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state
+            .subscribers
+            .retain_mut(|tx| tx.try_send(notification.clone()).is_ok());
+        state.running = !state.subscribers.is_empty();
+        state.running
+    }
+}
+
+/// Runs `f` on a blocking thread.
+async fn run_blocking<T, F>(executor: &SubscriptionTaskExecutor, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    executor.spawn_blocking(
+        "phala-rpc-storage-changes-compute",
+        Some("rpc"),
+        async move {
+            let _ = tx.send(f());
+        }
+        .boxed(),
+    );
+    rx.await
+        .map_err(|_| Error::Unavailable("The computing task was cancelled".into()))?
+}
+
+/// Computes the storage changes of each newly finalized block and sends them to the subscribers
+/// of the hub, until there is no subscriber left.
+async fn compute_storage_changes<Client, BE, Block>(
+    hub: Arc<StorageChangesHub<Block::Hash>>,
+    client: Arc<Client>,
+    backend: Arc<BE>,
+    executor: SubscriptionTaskExecutor,
+) where
+    BE: Backend<Block> + 'static,
+    Client: StorageProvider<Block, BE>
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + BlockchainEvents<Block>
+        + ProofProvider<Block>
+        + ProvideRuntimeApi<Block>
+        + 'static,
+    Block: BlockT + 'static,
+    Client::Api:
+        sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    let mut hashes = client
+        .finality_notification_stream()
+        .flat_map(|notification| {
+            // A notification might finalize several blocks at once, the tree route contains the
+            // ones between the previous finalized block and the new one.
+            let hashes: Vec<_> = notification
+                .tree_route
+                .iter()
+                .copied()
+                .chain(std::iter::once(notification.hash))
+                .collect();
+            futures::stream::iter(hashes)
+        });
+    while let Some(hash) = hashes.next().await {
+        let client = client.clone();
+        let backend = backend.clone();
+        let notification = run_blocking(&executor, move || {
+            let id = BlockId::<Block>::Hash(hash);
+            let block_number = client
+                .number(hash)
+                .map_err(|e| Error::invalid_block(id, e))?
+                .ok_or_else(|| Error::invalid_block(id, "header not found"))?
+                .into();
+            let changes = get_storage_changes::<Client, BE, Block>(
+                client.as_ref(),
+                backend.as_ref(),
+                hash,
+                hash,
+                None,
+            )?
+            .pop()
+            .ok_or_else(|| Error::invalid_block(id, "no storage changes"))?;
+            Ok(StorageChangesNotification {
+                block_hash: hash,
+                block_number,
+                changes,
+            })
+        })
+        .await;
+        if !hub.broadcast(notification.map(Arc::new).map_err(Arc::new)) {
+            break;
+        }
+    }
+    log::debug!("Storage changes computing stopped");
+}
+
+/// Pipes the storage changes of each newly finalized block to the subscriber.
+///
+/// The changes are computed once by the hub. The filtering by prefixes and the proofs are done
+/// for each subscriber, on a blocking thread.
+pub(super) fn subscribe_storage_changes<Client, BE, Block>(
+    hub: Arc<StorageChangesHub<Block::Hash>>,
+    client: Arc<Client>,
+    backend: Arc<BE>,
+    executor: SubscriptionTaskExecutor,
+    mut sink: SubscriptionSink,
+    prefixes: Option<Vec<StorageKey>>,
+) where
+    BE: Backend<Block> + 'static,
+    Client: StorageProvider<Block, BE>
+        + HeaderBackend<Block>
+        + BlockBackend<Block>
+        + HeaderMetadata<Block, Error = sp_blockchain::Error>
+        + BlockchainEvents<Block>
+        + ProofProvider<Block>
+        + ProvideRuntimeApi<Block>
+        + 'static,
+    Block: BlockT + 'static,
+    Client::Api:
+        sp_api::Metadata<Block> + ApiExt<Block, StateBackend = backend::StateBackendFor<BE, Block>>,
+    <<Block as BlockT>::Header as Header>::Number: Into<u64>,
+{
+    let (receiver, start) = match hub.subscribe() {
+        Ok(subscribed) => subscribed,
+        Err(err) => {
+            let _ = sink.reject(JsonRpseeError::from(err));
+            return;
+        }
+    };
+    if start {
+        let fut = compute_storage_changes::<Client, BE, Block>(
+            hub,
+            client.clone(),
+            backend,
+            executor.clone(),
+        );
+        executor.spawn("phala-rpc-storage-changes-hub", Some("rpc"), fut.boxed());
+    }
+    let prefixes = prefixes.map(Arc::new);
+    let task_executor = executor.clone();
+    let stream = receiver.then(move |notification| {
+        let client = client.clone();
+        let executor = task_executor.clone();
+        let prefixes = prefixes.clone();
+        async move {
+            let notification = notification?;
+            let prefixes = match prefixes {
+                Some(prefixes) => prefixes,
+                None => return Ok((*notification).clone()),
+            };
+            run_blocking(&executor, move || {
+                let id = BlockId::<Block>::Hash(notification.block_hash);
+                let changes =
+                    filter_changes(client.as_ref(), id, notification.changes.clone(), &prefixes)?;
+                Ok(StorageChangesNotification {
+                    block_hash: notification.block_hash,
+                    block_number: notification.block_number,
+                    changes,
+                })
+            })
+            .await
+            .map_err(Arc::new)
+        }
+    });
+    let fut = async move {
+        let closed = sink.pipe_from_try_stream(Box::pin(stream)).await;
+        log::debug!("Storage changes subscription closed: {:?}", closed);
+    };
+    executor.spawn("phala-rpc-storage-changes", Some("rpc"), fut.boxed());
+}
@@ -22,11 +22,41 @@ pub struct StorageChanges {
     pub main_storage_changes: StorageCollection<StorageKey, StorageValue>,
     /// All changes to the child storages.
     pub child_storage_changes: ChildStorageCollection<StorageKey, StorageValue>,
+    /// Trie nodes proving the changes against the state root of the block.
+    ///
+    /// Only present if the changes are filtered by key prefixes, in which case the state root can
+    /// no longer be recalculated from the changes. The proof only shows that the returned changes
+    /// are in the state of the block, not that there are no other changes under the prefixes, so
+    /// the filtered changes can't be used to follow the chain state.
+    #[codec(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<Vec<StorageValue>>,
 }
 
 /// Response for the `pha_getStorageChanges` RPC.
 pub type GetStorageChangesResponse = Vec<StorageChanges>;
 
+/// Item of the `pha_subscribeStorageChanges` subscription.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageChangesNotification<Hash> {
+    pub block_hash: Hash,
+    pub block_number: u64,
+    pub changes: StorageChanges,
+}
+
+impl StorageChanges {
+    /// Keeps only the changes of the keys starting with any of the given prefixes.
+    ///
+    /// The child storage changes are matched by the child storage keys. The proof is left
+    /// untouched.
+    pub fn retain_prefixes(&mut self, prefixes: &[StorageKey]) {
+        let matches = |key: &StorageKey| prefixes.iter().any(|prefix| key.0.starts_with(&prefix.0));
+        self.main_storage_changes.retain(|(key, _)| matches(key));
+        self.child_storage_changes.retain(|(key, _)| matches(key));
+    }
+}
+
 // Stuffs to convert ChildStorageCollection and StorageCollection types,
 // in order to dump the keys values into hex strings instead of list of dec numbers.
 pub trait MakeInto<T>: Sized {
@@ -66,3 +96,72 @@ impl<F: MakeInto<T>, T> MakeInto<Vec<T>> for Vec<F> {
         self.into_iter().map(|v| v.into_()).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(bytes: &[u8]) -> StorageKey {
+        StorageKey(bytes.to_vec())
+    }
+
+    fn main_keys(changes: &StorageChanges) -> Vec<&[u8]> {
+        changes
+            .main_storage_changes
+            .iter()
+            .map(|(key, _)| &key.0[..])
+            .collect()
+    }
+
+    fn child_keys(changes: &StorageChanges) -> Vec<&[u8]> {
+        changes
+            .child_storage_changes
+            .iter()
+            .map(|(key, _)| &key.0[..])
+            .collect()
+    }
+
+    fn changes() -> StorageChanges {
+        StorageChanges {
+            main_storage_changes: vec![
+                (key(b"aa1"), Some(key(b"v1"))),
+                (key(b"ab2"), None),
+                (key(b"b3"), Some(key(b"v3"))),
+                (key(b"c4"), Some(key(b"v4"))),
+            ],
+            child_storage_changes: vec![
+                (key(b"a-child"), vec![(key(b"x"), Some(key(b"y")))]),
+                (key(b"c-child"), vec![(key(b"z"), None)]),
+            ],
+            proof: Some(vec![key(b"node")]),
+        }
+    }
+
+    #[test]
+    fn retain_prefixes_filters_main_and_child_changes() {
+        let mut changes = changes();
+        changes.retain_prefixes(&[key(b"a"), key(b"c4")]);
+        assert_eq!(main_keys(&changes), vec![&b"aa1"[..], b"ab2", b"c4"]);
+        assert_eq!(child_keys(&changes), vec![&b"a-child"[..]]);
+        // The entries of a retained child storage are kept as a whole.
+        assert_eq!(changes.child_storage_changes[0].1.len(), 1);
+        assert_eq!(changes.proof.map(|p| p.len()), Some(1));
+    }
+
+    #[test]
+    fn retain_prefixes_with_edge_cases() {
+        let mut changes_all = changes();
+        changes_all.retain_prefixes(&[key(b"")]);
+        assert_eq!(main_keys(&changes_all).len(), 4);
+        assert_eq!(child_keys(&changes_all).len(), 2);
+
+        let mut changes_none = changes();
+        changes_none.retain_prefixes(&[]);
+        assert!(changes_none.main_storage_changes.is_empty());
+        assert!(changes_none.child_storage_changes.is_empty());
+
+        let mut longer = changes();
+        longer.retain_prefixes(&[key(b"aa1-longer")]);
+        assert!(longer.main_storage_changes.is_empty());
+    }
+}
@@ -44,7 +44,7 @@ pub mod messaging {
     use core::fmt::Debug;
     use scale_info::TypeInfo;
 
-    use super::{ContractClusterId, ContractInfo};
+    use super::{ContractClusterId, ContractId, ContractInfo};
     use crate::messaging::EncryptedKey;
     use crate::{ClusterPublicKey, WorkerIdentity, WorkerPublicKey};
     use phala_mq::bind_topic;
@@ -92,6 +92,96 @@ pub mod messaging {
         ClusterDeploymentFailed {
             id: ContractClusterId,
         },
+        /// Resources consumed by contracts in the cluster since the last report of the worker.
+        ContractUsageReport {
+            cluster: ContractClusterId,
+            usages: Vec<(ContractId, ContractUsage)>,
+        },
+    }
+
+    /// Max number of contracts in a `ContractUsageReport`.
+    pub const MAX_CONTRACT_USAGES_PER_REPORT: usize = 64;
+
+    /// Resources consumed by a contract on a worker.
+    ///
+    /// The encoding is identical to `pink_extension::system::ResourceUsage`, which is how the
+    /// report is forwarded to the cluster's tokenomic driver.
+    #[derive(Encode, Decode, Debug, Default, Clone, Copy, PartialEq, Eq, TypeInfo)]
+    pub struct ContractUsage {
+        /// Gas consumed by queries.
+        pub query_gas: u64,
+        /// Number of `http_request` calls.
+        pub http_requests: u32,
+        /// Bytes sent and received by `http_request` calls.
+        pub http_bytes: u64,
+        /// Gas consumed by the sidevm instance attached to the contract.
+        pub sidevm_gas: u64,
+    }
+
+    impl ContractUsage {
+        pub fn is_empty(&self) -> bool {
+            self == &Self::default()
+        }
+
+        pub fn merge(&mut self, other: &ContractUsage) {
+            self.query_gas = self.query_gas.saturating_add(other.query_gas);
+            self.http_requests = self.http_requests.saturating_add(other.http_requests);
+            self.http_bytes = self.http_bytes.saturating_add(other.http_bytes);
+            self.sidevm_gas = self.sidevm_gas.saturating_add(other.sidevm_gas);
+        }
+
+        /// The usage without the sidevm gas, which is measured identically by every worker
+        /// running the sidevm instance.
+        pub fn without_sidevm_gas(self) -> Self {
+            Self {
+                sidevm_gas: 0,
+                ..self
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::ContractUsage;
+
+        #[test]
+        fn contract_usage_merge() {
+            let mut usage = ContractUsage::default();
+            assert!(usage.is_empty());
+            let other = ContractUsage {
+                query_gas: 1,
+                http_requests: 2,
+                http_bytes: 3,
+                sidevm_gas: u64::MAX,
+            };
+            usage.merge(&other);
+            usage.merge(&other);
+            assert_eq!(
+                usage,
+                ContractUsage {
+                    query_gas: 2,
+                    http_requests: 4,
+                    http_bytes: 6,
+                    sidevm_gas: u64::MAX,
+                }
+            );
+            assert!(!usage.is_empty());
+        }
+
+        #[test]
+        fn contract_usage_without_sidevm_gas() {
+            let sidevm_only = ContractUsage {
+                sidevm_gas: 10,
+                ..Default::default()
+            };
+            assert!(sidevm_only.without_sidevm_gas().is_empty());
+            let usage = ContractUsage {
+                query_gas: 1,
+                sidevm_gas: 10,
+                ..Default::default()
+            };
+            assert_eq!(usage.without_sidevm_gas().query_gas, 1);
+        }
     }
 
     #[derive(Encode, Decode, Clone, PartialEq, Eq, Debug)]
@@ -1,7 +1,10 @@
 use parity_scale_codec::Encode;
+use phala_types::contract::{messaging::ResourceType, CodeIndex, ContractClusterId};
 use phala_types::messaging::SignedMessage;
 use subxt::{tx::StaticTxPayload, utils::Encoded};
 
+use crate::Hash;
+
 pub fn register_worker(
     pruntime_info: Vec<u8>,
     attestation: Vec<u8>,
@@ -39,3 +42,39 @@ pub fn sync_offchain_message(message: SignedMessage) -> StaticTxPayload<SignedMe
     )
     .unvalidated()
 }
+
+pub fn cluster_upload_resource(
+    cluster_id: ContractClusterId,
+    resource_type: ResourceType,
+    resource_data: Vec<u8>,
+) -> StaticTxPayload<Encoded> {
+    let args = (cluster_id, resource_type, resource_data).encode();
+    StaticTxPayload::new(
+        "PhalaFatContracts",
+        "cluster_upload_resource",
+        Encoded(args),
+        Default::default(),
+    )
+    .unvalidated()
+}
+
+pub fn instantiate_contract(
+    code_index: CodeIndex<Hash>,
+    data: Vec<u8>,
+    salt: Vec<u8>,
+    cluster_id: ContractClusterId,
+) -> StaticTxPayload<Encoded> {
+    let args = (code_index, data, salt, cluster_id).encode();
+    StaticTxPayload::new(
+        "PhalaFatContracts",
+        "instantiate_contract",
+        Encoded(args),
+        Default::default(),
+    )
+    .unvalidated()
+}
+
+pub fn push_message(destination: Vec<u8>, payload: Vec<u8>) -> StaticTxPayload<Encoded> {
+    let args = (destination, payload).encode();
+    StaticTxPayload::new("PhalaMq", "push_message", Encoded(args), Default::default()).unvalidated()
+}
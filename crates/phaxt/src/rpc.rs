@@ -41,6 +41,26 @@ impl<'a, T: Config> ExtraRpcClient<'a, T> {
             .map_err(Into::into)
     }
 
+    /// Query storage changes of the keys starting with any of the `prefixes`, along with the
+    /// storage proofs of the changes
+    pub async fn get_storage_changes_with_prefixes(
+        &self,
+        from: &T::Hash,
+        to: &T::Hash,
+        prefixes: &[Vec<u8>],
+    ) -> Result<GetStorageChangesResponse, Error> {
+        let prefixes: Vec<_> = prefixes.iter().map(|p| StorageKey(p.clone())).collect();
+        let params = rpc_params![
+            to_json_value(from)?,
+            to_json_value(to)?,
+            to_json_value(prefixes)?
+        ];
+        self.client
+            .request("pha_getStorageChanges", params)
+            .await
+            .map_err(Into::into)
+    }
+
     /// Returns the keys with prefix, leave empty to get all the keys
     pub async fn storage_pairs(
         &self,
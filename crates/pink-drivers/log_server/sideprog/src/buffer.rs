@@ -1,20 +1,152 @@
+use scale::{Decode, Encode};
 use sidevm::env::messages::{AccountId, SystemMessage, H256};
-use std::{collections::VecDeque, ops::Deref};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Deref,
+};
 
 pub struct Buffer {
     next_sequence: u64,
     capacity: usize,
+    /// Max bytes a single contract can occupy in the buffer. 0 means no quota.
+    contract_quota: usize,
     current_size: usize,
+    contract_usage: BTreeMap<String, usize>,
     records: VecDeque<Record>,
 }
 
 struct Record {
     contract_id: String,
+    meta: Meta,
     message: Message,
     size: usize,
     sequence: u64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode, serde::Deserialize)]
+pub enum RecordType {
+    #[serde(alias = "PinkLog")]
+    Log,
+    #[serde(alias = "PinkEvent")]
+    Event,
+    #[serde(alias = "PinkMessageOutput")]
+    MessageOutput,
+    Unknown,
+}
+
+/// The fields of a record that can be used as query conditions.
+#[derive(Clone, Copy, Encode, Decode)]
+struct Meta {
+    record_type: RecordType,
+    block_number: u32,
+    level: u8,
+    timestamp_ms: Option<u64>,
+}
+
+impl From<&SystemMessage> for Meta {
+    fn from(message: &SystemMessage) -> Self {
+        let (record_type, block_number, level, timestamp_ms) = match message {
+            SystemMessage::PinkLog {
+                block_number,
+                level,
+                timestamp_ms,
+                ..
+            } => (RecordType::Log, *block_number, *level, Some(*timestamp_ms)),
+            SystemMessage::PinkEvent { block_number, .. } => {
+                (RecordType::Event, *block_number, 0, None)
+            }
+            SystemMessage::PinkMessageOutput { block_number, .. } => {
+                (RecordType::MessageOutput, *block_number, 0, None)
+            }
+            _ => (RecordType::Unknown, 0, 0, None),
+        };
+        Self {
+            record_type,
+            block_number,
+            level,
+            timestamp_ms,
+        }
+    }
+}
+
+/// Conditions to select records from the buffer. Empty conditions match everything.
+#[derive(Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Filter {
+    /// Hex encoded contract id with 0x prefix.
+    #[serde(default)]
+    pub contract: String,
+    /// Record types to be selected.
+    #[serde(default)]
+    pub types: Vec<RecordType>,
+    /// Select logs with level less or equal to this value (1=Error, ..., 5=Trace). Non-log
+    /// records are not affected by this condition.
+    #[serde(default)]
+    pub max_level: Option<u8>,
+    #[serde(default)]
+    pub from_block: Option<u32>,
+    #[serde(default)]
+    pub to_block: Option<u32>,
+    /// Time range in milliseconds. Only logs carry a timestamp, so other records are excluded
+    /// once a time condition is given.
+    #[serde(default)]
+    pub from_time: Option<u64>,
+    #[serde(default)]
+    pub to_time: Option<u64>,
+}
+
+impl Filter {
+    fn matches(&self, rec: &Record) -> bool {
+        let meta = &rec.meta;
+        if !self.contract.is_empty() && rec.contract_id != self.contract {
+            return false;
+        }
+        if !self.types.is_empty() && !self.types.contains(&meta.record_type) {
+            return false;
+        }
+        if let Some(max_level) = self.max_level {
+            if meta.record_type == RecordType::Log && meta.level > max_level {
+                return false;
+            }
+        }
+        if matches!(self.from_block, Some(from) if meta.block_number < from) {
+            return false;
+        }
+        if matches!(self.to_block, Some(to) if meta.block_number > to) {
+            return false;
+        }
+        if self.from_time.is_some() || self.to_time.is_some() {
+            let timestamp = match meta.timestamp_ms {
+                Some(timestamp) => timestamp,
+                None => return false,
+            };
+            if matches!(self.from_time, Some(from) if timestamp < from) {
+                return false;
+            }
+            if matches!(self.to_time, Some(to) if timestamp > to) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The persisted form of a record.
+#[derive(Encode, Decode)]
+struct SavedRecord {
+    contract_id: String,
+    meta: Meta,
+    sequence: u64,
+    size: u32,
+    encoded: String,
+}
+
+#[derive(Encode, Decode)]
+struct Snapshot {
+    next_sequence: u64,
+    records: Vec<SavedRecord>,
+}
+
 enum Message {
     Origin(SerMessage),
     Encoded(String),
@@ -181,43 +313,92 @@ fn contract_id_of(sysmessage: &SystemMessage) -> String {
 }
 
 impl Buffer {
-    pub fn new(capacity: usize) -> Self {
+    pub fn new(capacity: usize, contract_quota: usize) -> Self {
         Buffer {
             next_sequence: 0,
             capacity,
+            contract_quota,
             current_size: 0,
+            contract_usage: Default::default(),
             records: Default::default(),
         }
     }
 
     pub fn push(&mut self, message: SystemMessage) {
         let contract_id = contract_id_of(&message);
+        let meta = Meta::from(&message);
         let mut message: SerMessage = message.into();
         let mut size = message.size();
-        if size > self.capacity {
+        if size > self.capacity || (self.contract_quota > 0 && size > self.contract_quota) {
             message = SerMessage::TooLarge;
             size = message.size();
         }
-        while self.capacity < self.current_size + size {
-            self.pop();
-        }
-        self.current_size += size;
-        self.records.push_back(Record {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.insert(Record {
             contract_id,
+            meta,
             message: Message::Origin(message),
             size,
-            sequence: self.next_sequence,
+            sequence,
         });
-        self.next_sequence += 1;
+    }
+
+    fn insert(&mut self, record: Record) {
+        if self.contract_quota > 0 {
+            while self.usage_of(&record.contract_id) + record.size > self.contract_quota {
+                if self.evict_oldest_of(&record.contract_id).is_none() {
+                    break;
+                }
+            }
+        }
+        while self.capacity < self.current_size + record.size {
+            if self.pop().is_none() {
+                break;
+            }
+        }
+        self.current_size += record.size;
+        *self
+            .contract_usage
+            .entry(record.contract_id.clone())
+            .or_default() += record.size;
+        self.records.push_back(record);
+    }
+
+    fn usage_of(&self, contract_id: &str) -> usize {
+        self.contract_usage.get(contract_id).copied().unwrap_or(0)
+    }
+
+    fn evict_oldest_of(&mut self, contract_id: &str) -> Option<Record> {
+        let pos = self
+            .records
+            .iter()
+            .position(|rec| rec.contract_id == contract_id)?;
+        let rec = self.records.remove(pos)?;
+        self.release(&rec);
+        Some(rec)
     }
 
     fn pop(&mut self) -> Option<Record> {
         let rec = self.records.pop_front()?;
-        self.current_size -= rec.size;
+        self.release(&rec);
         Some(rec)
     }
 
-    pub fn get_records(&mut self, contract: &str, from: u64, count: u64) -> String {
+    fn release(&mut self, rec: &Record) {
+        self.current_size -= rec.size;
+        if let Some(usage) = self.contract_usage.get_mut(&rec.contract_id) {
+            *usage -= rec.size;
+            if *usage == 0 {
+                self.contract_usage.remove(&rec.contract_id);
+            }
+        }
+    }
+
+    /// Get records matching the filter, starting from sequence `from`.
+    ///
+    /// The returned `next` can be used as the `from` of the next query to continue reading.
+    pub fn get_records(&mut self, filter: &Filter, from: u64, count: u64) -> String {
         let count = if count == 0 { u64::MAX } else { count };
         let mut result: String = "{\"records\":[".into();
         let mut n = 0_u64;
@@ -227,7 +408,7 @@ impl Buffer {
             if rec.sequence < from {
                 continue;
             }
-            if contract.is_empty() || rec.contract_id == contract {
+            if filter.matches(rec) {
                 if n > 0 {
                     result.push_str(",");
                 }
@@ -241,6 +422,62 @@ impl Buffer {
         result.push_str(&format!(r#"],"next":{}}}"#, next_seq));
         result
     }
+
+    /// Serialize the buffer so that it can be saved to the local cache.
+    /// The sequence to be assigned to the next pushed record, which changes on every push.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Encode the records into a snapshot of at most `max_size` bytes, dropping the oldest
+    /// records that don't fit.
+    pub fn snapshot(&mut self, max_size: usize) -> Vec<u8> {
+        // The sequence number and the length prefix of the records.
+        let mut size = 8 + 5;
+        let mut records = vec![];
+        for rec in self.records.iter_mut().rev() {
+            let saved = SavedRecord {
+                contract_id: rec.contract_id.clone(),
+                meta: rec.meta,
+                sequence: rec.sequence,
+                size: rec.size as u32,
+                encoded: rec.encoded().to_string(),
+            };
+            size += saved.encoded_size();
+            if size > max_size {
+                break;
+            }
+            records.push(saved);
+        }
+        records.reverse();
+        Snapshot {
+            next_sequence: self.next_sequence,
+            records,
+        }
+        .encode()
+    }
+
+    /// Restore records from a snapshot created by [`Buffer::snapshot`].
+    ///
+    /// Records are re-inserted under the current capacity and quota, so older ones may be
+    /// dropped if the buffer has been configured smaller than before.
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), scale::Error> {
+        let snapshot = Snapshot::decode(&mut &data[..])?;
+        self.records.clear();
+        self.contract_usage.clear();
+        self.current_size = 0;
+        self.next_sequence = snapshot.next_sequence;
+        for rec in snapshot.records {
+            self.insert(Record {
+                contract_id: rec.contract_id,
+                meta: rec.meta,
+                message: Message::Encoded(rec.encoded),
+                size: rec.size as usize,
+                sequence: rec.sequence,
+            });
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -252,8 +489,25 @@ mod tests {
         serde_json::to_string_pretty(&v).unwrap()
     }
 
+    fn by_contract(contract: &AccountId) -> Filter {
+        Filter {
+            contract: hex(contract),
+            ..Default::default()
+        }
+    }
+
+    fn sequences(s: &str) -> Vec<u64> {
+        let v: serde_json::Value = serde_json::from_str(s).unwrap();
+        v["records"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["sequence"].as_u64().unwrap())
+            .collect()
+    }
+
     fn test_buffer(cap: usize) -> Buffer {
-        let mut buffer = Buffer::new(cap);
+        let mut buffer = Buffer::new(cap, 0);
         buffer.push(SystemMessage::PinkLog {
             block_number: 0,
             contract: [1u8; 32],
@@ -281,33 +535,33 @@ mod tests {
     #[test]
     fn it_works() {
         let mut buffer = test_buffer(1024);
-        insta::assert_display_snapshot!(pretty(&buffer.get_records("".into(), 0, 0)));
+        insta::assert_display_snapshot!(pretty(&buffer.get_records(&Filter::default(), 0, 0)));
     }
 
     #[test]
     fn it_can_rotate() {
         let mut buffer = test_buffer(256);
-        insta::assert_display_snapshot!(pretty(&buffer.get_records("".into(), 0, 0)));
+        insta::assert_display_snapshot!(pretty(&buffer.get_records(&Filter::default(), 0, 0)));
     }
 
     #[test]
     fn it_can_filter_by_contract_id() {
         let mut buffer = test_buffer(1024);
         let contract = [1; 32];
-        insta::assert_display_snapshot!(pretty(&buffer.get_records(&hex(&contract), 0, 0)));
+        insta::assert_display_snapshot!(pretty(&buffer.get_records(&by_contract(&contract), 0, 0)));
     }
 
     #[test]
     fn it_can_query_with_from() {
         let mut buffer = test_buffer(1024);
-        insta::assert_display_snapshot!(pretty(&buffer.get_records("".into(), 1, 0)));
-        insta::assert_display_snapshot!(pretty(&buffer.get_records("".into(), 4, 0)));
+        insta::assert_display_snapshot!(pretty(&buffer.get_records(&Filter::default(), 1, 0)));
+        insta::assert_display_snapshot!(pretty(&buffer.get_records(&Filter::default(), 4, 0)));
     }
 
     #[test]
     fn it_can_query_with_count_limit() {
         let mut buffer = test_buffer(1024);
-        insta::assert_display_snapshot!(pretty(&buffer.get_records("".into(), 0, 1)));
+        insta::assert_display_snapshot!(pretty(&buffer.get_records(&Filter::default(), 0, 1)));
     }
 
     #[test]
@@ -319,6 +573,104 @@ mod tests {
             topics: vec![],
             payload: vec![1],
         });
-        insta::assert_display_snapshot!(pretty(&buffer.get_records(&hex(&[1; 32]), 1, 1)));
+        insta::assert_display_snapshot!(pretty(&buffer.get_records(&by_contract(&[1; 32]), 1, 1)));
+    }
+
+    #[test]
+    fn it_can_filter_by_type_level_and_block() {
+        let mut buffer = test_buffer(1024);
+        buffer.push(SystemMessage::PinkLog {
+            block_number: 3,
+            contract: [1u8; 32],
+            in_query: false,
+            timestamp_ms: 10,
+            level: 5,
+            message: "trace".into(),
+        });
+        let filter: Filter = serde_json::from_str(r#"{"types": ["PinkLog"]}"#).unwrap();
+        assert_eq!(sequences(&buffer.get_records(&filter, 0, 0)), [0, 3]);
+        let filter: Filter = serde_json::from_str(r#"{"maxLevel": 3}"#).unwrap();
+        assert_eq!(sequences(&buffer.get_records(&filter, 0, 0)), [0, 1, 2]);
+        let filter: Filter = serde_json::from_str(r#"{"fromBlock": 1, "toBlock": 2}"#).unwrap();
+        assert_eq!(sequences(&buffer.get_records(&filter, 0, 0)), [1, 2]);
+        let filter: Filter = serde_json::from_str(r#"{"fromTime": 2}"#).unwrap();
+        assert_eq!(sequences(&buffer.get_records(&filter, 0, 0)), [3]);
+    }
+
+    #[test]
+    fn it_can_page_with_filter() {
+        let mut buffer = test_buffer(1024);
+        let filter: Filter = serde_json::from_str(r#"{"types": ["Log", "MessageOutput"]}"#).unwrap();
+        let page: serde_json::Value =
+            serde_json::from_str(&buffer.get_records(&filter, 0, 1)).unwrap();
+        assert_eq!(page["next"], 1);
+        let next = page["next"].as_u64().unwrap();
+        assert_eq!(sequences(&buffer.get_records(&filter, next, 1)), [2]);
+    }
+
+    #[test]
+    fn noisy_contract_can_not_evict_others() {
+        let mut buffer = Buffer::new(1024, 300);
+        buffer.push(SystemMessage::PinkLog {
+            block_number: 0,
+            contract: [2u8; 32],
+            in_query: false,
+            timestamp_ms: 1,
+            level: 3,
+            message: "quiet".into(),
+        });
+        for i in 0..10 {
+            buffer.push(SystemMessage::PinkLog {
+                block_number: i,
+                contract: [1u8; 32],
+                in_query: false,
+                timestamp_ms: 1,
+                level: 3,
+                message: "noisy".into(),
+            });
+        }
+        assert_eq!(sequences(&buffer.get_records(&by_contract(&[2; 32]), 0, 0)), [0]);
+        assert_eq!(
+            sequences(&buffer.get_records(&by_contract(&[1; 32]), 0, 0)),
+            [9, 10]
+        );
+    }
+
+    #[test]
+    fn it_can_restore_from_snapshot() {
+        let mut buffer = test_buffer(1024);
+        let snapshot = buffer.snapshot(usize::MAX);
+        let mut restored = Buffer::new(1024, 0);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(
+            restored.get_records(&Filter::default(), 0, 0),
+            buffer.get_records(&Filter::default(), 0, 0)
+        );
+        let filter: Filter = serde_json::from_str(r#"{"types": ["Event"]}"#).unwrap();
+        assert_eq!(sequences(&restored.get_records(&filter, 0, 0)), [1]);
+        restored.push(SystemMessage::PinkEvent {
+            block_number: 1,
+            contract: [1; 32],
+            topics: vec![],
+            payload: vec![1],
+        });
+        assert_eq!(sequences(&restored.get_records(&filter, 0, 0)), [1, 3]);
+    }
+
+    #[test]
+    fn snapshot_keeps_the_newest_records_within_the_size() {
+        let mut buffer = test_buffer(1024);
+        let full = buffer.snapshot(usize::MAX);
+        let mut restored = Buffer::new(1024, 0);
+        restored.restore(&buffer.snapshot(full.len() - 1)).unwrap();
+        let all = sequences(&buffer.get_records(&Filter::default(), 0, 0));
+        let kept = sequences(&restored.get_records(&Filter::default(), 0, 0));
+        assert!(buffer.snapshot(full.len() - 1).len() < full.len());
+        assert_eq!(kept, all[all.len() - kept.len()..]);
+        assert!(kept.len() < all.len());
+        assert_eq!(restored.next_sequence(), buffer.next_sequence());
+
+        restored.restore(&buffer.snapshot(0)).unwrap();
+        assert!(sequences(&restored.get_records(&Filter::default(), 0, 0)).is_empty());
     }
 }
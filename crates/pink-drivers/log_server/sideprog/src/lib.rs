@@ -4,29 +4,64 @@ use std::rc::Rc;
 use log::{error, info};
 use scale::Decode;
 
-use sidevm::env::ocall_funcs_guest::local_cache_get;
+use std::time::Duration;
 
-use buffer::Buffer;
+use sidevm::env::ocall_funcs_guest::{local_cache_get, local_cache_set};
+
+use buffer::{Buffer, Filter};
 mod buffer;
 
+const SNAPSHOT_KEY: &[u8] = b"LOG_BUFFER_SNAPSHOT";
+/// Max size of the saved snapshot, leaving room for the other keys in the 10 MiB local cache quota
+/// of the contract.
+const MAX_SNAPSHOT_SIZE: usize = 8 * 1024 * 1024;
+
 #[derive(Clone)]
 struct AppState {
     log_buffer: Rc<RefCell<Buffer>>,
 }
 
 impl AppState {
-    fn new(buffer_size: usize) -> Self {
+    fn new(buffer_size: usize, contract_quota: usize) -> Self {
         Self {
-            log_buffer: Rc::new(RefCell::new(Buffer::new(buffer_size))),
+            log_buffer: Rc::new(RefCell::new(Buffer::new(buffer_size, contract_quota))),
         }
     }
 }
 
+fn config_u32(key: &[u8], default: u32) -> u32 {
+    let buf = local_cache_get(key).unwrap_or_default().unwrap_or_default();
+    u32::decode(&mut &buf[..]).unwrap_or(default)
+}
+
 fn log_buffer_size() -> u32 {
-    let buf = local_cache_get(b"LOG_BUFFER_SIZE")
-        .unwrap_or_default()
-        .unwrap_or_default();
-    u32::decode(&mut &buf[..]).unwrap_or(1024 * 1024 * 8)
+    config_u32(b"LOG_BUFFER_SIZE", 1024 * 1024 * 8)
+}
+
+/// Max bytes of records a single contract can hold in the buffer. 0 means no quota.
+fn contract_quota() -> u32 {
+    config_u32(b"LOG_CONTRACT_QUOTA", 0)
+}
+
+/// Interval in seconds to save the buffer into the local cache. 0 disables the persistence.
+fn persist_interval() -> u32 {
+    config_u32(b"LOG_PERSIST_INTERVAL", 0)
+}
+
+async fn persist_serve(app: AppState, interval: Duration) {
+    let mut saved_sequence = app.log_buffer.borrow().next_sequence();
+    loop {
+        sidevm::time::sleep(interval).await;
+        let mut buffer = app.log_buffer.borrow_mut();
+        if buffer.next_sequence() == saved_sequence {
+            continue;
+        }
+        let snapshot = buffer.snapshot(MAX_SNAPSHOT_SIZE);
+        match local_cache_set(SNAPSHOT_KEY, &snapshot) {
+            Ok(()) => saved_sequence = buffer.next_sequence(),
+            Err(err) => error!("Failed to save log buffer: {:?}", err),
+        }
+    }
 }
 
 async fn query_serve(app: AppState) {
@@ -34,8 +69,8 @@ async fn query_serve(app: AppState) {
     #[serde(tag="action")]
     enum Query {
         GetLog {
-            #[serde(default)]
-            contract: String,
+            #[serde(flatten)]
+            filter: Filter,
             #[serde(default)]
             from: u64,
             #[serde(default)]
@@ -48,7 +83,7 @@ async fn query_serve(app: AppState) {
         if let Some(query) = query {
             // todo: use `let else`
             let Query::GetLog {
-                contract,
+                filter,
                 from,
                 count,
             } = match serde_json::from_slice(&query.payload) {
@@ -61,7 +96,7 @@ async fn query_serve(app: AppState) {
             };
             let reply = app.log_buffer
                 .borrow_mut()
-                .get_records(&contract, from, count);
+                .get_records(&filter, from, count);
             let _ = query.reply_tx.send(reply.as_bytes());
         } else {
             info!("Query channel closed");
@@ -75,7 +110,21 @@ async fn main() {
     sidevm::logger::Logger::with_max_level(log::LevelFilter::Info).init();
     info!("Starting log server");
 
-    let app = AppState::new(log_buffer_size() as _);
+    let app = AppState::new(log_buffer_size() as _, contract_quota() as _);
+
+    let interval = persist_interval();
+    if interval > 0 {
+        if let Ok(Some(snapshot)) = local_cache_get(SNAPSHOT_KEY) {
+            match app.log_buffer.borrow_mut().restore(&snapshot) {
+                Ok(()) => info!("Log buffer restored from local cache"),
+                Err(err) => error!("Failed to restore log buffer: {}", err),
+            }
+        }
+        sidevm::spawn(persist_serve(
+            app.clone(),
+            Duration::from_secs(interval as _),
+        ));
+    }
 
     sidevm::spawn(query_serve(app.clone()));
 
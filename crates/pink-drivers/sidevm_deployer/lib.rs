@@ -48,6 +48,16 @@ mod sidevm_deployer {
             let system = pink::system::SystemRef::instance();
             system.deploy_sidevm_to(caller, code_hash)
         }
+
+        #[ink(message)]
+        fn upgrade(&self, code_hash: pink::Hash) -> Result<()> {
+            let caller = self.env().caller();
+            if !self.whitelist.contains(&caller) {
+                return Err(Error::BadOrigin);
+            }
+            let system = pink::system::SystemRef::instance();
+            system.upgrade_sidevm_at(caller, code_hash)
+        }
     }
 
     #[cfg(test)]
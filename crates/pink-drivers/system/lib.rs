@@ -9,9 +9,12 @@ pub use system::System;
 #[pink::contract(env = PinkEnvironment)]
 mod system {
     use super::pink;
-    use alloc::string::String;
+    use alloc::{string::String, vec::Vec};
     use ink_storage::{traits::SpreadAllocate, Mapping};
-    use pink::system::{ContractDeposit, ContractDepositRef, Error, Result};
+    use pink::system::{
+        ContractDeposit, ContractDepositRef, ContractUsageReport, ContractUsageReportRef, Error,
+        ResourceUsage, Result,
+    };
     use pink::{HookPoint, PinkEnvironment};
 
     /// Pink's system contract.
@@ -109,6 +112,13 @@ mod system {
             Ok(())
         }
 
+        #[ink(message)]
+        fn upgrade_sidevm_at(&self, contract_id: AccountId, code_hash: pink::Hash) -> Result<()> {
+            self.ensure_admin()?;
+            pink::upgrade_sidevm_at(contract_id, code_hash);
+            Ok(())
+        }
+
         #[ink(message)]
         fn set_hook(&mut self, hook: HookPoint, contract: AccountId, selector: u32) -> Result<()> {
             self.ensure_admin()?;
@@ -136,6 +146,18 @@ mod system {
         }
     }
 
+    impl ContractUsageReport for System {
+        #[ink(message)]
+        fn report_usage(&mut self, usages: Vec<(AccountId, ResourceUsage)>) -> Result<()> {
+            self.ensure_pallet()?;
+            let flags = ink_env::CallFlags::default().set_allow_reentry(true);
+            match ContractUsageReportRef::instance_with_call_flags(flags) {
+                Some(mut driver) => driver.report_usage(usages),
+                None => Ok(()),
+            }
+        }
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -220,5 +242,24 @@ mod system {
                 Ok(())
             );
         }
+
+        #[ink::test]
+        fn upgrade_sidevm_permissions() {
+            let mut system = test_system();
+            ink_env::test::set_callee::<PinkEnvironment>(OWNER.into());
+
+            // Only the administrators can upgrade a sidevm
+            assert_eq!(
+                system.upgrade_sidevm_at(Default::default(), Default::default()),
+                Err(Error::BadOrigin)
+            );
+
+            assert_eq!(system.grant_admin(OWNER.into()), Ok(()));
+
+            assert_eq!(
+                system.upgrade_sidevm_at(Default::default(), Default::default()),
+                Ok(())
+            );
+        }
     }
 }
@@ -1,20 +1,51 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
 use pink_extension as pink;
 
 #[pink::contract(env = PinkEnvironment)]
 mod tokenomic {
     use super::pink;
-    use pink::system::{ContractDeposit, Error, Result, SystemRef};
+    use alloc::vec::Vec;
+    use ink_storage::{traits::SpreadAllocate, Mapping};
+    use pink::system::{
+        ContractDeposit, ContractUsageReport, Error, ResourceUsage, Result, SystemRef,
+    };
     use pink::PinkEnvironment;
 
+    const CENTS: Balance = 10_000_000_000;
+    /// Fee for every 10^12 gas (about 1 second) consumed by queries.
+    const FEE_PER_QUERY_TERAGAS: Balance = CENTS;
+    /// Fee for every 10^12 gas consumed by sidevm.
+    const FEE_PER_SIDEVM_TERAGAS: Balance = CENTS / 2;
+    /// Fee for every http request.
+    const FEE_PER_HTTP_REQUEST: Balance = CENTS / 100;
+    /// Fee for every KiB transferred by http requests.
+    const FEE_PER_HTTP_KIB: Balance = CENTS / 1000;
+
     #[ink(storage)]
-    pub struct PhatTokenomic {}
+    #[derive(SpreadAllocate)]
+    pub struct PhatTokenomic {
+        /// The deposit of contracts reported by the chain.
+        deposits: Mapping<AccountId, Balance>,
+        /// The fees charged from the deposit of contracts.
+        charged: Mapping<AccountId, Balance>,
+    }
 
     impl PhatTokenomic {
         #[ink(constructor)]
         pub fn default() -> Self {
-            Self {}
+            ink_lang::utils::initialize_contract(|_: &mut Self| {})
+        }
+
+        /// The deposit of a contract that has not been charged yet.
+        #[ink(message)]
+        pub fn remaining_deposit(&self, contract_id: AccountId) -> Balance {
+            self.deposits
+                .get(contract_id)
+                .unwrap_or_default()
+                .saturating_sub(self.charged.get(contract_id).unwrap_or_default())
         }
 
         fn ensure_system(&self) -> Result<AccountId> {
@@ -24,16 +55,123 @@ mod tokenomic {
             }
             Err(Error::BadOrigin)
         }
+
+        /// Charge the fee of the usage from the deposit of a contract.
+        fn charge(&mut self, contract_id: AccountId, usage: &ResourceUsage) {
+            let charged = self
+                .charged
+                .get(contract_id)
+                .unwrap_or_default()
+                .saturating_add(fee_of(usage));
+            self.charged.insert(contract_id, &charged);
+        }
+
+        /// The weight of a contract by its remaining deposit.
+        ///
+        /// None if no deposit of the contract has been reported since this driver was deployed,
+        /// in which case its weight is left as is rather than dropped to zero.
+        fn weight_of(&self, contract_id: AccountId) -> Option<u32> {
+            self.deposits.get(contract_id)?;
+            let weight = self.remaining_deposit(contract_id) / CENTS;
+            Some(weight.try_into().unwrap_or(u32::MAX))
+        }
+
+        fn update_weight(&self, contract_id: AccountId) -> Result<()> {
+            match self.weight_of(contract_id) {
+                Some(weight) => SystemRef::instance().set_contract_weight(contract_id, weight),
+                None => Ok(()),
+            }
+        }
+    }
+
+    fn fee_of(usage: &ResourceUsage) -> Balance {
+        const TERA: Balance = 1_000_000_000_000;
+        let query_fee = usage.query_gas as Balance * FEE_PER_QUERY_TERAGAS / TERA;
+        let sidevm_fee = usage.sidevm_gas as Balance * FEE_PER_SIDEVM_TERAGAS / TERA;
+        let http_fee = usage.http_requests as Balance * FEE_PER_HTTP_REQUEST
+            + usage.http_bytes as Balance * FEE_PER_HTTP_KIB / 1024;
+        query_fee + sidevm_fee + http_fee
     }
 
     impl ContractDeposit for PhatTokenomic {
         #[ink(message)]
         fn change_deposit(&mut self, contract_id: AccountId, deposit: Balance) -> Result<()> {
             self.ensure_system()?;
-            const CENTS: Balance = 10_000_000_000;
-            let system = SystemRef::instance();
-            let weight = deposit / CENTS;
-            system.set_contract_weight(contract_id, weight.try_into().unwrap_or(u32::MAX))
+            self.deposits.insert(contract_id, &deposit);
+            self.update_weight(contract_id)
+        }
+    }
+
+    impl ContractUsageReport for PhatTokenomic {
+        #[ink(message)]
+        fn report_usage(&mut self, usages: Vec<(AccountId, ResourceUsage)>) -> Result<()> {
+            self.ensure_system()?;
+            for (contract_id, usage) in usages {
+                self.charge(contract_id, &usage);
+                self.update_weight(contract_id)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ink_lang as ink;
+
+        #[ink::test]
+        fn fee_of_usage() {
+            assert_eq!(fee_of(&ResourceUsage::default()), 0);
+            let usage = ResourceUsage {
+                query_gas: 2_000_000_000_000,
+                http_requests: 3,
+                http_bytes: 2048,
+                sidevm_gas: 4_000_000_000_000,
+            };
+            assert_eq!(
+                fee_of(&usage),
+                2 * FEE_PER_QUERY_TERAGAS
+                    + 3 * FEE_PER_HTTP_REQUEST
+                    + 2 * FEE_PER_HTTP_KIB
+                    + 4 * FEE_PER_SIDEVM_TERAGAS
+            );
+        }
+
+        #[ink::test]
+        fn charge_reduces_remaining_deposit() {
+            let contract = AccountId::from([1u8; 32]);
+            let mut tokenomic = PhatTokenomic::default();
+            tokenomic.deposits.insert(contract, &(10 * CENTS));
+            let usage = ResourceUsage {
+                query_gas: 3_000_000_000_000,
+                ..Default::default()
+            };
+            tokenomic.charge(contract, &usage);
+            assert_eq!(tokenomic.remaining_deposit(contract), 7 * CENTS);
+            tokenomic.charge(contract, &usage);
+            tokenomic.charge(contract, &usage);
+            tokenomic.charge(contract, &usage);
+            // Never goes below zero.
+            assert_eq!(tokenomic.remaining_deposit(contract), 0);
+            // Other contracts are not affected.
+            assert_eq!(tokenomic.remaining_deposit([2u8; 32].into()), 0);
+        }
+
+        #[ink::test]
+        fn weight_follows_remaining_deposit() {
+            let contract = AccountId::from([1u8; 32]);
+            let mut tokenomic = PhatTokenomic::default();
+            let usage = ResourceUsage {
+                query_gas: 3_000_000_000_000,
+                ..Default::default()
+            };
+            // The weight of contracts deposited before the driver was deployed is kept.
+            tokenomic.charge(contract, &usage);
+            assert_eq!(tokenomic.weight_of(contract), None);
+            tokenomic.deposits.insert(contract, &(10 * CENTS));
+            assert_eq!(tokenomic.weight_of(contract), Some(7));
+            tokenomic.deposits.insert(contract, &0);
+            assert_eq!(tokenomic.weight_of(contract), Some(0));
         }
     }
 }
@@ -2,7 +2,7 @@
 
 extern crate alloc;
 
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 use ink_env::{emit_event, topics::state::HasRemainingTopics, Environment, Topics};
 
 use ink_lang::EnvAccess;
@@ -101,6 +101,37 @@ pub enum PinkEvent {
     SetLogHandler(AccountId),
     /// Set the weight of contract used to schedule queries and sidevm vruntime
     SetContractWeight { contract: AccountId, weight: u32 },
+    /// Send an ink message to a contract, which may live in another cluster.
+    ///
+    /// The message is executed with the sender contract as the caller. The output is delivered
+    /// back to the log handler of the sender's cluster as a message output record.
+    CrossClusterMessage {
+        /// The destination contract address
+        contract: AccountId,
+        /// The encoded ink message, selector included.
+        message: Vec<u8>,
+    },
+    /// Expose a TCP listener of the associated sidevm instance through the HTTP gateway of the
+    /// workers.
+    ///
+    /// Requests to `/sidevm/<contract_id>/...` on the workers, and to the hostname if given, are
+    /// forwarded to the sidevm listener on `port`. A `None` port closes the gateway.
+    SetHttpGateway {
+        /// The port the sidevm instance is listening on.
+        port: Option<u16>,
+        /// The hostname routed to the sidevm instance in addition to the path prefix.
+        hostname: Option<String>,
+    },
+    /// Upgrade the running sidevm instance of given contract to new code.
+    ///
+    /// The running instance hands off its state to the new code, and is rolled back if the new
+    /// code crashes during startup.
+    UpgradeSidevmAt {
+        /// The target contract address
+        contract: AccountId,
+        /// The hash of the new sidevm code.
+        code_hash: Hash,
+    },
 }
 
 impl PinkEvent {
@@ -116,6 +147,9 @@ impl PinkEvent {
             PinkEvent::ForceStopSidevm { .. } => true,
             PinkEvent::SetLogHandler(_) => false,
             PinkEvent::SetContractWeight { .. } => false,
+            PinkEvent::CrossClusterMessage { .. } => false,
+            PinkEvent::SetHttpGateway { .. } => false,
+            PinkEvent::UpgradeSidevmAt { .. } => false,
         }
     }
 
@@ -131,6 +165,9 @@ impl PinkEvent {
             PinkEvent::ForceStopSidevm { .. } => "ForceStopSidevm",
             PinkEvent::SetLogHandler(_) => "SetLogHandler",
             PinkEvent::SetContractWeight { .. } => "SetContractWeight",
+            PinkEvent::CrossClusterMessage { .. } => "CrossClusterMessage",
+            PinkEvent::SetHttpGateway { .. } => "SetHttpGateway",
+            PinkEvent::UpgradeSidevmAt { .. } => "UpgradeSidevmAt",
         }
     }
 }
@@ -214,6 +251,22 @@ pub fn deploy_sidevm_to(contract: AccountId, code_hash: Hash) {
     });
 }
 
+/// Upgrade the SideVM instance attached to the calling contract to new code.
+pub fn upgrade_sidevm(code_hash: Hash) -> Result<(), system::Error> {
+    let driver =
+        crate::system::SidevmOperationRef::instance().ok_or(system::Error::DriverNotFound)?;
+    driver.upgrade(code_hash)
+}
+
+/// Upgrade the SideVM instance running at given contract address to new code.
+/// The caller must be the system contract.
+pub fn upgrade_sidevm_at(contract: AccountId, code_hash: Hash) {
+    emit_event::<PinkEnvironment, _>(PinkEvent::UpgradeSidevmAt {
+        contract,
+        code_hash,
+    });
+}
+
 /// Stop a SideVM instance running at given contract address.
 /// The caller must be the system contract.
 pub fn stop_sidevm_at(contract: AccountId) {
@@ -243,6 +296,23 @@ pub fn set_contract_weight(contract: AccountId, weight: u32) {
     emit_event::<PinkEnvironment, _>(PinkEvent::SetContractWeight { contract, weight });
 }
 
+/// Send an ink message to a contract in any cluster
+///
+/// The message is delivered in a later block and is executed with the current contract as the
+/// caller. Messages to the same destination carry increasing nonces, and the output of each one
+/// is reported back through the log handler of the current cluster.
+pub fn send_cross_cluster_message(contract: AccountId, message: Vec<u8>) {
+    emit_event::<PinkEnvironment, _>(PinkEvent::CrossClusterMessage { contract, message });
+}
+
+/// Route the inbound HTTP requests of the worker gateway to a listener of the associated sidevm
+///
+/// The requests to `/sidevm/<contract_id>/...` and to `hostname`, if given, are forwarded to the
+/// sidevm instance listening on `port`. Pass `None` as the port to close the gateway.
+pub fn set_http_gateway(port: Option<u16>, hostname: Option<String>) {
+    emit_event::<PinkEnvironment, _>(PinkEvent::SetHttpGateway { port, hostname });
+}
+
 /// Pink defined environment. Used this environment to access the fat contract runtime features.
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
@@ -4,6 +4,7 @@ use ink_lang as ink;
 use pink_extension_macro as pink;
 
 use alloc::string::String;
+use alloc::vec::Vec;
 use scale::{Decode, Encode};
 
 use crate::{AccountId, Balance, Hash};
@@ -19,6 +20,20 @@ pub enum Error {
 /// Result type for the system contract messages
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// Resources consumed by a contract on a worker since the last report.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub struct ResourceUsage {
+    /// Gas consumed by queries.
+    pub query_gas: u64,
+    /// Number of `http_request` calls.
+    pub http_requests: u32,
+    /// Bytes sent and received by `http_request` calls.
+    pub http_bytes: u64,
+    /// Gas consumed by the sidevm instance attached to the contract.
+    pub sidevm_gas: u64,
+}
+
 /// The pink system contract interface.
 ///
 /// A system contract would be instantiated whenever a cluster is created.
@@ -58,6 +73,12 @@ pub trait System {
     #[ink(message)]
     fn stop_sidevm_at(&self, contract_id: AccountId) -> Result<()>;
 
+    /// Upgrade the running sidevm instance attached to a given contract to new code.
+    ///
+    /// The caller must be an administrator.
+    #[ink(message)]
+    fn upgrade_sidevm_at(&self, contract_id: AccountId, code_hash: Hash) -> Result<()>;
+
     /// Set block hook, such as OnBlockEnd, for given contract
     ///
     /// The caller must be an administrator.
@@ -83,6 +104,10 @@ pub trait SidevmOperation {
     /// Invoked by a contract to deploy a sidevm instance that attached to itself.
     #[ink(message)]
     fn deploy(&self, code_hash: Hash) -> Result<()>;
+
+    /// Invoked by a contract to upgrade the sidevm instance attached to itself to new code.
+    #[ink(message)]
+    fn upgrade(&self, code_hash: Hash) -> Result<()>;
 }
 
 /// Contracts receiving processing deposit events. Can be a driver and the system.
@@ -94,3 +119,14 @@ pub trait ContractDeposit {
     #[ink(message, selector = 0xa24bcb44)]
     fn change_deposit(&mut self, contract_id: AccountId, deposit: Balance) -> Result<()>;
 }
+
+/// Contracts receiving the resources consumed by contracts. Can be a driver and the system.
+#[pink::driver]
+#[ink::trait_definition]
+pub trait ContractUsageReport {
+    /// Report resources consumed by contracts, which are measured by a worker. A driver should
+    /// debit the deposit of the contracts accordingly, and throttle the ones running out of
+    /// deposit.
+    #[ink(message, selector = 0xf227dbc8)]
+    fn report_usage(&mut self, usages: Vec<(AccountId, ResourceUsage)>) -> Result<()>;
+}
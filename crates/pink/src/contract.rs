@@ -13,7 +13,8 @@ use crate::{
     },
 };
 
-type ContractExecResult = pallet_contracts_primitives::ContractExecResult<crate::types::Balance>;
+pub type ContractExecResult =
+    pallet_contracts_primitives::ContractExecResult<crate::types::Balance>;
 
 pub type Storage = storage::Storage<storage::InMemoryBackend>;
 
@@ -9,7 +9,7 @@ pub mod storage;
 
 pub mod types;
 
-pub use contract::{Contract, ContractFile, Storage, transpose_contract_result};
+pub use contract::{Contract, ContractExecResult, ContractFile, Storage, transpose_contract_result};
 pub use export_fixtures::load_test_wasm;
 
 pub mod predefined_accounts {
@@ -99,9 +99,8 @@ impl LocalCache {
             .or_insert_with(Storage::default);
         let key_len = key.len();
         let value_len = value.len();
-        let prev_value = store.kvs.remove(key.as_ref());
-
-        let new_size = match prev_value {
+        // Keep the previous value if the new one doesn't fit.
+        let new_size = match store.kvs.get(key.as_ref()) {
             Some(v) => store.size + value_len - v.value.len(),
             None => store.size + key_len + value_len,
         };
@@ -144,6 +143,12 @@ impl LocalCache {
         v
     }
 
+    /// Sum of the size of the keys and values stored for `id`, including the expired ones not
+    /// collected yet.
+    pub fn size(&self, id: &[u8]) -> usize {
+        self.storages.get(id).map_or(0, |storage| storage.size)
+    }
+
     #[allow(dead_code)]
     pub fn remove_storage(&mut self, id: &[u8]) {
         let _ = self.storages.remove(id);
@@ -197,6 +202,10 @@ pub fn local_cache_remove(contract: &[u8], key: &[u8]) -> Option<Vec<u8>> {
     GLOBAL_CACHE.write().unwrap().remove(contract, key)
 }
 
+pub fn local_cache_size(contract: &[u8]) -> usize {
+    GLOBAL_CACHE.read().unwrap().size(contract)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -268,6 +277,18 @@ mod test {
         assert!(cache.set(cow(b"id"), cow(b"bar"), cow(b"value")).is_err());
     }
 
+    #[test]
+    fn failed_set_keeps_the_previous_value() {
+        let mut cache = test_cache();
+        cache.max_cache_size_per_contract = 10;
+        assert!(cache.set(cow(b"id"), cow(b"foo"), cow(b"value")).is_ok());
+        assert!(cache
+            .set(cow(b"id"), cow(b"foo"), cow(b"too large"))
+            .is_err());
+        assert_eq!(cache.get(b"id", b"foo"), Some(b"value".to_vec()));
+        assert_eq!(get_size(&cache, b"id"), 8);
+    }
+
     #[test]
     fn size_calc() {
         let mut cache = test_cache();
@@ -279,5 +300,7 @@ mod test {
         assert_eq!(get_size(&cache, b"id"), 6);
         assert!(cache.remove(b"id", b"foo").is_some());
         assert_eq!(get_size(&cache, b"id"), 0);
+        assert_eq!(cache.size(b"id"), 0);
+        assert_eq!(cache.size(b"unknown"), 0);
     }
 }
@@ -3,7 +3,10 @@ mod mock_types;
 mod pallet_pink;
 mod weights;
 
-use std::time::{Duration, Instant};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
 
 use crate::types::{AccountId, Balance, BlockNumber, Hash, Hashing, Index};
 use frame_support::{parameter_types, traits::ConstU128, weights::Weight};
@@ -14,7 +17,7 @@ use sp_runtime::{
     Perbill,
 };
 
-pub use extension::{get_side_effects, ExecSideEffects};
+pub use extension::{get_side_effects, ExecSideEffects, ResourceUsage};
 pub use pink_extension::{HookPoint, Message, OspMessage, PinkEvent};
 
 type UncheckedExtrinsic = frame_system::mocking::MockUncheckedExtrinsic<PinkRuntime>;
@@ -168,6 +171,7 @@ struct CallInfo {
     mode: CallMode,
     start_at: Instant,
     callbacks: Option<BoxedEventCallbacks>,
+    resource_usage: BTreeMap<AccountId, ResourceUsage>,
 }
 
 environmental::environmental!(call_info: CallInfo);
@@ -181,6 +185,7 @@ pub fn using_mode<T>(
         mode,
         start_at: Instant::now(),
         callbacks,
+        resource_usage: Default::default(),
     };
     call_info::using(&mut info, f)
 }
@@ -193,6 +198,18 @@ pub fn get_call_elapsed() -> Option<Duration> {
     call_info::with(|info| info.start_at.elapsed())
 }
 
+pub fn record_http_usage(id: &AccountId, bytes: u64) {
+    call_info::with(|info| {
+        let usage = info.resource_usage.entry(id.clone()).or_default();
+        usage.http_requests += 1;
+        usage.http_bytes += bytes;
+    });
+}
+
+pub fn take_resource_usage() -> BTreeMap<AccountId, ResourceUsage> {
+    call_info::with(|info| core::mem::take(&mut info.resource_usage)).unwrap_or_default()
+}
+
 pub fn emit_log(id: &AccountId, level: u8, msg: String) {
     call_info::with(|info| {
         if let Some(callbacks) = &info.callbacks {
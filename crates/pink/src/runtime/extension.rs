@@ -1,5 +1,5 @@
 use std::time::Duration;
-use std::{borrow::Cow, convert::TryFrom};
+use std::{borrow::Cow, collections::BTreeMap, convert::TryFrom};
 
 use frame_support::log::error;
 use pallet_contracts::chain_extension::{
@@ -19,17 +19,28 @@ use sp_core::H256;
 use sp_runtime::DispatchError;
 
 use crate::{
-    runtime::{get_call_elapsed, get_call_mode, CallMode},
+    runtime::{get_call_elapsed, get_call_mode, record_http_usage, take_resource_usage, CallMode},
     types::AccountId,
 };
 
 use crate::local_cache::GLOBAL_CACHE;
 
+/// Resources consumed by a contract during an execution.
+///
+/// These are not rolled back with the storage, so queries are accounted as well.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub gas: u64,
+    pub http_requests: u32,
+    pub http_bytes: u64,
+}
+
 #[derive(Default, Debug)]
 pub struct ExecSideEffects {
     pub pink_events: Vec<(AccountId, PinkEvent)>,
     pub ink_events: Vec<(AccountId, Vec<H256>, Vec<u8>)>,
     pub instantiated: Vec<(AccountId, AccountId)>,
+    pub resource_usage: BTreeMap<AccountId, ResourceUsage>,
 }
 
 impl ExecSideEffects {
@@ -84,6 +95,7 @@ pub fn get_side_effects() -> ExecSideEffects {
             }
         }
     }
+    result.resource_usage = take_resource_usage();
     result
 }
 
@@ -143,6 +155,10 @@ impl ChainExtension<super::PinkRuntime> for PinkExtension {
     }
 }
 
+fn headers_size(headers: &[(String, String)]) -> usize {
+    headers.iter().map(|(k, v)| k.len() + v.len()).sum()
+}
+
 struct CallInQuery {
     address: AccountId,
 }
@@ -162,7 +178,19 @@ impl PinkRuntimeEnv for CallInQuery {
 impl PinkExtBackend for CallInQuery {
     type Error = DispatchError;
     fn http_request(&self, request: HttpRequest) -> Result<HttpResponse, Self::Error> {
-        DefaultPinkExtension::new(self).http_request(request)
+        let request_size = request.url.len()
+            + request.method.len()
+            + request.body.len()
+            + headers_size(&request.headers);
+        let response = DefaultPinkExtension::new(self).http_request(request);
+        let response_size = match &response {
+            Ok(response) => {
+                response.reason_phrase.len() + response.body.len() + headers_size(&response.headers)
+            }
+            Err(_) => 0,
+        };
+        record_http_usage(&self.address, (request_size + response_size) as u64);
+        response
     }
 
     fn sign(
@@ -83,8 +83,10 @@ where
             } else {
                 crate::runtime::CallMode::Command
             };
-            let r = crate::runtime::using_mode(mode, callbacks, f);
-            (r, crate::runtime::get_side_effects())
+            crate::runtime::using_mode(mode, callbacks, move || {
+                let r = f();
+                (r, crate::runtime::get_side_effects())
+            })
         });
         overlay
             .commit_transaction()
@@ -10,6 +10,11 @@ pub struct QueryRequest {
     pub reply_tx: i32,
 }
 
+#[derive(Encode, Decode)]
+pub struct UpgradeRequest {
+    pub reply_tx: i32,
+}
+
 #[derive(Encode, Decode)]
 #[non_exhaustive]
 pub enum SystemMessage {
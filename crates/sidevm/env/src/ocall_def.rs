@@ -110,6 +110,28 @@ pub trait OcallFuncs {
     /// Create input channel
     #[ocall(id = 240, encode_output)]
     fn create_input_channel(ch: InputChannel) -> Result<i32>;
+
+    /// Query a pink contract in the same cluster with given SCALE-encoded ink message.
+    ///
+    /// The query is read-only and runs on a snapshot of the cluster storage. Poll the returned
+    /// resource to get the SCALE-encoded output of the message.
+    #[ocall(id = 250)]
+    fn pink_query(contract: &[u8], payload: &[u8]) -> Result<i32>;
+
+    /// Queue a command to the pink contract owning this instance.
+    ///
+    /// The SCALE-encoded ink message is sent on chain by every worker running the instance, and
+    /// then executed by the contract as a transaction. The contract only executes the first copy
+    /// of each `nonce`, which is at most 32 bytes and reported along with the output of the
+    /// message. Commands are charged with gas and rate limited.
+    #[ocall(id = 251)]
+    fn pink_push_command(nonce: &[u8], message: &[u8]) -> Result<()>;
+
+    /// Take the state handed off by the previous instance on a code upgrade.
+    ///
+    /// Returns `None` if the instance wasn't started by an upgrade or the state has been taken.
+    #[ocall(id = 260, encode_output)]
+    fn upgrade_take_state() -> Result<Option<Vec<u8>>>;
 }
 
 #[repr(u8)]
@@ -121,6 +143,8 @@ pub enum InputChannel {
     GeneralMessage = 2,
     /// Input channel for queries from external RPC requests.
     Query = 3,
+    /// Input channel for requests to hand off the state to the upgraded code.
+    UpgradeRequest = 4,
 }
 
 impl I32Convertible for InputChannel {
@@ -132,6 +156,7 @@ impl I32Convertible for InputChannel {
             1 => Ok(InputChannel::SystemMessage),
             2 => Ok(InputChannel::GeneralMessage),
             3 => Ok(InputChannel::Query),
+            4 => Ok(InputChannel::UpgradeRequest),
             _ => Err(OcallError::InvalidParameter),
         }
     }
@@ -9,6 +9,25 @@ pub enum TlsServerConfig {
         /// The private key of the certificate, in PEM format.
         key: String,
     },
+    V1 {
+        /// Certificate chain in PEM format.
+        cert: String,
+        /// The private key of the certificate, in PEM format.
+        key: String,
+        /// ALPN protocols to negotiate, in order of preference.
+        alpn_protocols: Vec<Vec<u8>>,
+        /// Verify client certificates if given.
+        client_auth: Option<TlsClientAuth>,
+    },
+}
+
+/// Client certificate verification of a TLS server.
+#[derive(Encode, Decode, Clone)]
+pub struct TlsClientAuth {
+    /// CA certificates to verify the client certificates against, in PEM format.
+    pub root_certs: String,
+    /// Reject clients that don't present a certificate.
+    pub mandatory: bool,
 }
 
 /// TLS client configuration.
@@ -16,4 +35,25 @@ pub enum TlsServerConfig {
 pub enum TlsClientConfig {
     /// Nothing to be configured in this version.
     V0,
+    V1 {
+        /// Additional trusted root certificates in PEM format.
+        root_certs: Option<String>,
+        /// Whether to trust the builtin webpki root certificates as well.
+        builtin_roots: bool,
+        /// Client certificate to present to the server.
+        client_cert: Option<TlsClientCert>,
+        /// ALPN protocols to negotiate, in order of preference.
+        alpn_protocols: Vec<Vec<u8>>,
+        /// Server name used for SNI and certificate verification instead of the connecting host.
+        server_name: Option<String>,
+    },
+}
+
+/// Client certificate for mutual TLS.
+#[derive(Encode, Decode, Clone)]
+pub struct TlsClientCert {
+    /// Certificate chain in PEM format.
+    pub cert: String,
+    /// The private key of the certificate, in PEM format.
+    pub key: String,
 }
@@ -5,11 +5,15 @@ use std::{
     fmt,
     future::Future,
     ops::{Deref, DerefMut},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     task::Poll::{Pending, Ready},
     time::Duration,
 };
 
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use tokio::{
     net::TcpListener,
@@ -22,7 +26,7 @@ use wasmer::{
 };
 
 use env::{
-    messages::{AccountId, QueryRequest, SystemMessage},
+    messages::{AccountId, QueryRequest, SystemMessage, UpgradeRequest},
     tls::{TlsClientConfig, TlsServerConfig},
     IntPtr, IntRet, OcallError, Result, RetEncode,
 };
@@ -33,13 +37,17 @@ use wasmer_middlewares::metering;
 
 use crate::{
     async_context::{get_task_cx, set_task_env, GuestWaker},
-    resource::{Resource, ResourceKeeper},
-    tls::{load_tls_config, TlsStream},
+    resource::{ListenerRegistration, Resource, ResourceKeeper, ResourceLimits, ResourceUsage},
+    tls::{load_client_config, load_tls_config, TlsStream},
     VmId,
 };
 
+mod vfs;
 mod wasi_env;
 
+pub(crate) use vfs::PendingSave;
+pub use vfs::{DynFsStore, FsConfig, FsStore, DEFAULT_FS_QUOTA};
+
 pub struct FnEnvMut<'a, T> {
     store: StoreMut<'a>,
     inner: T,
@@ -82,8 +90,20 @@ fn _sizeof_i32_must_eq_to_intptr() {
     let _ = core::mem::transmute::<i32, IntPtr>;
 }
 
-pub fn create_env(id: VmId, store: &mut Store, cache_ops: DynCacheOps) -> (Env, Imports) {
-    let raw_env = Env::new(id, cache_ops);
+/// Version of the host functions imported by the guest. Bump it on changing their signatures, to
+/// invalidate the cached modules.
+pub(crate) const OCALL_ABI_VERSION: u32 = 1;
+
+pub fn create_env(
+    id: VmId,
+    store: &mut Store,
+    cache_ops: DynCacheOps,
+    pink_ops: DynPinkOps,
+    fs_config: FsConfig,
+    limits: ResourceLimits,
+    usage: ResourceUsage,
+) -> (Env, Imports) {
+    let raw_env = Env::new(id, cache_ops, pink_ops, fs_config, limits, usage);
     let env = FunctionEnv::new(store, raw_env.clone());
     let wasi_imports = wasi_env::wasi_imports(store, &env);
     (
@@ -113,7 +133,7 @@ pub(crate) struct TaskSet {
 }
 
 impl TaskSet {
-    fn with_task0() -> Self {
+    pub(crate) fn with_task0() -> Self {
         let awake_tasks = dashmap::DashSet::new();
         awake_tasks.insert(0);
         Self {
@@ -147,27 +167,146 @@ pub trait CacheOps {
     fn set(&self, contract: &[u8], key: &[u8], value: &[u8]) -> Result<()>;
     fn set_expiration(&self, contract: &[u8], key: &[u8], expire_after_secs: u64) -> Result<()>;
     fn remove(&self, contract: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Sum of the size of the keys and values stored for `contract`.
+    fn size(&self, contract: &[u8]) -> Result<u64>;
 }
 
 pub type DynCacheOps = &'static (dyn CacheOps + Send + Sync);
 
+/// Serves the calls from the sidevm instances to the pink contracts.
+pub trait PinkOps {
+    /// Query `contract` with the SCALE-encoded ink message `payload` on behalf of the sidevm
+    /// instance `origin`. Returns the SCALE-encoded output of the message.
+    fn query(
+        &self,
+        origin: VmId,
+        contract: AccountId,
+        payload: Vec<u8>,
+    ) -> BoxFuture<'static, Result<Vec<u8>>>;
+
+    /// Queue a command to the pink contract owning the sidevm instance `origin`, which is
+    /// delivered via the chain. `message` is a SCALE-encoded ink message.
+    fn push_command(&self, origin: VmId, nonce: Vec<u8>, message: Vec<u8>) -> Result<()>;
+}
+
+pub type DynPinkOps = &'static (dyn PinkOps + Send + Sync);
+
+/// Accumulates the gas consumed by a sidevm instance, shared with the host for metering.
+#[derive(Clone, Default, Debug)]
+pub struct GasMeter(Arc<AtomicU64>);
+
+impl GasMeter {
+    /// Take the gas consumed since the last take.
+    pub fn take(&self) -> u64 {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+
+    fn add(&self, gas: u64) {
+        self.0.fetch_add(gas, Ordering::Relaxed);
+    }
+}
+
+/// Serialized as the gas not taken yet, to keep it across checkpoints.
+impl Serialize for GasMeter {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        self.0.load(Ordering::Relaxed).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for GasMeter {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let gas = u64::deserialize(deserializer)?;
+        Ok(Self(Arc::new(AtomicU64::new(gas))))
+    }
+}
+
+/// An ocall made by the guest, recorded while the guest has enabled the ocall trace.
+#[derive(Debug, Clone)]
+pub struct OcallTrace {
+    pub task_id: i32,
+    pub func_name: &'static str,
+    pub args: [IntPtr; 4],
+    pub result: Result<i32>,
+}
+
+/// Collects the ocall traces of a sidevm instance, shared with the host for inspection.
+#[derive(Clone, Default, Debug)]
+pub struct OcallTraceRecorder(Arc<Mutex<Vec<OcallTrace>>>);
+
+impl OcallTraceRecorder {
+    /// Take the traces recorded since the last take.
+    pub fn take(&self) -> Vec<OcallTrace> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    fn record(&self, trace: OcallTrace) {
+        self.0.lock().unwrap().push(trace);
+    }
+}
+
+/// The clock seen by the guest via WASI, driven by the tokio time instead of the system clock.
+///
+/// Together with a paused tokio runtime, it gives the guest a deterministic virtual clock.
+#[derive(Clone, Copy, Debug)]
+pub struct VirtualClock {
+    start: tokio::time::Instant,
+    unix_start: Duration,
+}
+
+impl VirtualClock {
+    /// Create a clock whose realtime reads `unix_start` since the unix epoch at the moment.
+    ///
+    /// Must be called in the context of the tokio runtime running the instance.
+    pub fn new(unix_start: Duration) -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+            unix_start,
+        }
+    }
+
+    fn now(&self, realtime: bool) -> Duration {
+        if realtime {
+            self.unix_start + self.start.elapsed()
+        } else {
+            self.start.elapsed()
+        }
+    }
+}
+
 struct VmMemory(Option<Memory>);
 
 pub(crate) struct EnvInner {
     memory: VmMemory,
     id: VmId,
     gas_per_breath: u64,
+    gas_meter: GasMeter,
     resources: ResourceKeeper,
+    limits: ResourceLimits,
+    usage: ResourceUsage,
+    /// Set when a hard resource limit is exceeded, to terminate the instance.
+    limit_exceeded: bool,
     temp_return_value: ThreadLocal<Cell<Option<Vec<u8>>>>,
     ocall_trace_enabled: bool,
+    ocall_trace_recorder: Option<OcallTraceRecorder>,
+    virtual_clock: Option<VirtualClock>,
     message_tx: Option<Sender<Vec<u8>>>,
     query_tx: Option<Sender<Vec<u8>>>,
     sys_message_tx: Option<Sender<Vec<u8>>>,
+    upgrade_tx: Option<Sender<Vec<u8>>>,
+    /// The state handed off by the previous instance on an upgrade.
+    handoff_state: Option<Vec<u8>>,
     awake_tasks: Arc<TaskSet>,
     current_task: i32,
     cache_ops: DynCacheOps,
+    pink_ops: DynPinkOps,
     weight: u32,
     instance: Option<Instance>,
+    fs: vfs::VirtualFs,
 }
 
 impl VmMemory {
@@ -225,23 +364,42 @@ pub struct Env {
 }
 
 impl Env {
-    fn new(id: VmId, cache_ops: DynCacheOps) -> Self {
+    fn new(
+        id: VmId,
+        cache_ops: DynCacheOps,
+        pink_ops: DynPinkOps,
+        fs_config: FsConfig,
+        limits: ResourceLimits,
+        usage: ResourceUsage,
+    ) -> Self {
+        // The cache may outlive the instance, so the usage starts from what is left in it.
+        usage.set_cache_bytes(cache_ops.size(&id[..]).unwrap_or_default());
         Self {
             inner: Arc::new(Mutex::new(EnvInner {
                 memory: VmMemory(None),
                 id,
                 gas_per_breath: 0,
-                resources: Default::default(),
+                gas_meter: Default::default(),
+                resources: ResourceKeeper::new(limits, usage.clone()),
+                limits,
+                usage,
+                limit_exceeded: false,
                 temp_return_value: Default::default(),
                 ocall_trace_enabled: false,
+                ocall_trace_recorder: None,
+                virtual_clock: None,
                 message_tx: None,
                 sys_message_tx: None,
                 query_tx: None,
+                upgrade_tx: None,
+                handoff_state: None,
                 awake_tasks: Arc::new(TaskSet::with_task0()),
                 current_task: 0,
                 cache_ops,
+                pink_ops,
                 weight: 1,
                 instance: None,
+                fs: vfs::VirtualFs::new(id, fs_config),
             })),
         }
     }
@@ -251,8 +409,10 @@ impl Env {
     }
 
     pub fn cleanup(&self) {
+        let mut inner = self.inner.lock().unwrap();
         // Cut up the reference cycle to avoid leaks.
-        self.inner.lock().unwrap().memory.0 = None;
+        inner.memory.0 = None;
+        inner.fs.sync();
     }
 
     /// Push a pink message into the Sidevm instance.
@@ -305,6 +465,51 @@ impl Env {
         })
     }
 
+    /// Ask the Sidevm instance to hand off its state for a code upgrade.
+    ///
+    /// The state is sent back through `reply_tx`.
+    pub fn push_upgrade_request(
+        &self,
+        reply_tx: OneshotSender<Vec<u8>>,
+    ) -> Option<impl Future<Output = anyhow::Result<()>>> {
+        let mut env_guard = self.inner.lock().unwrap();
+        let tx = env_guard.upgrade_tx.clone()?;
+        let reply_tx = env_guard
+            .resources
+            .push(Resource::OneshotTx(Some(reply_tx)));
+        let inner = self.inner.clone();
+        Some(async move {
+            let reply_tx = reply_tx?;
+            let result = tx.send(UpgradeRequest { reply_tx }.encode()).await;
+            if result.is_err() {
+                let mut env_guard = inner.lock().unwrap();
+                let _ = env_guard.close(reply_tx);
+            }
+            result?;
+            Ok(())
+        })
+    }
+
+    /// Set the state handed off by the previous instance, to be taken by the guest program.
+    pub fn set_handoff_state(&self, state: Vec<u8>) {
+        self.inner.lock().unwrap().handoff_state = Some(state);
+    }
+
+    /// Record the ocall traces into `recorder` while the guest has enabled the ocall trace.
+    pub fn set_ocall_trace_recorder(&self, recorder: OcallTraceRecorder) {
+        self.inner.lock().unwrap().ocall_trace_recorder = Some(recorder);
+    }
+
+    /// Enable or disable the ocall trace on behalf of the guest.
+    pub fn set_ocall_trace_enabled(&self, enable: bool) {
+        self.inner.lock().unwrap().ocall_trace_enabled = enable;
+    }
+
+    /// Use `clock` instead of the system clock for the WASI clocks.
+    pub fn set_virtual_clock(&self, clock: VirtualClock) {
+        self.inner.lock().unwrap().virtual_clock = Some(clock);
+    }
+
     pub fn set_gas_per_breath(&self, gas: u64) {
         self.inner.lock().unwrap().gas_per_breath = gas;
     }
@@ -318,6 +523,19 @@ impl Env {
         metering::set_remaining_points(store, instance, guard.gas_per_breath);
     }
 
+    pub fn set_gas_meter(&self, meter: GasMeter) {
+        self.inner.lock().unwrap().gas_meter = meter;
+    }
+
+    /// Add the gas consumed in the current breath to the gas meter.
+    pub fn meter_gas_consumed(&self, store: &mut impl AsStoreMut) {
+        let guard = self.inner.lock().unwrap();
+        let consumed = guard
+            .gas_per_breath
+            .saturating_sub(guard.gas_to_breath(store));
+        guard.gas_meter.add(consumed);
+    }
+
     pub fn has_more_ready(&self) -> bool {
         !self.inner.lock().unwrap().awake_tasks.is_empty()
     }
@@ -370,9 +588,15 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
     }
 
     fn poll_write(&mut self, waker_id: i32, resource_id: i32, data: &[u8]) -> Result<u32> {
-        self.resources
+        let written = self
+            .resources
             .get_mut(resource_id)?
-            .poll_write(waker_id, data)
+            .poll_write(waker_id, data)?;
+        if self.usage.add_outbound_bytes(written as _) > self.limits.max_outbound_bytes {
+            self.limit_exceeded = true;
+            return Err(OcallError::ResourceLimited);
+        }
+        Ok(written)
     }
 
     fn poll_shutdown(&mut self, waker_id: i32, resource_id: i32) -> Result<()> {
@@ -410,9 +634,14 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
             .or(Err(OcallError::IoError))?;
         let listener = TcpListener::from_std(std_listener).or(Err(OcallError::IoError))?;
         let tls_config = tls_config.map(load_tls_config).transpose()?.map(Arc::new);
+        let registration = listener
+            .local_addr()
+            .ok()
+            .map(|addr| ListenerRegistration::new(self.id, addr));
         self.resources.push(Resource::TcpListener {
             listener,
             tls_config,
+            _registration: registration,
         })
     }
 
@@ -424,6 +653,7 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
                 Resource::TcpListener {
                     listener,
                     tls_config,
+                    ..
                 } => (listener, tls_config),
                 _ => return Err(OcallError::UnsupportedOperation),
             };
@@ -462,15 +692,16 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
         if host.len() > 253 {
             return Err(OcallError::InvalidParameter);
         }
-        let TlsClientConfig::V0 = config;
-        let domain = host
-            .as_str()
+        let (client_config, server_name) = load_client_config(config)?;
+        let domain = server_name
+            .as_deref()
+            .unwrap_or(&host)
             .try_into()
             .or(Err(OcallError::InvalidParameter))?;
         let fut = async move {
             tcp_connect(&host, port)
                 .await
-                .map(move |stream| TlsStream::connect(domain, stream))
+                .map(move |stream| TlsStream::connect(domain, stream, client_config))
         };
         self.resources.push(Resource::TlsConnect(Box::pin(fut)))
     }
@@ -487,16 +718,29 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
     }
 
     fn local_cache_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        self.cache_ops.set(&self.id[..], key, value)
+        let replaced = self
+            .cache_ops
+            .get(&self.id[..], key)?
+            .map_or(0, |value| key.len() + value.len());
+        let usage = (self.cache_ops.size(&self.id[..])? + (key.len() + value.len()) as u64)
+            .saturating_sub(replaced as _);
+        if usage > self.limits.max_cache_bytes {
+            return Err(OcallError::ResourceLimited);
+        }
+        self.cache_ops.set(&self.id[..], key, value)?;
+        self.update_cache_usage()
     }
 
     fn local_cache_set_expiration(&mut self, key: &[u8], expire_after_secs: u64) -> Result<()> {
         self.cache_ops
-            .set_expiration(&self.id[..], key, expire_after_secs)
+            .set_expiration(&self.id[..], key, expire_after_secs)?;
+        self.update_cache_usage()
     }
 
     fn local_cache_remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        self.cache_ops.remove(&self.id[..], key)
+        let removed = self.cache_ops.remove(&self.id[..], key)?;
+        self.update_cache_usage()?;
+        Ok(removed)
     }
 
     fn awake_wakers(&mut self) -> Result<Vec<i32>> {
@@ -548,9 +792,41 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
             GeneralMessage => create_channel!(self.message_tx),
             SystemMessage => create_channel!(self.sys_message_tx),
             Query => create_channel!(self.query_tx),
+            UpgradeRequest => create_channel!(self.upgrade_tx),
         }
     }
 
+    fn pink_query(&mut self, contract: &[u8], payload: &[u8]) -> Result<i32> {
+        // The query runs a contract on the host, so it is charged like a lot of guest instructions.
+        const PINK_QUERY_WEIGHT: usize = 1_000_000_000;
+        const PINK_QUERY_BYTE_WEIGHT: usize = 1_000_000;
+
+        let contract: AccountId = contract.try_into().or(Err(OcallError::InvalidParameter))?;
+        self.inner.pay(
+            &mut self.store,
+            (PINK_QUERY_WEIGHT + PINK_QUERY_BYTE_WEIGHT * payload.len()) as _,
+        )?;
+        let fut = self.pink_ops.query(self.id, contract, payload.to_vec());
+        self.resources.push(Resource::PinkQuery(Some(fut)))
+    }
+
+    fn pink_push_command(&mut self, nonce: &[u8], message: &[u8]) -> Result<()> {
+        // Each command ends up as a transaction on chain.
+        const PINK_COMMAND_WEIGHT: usize = 10_000_000_000;
+        const PINK_COMMAND_BYTE_WEIGHT: usize = 10_000_000;
+
+        self.inner.pay(
+            &mut self.store,
+            (PINK_COMMAND_WEIGHT + PINK_COMMAND_BYTE_WEIGHT * (nonce.len() + message.len())) as _,
+        )?;
+        self.pink_ops
+            .push_command(self.id, nonce.to_vec(), message.to_vec())
+    }
+
+    fn upgrade_take_state(&mut self) -> Result<Option<Vec<u8>>> {
+        Ok(self.handoff_state.take())
+    }
+
     fn gas_remaining(&mut self) -> Result<u8> {
         self.inner.pay(&mut self.store, 1_000_000)?;
         Ok(if self.gas_per_breath == 0 {
@@ -605,6 +881,13 @@ impl EnvInner {
         self.set_gas_to_breath(store, gas - cost);
         Ok(())
     }
+
+    /// Syncs the cache usage with the cache, which drops the expired entries on its own.
+    fn update_cache_usage(&self) -> Result<()> {
+        self.usage
+            .set_cache_bytes(self.cache_ops.size(&self.id[..])?);
+        Ok(())
+    }
 }
 
 async fn tcp_connect(host: &str, port: u16) -> std::io::Result<tokio::net::TcpStream> {
@@ -687,6 +970,19 @@ fn do_ocall(
             target: "sidevm",
             "[{vm_id}][tid={task_id:<3}] {func_name}({p0}, {p1}, {p2}, {p3}) = {result:?}"
         );
+        if let Some(recorder) = &env.ocall_trace_recorder {
+            recorder.record(OcallTrace {
+                task_id,
+                func_name,
+                args: [p0, p1, p2, p3],
+                result,
+            });
+        }
+    }
+    if env.limit_exceeded {
+        let vm_id = ShortId(&env.id);
+        log::warn!(target: "sidevm", "[{vm_id}] Resource limit exceeded, terminating...");
+        return Err(OcallAborted::ResourceLimitExceeded);
     }
     convert(result)
 }
@@ -703,6 +999,7 @@ fn convert(result: Result<i32, OcallError>) -> Result<IntRet, OcallAborted> {
 pub enum OcallAborted {
     GasExhausted,
     Stifled,
+    ResourceLimitExceeded,
 }
 
 impl From<OcallAborted> for OcallError {
@@ -710,6 +1007,7 @@ impl From<OcallAborted> for OcallError {
         match aborted {
             OcallAborted::GasExhausted => OcallError::GasExhausted,
             OcallAborted::Stifled => OcallError::Stifled,
+            OcallAborted::ResourceLimitExceeded => OcallError::ResourceLimited,
         }
     }
 }
@@ -719,8 +1017,24 @@ impl fmt::Display for OcallAborted {
         match self {
             OcallAborted::GasExhausted => write!(f, "Gas exhausted"),
             OcallAborted::Stifled => write!(f, "Stifled"),
+            OcallAborted::ResourceLimitExceeded => write!(f, "Resource limit exceeded"),
         }
     }
 }
 
 impl std::error::Error for OcallAborted {}
+
+#[cfg(test)]
+mod tests {
+    use super::GasMeter;
+
+    #[test]
+    fn gas_meter_take_resets() {
+        let meter = GasMeter::default();
+        let shared = meter.clone();
+        shared.add(10);
+        shared.add(5);
+        assert_eq!(meter.take(), 15);
+        assert_eq!(meter.take(), 0);
+    }
+}
@@ -0,0 +1,998 @@
+//! A per-instance in-memory filesystem backing the WASI filesystem calls.
+//!
+//! The root directory `/` is preopened to the guest as fd 3, so ordinary `std::fs` code works with
+//! both absolute and relative paths. The file contents, together with a fixed overhead for every
+//! file and directory, are limited by a quota, and so is the number of opened fds. The contents can
+//! optionally be persisted through a [`FsStore`] when the guest syncs a file, at most once every
+//! [`MIN_SAVE_INTERVAL`], or when the instance is dropped. Writes to stdout and stderr are routed to the sidevm logger line by
+//! line.
+
+use std::collections::{hash_map::DefaultHasher, BTreeMap};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use scale::{Decode, Encode};
+use wasmer_wasi_types::*;
+
+use super::ShortId;
+use crate::VmId;
+
+type Result<T, E = __wasi_errno_t> = std::result::Result<T, E>;
+
+/// Default max total size of the files in a sidevm instance.
+pub const DEFAULT_FS_QUOTA: usize = 16 * 1024 * 1024;
+
+/// Size charged to the quota for every file or directory, in addition to its path and contents.
+const NODE_OVERHEAD: usize = 128;
+
+/// Max number of fds opened at the same time, including the stdio and the root directory.
+const MAX_OPEN_FDS: usize = 1024;
+
+/// Min interval between two saves requested by the guest. Each save seals the whole filesystem.
+pub const MIN_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Max length of a line buffered from stdout or stderr before it's flushed to the logger.
+const MAX_LOG_LINE: usize = 4096;
+
+const STDIN_FD: u32 = 0;
+const STDOUT_FD: u32 = 1;
+const STDERR_FD: u32 = 2;
+/// The preopened root directory.
+pub const ROOT_FD: u32 = 3;
+
+/// All the rights defined in WASI preview1.
+pub const ALL_RIGHTS: __wasi_rights_t = (1 << 29) - 1;
+
+/// Persistent storage of the filesystem contents, keyed by the VM id.
+pub trait FsStore {
+    fn load(&self, vm_id: &[u8]) -> Option<Vec<u8>>;
+    fn save(&self, vm_id: &[u8], snapshot: &[u8]);
+}
+
+pub type DynFsStore = &'static (dyn FsStore + Send + Sync);
+
+/// Configuration of the virtual filesystem of a sidevm instance.
+#[derive(Clone, Copy)]
+pub struct FsConfig {
+    /// Max total size of the files in bytes.
+    pub quota: usize,
+    /// Where to persist the filesystem. The filesystem is in-memory only if not given.
+    pub store: Option<DynFsStore>,
+}
+
+impl FsConfig {
+    /// Takes a copy of the filesystem persisted for `vm_id`, to put it back by saving the copy.
+    ///
+    /// Returns `None` if there is no store.
+    pub(crate) fn backup(&self, vm_id: &VmId) -> Option<PendingSave> {
+        let store = self.store?;
+        let snapshot = store
+            .load(vm_id)
+            .unwrap_or_else(|| BTreeMap::<String, Node>::new().encode());
+        Some(PendingSave {
+            store,
+            vm_id: *vm_id,
+            snapshot,
+        })
+    }
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        Self {
+            quota: DEFAULT_FS_QUOTA,
+            store: None,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Clone)]
+enum Node {
+    File(Vec<u8>),
+    Dir,
+}
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File {
+        path: String,
+        offset: u64,
+        append: bool,
+        readable: bool,
+        writable: bool,
+    },
+    Dir {
+        path: String,
+    },
+}
+
+impl Handle {
+    fn path_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Handle::File { path, .. } | Handle::Dir { path } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+pub struct Stat {
+    pub filetype: __wasi_filetype_t,
+    pub ino: u64,
+    pub size: u64,
+}
+
+pub struct FdStat {
+    pub filetype: __wasi_filetype_t,
+    pub flags: __wasi_fdflags_t,
+    pub rights: __wasi_rights_t,
+}
+
+/// The size charged to the quota for the node at `key`, besides the file contents.
+fn node_cost(key: &str) -> usize {
+    if key.is_empty() {
+        // The root directory is always there.
+        0
+    } else {
+        NODE_OVERHEAD + key.len()
+    }
+}
+
+/// A snapshot of the filesystem, to be saved to the store out of the instance lock.
+pub(crate) struct PendingSave {
+    store: DynFsStore,
+    vm_id: VmId,
+    snapshot: Vec<u8>,
+}
+
+impl PendingSave {
+    pub fn save(self) {
+        self.store.save(&self.vm_id, &self.snapshot);
+    }
+}
+
+pub(crate) struct VirtualFs {
+    vm_id: VmId,
+    /// All files and directories keyed by their path without the leading `/`. The root is "".
+    nodes: BTreeMap<String, Node>,
+    handles: BTreeMap<u32, Handle>,
+    used: usize,
+    quota: usize,
+    store: Option<DynFsStore>,
+    dirty: bool,
+    last_saved: Option<Instant>,
+    stdout_buf: Vec<u8>,
+    stderr_buf: Vec<u8>,
+}
+
+impl VirtualFs {
+    pub fn new(vm_id: VmId, config: FsConfig) -> Self {
+        let mut nodes = BTreeMap::new();
+        if let Some(snapshot) = config.store.and_then(|store| store.load(&vm_id)) {
+            match Decode::decode(&mut &snapshot[..]) {
+                Ok(restored) => nodes = restored,
+                Err(err) => {
+                    let vm_id = ShortId(&vm_id);
+                    log::error!(target: "sidevm", "[{vm_id}] Failed to restore the filesystem: {err}");
+                }
+            }
+        }
+        nodes.insert(String::new(), Node::Dir);
+        let used = nodes
+            .iter()
+            .map(|(key, node)| match node {
+                Node::File(data) => node_cost(key) + data.len(),
+                Node::Dir => node_cost(key),
+            })
+            .sum();
+        let mut handles = BTreeMap::new();
+        handles.insert(STDIN_FD, Handle::Stdin);
+        handles.insert(STDOUT_FD, Handle::Stdout);
+        handles.insert(STDERR_FD, Handle::Stderr);
+        handles.insert(
+            ROOT_FD,
+            Handle::Dir {
+                path: String::new(),
+            },
+        );
+        Self {
+            vm_id,
+            nodes,
+            handles,
+            used,
+            quota: config.quota,
+            store: config.store,
+            dirty: false,
+            last_saved: None,
+            stdout_buf: vec![],
+            stderr_buf: vec![],
+        }
+    }
+
+    /// Flushes the buffered output and saves the contents to the store if changed.
+    pub fn sync(&mut self) {
+        if let Some(pending) = self.take_snapshot() {
+            pending.save();
+        }
+    }
+
+    /// Flushes the buffered output to the logger.
+    pub fn flush_logs(&mut self) {
+        self.flush_log(STDOUT_FD, true);
+        self.flush_log(STDERR_FD, true);
+    }
+
+    /// Whether a save requested by the guest at `now` should be done: the contents are changed,
+    /// there is a store, and the last save is at least [`MIN_SAVE_INTERVAL`] ago.
+    pub fn save_due(&self, now: Instant) -> bool {
+        let interval_passed = match self.last_saved {
+            Some(last) => now.saturating_duration_since(last) >= MIN_SAVE_INTERVAL,
+            None => true,
+        };
+        self.dirty && self.store.is_some() && interval_passed
+    }
+
+    /// Flushes the buffered output and takes a snapshot of the contents if changed.
+    pub fn take_snapshot(&mut self) -> Option<PendingSave> {
+        self.flush_logs();
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        let store = self.store?;
+        self.last_saved = Some(Instant::now());
+        Some(PendingSave {
+            store,
+            vm_id: self.vm_id,
+            snapshot: self.nodes.encode(),
+        })
+    }
+
+    /// Total size of the files, charged to the quota.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Max total size of the files.
+    pub fn quota(&self) -> usize {
+        self.quota
+    }
+
+    /// Charges `cost` bytes to the quota.
+    fn charge(&mut self, cost: usize) -> Result<()> {
+        let used = self.used.checked_add(cost).ok_or(__WASI_ENOSPC)?;
+        if used > self.quota {
+            return Err(__WASI_ENOSPC);
+        }
+        self.used = used;
+        Ok(())
+    }
+
+    fn handle(&self, fd: u32) -> Result<&Handle> {
+        self.handles.get(&fd).ok_or(__WASI_EBADF)
+    }
+
+    fn handle_mut(&mut self, fd: u32) -> Result<&mut Handle> {
+        self.handles.get_mut(&fd).ok_or(__WASI_EBADF)
+    }
+
+    fn dir_path(&self, fd: u32) -> Result<&str> {
+        match self.handle(fd)? {
+            Handle::Dir { path } => Ok(path),
+            _ => Err(__WASI_ENOTDIR),
+        }
+    }
+
+    /// Resolves a guest path relative to the directory `dir_fd` to a node key. Absolute paths are
+    /// resolved from the root.
+    fn resolve(&self, dir_fd: u32, path: &str) -> Result<String> {
+        let base = self.dir_path(dir_fd)?;
+        let base = if path.starts_with('/') { "" } else { base };
+        let mut components: Vec<&str> = base.split('/').filter(|c| !c.is_empty()).collect();
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop().ok_or(__WASI_ENOTCAPABLE)?;
+                }
+                _ => components.push(component),
+            }
+        }
+        Ok(components.join("/"))
+    }
+
+    fn parent_of(key: &str) -> &str {
+        key.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+    }
+
+    fn ensure_parent_dir(&self, key: &str) -> Result<()> {
+        match self.nodes.get(Self::parent_of(key)) {
+            Some(Node::Dir) => Ok(()),
+            Some(Node::File(_)) => Err(__WASI_ENOTDIR),
+            None => Err(__WASI_ENOENT),
+        }
+    }
+
+    /// Keys of the direct children of the directory.
+    fn children(&self, key: &str) -> Vec<String> {
+        let prefix = if key.is_empty() {
+            String::new()
+        } else {
+            format!("{key}/")
+        };
+        self.nodes
+            .range(prefix.clone()..)
+            .map(|(k, _)| k)
+            .take_while(|k| k.starts_with(&prefix))
+            .filter(|k| k.len() > prefix.len() && !k[prefix.len()..].contains('/'))
+            .cloned()
+            .collect()
+    }
+
+    fn alloc_fd(&self) -> Result<u32> {
+        if self.handles.len() >= MAX_OPEN_FDS {
+            return Err(__WASI_EMFILE);
+        }
+        (ROOT_FD + 1..u32::MAX)
+            .find(|fd| !self.handles.contains_key(fd))
+            .ok_or(__WASI_EMFILE)
+    }
+
+    pub fn open(
+        &mut self,
+        dir_fd: u32,
+        path: &str,
+        oflags: __wasi_oflags_t,
+        rights: __wasi_rights_t,
+        fdflags: __wasi_fdflags_t,
+    ) -> Result<u32> {
+        let key = self.resolve(dir_fd, path)?;
+        let create = oflags & __WASI_O_CREAT != 0;
+        let exclusive = oflags & __WASI_O_EXCL != 0;
+        let directory = oflags & __WASI_O_DIRECTORY != 0;
+        let truncate = oflags & __WASI_O_TRUNC != 0;
+        let handle = match self.nodes.get(&key) {
+            None => {
+                if !create || directory {
+                    return Err(__WASI_ENOENT);
+                }
+                self.ensure_parent_dir(&key)?;
+                self.alloc_fd()?;
+                self.charge(node_cost(&key))?;
+                self.nodes.insert(key.clone(), Node::File(vec![]));
+                self.dirty = true;
+                None
+            }
+            Some(_) if create && exclusive => return Err(__WASI_EEXIST),
+            Some(Node::Dir) => {
+                if truncate {
+                    return Err(__WASI_EISDIR);
+                }
+                Some(Handle::Dir { path: key.clone() })
+            }
+            Some(Node::File(_)) if directory => return Err(__WASI_ENOTDIR),
+            Some(Node::File(_)) => None,
+        };
+        let handle = match handle {
+            Some(handle) => handle,
+            None => {
+                if truncate {
+                    self.resize(&key, 0)?;
+                }
+                Handle::File {
+                    path: key,
+                    offset: 0,
+                    append: fdflags & __WASI_FDFLAG_APPEND != 0,
+                    readable: rights & __WASI_RIGHT_FD_READ != 0,
+                    writable: rights & __WASI_RIGHT_FD_WRITE != 0,
+                }
+            }
+        };
+        let fd = self.alloc_fd()?;
+        self.handles.insert(fd, handle);
+        Ok(fd)
+    }
+
+    pub fn close(&mut self, fd: u32) -> Result<()> {
+        if fd == STDOUT_FD || fd == STDERR_FD {
+            self.flush_log(fd, true);
+        }
+        self.handles.remove(&fd).map(drop).ok_or(__WASI_EBADF)
+    }
+
+    pub fn renumber(&mut self, from: u32, to: u32) -> Result<()> {
+        let handle = self.handles.remove(&from).ok_or(__WASI_EBADF)?;
+        self.handles.insert(to, handle);
+        Ok(())
+    }
+
+    fn file(&self, key: &str) -> Result<&Vec<u8>> {
+        match self.nodes.get(key) {
+            Some(Node::File(data)) => Ok(data),
+            Some(Node::Dir) => Err(__WASI_EISDIR),
+            None => Err(__WASI_ENOENT),
+        }
+    }
+
+    fn file_mut(&mut self, key: &str) -> Result<&mut Vec<u8>> {
+        match self.nodes.get_mut(key) {
+            Some(Node::File(data)) => Ok(data),
+            Some(Node::Dir) => Err(__WASI_EISDIR),
+            None => Err(__WASI_ENOENT),
+        }
+    }
+
+    /// Resizes the file, charging the growth to the quota.
+    fn resize(&mut self, key: &str, new_len: u64) -> Result<()> {
+        let new_len = usize::try_from(new_len).or(Err(__WASI_EFBIG))?;
+        let old_len = self.file(key)?.len();
+        if new_len > old_len {
+            self.charge(new_len - old_len)?;
+        } else {
+            self.used -= old_len - new_len;
+        }
+        self.file_mut(key)?.resize(new_len, 0);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn write_at(&mut self, key: &str, offset: u64, data: &[u8]) -> Result<()> {
+        let end = offset.checked_add(data.len() as u64).ok_or(__WASI_EFBIG)?;
+        if end > self.file(key)?.len() as u64 {
+            self.resize(key, end)?;
+        }
+        let offset = offset as usize;
+        self.file_mut(key)?[offset..offset + data.len()].copy_from_slice(data);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn read_at(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let data = self.file(key)?;
+        let start = (offset.min(data.len() as u64)) as usize;
+        let end = start.saturating_add(len).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    /// Reads at most `len` bytes from the current offset of the file.
+    pub fn read(&mut self, fd: u32, len: usize) -> Result<Vec<u8>> {
+        let (key, cur) = match self.handle(fd)? {
+            Handle::Stdin => return Ok(vec![]),
+            Handle::File {
+                path,
+                offset,
+                readable: true,
+                ..
+            } => (path.clone(), *offset),
+            Handle::Dir { .. } => return Err(__WASI_EISDIR),
+            _ => return Err(__WASI_EBADF),
+        };
+        let data = self.read_at(&key, cur, len)?;
+        if let Handle::File { offset, .. } = self.handle_mut(fd)? {
+            *offset = cur + data.len() as u64;
+        }
+        Ok(data)
+    }
+
+    pub fn pread(&self, fd: u32, len: usize, offset: u64) -> Result<Vec<u8>> {
+        match self.handle(fd)? {
+            Handle::File {
+                path,
+                readable: true,
+                ..
+            } => self.read_at(path, offset, len),
+            Handle::Dir { .. } => Err(__WASI_EISDIR),
+            _ => Err(__WASI_EBADF),
+        }
+    }
+
+    /// Writes the data at the current offset of the file, or to the logger for stdout and stderr.
+    pub fn write(&mut self, fd: u32, data: &[u8]) -> Result<usize> {
+        let (key, cur) = match self.handle(fd)? {
+            Handle::Stdout | Handle::Stderr => {
+                self.write_log(fd, data);
+                return Ok(data.len());
+            }
+            Handle::File {
+                path,
+                offset,
+                append,
+                writable: true,
+                ..
+            } => {
+                let cur = if *append {
+                    self.file(path)?.len() as u64
+                } else {
+                    *offset
+                };
+                (path.clone(), cur)
+            }
+            Handle::Dir { .. } => return Err(__WASI_EISDIR),
+            _ => return Err(__WASI_EBADF),
+        };
+        self.write_at(&key, cur, data)?;
+        if let Handle::File { offset, .. } = self.handle_mut(fd)? {
+            *offset = cur + data.len() as u64;
+        }
+        Ok(data.len())
+    }
+
+    pub fn pwrite(&mut self, fd: u32, data: &[u8], offset: u64) -> Result<usize> {
+        let key = match self.handle(fd)? {
+            Handle::Stdout | Handle::Stderr => return self.write(fd, data),
+            Handle::File {
+                path,
+                writable: true,
+                ..
+            } => path.clone(),
+            Handle::Dir { .. } => return Err(__WASI_EISDIR),
+            _ => return Err(__WASI_EBADF),
+        };
+        self.write_at(&key, offset, data)?;
+        Ok(data.len())
+    }
+
+    pub fn seek(&mut self, fd: u32, delta: i64, whence: __wasi_whence_t) -> Result<u64> {
+        let (key, cur) = match self.handle(fd)? {
+            Handle::File { path, offset, .. } => (path.clone(), *offset),
+            Handle::Dir { .. } => return Err(__WASI_EISDIR),
+            _ => return Err(__WASI_ESPIPE),
+        };
+        let base = match whence {
+            __WASI_WHENCE_SET => 0,
+            __WASI_WHENCE_CUR => cur,
+            __WASI_WHENCE_END => self.file(&key)?.len() as u64,
+            _ => return Err(__WASI_EINVAL),
+        };
+        let new_offset = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        }
+        .ok_or(__WASI_EINVAL)?;
+        if let Handle::File { offset, .. } = self.handle_mut(fd)? {
+            *offset = new_offset;
+        }
+        Ok(new_offset)
+    }
+
+    pub fn set_size(&mut self, fd: u32, size: u64) -> Result<()> {
+        let key = match self.handle(fd)? {
+            Handle::File {
+                path,
+                writable: true,
+                ..
+            } => path.clone(),
+            Handle::Dir { .. } => return Err(__WASI_EISDIR),
+            _ => return Err(__WASI_EBADF),
+        };
+        self.resize(&key, size)
+    }
+
+    pub fn allocate(&mut self, fd: u32, offset: u64, len: u64) -> Result<()> {
+        let key = match self.handle(fd)? {
+            Handle::File {
+                path,
+                writable: true,
+                ..
+            } => path.clone(),
+            Handle::Dir { .. } => return Err(__WASI_EISDIR),
+            _ => return Err(__WASI_EBADF),
+        };
+        let end = offset.checked_add(len).ok_or(__WASI_EFBIG)?;
+        if end > self.file(&key)?.len() as u64 {
+            self.resize(&key, end)?;
+        }
+        Ok(())
+    }
+
+    pub fn fdstat(&self, fd: u32) -> Result<FdStat> {
+        let stat = match self.handle(fd)? {
+            Handle::Stdin => FdStat {
+                filetype: __WASI_FILETYPE_CHARACTER_DEVICE,
+                flags: 0,
+                rights: __WASI_RIGHT_FD_READ,
+            },
+            Handle::Stdout | Handle::Stderr => FdStat {
+                filetype: __WASI_FILETYPE_CHARACTER_DEVICE,
+                flags: 0,
+                rights: __WASI_RIGHT_FD_WRITE,
+            },
+            Handle::File { append, .. } => FdStat {
+                filetype: __WASI_FILETYPE_REGULAR_FILE,
+                flags: if *append { __WASI_FDFLAG_APPEND } else { 0 },
+                rights: ALL_RIGHTS,
+            },
+            Handle::Dir { .. } => FdStat {
+                filetype: __WASI_FILETYPE_DIRECTORY,
+                flags: 0,
+                rights: ALL_RIGHTS,
+            },
+        };
+        Ok(stat)
+    }
+
+    fn stat_key(&self, key: &str) -> Result<Stat> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let (filetype, size) = match self.nodes.get(key).ok_or(__WASI_ENOENT)? {
+            Node::File(data) => (__WASI_FILETYPE_REGULAR_FILE, data.len() as u64),
+            Node::Dir => (__WASI_FILETYPE_DIRECTORY, 0),
+        };
+        Ok(Stat {
+            filetype,
+            ino: hasher.finish(),
+            size,
+        })
+    }
+
+    pub fn filestat(&self, fd: u32) -> Result<Stat> {
+        match self.handle(fd)? {
+            Handle::File { path, .. } | Handle::Dir { path } => self.stat_key(path),
+            _ => Ok(Stat {
+                filetype: __WASI_FILETYPE_CHARACTER_DEVICE,
+                ino: fd as u64,
+                size: 0,
+            }),
+        }
+    }
+
+    pub fn path_filestat(&self, dir_fd: u32, path: &str) -> Result<Stat> {
+        let key = self.resolve(dir_fd, path)?;
+        self.stat_key(&key)
+    }
+
+    /// Lists the directory entries as `(name, stat)`.
+    pub fn readdir(&self, fd: u32) -> Result<Vec<(String, Stat)>> {
+        let key = self.dir_path(fd)?;
+        self.children(key)
+            .into_iter()
+            .map(|child| {
+                let stat = self.stat_key(&child)?;
+                let name = child.rsplit('/').next().unwrap_or_default().to_string();
+                Ok((name, stat))
+            })
+            .collect()
+    }
+
+    pub fn create_dir(&mut self, dir_fd: u32, path: &str) -> Result<()> {
+        let key = self.resolve(dir_fd, path)?;
+        if self.nodes.contains_key(&key) {
+            return Err(__WASI_EEXIST);
+        }
+        self.ensure_parent_dir(&key)?;
+        self.charge(node_cost(&key))?;
+        self.nodes.insert(key, Node::Dir);
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn remove_dir(&mut self, dir_fd: u32, path: &str) -> Result<()> {
+        let key = self.resolve(dir_fd, path)?;
+        match self.nodes.get(&key) {
+            Some(Node::Dir) if key.is_empty() => return Err(__WASI_EBUSY),
+            Some(Node::Dir) => {}
+            Some(Node::File(_)) => return Err(__WASI_ENOTDIR),
+            None => return Err(__WASI_ENOENT),
+        }
+        if !self.children(&key).is_empty() {
+            return Err(__WASI_ENOTEMPTY);
+        }
+        self.nodes.remove(&key);
+        self.used -= node_cost(&key);
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn unlink(&mut self, dir_fd: u32, path: &str) -> Result<()> {
+        let key = self.resolve(dir_fd, path)?;
+        let len = self.file(&key)?.len();
+        self.nodes.remove(&key);
+        self.used -= node_cost(&key) + len;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn rename(
+        &mut self,
+        old_fd: u32,
+        old_path: &str,
+        new_fd: u32,
+        new_path: &str,
+    ) -> Result<()> {
+        let old_key = self.resolve(old_fd, old_path)?;
+        let new_key = self.resolve(new_fd, new_path)?;
+        if old_key.is_empty() || new_key.is_empty() {
+            return Err(__WASI_EBUSY);
+        }
+        let is_dir = match self.nodes.get(&old_key).ok_or(__WASI_ENOENT)? {
+            Node::Dir => true,
+            Node::File(_) => false,
+        };
+        if old_key == new_key {
+            return Ok(());
+        }
+        if is_dir && new_key.starts_with(&format!("{old_key}/")) {
+            return Err(__WASI_EINVAL);
+        }
+        self.ensure_parent_dir(&new_key)?;
+        let old_prefix = format!("{old_key}/");
+        let moved: Vec<String> = self
+            .nodes
+            .keys()
+            .filter(|k| **k == old_key || k.starts_with(&old_prefix))
+            .cloned()
+            .collect();
+        match self.nodes.get(&new_key) {
+            None => {}
+            Some(Node::File(_)) if is_dir => return Err(__WASI_ENOTDIR),
+            Some(Node::Dir) if !is_dir => return Err(__WASI_EISDIR),
+            Some(Node::Dir) if !self.children(&new_key).is_empty() => return Err(__WASI_ENOTEMPTY),
+            _ => {}
+        }
+        // The paths of the moved nodes are charged to the quota as well.
+        if new_key.len() > old_key.len() {
+            self.charge((new_key.len() - old_key.len()) * moved.len())?;
+        } else {
+            self.used -= (old_key.len() - new_key.len()) * moved.len();
+        }
+        match self.nodes.get(&new_key) {
+            None => {}
+            Some(Node::File(_)) => self.unlink(ROOT_FD, &new_key)?,
+            Some(Node::Dir) => self.remove_dir(ROOT_FD, &new_key)?,
+        }
+        for key in moved {
+            let node = self.nodes.remove(&key).expect("The key exists");
+            let renamed = format!("{new_key}{}", &key[old_key.len()..]);
+            self.nodes.insert(renamed, node);
+        }
+        // The opened handles follow the renamed files.
+        for handle in self.handles.values_mut() {
+            if let Some(path) = handle.path_mut() {
+                if *path == old_key || path.starts_with(&old_prefix) {
+                    *path = format!("{new_key}{}", &path[old_key.len()..]);
+                }
+            }
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn write_log(&mut self, fd: u32, data: &[u8]) {
+        let buf = if fd == STDOUT_FD {
+            &mut self.stdout_buf
+        } else {
+            &mut self.stderr_buf
+        };
+        buf.extend_from_slice(data);
+        self.flush_log(fd, false);
+    }
+
+    /// Logs the complete lines in the buffer, or everything if `all` is set.
+    fn flush_log(&mut self, fd: u32, all: bool) {
+        let (buf, name, level) = if fd == STDOUT_FD {
+            (&mut self.stdout_buf, "stdout", log::Level::Info)
+        } else {
+            (&mut self.stderr_buf, "stderr", log::Level::Warn)
+        };
+        let vm_id = ShortId(&self.vm_id);
+        while let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line[..pos]);
+            log::log!(target: "sidevm", level, "[{vm_id}][{name}] {line}");
+        }
+        if !buf.is_empty() && (all || buf.len() >= MAX_LOG_LINE) {
+            let line = String::from_utf8_lossy(buf);
+            log::log!(target: "sidevm", level, "[{vm_id}][{name}] {line}");
+            buf.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    const RW: __wasi_rights_t = __WASI_RIGHT_FD_READ | __WASI_RIGHT_FD_WRITE;
+
+    fn new_fs(quota: usize) -> VirtualFs {
+        VirtualFs::new([0; 32], FsConfig { quota, store: None })
+    }
+
+    fn create(fs: &mut VirtualFs, path: &str) -> Result<u32> {
+        fs.open(ROOT_FD, path, __WASI_O_CREAT, RW, 0)
+    }
+
+    #[test]
+    fn write_then_read_back() {
+        let mut fs = new_fs(DEFAULT_FS_QUOTA);
+        let fd = create(&mut fs, "a.txt").unwrap();
+        assert_eq!(fs.write(fd, b"hello").unwrap(), 5);
+        assert_eq!(fs.seek(fd, 0, __WASI_WHENCE_SET).unwrap(), 0);
+        assert_eq!(fs.read(fd, 100).unwrap(), b"hello");
+        assert_eq!(fs.pread(fd, 3, 2).unwrap(), b"llo");
+    }
+
+    #[test]
+    fn absolute_paths_resolve_from_root() {
+        let mut fs = new_fs(DEFAULT_FS_QUOTA);
+        fs.create_dir(ROOT_FD, "/data").unwrap();
+        let dir = fs.open(ROOT_FD, "data", __WASI_O_DIRECTORY, RW, 0).unwrap();
+        let fd = fs.open(dir, "/data/a", __WASI_O_CREAT, RW, 0).unwrap();
+        fs.write(fd, b"x").unwrap();
+        assert_eq!(fs.path_filestat(dir, "a").unwrap().size, 1);
+        assert_eq!(fs.path_filestat(ROOT_FD, "/data/./a").unwrap().size, 1);
+    }
+
+    #[test]
+    fn escaping_the_root_is_rejected() {
+        let mut fs = new_fs(DEFAULT_FS_QUOTA);
+        assert_eq!(create(&mut fs, "../a").unwrap_err(), __WASI_ENOTCAPABLE);
+        assert_eq!(create(&mut fs, "/..").unwrap_err(), __WASI_ENOTCAPABLE);
+    }
+
+    #[test]
+    fn empty_files_and_dirs_count_towards_quota() {
+        let mut fs = new_fs(10 * NODE_OVERHEAD);
+        let mut created = 0;
+        loop {
+            match fs.create_dir(ROOT_FD, &format!("d{created}")) {
+                Ok(()) => created += 1,
+                Err(err) => {
+                    assert_eq!(err, __WASI_ENOSPC);
+                    break;
+                }
+            }
+        }
+        assert!(created < 10);
+        assert_eq!(create(&mut fs, "f").unwrap_err(), __WASI_ENOSPC);
+        fs.remove_dir(ROOT_FD, "d0").unwrap();
+        assert!(create(&mut fs, "f").is_ok());
+    }
+
+    #[test]
+    fn writes_beyond_quota_fail_and_unlink_releases_space() {
+        let mut fs = new_fs(NODE_OVERHEAD + 100);
+        let fd = create(&mut fs, "f").unwrap();
+        assert_eq!(fs.write(fd, &[0; 100]).unwrap_err(), __WASI_ENOSPC);
+        fs.write(fd, &[0; 50]).unwrap();
+        fs.close(fd).unwrap();
+        fs.unlink(ROOT_FD, "f").unwrap();
+        assert_eq!(fs.used, 0);
+    }
+
+    #[test]
+    fn rename_moves_children_and_handles() {
+        let mut fs = new_fs(DEFAULT_FS_QUOTA);
+        fs.create_dir(ROOT_FD, "a").unwrap();
+        let fd = create(&mut fs, "a/f").unwrap();
+        let used = fs.used;
+        fs.rename(ROOT_FD, "a", ROOT_FD, "bb").unwrap();
+        assert_eq!(fs.used, used + 2);
+        assert!(fs.path_filestat(ROOT_FD, "bb/f").is_ok());
+        assert_eq!(fs.path_filestat(ROOT_FD, "a").unwrap_err(), __WASI_ENOENT);
+        fs.write(fd, b"x").unwrap();
+        assert_eq!(fs.path_filestat(ROOT_FD, "bb/f").unwrap().size, 1);
+    }
+
+    #[test]
+    fn rename_onto_non_empty_dir_keeps_usage() {
+        let mut fs = new_fs(DEFAULT_FS_QUOTA);
+        fs.create_dir(ROOT_FD, "a").unwrap();
+        fs.create_dir(ROOT_FD, "bbbb").unwrap();
+        create(&mut fs, "bbbb/f").unwrap();
+        let used = fs.used;
+        assert_eq!(
+            fs.rename(ROOT_FD, "a", ROOT_FD, "bbbb").unwrap_err(),
+            __WASI_ENOTEMPTY
+        );
+        assert_eq!(fs.used, used);
+    }
+
+    #[test]
+    fn open_fds_are_limited() {
+        let mut fs = new_fs(DEFAULT_FS_QUOTA);
+        create(&mut fs, "f").unwrap();
+        while fs.handles.len() < MAX_OPEN_FDS {
+            fs.open(ROOT_FD, "f", 0, RW, 0).unwrap();
+        }
+        assert_eq!(fs.open(ROOT_FD, "f", 0, RW, 0).unwrap_err(), __WASI_EMFILE);
+        assert_eq!(create(&mut fs, "g").unwrap_err(), __WASI_EMFILE);
+        assert_eq!(fs.path_filestat(ROOT_FD, "g").unwrap_err(), __WASI_ENOENT);
+    }
+
+    #[derive(Default)]
+    struct MemStore(Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+    impl FsStore for MemStore {
+        fn load(&self, vm_id: &[u8]) -> Option<Vec<u8>> {
+            self.0.lock().unwrap().get(vm_id).cloned()
+        }
+        fn save(&self, vm_id: &[u8], snapshot: &[u8]) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(vm_id.to_vec(), snapshot.to_vec());
+        }
+    }
+
+    #[test]
+    fn contents_survive_restart_through_store() {
+        let store: DynFsStore = Box::leak(Box::new(MemStore::default()));
+        let config = FsConfig {
+            quota: DEFAULT_FS_QUOTA,
+            store: Some(store),
+        };
+        let mut fs = VirtualFs::new([1; 32], config);
+        fs.create_dir(ROOT_FD, "d").unwrap();
+        let fd = create(&mut fs, "d/f").unwrap();
+        fs.write(fd, b"persisted").unwrap();
+        let used = fs.used;
+        fs.sync();
+
+        let mut fs = VirtualFs::new([1; 32], config);
+        assert_eq!(fs.used, used);
+        let fd = fs.open(ROOT_FD, "/d/f", 0, RW, 0).unwrap();
+        assert_eq!(fs.read(fd, 100).unwrap(), b"persisted");
+        assert!(VirtualFs::new([2; 32], config)
+            .path_filestat(ROOT_FD, "d")
+            .is_err());
+    }
+
+    #[test]
+    fn saves_are_rate_limited() {
+        let store: DynFsStore = Box::leak(Box::new(MemStore::default()));
+        let config = FsConfig {
+            quota: DEFAULT_FS_QUOTA,
+            store: Some(store),
+        };
+        let mut fs = VirtualFs::new([3; 32], config);
+        let now = Instant::now();
+        assert!(!fs.save_due(now));
+        create(&mut fs, "f").unwrap();
+        assert!(fs.save_due(now));
+        fs.take_snapshot().expect("Changed").save();
+        assert!(!fs.save_due(now));
+
+        create(&mut fs, "g").unwrap();
+        assert!(!fs.save_due(Instant::now()));
+        assert!(fs.save_due(Instant::now() + MIN_SAVE_INTERVAL));
+        // The contents are still saved on shutdown.
+        fs.sync();
+        let fs = VirtualFs::new([3; 32], config);
+        assert!(fs.path_filestat(ROOT_FD, "g").is_ok());
+
+        let mut fs = VirtualFs::new([4; 32], FsConfig::default());
+        create(&mut fs, "f").unwrap();
+        assert!(!fs.save_due(now));
+    }
+
+    #[test]
+    fn backup_puts_back_the_stored_contents() {
+        let store: DynFsStore = Box::leak(Box::new(MemStore::default()));
+        let config = FsConfig {
+            quota: DEFAULT_FS_QUOTA,
+            store: Some(store),
+        };
+        assert!(FsConfig::default().backup(&[5; 32]).is_none());
+        let backup = config.backup(&[5; 32]).expect("Has store");
+        let mut fs = VirtualFs::new([5; 32], config);
+        create(&mut fs, "f").unwrap();
+        fs.sync();
+        backup.save();
+        let mut fs = VirtualFs::new([5; 32], config);
+        assert!(fs.path_filestat(ROOT_FD, "f").is_err());
+
+        create(&mut fs, "g").unwrap();
+        fs.sync();
+        let backup = config.backup(&[5; 32]).expect("Has store");
+        let mut fs = VirtualFs::new([5; 32], config);
+        fs.unlink(ROOT_FD, "g").unwrap();
+        fs.sync();
+        backup.save();
+        let fs = VirtualFs::new([5; 32], config);
+        assert!(fs.path_filestat(ROOT_FD, "g").is_ok());
+    }
+}
@@ -1,4 +1,7 @@
-use super::{Env as WasiEnv, Result};
+use super::{
+    vfs::{Stat, VirtualFs, ROOT_FD},
+    Env as WasiEnv, Result,
+};
 use libc::{
     clock_getres, clock_gettime, timespec, CLOCK_MONOTONIC, CLOCK_PROCESS_CPUTIME_ID,
     CLOCK_REALTIME, CLOCK_THREAD_CPUTIME_ID,
@@ -6,7 +9,8 @@ use libc::{
 use sidevm_env::{OcallError, OcallFuncs};
 use thiserror::Error;
 use wasmer::{
-    namespace, AsStoreMut, Exports, Function, FunctionEnv, FunctionEnvMut, Memory32, WasmPtr,
+    namespace, AsStoreMut, Exports, Function, FunctionEnv, FunctionEnvMut, Memory32, MemoryView,
+    WasmPtr,
 };
 use wasmer_wasi_types::*;
 
@@ -84,6 +88,113 @@ pub(crate) fn wasi_imports(store: &mut impl AsStoreMut, env: &FunctionEnv<WasiEn
     }
 }
 
+/// Name of the preopened root directory.
+const ROOT_DIR_NAME: &[u8] = b"/";
+
+/// Runs `f` with the virtual filesystem and the guest memory, converting the result to an errno.
+fn with_fs(
+    env: &FunctionEnvMut<WasiEnv>,
+    f: impl FnOnce(&mut VirtualFs, &MemoryView) -> Result<(), __wasi_errno_t>,
+) -> __wasi_errno_t {
+    let mut guard = env.data().inner.lock().unwrap();
+    let inner = &mut *guard;
+    let memory = inner.memory.unwrap_ref().view(env);
+    match f(&mut inner.fs, &memory) {
+        Ok(()) => __WASI_ESUCCESS,
+        Err(err) => err,
+    }
+}
+
+fn write_u32(memory: &MemoryView, ptr: WasmPtr<u32>, value: u32) -> Result<(), __wasi_errno_t> {
+    ptr.deref(memory).write(value).or(Err(__WASI_EFAULT))
+}
+
+fn write_filestat(
+    memory: &MemoryView,
+    ptr: WasmPtr<__wasi_filestat_t>,
+    stat: Stat,
+) -> Result<(), __wasi_errno_t> {
+    let filestat = __wasi_filestat_t {
+        st_dev: 0,
+        st_ino: stat.ino,
+        st_filetype: stat.filetype,
+        st_nlink: 1,
+        st_size: stat.size,
+        st_atim: 0,
+        st_mtim: 0,
+        st_ctim: 0,
+    };
+    ptr.deref(memory).write(filestat).or(Err(__WASI_EFAULT))
+}
+
+fn read_path(memory: &MemoryView, ptr: WasmPtr<u8>, len: u32) -> Result<String, __wasi_errno_t> {
+    ptr.read_utf8_string(memory, len).or(Err(__WASI_EINVAL))
+}
+
+/// Reads the `(buf, buf_len)` pairs of an iovec array.
+fn read_iovecs(
+    memory: &MemoryView,
+    iovs: WasmPtr<__wasi_iovec_t<Memory32>>,
+    iovs_len: u32,
+) -> Result<Vec<(u64, usize)>, __wasi_errno_t> {
+    let iovs = iovs
+        .slice(memory, iovs_len)
+        .and_then(|slice| slice.read_to_vec())
+        .or(Err(__WASI_EFAULT))?;
+    Ok(iovs
+        .into_iter()
+        .map(|iov| (iov.buf as u64, iov.buf_len as usize))
+        .collect())
+}
+
+fn total_len(iovs: &[(u64, usize)]) -> usize {
+    iovs.iter()
+        .map(|(_, len)| len)
+        .fold(0, |a, b| a.saturating_add(*b))
+}
+
+/// Copies the data into the guest buffers in order.
+fn scatter(memory: &MemoryView, iovs: &[(u64, usize)], data: &[u8]) -> Result<(), __wasi_errno_t> {
+    let mut rest = data;
+    for &(buf, len) in iovs {
+        if rest.is_empty() {
+            break;
+        }
+        let n = len.min(rest.len());
+        memory.write(buf, &rest[..n]).or(Err(__WASI_EFAULT))?;
+        rest = &rest[n..];
+    }
+    Ok(())
+}
+
+/// Concatenates the data of the guest buffers, which must not exceed `max_len` bytes in total.
+fn gather(
+    memory: &MemoryView,
+    iovs: WasmPtr<__wasi_ciovec_t<Memory32>>,
+    iovs_len: u32,
+    max_len: usize,
+) -> Result<Vec<u8>, __wasi_errno_t> {
+    let iovs = iovs
+        .slice(memory, iovs_len)
+        .and_then(|slice| slice.read_to_vec())
+        .or(Err(__WASI_EFAULT))?;
+    let total = iovs
+        .iter()
+        .fold(0usize, |a, iov| a.saturating_add(iov.buf_len as usize));
+    if total > max_len {
+        return Err(__WASI_EFBIG);
+    }
+    let mut data = Vec::with_capacity(total);
+    for iov in iovs {
+        let buf = WasmPtr::<u8>::new(iov.buf)
+            .slice(memory, iov.buf_len)
+            .and_then(|slice| slice.read_to_vec())
+            .or(Err(__WASI_EFAULT))?;
+        data.extend_from_slice(&buf);
+    }
+    Ok(data)
+}
+
 pub fn args_get(
     _env: FunctionEnvMut<WasiEnv>,
     _argv: WasmPtr<WasmPtr<u8>>,
@@ -140,6 +251,16 @@ pub fn clock_time_get(
     _precision: __wasi_timestamp_t,
     time: WasmPtr<__wasi_timestamp_t>,
 ) -> __wasi_errno_t {
+    let guard = env.data().inner.lock().unwrap();
+    if let Some(clock) = &guard.virtual_clock {
+        let t_out = clock.now(clock_id == __WASI_CLOCK_REALTIME).as_nanos();
+        let memory = guard.memory.unwrap_ref().view(&env);
+        let time = time.deref(&memory);
+        wasi_try!(time.write(t_out as __wasi_timestamp_t).ok(), __WASI_EACCES);
+        return __WASI_ESUCCESS;
+    }
+    drop(guard);
+
     let unix_clock_id = match clock_id {
         __WASI_CLOCK_MONOTONIC => CLOCK_MONOTONIC,
         __WASI_CLOCK_PROCESS_CPUTIME_ID => CLOCK_PROCESS_CPUTIME_ID,
@@ -202,28 +323,37 @@ pub fn fd_advise(
 }
 
 pub fn fd_allocate(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _offset: __wasi_filesize_t,
-    _len: __wasi_filesize_t,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    offset: __wasi_filesize_t,
+    len: __wasi_filesize_t,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, _| fs.allocate(fd, offset, len))
 }
 
-pub fn fd_close(_env: FunctionEnvMut<WasiEnv>, _fd: __wasi_fd_t) -> __wasi_errno_t {
-    __WASI_ENOSYS
+pub fn fd_close(env: FunctionEnvMut<WasiEnv>, fd: __wasi_fd_t) -> __wasi_errno_t {
+    with_fs(&env, |fs, _| fs.close(fd))
 }
 
-pub fn fd_datasync(_env: FunctionEnvMut<WasiEnv>, _fd: __wasi_fd_t) -> __wasi_errno_t {
-    __WASI_ENOSYS
+pub fn fd_datasync(env: FunctionEnvMut<WasiEnv>, fd: __wasi_fd_t) -> __wasi_errno_t {
+    fd_sync(env, fd)
 }
 
 pub fn fd_fdstat_get(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _buf_ptr: WasmPtr<__wasi_fdstat_t>,
-) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    buf_ptr: WasmPtr<__wasi_fdstat_t>,
+) -> __wasi_errno_t {
+    with_fs(&env, |fs, memory| {
+        let stat = fs.fdstat(fd)?;
+        let fdstat = __wasi_fdstat_t {
+            fs_filetype: stat.filetype,
+            fs_flags: stat.flags,
+            fs_rights_base: stat.rights,
+            fs_rights_inheriting: stat.rights,
+        };
+        buf_ptr.deref(memory).write(fdstat).or(Err(__WASI_EFAULT))
+    })
 }
 
 pub fn fd_fdstat_set_flags(
@@ -231,7 +361,7 @@ pub fn fd_fdstat_set_flags(
     _fd: __wasi_fd_t,
     _flags: __wasi_fdflags_t,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    __WASI_ESUCCESS
 }
 
 pub fn fd_fdstat_set_rights(
@@ -240,23 +370,25 @@ pub fn fd_fdstat_set_rights(
     _fs_rights_base: __wasi_rights_t,
     _fs_rights_inheriting: __wasi_rights_t,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    __WASI_ESUCCESS
 }
 
 pub fn fd_filestat_get(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _buf: WasmPtr<__wasi_filestat_t>,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    buf: WasmPtr<__wasi_filestat_t>,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        write_filestat(memory, buf, fs.filestat(fd)?)
+    })
 }
 
 pub fn fd_filestat_set_size(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _st_size: __wasi_filesize_t,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    st_size: __wasi_filesize_t,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, _| fs.set_size(fd, st_size))
 }
 
 pub fn fd_filestat_set_times(
@@ -266,127 +398,222 @@ pub fn fd_filestat_set_times(
     _st_mtim: __wasi_timestamp_t,
     _fst_flags: __wasi_fstflags_t,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    // Timestamps are not tracked by the virtual filesystem.
+    __WASI_ESUCCESS
 }
 
 pub fn fd_pread(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _iovs: WasmPtr<__wasi_iovec_t<Memory32>>,
-    _iovs_len: u32,
-    _offset: __wasi_filesize_t,
-    _nread: WasmPtr<u32>,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    iovs: WasmPtr<__wasi_iovec_t<Memory32>>,
+    iovs_len: u32,
+    offset: __wasi_filesize_t,
+    nread: WasmPtr<u32>,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        let iovs = read_iovecs(memory, iovs, iovs_len)?;
+        let data = fs.pread(fd, total_len(&iovs), offset)?;
+        scatter(memory, &iovs, &data)?;
+        write_u32(memory, nread, data.len() as u32)
+    })
 }
 
 pub fn fd_prestat_get(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _buf: WasmPtr<__wasi_prestat_t>,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    buf: WasmPtr<__wasi_prestat_t>,
 ) -> __wasi_errno_t {
-    __WASI_EBADF
+    if fd != ROOT_FD {
+        return __WASI_EBADF;
+    }
+    with_fs(&env, |_, memory| {
+        // The layout of `__wasi_prestat_t` for a directory: the tag followed by the name length.
+        let mut prestat = [0u8; 8];
+        prestat[0] = __WASI_PREOPENTYPE_DIR;
+        prestat[4..].copy_from_slice(&(ROOT_DIR_NAME.len() as u32).to_le_bytes());
+        memory
+            .write(buf.offset() as u64, &prestat)
+            .or(Err(__WASI_EFAULT))
+    })
 }
 
 pub fn fd_prestat_dir_name(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _path: WasmPtr<u8>,
-    _path_len: u32,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    path: WasmPtr<u8>,
+    path_len: u32,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    if fd != ROOT_FD {
+        return __WASI_EBADF;
+    }
+    if (path_len as usize) < ROOT_DIR_NAME.len() {
+        return __WASI_EINVAL;
+    }
+    with_fs(&env, |_, memory| {
+        memory
+            .write(path.offset() as u64, ROOT_DIR_NAME)
+            .or(Err(__WASI_EFAULT))
+    })
 }
 
 pub fn fd_pwrite(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _iovs: WasmPtr<__wasi_ciovec_t<Memory32>>,
-    _iovs_len: u32,
-    _offset: __wasi_filesize_t,
-    _nwritten: WasmPtr<u32>,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    iovs: WasmPtr<__wasi_ciovec_t<Memory32>>,
+    iovs_len: u32,
+    offset: __wasi_filesize_t,
+    nwritten: WasmPtr<u32>,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        let data = gather(memory, iovs, iovs_len, fs.quota())?;
+        let written = fs.pwrite(fd, &data, offset)?;
+        write_u32(memory, nwritten, written as u32)
+    })
 }
 
 pub fn fd_read(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _iovs: WasmPtr<__wasi_iovec_t<Memory32>>,
-    _iovs_len: u32,
-    _nread: WasmPtr<u32>,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    iovs: WasmPtr<__wasi_iovec_t<Memory32>>,
+    iovs_len: u32,
+    nread: WasmPtr<u32>,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        let iovs = read_iovecs(memory, iovs, iovs_len)?;
+        let data = fs.read(fd, total_len(&iovs))?;
+        scatter(memory, &iovs, &data)?;
+        write_u32(memory, nread, data.len() as u32)
+    })
 }
 
 pub fn fd_readdir(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _buf: WasmPtr<u8>,
-    _buf_len: u32,
-    _cookie: __wasi_dircookie_t,
-    _bufused: WasmPtr<u32>,
-) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    buf: WasmPtr<u8>,
+    buf_len: u32,
+    cookie: __wasi_dircookie_t,
+    bufused: WasmPtr<u32>,
+) -> __wasi_errno_t {
+    with_fs(&env, |fs, memory| {
+        let mut output = vec![];
+        let entries = fs.readdir(fd)?;
+        for (i, (name, stat)) in entries.iter().enumerate().skip(cookie as usize) {
+            if output.len() >= buf_len as usize {
+                break;
+            }
+            // The layout of `__wasi_dirent_t`, followed by the name.
+            output.extend_from_slice(&(i as u64 + 1).to_le_bytes());
+            output.extend_from_slice(&stat.ino.to_le_bytes());
+            output.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            output.extend_from_slice(&[stat.filetype, 0, 0, 0]);
+            output.extend_from_slice(name.as_bytes());
+        }
+        // A truncated last entry tells the guest to retry with a larger buffer.
+        output.truncate(buf_len as usize);
+        memory
+            .write(buf.offset() as u64, &output)
+            .or(Err(__WASI_EFAULT))?;
+        write_u32(memory, bufused, output.len() as u32)
+    })
 }
 
 pub fn fd_renumber(
-    _env: FunctionEnvMut<WasiEnv>,
-    _from: __wasi_fd_t,
-    _to: __wasi_fd_t,
+    env: FunctionEnvMut<WasiEnv>,
+    from: __wasi_fd_t,
+    to: __wasi_fd_t,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, _| fs.renumber(from, to))
 }
 
 pub fn fd_seek(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _offset: __wasi_filedelta_t,
-    _whence: __wasi_whence_t,
-    _newoffset: WasmPtr<__wasi_filesize_t>,
-) -> __wasi_errno_t {
-    __WASI_ENOSYS
-}
-
-pub fn fd_sync(_env: FunctionEnvMut<WasiEnv>, _fd: __wasi_fd_t) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    offset: __wasi_filedelta_t,
+    whence: __wasi_whence_t,
+    newoffset: WasmPtr<__wasi_filesize_t>,
+) -> __wasi_errno_t {
+    with_fs(&env, |fs, memory| {
+        let offset = fs.seek(fd, offset, whence)?;
+        newoffset.deref(memory).write(offset).or(Err(__WASI_EFAULT))
+    })
+}
+
+/// Saves the filesystem to the store, at most once every `MIN_SAVE_INTERVAL`. The skipped changes
+/// are saved by a later sync or when the instance is dropped.
+pub fn fd_sync(mut env: FunctionEnvMut<WasiEnv>, fd: __wasi_fd_t) -> __wasi_errno_t {
+    // A save seals the whole filesystem, so it's charged by the size of the files.
+    const FS_SAVE_WEIGHT: u64 = 100_000_000;
+    const FS_SAVE_BYTE_WEIGHT: u64 = 1_000;
+
+    let (data, mut store) = env.data_and_store_mut();
+    let pending = {
+        let mut inner = data.inner.lock().unwrap();
+        wasi_try!(inner.fs.fdstat(fd));
+        if !inner.fs.save_due(std::time::Instant::now()) {
+            inner.fs.flush_logs();
+            return __WASI_ESUCCESS;
+        }
+        let cost = FS_SAVE_WEIGHT + FS_SAVE_BYTE_WEIGHT * inner.fs.used() as u64;
+        if inner.pay(&mut store, cost).is_err() {
+            return __WASI_EDQUOT;
+        }
+        inner.fs.take_snapshot()
+    };
+    // Sealing and writing the snapshot don't need the instance lock.
+    if let Some(pending) = pending {
+        pending.save();
+    }
+    __WASI_ESUCCESS
 }
 
 pub fn fd_tell(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _offset: WasmPtr<__wasi_filesize_t>,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    offset: WasmPtr<__wasi_filesize_t>,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        let cur = fs.seek(fd, 0, __WASI_WHENCE_CUR)?;
+        offset.deref(memory).write(cur).or(Err(__WASI_EFAULT))
+    })
 }
 
 pub fn fd_write(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _iovs: WasmPtr<__wasi_ciovec_t<Memory32>>,
-    _iovs_len: u32,
-    _nwritten: WasmPtr<u32>,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    iovs: WasmPtr<__wasi_ciovec_t<Memory32>>,
+    iovs_len: u32,
+    nwritten: WasmPtr<u32>,
 ) -> __wasi_errno_t {
-    __WASI_ESUCCESS
+    with_fs(&env, |fs, memory| {
+        let data = gather(memory, iovs, iovs_len, fs.quota())?;
+        let written = fs.write(fd, &data)?;
+        write_u32(memory, nwritten, written as u32)
+    })
 }
 
 pub fn path_create_directory(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _path: WasmPtr<u8>,
-    _path_len: u32,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    path: WasmPtr<u8>,
+    path_len: u32,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        fs.create_dir(fd, &read_path(memory, path, path_len)?)
+    })
 }
 
 pub fn path_filestat_get(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
     _flags: __wasi_lookupflags_t,
-    _path: WasmPtr<u8>,
-    _path_len: u32,
-    _buf: WasmPtr<__wasi_filestat_t>,
+    path: WasmPtr<u8>,
+    path_len: u32,
+    buf: WasmPtr<__wasi_filestat_t>,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        let stat = fs.path_filestat(fd, &read_path(memory, path, path_len)?)?;
+        write_filestat(memory, buf, stat)
+    })
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -400,7 +627,7 @@ pub fn path_filestat_set_times(
     _st_mtim: __wasi_timestamp_t,
     _fst_flags: __wasi_fstflags_t,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    __WASI_ESUCCESS
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -414,23 +641,27 @@ pub fn path_link(
     _new_path: WasmPtr<u8>,
     _new_path_len: u32,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    __WASI_ENOTSUP
 }
 
 #[allow(clippy::too_many_arguments)]
 pub fn path_open(
-    _env: FunctionEnvMut<WasiEnv>,
-    _dirfd: __wasi_fd_t,
+    env: FunctionEnvMut<WasiEnv>,
+    dirfd: __wasi_fd_t,
     _dirflags: __wasi_lookupflags_t,
-    _path: WasmPtr<u8>,
-    _path_len: u32,
-    _o_flags: __wasi_oflags_t,
-    _fs_rights_base: __wasi_rights_t,
+    path: WasmPtr<u8>,
+    path_len: u32,
+    o_flags: __wasi_oflags_t,
+    fs_rights_base: __wasi_rights_t,
     _fs_rights_inheriting: __wasi_rights_t,
-    _fs_flags: __wasi_fdflags_t,
-    _fd: WasmPtr<__wasi_fd_t>,
+    fs_flags: __wasi_fdflags_t,
+    fd: WasmPtr<__wasi_fd_t>,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        let path = read_path(memory, path, path_len)?;
+        let new_fd = fs.open(dirfd, &path, o_flags, fs_rights_base, fs_flags)?;
+        write_u32(memory, fd, new_fd)
+    })
 }
 
 pub fn path_readlink(
@@ -442,28 +673,35 @@ pub fn path_readlink(
     _buf_len: u32,
     _buf_used: WasmPtr<u32>,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    // There are no symlinks in the virtual filesystem.
+    __WASI_EINVAL
 }
 
 pub fn path_remove_directory(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _path: WasmPtr<u8>,
-    _path_len: u32,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    path: WasmPtr<u8>,
+    path_len: u32,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        fs.remove_dir(fd, &read_path(memory, path, path_len)?)
+    })
 }
 
 pub fn path_rename(
-    _env: FunctionEnvMut<WasiEnv>,
-    _old_fd: __wasi_fd_t,
-    _old_path: WasmPtr<u8>,
-    _old_path_len: u32,
-    _new_fd: __wasi_fd_t,
-    _new_path: WasmPtr<u8>,
-    _new_path_len: u32,
+    env: FunctionEnvMut<WasiEnv>,
+    old_fd: __wasi_fd_t,
+    old_path: WasmPtr<u8>,
+    old_path_len: u32,
+    new_fd: __wasi_fd_t,
+    new_path: WasmPtr<u8>,
+    new_path_len: u32,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        let old_path = read_path(memory, old_path, old_path_len)?;
+        let new_path = read_path(memory, new_path, new_path_len)?;
+        fs.rename(old_fd, &old_path, new_fd, &new_path)
+    })
 }
 
 pub fn path_symlink(
@@ -474,16 +712,18 @@ pub fn path_symlink(
     _new_path: WasmPtr<u8>,
     _new_path_len: u32,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    __WASI_ENOTSUP
 }
 
 pub fn path_unlink_file(
-    _env: FunctionEnvMut<WasiEnv>,
-    _fd: __wasi_fd_t,
-    _path: WasmPtr<u8>,
-    _path_len: u32,
+    env: FunctionEnvMut<WasiEnv>,
+    fd: __wasi_fd_t,
+    path: WasmPtr<u8>,
+    path_len: u32,
 ) -> __wasi_errno_t {
-    __WASI_ENOSYS
+    with_fs(&env, |fs, memory| {
+        fs.unlink(fd, &read_path(memory, path, path_len)?)
+    })
 }
 
 pub fn poll_oneoff(
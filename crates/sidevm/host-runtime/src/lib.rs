@@ -2,14 +2,20 @@ mod async_context;
 mod env;
 pub mod instrument;
 mod metering;
+mod module_cache;
 mod resource;
 mod run;
 pub mod service;
 mod tls;
 
-pub use env::{CacheOps, DynCacheOps, OcallAborted, ShortId};
+pub use env::{
+    CacheOps, DynCacheOps, DynFsStore, DynPinkOps, Env, FsConfig, FsStore, GasMeter, OcallAborted,
+    OcallTrace, OcallTraceRecorder, PinkOps, ShortId, VirtualClock, DEFAULT_FS_QUOTA,
+};
 
 pub type VmId = [u8; 32];
+pub use module_cache::{set_module_cache, DynModuleCacheStore, ModuleCacheStore};
+pub use resource::{vm_listener_addr, ResourceLimits, ResourceUsage, ResourceUsageStats};
 pub use run::WasmRun;
 
 pub use sidevm_env::OcallError;
@@ -2,6 +2,9 @@ use std::sync::Arc;
 use wasmer::{wasmparser::Operator, CompilerConfig};
 use wasmer_middlewares::metering::Metering;
 
+/// Version of the cost function. Bump it on changing the costs, to invalidate the cached modules.
+pub(crate) const METERING_VERSION: u32 = 1;
+
 pub(crate) fn metering<C: CompilerConfig>(mut compiler: C) -> C {
     compiler.push_middleware(Arc::new(Metering::new(u64::MAX, cost_function)));
     compiler
@@ -0,0 +1,196 @@
+//! Cache of the compiled wasm modules, to avoid compiling the same code on every start.
+
+use anyhow::{bail, Result};
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use wasmer::{Module, Store};
+
+use crate::{env::OCALL_ABI_VERSION, metering::METERING_VERSION};
+
+/// Persistent storage of the compiled module artifacts, keyed by a hash of the code and the
+/// compiler configuration.
+pub trait ModuleCacheStore {
+    fn load(&self, key: &[u8]) -> Option<Vec<u8>>;
+    fn save(&self, key: &[u8], artifact: &[u8]);
+}
+
+pub type DynModuleCacheStore = &'static (dyn ModuleCacheStore + Send + Sync);
+
+static MODULE_CACHE: OnceCell<DynModuleCacheStore> = OnceCell::new();
+
+/// Set the storage to cache the compiled modules of all instances in. Can only be set once.
+pub fn set_module_cache(store: DynModuleCacheStore) {
+    if MODULE_CACHE.set(store).is_err() {
+        log::warn!(target: "sidevm", "The module cache has already been set");
+    }
+}
+
+const MAGIC: &[u8; 8] = b"svmmod01";
+/// Magic, cache key and checksum of the artifact.
+const HEADER_LEN: usize = 8 + 32 + 32;
+
+/// The key identifying the code and everything else the compiled artifact depends on.
+fn cache_key(code: &[u8], engine: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    let parts: [&[u8]; 8] = [
+        MAGIC,
+        env!("CARGO_PKG_VERSION").as_bytes(),
+        &OCALL_ABI_VERSION.to_le_bytes(),
+        wasmer::VERSION.as_bytes(),
+        std::env::consts::ARCH.as_bytes(),
+        engine.as_bytes(),
+        &METERING_VERSION.to_le_bytes(),
+        &Sha256::digest(code),
+    ];
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Load the compiled module from the cache, or compile the code and put the artifact into the
+/// cache.
+pub(crate) fn load_or_compile(store: &Store, code: &[u8], engine: &str) -> Result<Module> {
+    let cache = match MODULE_CACHE.get() {
+        Some(cache) => *cache,
+        None => return Ok(Module::new(store, code)?),
+    };
+    let key = cache_key(code, engine);
+    let key_hex = hex_fmt::HexFmt(&key[..4]);
+    if let Some(cached) = cache.load(&key) {
+        match load_artifact(store, &key, &cached) {
+            Ok(module) => {
+                log::debug!(target: "sidevm", "Loaded compiled module {key_hex} from cache");
+                return Ok(module);
+            }
+            Err(err) => {
+                log::warn!(target: "sidevm", "Invalid compiled module {key_hex} in cache: {err}");
+            }
+        }
+    }
+    let module = Module::new(store, code)?;
+    match module.serialize() {
+        Ok(artifact) => cache.save(&key, &with_header(&key, &artifact)),
+        Err(err) => {
+            log::warn!(target: "sidevm", "Failed to serialize compiled module {key_hex}: {err}");
+        }
+    }
+    Ok(module)
+}
+
+/// Prepend the header checked by `load_artifact` to the serialized module.
+fn with_header(key: &[u8; 32], artifact: &[u8]) -> Vec<u8> {
+    let mut cached = Vec::with_capacity(HEADER_LEN + artifact.len());
+    cached.extend_from_slice(MAGIC);
+    cached.extend_from_slice(key);
+    cached.extend_from_slice(&Sha256::digest(artifact));
+    cached.extend_from_slice(artifact);
+    cached
+}
+
+fn load_artifact(store: &Store, key: &[u8; 32], cached: &[u8]) -> Result<Module> {
+    if cached.len() < HEADER_LEN {
+        bail!("Truncated artifact");
+    }
+    let (header, artifact) = cached.split_at(HEADER_LEN);
+    if &header[..8] != MAGIC {
+        bail!("Bad magic");
+    }
+    if &header[8..40] != key {
+        bail!("Cache key mismatch");
+    }
+    if header[40..] != Sha256::digest(artifact)[..] {
+        bail!("Checksum mismatch");
+    }
+    // Safety: The artifact was serialized by the same wasmer version and engine, which are
+    // covered by the cache key, and its integrity has been checked above.
+    let module = unsafe { Module::deserialize(store, artifact)? };
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: &str = r#"(module (func (export "answer") (result i32) i32.const 42))"#;
+
+    fn compiled(store: &Store) -> Vec<u8> {
+        let module = Module::new(store, CODE).unwrap();
+        module.serialize().unwrap().to_vec()
+    }
+
+    fn load_error(store: &Store, key: &[u8; 32], cached: &[u8]) -> String {
+        load_artifact(store, key, cached).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn cache_key_covers_the_code_and_engine() {
+        let key = cache_key(b"code", "singlepass");
+        assert_eq!(key, cache_key(b"code", "singlepass"));
+        assert_ne!(key, cache_key(b"code2", "singlepass"));
+        assert_ne!(key, cache_key(b"code", "cranelift"));
+        // The parts are length prefixed, so moving bytes between them changes the key.
+        assert_ne!(cache_key(b"", "ab"), cache_key(b"a", "b"));
+    }
+
+    #[test]
+    fn valid_artifact_is_loaded() {
+        let store = Store::default();
+        let artifact = compiled(&store);
+        let key = cache_key(CODE.as_bytes(), "test");
+        let module = load_artifact(&store, &key, &with_header(&key, &artifact)).unwrap();
+        assert!(module.exports().any(|export| export.name() == "answer"));
+    }
+
+    #[test]
+    fn truncated_artifact_is_rejected() {
+        let store = Store::default();
+        let key = cache_key(CODE.as_bytes(), "test");
+        let cached = with_header(&key, b"artifact");
+        assert_eq!(
+            load_error(&store, &key, &cached[..HEADER_LEN - 1]),
+            "Truncated artifact"
+        );
+        assert_eq!(load_error(&store, &key, &[]), "Truncated artifact");
+        // Truncated in the artifact part.
+        let artifact = compiled(&store);
+        let cached = with_header(&key, &artifact);
+        assert_eq!(
+            load_error(&store, &key, &cached[..cached.len() - 1]),
+            "Checksum mismatch"
+        );
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let store = Store::default();
+        let key = cache_key(CODE.as_bytes(), "test");
+        let mut cached = with_header(&key, b"artifact");
+        cached[0] ^= 1;
+        assert_eq!(load_error(&store, &key, &cached), "Bad magic");
+    }
+
+    #[test]
+    fn mismatched_key_is_rejected() {
+        let store = Store::default();
+        let key = cache_key(CODE.as_bytes(), "test");
+        let other = cache_key(CODE.as_bytes(), "other");
+        let cached = with_header(&other, b"artifact");
+        assert_eq!(load_error(&store, &key, &cached), "Cache key mismatch");
+    }
+
+    #[test]
+    fn checksum_mismatch_is_rejected() {
+        let store = Store::default();
+        let key = cache_key(CODE.as_bytes(), "test");
+        let artifact = compiled(&store);
+        let mut cached = with_header(&key, &artifact);
+        let last = cached.len() - 1;
+        cached[last] ^= 1;
+        assert_eq!(load_error(&store, &key, &cached), "Checksum mismatch");
+        let mut cached = with_header(&key, &artifact);
+        cached[HEADER_LEN - 1] ^= 1;
+        assert_eq!(load_error(&store, &key, &cached), "Checksum mismatch");
+    }
+}
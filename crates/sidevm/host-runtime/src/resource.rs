@@ -1,8 +1,13 @@
+use dashmap::DashMap;
 use futures::pin_mut;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use sidevm_env::{OcallError, Result};
 use std::future::Future;
 use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::Poll::*;
 use tokio::io::{AsyncRead, AsyncWrite as _};
@@ -15,6 +20,153 @@ use Resource::*;
 
 use crate::async_context::{get_task_cx, GuestWaker};
 use crate::tls::TlsStream;
+use crate::VmId;
+
+/// The TCP listeners of all the sidevm instances in this process, keyed by port.
+static LISTENERS: Lazy<DashMap<u16, (VmId, SocketAddr)>> = Lazy::new(Default::default);
+
+/// Registration of a listener in `LISTENERS`, removed on drop.
+pub struct ListenerRegistration {
+    vm_id: VmId,
+    addr: SocketAddr,
+}
+
+impl ListenerRegistration {
+    pub(crate) fn new(vm_id: VmId, addr: SocketAddr) -> Self {
+        LISTENERS.insert(addr.port(), (vm_id, addr));
+        Self { vm_id, addr }
+    }
+}
+
+impl Drop for ListenerRegistration {
+    fn drop(&mut self) {
+        LISTENERS.remove_if(&self.addr.port(), |_, v| *v == (self.vm_id, self.addr));
+    }
+}
+
+/// Returns the address to connect to the listener of the given sidevm instance on `port`, if the
+/// instance is listening on it.
+pub fn vm_listener_addr(vm_id: &VmId, port: u16) -> Option<SocketAddr> {
+    let (owner, mut addr) = *LISTENERS.get(&port)?;
+    if &owner != vm_id {
+        return None;
+    }
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    Some(addr)
+}
+
+/// Limits on the resources held by a sidevm instance.
+///
+/// Creating a resource beyond the limits fails with `OcallError::ResourceLimited`, except that
+/// exceeding `max_outbound_bytes` terminates the instance.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    /// Max number of TCP connections, including the ones being established.
+    pub max_connections: u32,
+    /// Max number of timers.
+    pub max_timers: u32,
+    /// Max number of TCP listeners.
+    pub max_listeners: u32,
+    /// Max number of bytes sent through the TCP connections in total. This is a hard limit.
+    pub max_outbound_bytes: u64,
+    /// Max number of bytes of the keys and values put into the local cache.
+    pub max_cache_bytes: u64,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 256,
+            max_timers: 1024,
+            max_listeners: 8,
+            max_outbound_bytes: 4 * 1024 * 1024 * 1024,
+            max_cache_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// The resources held by a sidevm instance, shared with the host for accounting.
+#[derive(Clone, Default, Debug)]
+pub struct ResourceUsage(Arc<UsageCounters>);
+
+#[derive(Default, Debug)]
+struct UsageCounters {
+    connections: AtomicU32,
+    timers: AtomicU32,
+    listeners: AtomicU32,
+    outbound_bytes: AtomicU64,
+    cache_bytes: AtomicU64,
+}
+
+/// A snapshot of `ResourceUsage`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ResourceUsageStats {
+    pub connections: u32,
+    pub timers: u32,
+    pub listeners: u32,
+    pub outbound_bytes: u64,
+    pub cache_bytes: u64,
+}
+
+impl ResourceUsage {
+    pub fn stats(&self) -> ResourceUsageStats {
+        let counters = &self.0;
+        ResourceUsageStats {
+            connections: counters.connections.load(Ordering::Relaxed),
+            timers: counters.timers.load(Ordering::Relaxed),
+            listeners: counters.listeners.load(Ordering::Relaxed),
+            outbound_bytes: counters.outbound_bytes.load(Ordering::Relaxed),
+            cache_bytes: counters.cache_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn counter(&self, kind: CountedKind) -> &AtomicU32 {
+        match kind {
+            CountedKind::Connection => &self.0.connections,
+            CountedKind::Timer => &self.0.timers,
+            CountedKind::Listener => &self.0.listeners,
+        }
+    }
+
+    /// Adds the bytes sent, returns the total.
+    pub(crate) fn add_outbound_bytes(&self, bytes: u64) -> u64 {
+        self.0
+            .outbound_bytes
+            .fetch_add(bytes, Ordering::Relaxed)
+            .saturating_add(bytes)
+    }
+
+    pub(crate) fn cache_bytes(&self) -> u64 {
+        self.0.cache_bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_cache_bytes(&self, bytes: u64) {
+        self.0.cache_bytes.store(bytes, Ordering::Relaxed)
+    }
+}
+
+/// Kinds of resources limited by count.
+#[derive(Clone, Copy)]
+enum CountedKind {
+    Connection,
+    Timer,
+    Listener,
+}
+
+impl CountedKind {
+    fn limit(self, limits: &ResourceLimits) -> u32 {
+        match self {
+            CountedKind::Connection => limits.max_connections,
+            CountedKind::Timer => limits.max_timers,
+            CountedKind::Listener => limits.max_listeners,
+        }
+    }
+}
 
 pub enum Resource {
     Sleep(Pin<Box<Sleep>>),
@@ -23,14 +175,27 @@ pub enum Resource {
     TcpListener {
         listener: TcpListener,
         tls_config: Option<Arc<ServerConfig>>,
+        _registration: Option<ListenerRegistration>,
     },
     TcpStream(TcpStream),
     TlsStream(Box<TlsStream>),
     TcpConnect(Pin<Box<dyn Future<Output = std::io::Result<TcpStream>> + Send>>),
     TlsConnect(Pin<Box<dyn Future<Output = std::io::Result<TlsStream>> + Send>>),
+    PinkQuery(Option<Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send>>>),
 }
 
 impl Resource {
+    fn counted_kind(&self) -> Option<CountedKind> {
+        match self {
+            Sleep(_) => Some(CountedKind::Timer),
+            TcpListener { .. } => Some(CountedKind::Listener),
+            TcpStream(_) | TlsStream(_) | TcpConnect(_) | TlsConnect(_) => {
+                Some(CountedKind::Connection)
+            }
+            ChannelRx(_) | OneshotTx(_) | PinkQuery(_) => None,
+        }
+    }
+
     pub(crate) fn poll(&mut self, waker_id: i32) -> Result<Vec<u8>> {
         use crate::async_context::poll_in_task_cx;
         let waker = GuestWaker::from_id(waker_id);
@@ -45,6 +210,16 @@ impl Resource {
                     Pending => Err(OcallError::Pending),
                 }
             }
+            PinkQuery(query) => {
+                let fut = query.as_mut().ok_or(OcallError::EndOfFile)?;
+                match poll_in_task_cx(waker, fut.as_mut()) {
+                    Ready(rv) => {
+                        *query = None;
+                        rv
+                    }
+                    Pending => Err(OcallError::Pending),
+                }
+            }
             _ => Err(OcallError::UnsupportedOperation),
         }
     }
@@ -171,14 +346,23 @@ impl Resource {
     }
 }
 
-#[derive(Default)]
 pub struct ResourceKeeper {
     resources: Vec<Option<Resource>>,
+    limits: ResourceLimits,
+    usage: ResourceUsage,
 }
 
 const RESOURCE_ID_MAX: usize = 8192;
 
 impl ResourceKeeper {
+    pub fn new(limits: ResourceLimits, usage: ResourceUsage) -> Self {
+        Self {
+            resources: Default::default(),
+            limits,
+            usage,
+        }
+    }
+
     pub fn get_mut(&mut self, id: i32) -> Result<&mut Resource> {
         self.resources
             .get_mut(id as usize)
@@ -187,6 +371,22 @@ impl ResourceKeeper {
     }
 
     pub fn push(&mut self, resource: Resource) -> Result<i32> {
+        // Every counted resource is checked here, including the connections established or
+        // accepted, so they can never exceed the limits.
+        if let Some(kind) = resource.counted_kind() {
+            if self.usage.counter(kind).load(Ordering::Relaxed) >= kind.limit(&self.limits) {
+                return Err(OcallError::ResourceLimited);
+            }
+        }
+        let kind = resource.counted_kind();
+        let id = self.insert(resource)?;
+        if let Some(kind) = kind {
+            self.usage.counter(kind).fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(id)
+    }
+
+    fn insert(&mut self, resource: Resource) -> Result<i32> {
         for (i, res) in self.resources.iter_mut().enumerate() {
             if res.is_none() {
                 let id = i.try_into().or(Err(OcallError::ResourceLimited))?;
@@ -211,6 +411,101 @@ impl ResourceKeeper {
         if resource_id >= self.resources.len() {
             return None;
         }
-        self.resources[resource_id].take()
+        let resource = self.resources[resource_id].take()?;
+        if let Some(kind) = resource.counted_kind() {
+            self.usage.counter(kind).fetch_sub(1, Ordering::Relaxed);
+        }
+        Some(resource)
+    }
+}
+
+impl Drop for ResourceKeeper {
+    fn drop(&mut self) {
+        for id in 0..self.resources.len() {
+            self.take(id as _);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_context::{set_task_cx, set_task_env};
+    use crate::env::TaskSet;
+
+    /// Poll the resource as the guest would do in an ocall.
+    fn guest_poll(res: &mut Resource) -> Result<Vec<u8>> {
+        let waker = futures::task::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+        let tasks = Arc::new(TaskSet::with_task0());
+        set_task_env(tasks, 0, || set_task_cx(&mut cx, || res.poll(0)))
+    }
+
+    #[test]
+    fn pink_query_resolves_once() {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<Vec<u8>>>();
+        let mut res = PinkQuery(Some(Box::pin(async move {
+            rx.await.or(Err(OcallError::IoError))?
+        })));
+        assert!(matches!(guest_poll(&mut res), Err(OcallError::Pending)));
+        tx.send(Ok(b"output".to_vec())).unwrap();
+        assert_eq!(guest_poll(&mut res).unwrap(), b"output");
+        assert!(matches!(guest_poll(&mut res), Err(OcallError::EndOfFile)));
+    }
+
+    #[test]
+    fn pink_query_error_is_returned() {
+        let mut res = PinkQuery(Some(Box::pin(async { Err(OcallError::NotFound) })));
+        assert!(matches!(guest_poll(&mut res), Err(OcallError::NotFound)));
+        assert!(matches!(guest_poll(&mut res), Err(OcallError::EndOfFile)));
+    }
+
+    fn pending_connect() -> Resource {
+        TcpConnect(Box::pin(futures::future::pending()))
+    }
+
+    #[test]
+    fn push_is_limited_by_count() {
+        let limits = ResourceLimits {
+            max_connections: 2,
+            ..Default::default()
+        };
+        let usage = ResourceUsage::default();
+        let mut keeper = ResourceKeeper::new(limits, usage.clone());
+        let first = keeper.push(pending_connect()).unwrap();
+        keeper.push(pending_connect()).unwrap();
+        assert!(matches!(
+            keeper.push(pending_connect()),
+            Err(OcallError::ResourceLimited)
+        ));
+        assert_eq!(usage.stats().connections, 2);
+
+        // Uncounted resources are not limited.
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        keeper.push(ChannelRx(rx)).unwrap();
+
+        assert!(keeper.take(first).is_some());
+        assert_eq!(usage.stats().connections, 1);
+        keeper.push(pending_connect()).unwrap();
+        assert_eq!(usage.stats().connections, 2);
+    }
+
+    #[test]
+    fn usage_is_released_on_drop() {
+        let usage = ResourceUsage::default();
+        let mut keeper = ResourceKeeper::new(Default::default(), usage.clone());
+        keeper.push(pending_connect()).unwrap();
+        keeper.push(pending_connect()).unwrap();
+        drop(keeper);
+        assert_eq!(usage.stats().connections, 0);
+    }
+
+    #[test]
+    fn outbound_bytes_are_accumulated() {
+        let usage = ResourceUsage::default();
+        assert_eq!(usage.add_outbound_bytes(10), 10);
+        assert_eq!(usage.clone().add_outbound_bytes(5), 15);
+        assert_eq!(usage.add_outbound_bytes(u64::MAX), u64::MAX);
+        assert!(ResourceLimits::default().max_outbound_bytes < u64::MAX);
     }
 }
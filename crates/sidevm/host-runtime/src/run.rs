@@ -3,7 +3,7 @@ use phala_scheduler::TaskScheduler;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use wasmer::{BaseTunables, Engine, Instance, Module, Pages, RuntimeError, Store, TypedFunction};
+use wasmer::{BaseTunables, Engine, Instance, Pages, RuntimeError, Store, TypedFunction};
 #[cfg(feature = "wasmer-compiler-cranelift")]
 use wasmer_compiler_cranelift::Cranelift;
 #[cfg(feature = "wasmer-compiler-llvm")]
@@ -11,8 +11,9 @@ use wasmer_compiler_llvm::LLVM;
 use wasmer_compiler_singlepass::Singlepass;
 use wasmer_tunables::LimitingTunables;
 
-use crate::env::DynCacheOps;
-use crate::{async_context, env, metering::metering, VmId};
+use crate::env::{DynCacheOps, DynPinkOps, FsConfig, GasMeter};
+use crate::resource::{ResourceLimits, ResourceUsage};
+use crate::{async_context, env, metering::metering, module_cache, VmId};
 
 pub struct WasmRun {
     id: VmId,
@@ -29,14 +30,20 @@ impl Drop for WasmRun {
 }
 
 impl WasmRun {
+    #[allow(clippy::too_many_arguments)]
     pub fn run(
         code: &[u8],
         max_pages: u32,
         id: crate::VmId,
         gas_per_breath: u64,
         cache_ops: DynCacheOps,
+        pink_ops: DynPinkOps,
         scheduler: TaskScheduler<VmId>,
         weight: u32,
+        gas_meter: GasMeter,
+        fs_config: FsConfig,
+        limits: ResourceLimits,
+        usage: ResourceUsage,
     ) -> Result<(WasmRun, env::Env)> {
         let compiler_env = std::env::var("WASMER_COMPILER");
         let compiler_env = compiler_env
@@ -60,8 +67,10 @@ impl WasmRun {
         };
         let tunables = LimitingTunables::new(base, Pages(max_pages));
         let mut store = Store::new_with_tunables(&engine, tunables);
-        let module = Module::new(&store, code)?;
-        let (env, import_object) = env::create_env(id, &mut store, cache_ops);
+        let module = module_cache::load_or_compile(&store, code, compiler_env)?;
+        let (env, import_object) = env::create_env(
+            id, &mut store, cache_ops, pink_ops, fs_config, limits, usage,
+        );
         let instance = Instance::new(&mut store, &module, &import_object)?;
         let memory = instance
             .exports
@@ -71,6 +80,7 @@ impl WasmRun {
         env.set_memory(memory.clone());
         env.set_instance(instance);
         env.set_gas_per_breath(gas_per_breath);
+        env.set_gas_meter(gas_meter);
         env.set_weight(weight);
         Ok((
             WasmRun {
@@ -92,8 +102,9 @@ impl Future for WasmRun {
         let _guard = futures::ready!(self.scheduler.poll_resume(cx, &self.id, self.env.weight()));
         let run = self.get_mut();
         run.env.reset_gas_to_breath(&mut run.store);
-        match async_context::set_task_cx(cx, || run.wasm_poll_entry.call(&mut run.store))
-        {
+        let rv = async_context::set_task_cx(cx, || run.wasm_poll_entry.call(&mut run.store));
+        run.env.meter_gas_consumed(&mut run.store);
+        match rv {
             Ok(rv) => {
                 if rv == 0 {
                     if run.env.has_more_ready() {
@@ -1,4 +1,5 @@
-use crate::env::DynCacheOps;
+use crate::env::{DynCacheOps, DynPinkOps, Env, FsConfig, GasMeter, PendingSave};
+use crate::resource::{ResourceLimits, ResourceUsage};
 use crate::{env::OcallAborted, run::WasmRun};
 use crate::{ShortId, VmId};
 use anyhow::{Context as _, Result};
@@ -7,10 +8,12 @@ use phala_scheduler::TaskScheduler;
 use serde::{Deserialize, Serialize};
 use sidevm_env::messages::AccountId;
 use std::future::Future;
+use std::time::Duration;
 use tokio::{
     sync::mpsc::{channel, Receiver, Sender},
-    sync::oneshot::Sender as OneshotSender,
+    sync::oneshot::{self, Receiver as OneshotReceiver, Sender as OneshotSender},
     task::JoinHandle,
+    time::Instant,
 };
 
 pub use sidevm_env::messages::SystemMessage;
@@ -39,6 +42,10 @@ pub enum ExitReason {
     Restore,
     /// The sidevm was deployed without code, so it it waiting to a custom code uploading.
     WaitingForCode,
+    /// Terminated due to exceeding a hard limit in `ResourceLimits`.
+    ResourceLimitExceeded,
+    /// Crashed too many times, and the host gave up restarting it.
+    RestartsExhausted,
 }
 
 pub enum Command {
@@ -56,6 +63,63 @@ pub enum Command {
     },
     // Update the task scheduling weight
     UpdateWeight(u32),
+    // Upgrade the instance to new code, handing off the state of the running instance.
+    Upgrade {
+        code: Vec<u8>,
+        reply_tx: OneshotSender<UpgradeResult>,
+    },
+}
+
+/// The result of a code upgrade.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum UpgradeResult {
+    /// The new code is running.
+    Upgraded,
+    /// The new code failed to start or crashed during startup. Rolled back to the old code.
+    RolledBack,
+    /// The running instance doesn't accept upgrade requests. Nothing changed.
+    Unsupported,
+    /// The running instance didn't hand off its state in time. Nothing changed.
+    HandoffFailed,
+    /// Another upgrade is in progress. Nothing changed.
+    InProgress,
+}
+
+/// Time for the running instance to hand off its state on an upgrade request.
+const UPGRADE_HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+/// The upgraded instance is rolled back if it crashes within this period after started.
+const UPGRADE_STARTUP_PERIOD: Duration = Duration::from_secs(10);
+
+/// An upgrade waiting for the running instance to hand off its state.
+struct Handoff {
+    code: Vec<u8>,
+    state_rx: OneshotReceiver<Vec<u8>>,
+    deadline: Instant,
+    reply_tx: OneshotSender<UpgradeResult>,
+}
+
+/// An upgraded instance in its startup period.
+struct Startup {
+    /// The code to roll back to.
+    fallback: Vec<u8>,
+    state: Vec<u8>,
+    /// The filesystem to roll back to.
+    fs_backup: Option<PendingSave>,
+    deadline: Instant,
+    reply_tx: OneshotSender<UpgradeResult>,
+}
+
+enum InstanceEnd {
+    /// The instance terminated.
+    Exited(ExitReason),
+    /// The instance handed off its state to be upgraded to `code`.
+    HandedOff {
+        code: Vec<u8>,
+        state: Vec<u8>,
+        reply_tx: OneshotSender<UpgradeResult>,
+    },
+    /// The upgraded instance crashed during startup.
+    StartupFailed(Startup),
 }
 
 pub struct ServiceRun {
@@ -117,6 +181,7 @@ impl ServiceRun {
 }
 
 impl Spawner {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         &self,
         wasm_bytes: &[u8],
@@ -124,85 +189,101 @@ impl Spawner {
         id: VmId,
         gas_per_breath: u64,
         cache_ops: DynCacheOps,
+        pink_ops: DynPinkOps,
         weight: u32,
+        gas_meter: GasMeter,
+        fs_config: FsConfig,
+        limits: ResourceLimits,
+        usage: ResourceUsage,
     ) -> Result<(CommandSender, JoinHandle<ExitReason>)> {
         let (cmd_tx, mut cmd_rx) = channel(128);
-        let (mut wasm_run, env) = WasmRun::run(
-            wasm_bytes,
-            max_memory_pages,
-            id,
-            gas_per_breath,
-            cache_ops,
-            self.scheduler.clone(),
-            weight,
-        )
-        .context("Failed to create sidevm instance")?;
+        let scheduler = self.scheduler.clone();
+        let start_instance = move |code: &[u8], weight: u32| {
+            WasmRun::run(
+                code,
+                max_memory_pages,
+                id,
+                gas_per_breath,
+                cache_ops,
+                pink_ops,
+                scheduler.clone(),
+                weight,
+                gas_meter.clone(),
+                fs_config,
+                limits,
+                usage.clone(),
+            )
+            .context("Failed to create sidevm instance")
+        };
+        let mut instance = start_instance(wasm_bytes, weight)?;
+        let mut code = wasm_bytes.to_vec();
         let spawner = self.runtime_handle.clone();
         let handle = self.spawn(async move {
             let vmid = ShortId(&id);
-            macro_rules! spawn_push_msg {
-                ($expr: expr, $level: ident, $msg: expr) => {
-                    $level!(target: "sidevm", "[{vmid}] Pushing {} to sidevm", $msg);
-                    let push = match $expr {
-                        None => {
-                            $level!(target: "sidevm", "[{vmid}] Doesn't accept {}", $msg);
-                            continue;
-                        },
-                        Some(v) => v,
-                    };
-                    spawner.spawn(async move {
-                        let vmid = ShortId(&id);
-                        if let Err(e) = push.await {
-                            error!(target: "sidevm", "[{vmid}] Failed to push {} to sidevm: {}", $msg, e);
-                        }
-                    });
-                };
-            }
+            let mut weight = weight;
+            let mut startup = None;
             loop {
-                tokio::select! {
-                    cmd = cmd_rx.recv() => {
-                        match cmd {
-                            None => {
-                                info!(target: "sidevm", "[{vmid}] The command channel is closed. Exiting...");
-                                break ExitReason::InputClosed;
-                            }
-                            Some(Command::Stop) => {
-                                info!(target: "sidevm", "[{vmid}] Received stop command. Exiting...");
-                                break ExitReason::Stopped;
-                            }
-                            Some(Command::PushMessage(msg)) => {
-                                spawn_push_msg!(env.push_message(msg), debug, "message");
-                            }
-                            Some(Command::PushSystemMessage(msg)) => {
-                                spawn_push_msg!(env.push_system_message(msg), trace, "system message");
-                            }
-                            Some(Command::PushQuery{ origin, payload, reply_tx }) => {
-                                spawn_push_msg!(env.push_query(origin, payload, reply_tx), debug, "query");
-                            }
-                            Some(Command::UpdateWeight(weight)) => {
-                                env.set_weight(weight);
-                            }
-                        }
-                    }
-                    rv = &mut wasm_run => {
-                        match rv {
-                            Ok(ret) => {
-                                info!(target: "sidevm", "[{vmid}] The sidevm instance exited with {} normally.", ret);
-                                break ExitReason::Exited(ret);
+                let end =
+                    run_instance(id, instance, &mut cmd_rx, &spawner, &mut weight, startup).await;
+                let (fallback, state, reply_tx, fs_backup) = match end {
+                    InstanceEnd::Exited(reason) => break reason,
+                    InstanceEnd::HandedOff {
+                        code: new_code,
+                        state,
+                        reply_tx,
+                    } => {
+                        // The previous instance has been dropped here, so the new instance can
+                        // take over its listeners. Dropping it also synced its filesystem to the
+                        // `FsStore`, where the new instance loads it from. Without a store, the
+                        // upgraded instance starts with an empty filesystem.
+                        info!(target: "sidevm", "[{vmid}] Starting the upgraded instance...");
+                        let fallback = std::mem::replace(&mut code, new_code);
+                        let fs_backup = fs_config.backup(&id);
+                        match start_instance(&code, weight) {
+                            Ok(new_instance) => {
+                                new_instance.1.set_handoff_state(state.clone());
+                                instance = new_instance;
+                                startup = Some(Startup {
+                                    fallback,
+                                    state,
+                                    fs_backup,
+                                    deadline: Instant::now() + UPGRADE_STARTUP_PERIOD,
+                                    reply_tx,
+                                });
+                                continue;
                             }
                             Err(err) => {
-                                info!(target: "sidevm", "[{vmid}] The sidevm instance exited with error: {}", err);
-                                match err.downcast::<crate::env::OcallAborted>() {
-                                    Ok(err) => {
-                                        break ExitReason::OcallAborted(err);
-                                    }
-                                    Err(_) => {
-                                        break ExitReason::Panicked;
-                                    }
-                                }
+                                error!(target: "sidevm", "[{vmid}] Failed to start the upgraded instance: {:?}", err);
+                                (fallback, state, reply_tx, fs_backup)
                             }
                         }
                     }
+                    InstanceEnd::StartupFailed(Startup {
+                        fallback,
+                        state,
+                        fs_backup,
+                        reply_tx,
+                        ..
+                    }) => (fallback, state, reply_tx, fs_backup),
+                };
+                warn!(target: "sidevm", "[{vmid}] Rolling back to the previous code...");
+                // The failed instance has synced its filesystem to the store on drop, put back
+                // the one the previous code left.
+                if let Some(fs_backup) = fs_backup {
+                    fs_backup.save();
+                }
+                code = fallback;
+                startup = None;
+                match start_instance(&code, weight) {
+                    Ok(new_instance) => {
+                        new_instance.1.set_handoff_state(state);
+                        instance = new_instance;
+                        let _ = reply_tx.send(UpgradeResult::RolledBack);
+                    }
+                    Err(err) => {
+                        error!(target: "sidevm", "[{vmid}] Failed to roll back: {:?}", err);
+                        break ExitReason::Panicked;
+                    }
                 }
             }
         });
@@ -235,3 +316,335 @@ impl Spawner {
         self.runtime_handle.spawn(fut)
     }
 }
+
+/// Drive a sidevm instance until it terminates or hands off its state for an upgrade.
+async fn run_instance(
+    id: VmId,
+    (mut wasm_run, env): (WasmRun, Env),
+    cmd_rx: &mut Receiver<Command>,
+    spawner: &tokio::runtime::Handle,
+    weight: &mut u32,
+    mut startup: Option<Startup>,
+) -> InstanceEnd {
+    let vmid = ShortId(&id);
+    let mut handoff: Option<Handoff> = None;
+    macro_rules! spawn_push_msg {
+        ($expr: expr, $level: ident, $msg: expr) => {
+            $level!(target: "sidevm", "[{vmid}] Pushing {} to sidevm", $msg);
+            let push = match $expr {
+                None => {
+                    $level!(target: "sidevm", "[{vmid}] Doesn't accept {}", $msg);
+                    continue;
+                },
+                Some(v) => v,
+            };
+            spawner.spawn(async move {
+                let vmid = ShortId(&id);
+                if let Err(e) = push.await {
+                    error!(target: "sidevm", "[{vmid}] Failed to push {} to sidevm: {}", $msg, e);
+                }
+            });
+        };
+    }
+    let end = loop {
+        tokio::select! {
+            cmd = cmd_rx.recv() => {
+                match cmd {
+                    None => {
+                        info!(target: "sidevm", "[{vmid}] The command channel is closed. Exiting...");
+                        break InstanceEnd::Exited(ExitReason::InputClosed);
+                    }
+                    Some(Command::Stop) => {
+                        info!(target: "sidevm", "[{vmid}] Received stop command. Exiting...");
+                        break InstanceEnd::Exited(ExitReason::Stopped);
+                    }
+                    Some(Command::PushMessage(msg)) => {
+                        spawn_push_msg!(env.push_message(msg), debug, "message");
+                    }
+                    Some(Command::PushSystemMessage(msg)) => {
+                        spawn_push_msg!(env.push_system_message(msg), trace, "system message");
+                    }
+                    Some(Command::PushQuery{ origin, payload, reply_tx }) => {
+                        spawn_push_msg!(env.push_query(origin, payload, reply_tx), debug, "query");
+                    }
+                    Some(Command::UpdateWeight(new_weight)) => {
+                        *weight = new_weight;
+                        env.set_weight(new_weight);
+                    }
+                    Some(Command::Upgrade { code, reply_tx }) => {
+                        if handoff.is_some() || startup.is_some() {
+                            warn!(target: "sidevm", "[{vmid}] Another upgrade is in progress");
+                            let _ = reply_tx.send(UpgradeResult::InProgress);
+                            continue;
+                        }
+                        let (state_tx, state_rx) = oneshot::channel();
+                        let push = match env.push_upgrade_request(state_tx) {
+                            None => {
+                                warn!(target: "sidevm", "[{vmid}] Doesn't accept upgrade request");
+                                let _ = reply_tx.send(UpgradeResult::Unsupported);
+                                continue;
+                            }
+                            Some(v) => v,
+                        };
+                        info!(target: "sidevm", "[{vmid}] Requesting the instance to hand off its state");
+                        spawner.spawn(async move {
+                            let vmid = ShortId(&id);
+                            if let Err(e) = push.await {
+                                error!(target: "sidevm", "[{vmid}] Failed to push upgrade request to sidevm: {}", e);
+                            }
+                        });
+                        handoff = Some(Handoff {
+                            code,
+                            state_rx,
+                            deadline: Instant::now() + UPGRADE_HANDOFF_TIMEOUT,
+                            reply_tx,
+                        });
+                    }
+                }
+            }
+            state = wait_handoff(&mut handoff) => {
+                let Handoff { code, reply_tx, .. } = handoff.take().expect("BUG: no handoff");
+                match state {
+                    Some(state) => {
+                        info!(target: "sidevm", "[{vmid}] The instance handed off {} bytes of state", state.len());
+                        break InstanceEnd::HandedOff { code, state, reply_tx };
+                    }
+                    None => {
+                        warn!(target: "sidevm", "[{vmid}] The instance didn't hand off its state. Upgrade aborted.");
+                        let _ = reply_tx.send(UpgradeResult::HandoffFailed);
+                    }
+                }
+            }
+            _ = wait_startup(&startup) => {
+                info!(target: "sidevm", "[{vmid}] The upgraded instance started successfully");
+                let startup = startup.take().expect("BUG: no startup");
+                let _ = startup.reply_tx.send(UpgradeResult::Upgraded);
+            }
+            rv = &mut wasm_run => {
+                match rv {
+                    Ok(ret) => {
+                        info!(target: "sidevm", "[{vmid}] The sidevm instance exited with {} normally.", ret);
+                        if let Some(startup) = startup.take() {
+                            // An upgraded program quitting during its startup is no better than
+                            // a crash, roll it back as well.
+                            break InstanceEnd::StartupFailed(startup);
+                        }
+                        break InstanceEnd::Exited(ExitReason::Exited(ret));
+                    }
+                    Err(err) => {
+                        info!(target: "sidevm", "[{vmid}] The sidevm instance exited with error: {}", err);
+                        if let Some(startup) = startup.take() {
+                            break InstanceEnd::StartupFailed(startup);
+                        }
+                        match err.downcast::<OcallAborted>() {
+                            Ok(OcallAborted::ResourceLimitExceeded) => {
+                                break InstanceEnd::Exited(ExitReason::ResourceLimitExceeded);
+                            }
+                            Ok(err) => {
+                                break InstanceEnd::Exited(ExitReason::OcallAborted(err));
+                            }
+                            Err(_) => {
+                                break InstanceEnd::Exited(ExitReason::Panicked);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    };
+    // Drop the instance before the next one starts, which syncs its filesystem to the store.
+    drop(wasm_run);
+    end
+}
+
+/// Wait for the running instance to hand off its state. Resolves to `None` on timeout or if the
+/// instance dropped the request.
+async fn wait_handoff(handoff: &mut Option<Handoff>) -> Option<Vec<u8>> {
+    let handoff = match handoff {
+        Some(handoff) => handoff,
+        None => return std::future::pending().await,
+    };
+    tokio::select! {
+        state = &mut handoff.state_rx => state.ok(),
+        _ = tokio::time::sleep_until(handoff.deadline) => None,
+    }
+}
+
+/// Wait for the upgraded instance to pass its startup period.
+async fn wait_startup(startup: &Option<Startup>) {
+    match startup {
+        Some(startup) => tokio::time::sleep_until(startup.deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::{CacheOps, PinkOps};
+    use futures::future::BoxFuture;
+    use sidevm_env::OcallError;
+
+    type OcallResult<T> = std::result::Result<T, OcallError>;
+
+    struct NoOps;
+
+    impl CacheOps for NoOps {
+        fn get(&self, _contract: &[u8], _key: &[u8]) -> OcallResult<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        fn set(&self, _contract: &[u8], _key: &[u8], _value: &[u8]) -> OcallResult<()> {
+            Err(OcallError::ResourceLimited)
+        }
+
+        fn set_expiration(
+            &self,
+            _contract: &[u8],
+            _key: &[u8],
+            _expire_after_secs: u64,
+        ) -> OcallResult<()> {
+            Ok(())
+        }
+
+        fn remove(&self, _contract: &[u8], _key: &[u8]) -> OcallResult<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        fn size(&self, _contract: &[u8]) -> OcallResult<u64> {
+            Ok(0)
+        }
+    }
+
+    impl PinkOps for NoOps {
+        fn query(
+            &self,
+            _origin: VmId,
+            _contract: AccountId,
+            _payload: Vec<u8>,
+        ) -> BoxFuture<'static, OcallResult<Vec<u8>>> {
+            Box::pin(async { Err(OcallError::NotFound) })
+        }
+
+        fn push_command(
+            &self,
+            _origin: VmId,
+            _nonce: Vec<u8>,
+            _message: Vec<u8>,
+        ) -> OcallResult<()> {
+            Err(OcallError::NotFound)
+        }
+    }
+
+    /// A program exiting with `code` on the first poll, or trapping if `code` is `None`. A program
+    /// exiting with 0 keeps pending forever.
+    fn program(code: Option<i32>) -> Vec<u8> {
+        let body = match code {
+            Some(code) => format!("i32.const {code}"),
+            None => "unreachable".into(),
+        };
+        let wat = format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "sidevm_poll") (result i32) {body}))"#
+        );
+        wasmer::wat2wasm(wat.as_bytes()).unwrap().into_owned()
+    }
+
+    fn start(code: Option<i32>) -> (WasmRun, Env) {
+        WasmRun::run(
+            &program(code),
+            16,
+            [0; 32],
+            1_000_000_000,
+            &NoOps,
+            &NoOps,
+            TaskScheduler::new(1),
+            1,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap()
+    }
+
+    fn startup(period: Duration) -> (Startup, OneshotReceiver<UpgradeResult>) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let startup = Startup {
+            fallback: program(Some(0)),
+            state: b"state".to_vec(),
+            fs_backup: None,
+            deadline: Instant::now() + period,
+            reply_tx,
+        };
+        (startup, reply_rx)
+    }
+
+    async fn run(
+        instance: (WasmRun, Env),
+        cmd_rx: &mut Receiver<Command>,
+        startup: Option<Startup>,
+    ) -> InstanceEnd {
+        let spawner = tokio::runtime::Handle::current();
+        run_instance([0; 32], instance, cmd_rx, &spawner, &mut 1, startup).await
+    }
+
+    fn upgrade_cmd() -> (Command, OneshotReceiver<UpgradeResult>) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let code = program(Some(0));
+        (Command::Upgrade { code, reply_tx }, reply_rx)
+    }
+
+    #[tokio::test]
+    async fn exit_and_crash_end_the_instance() {
+        let (_cmd_tx, mut cmd_rx) = channel(8);
+        let end = run(start(Some(7)), &mut cmd_rx, None).await;
+        assert!(matches!(end, InstanceEnd::Exited(ExitReason::Exited(7))));
+        let end = run(start(None), &mut cmd_rx, None).await;
+        assert!(matches!(end, InstanceEnd::Exited(ExitReason::Panicked)));
+    }
+
+    #[tokio::test]
+    async fn exit_or_crash_in_startup_fails_the_upgrade() {
+        let (_cmd_tx, mut cmd_rx) = channel(8);
+        for code in [Some(7), None] {
+            let (startup, _reply_rx) = startup(UPGRADE_STARTUP_PERIOD);
+            let end = run(start(code), &mut cmd_rx, Some(startup)).await;
+            match end {
+                InstanceEnd::StartupFailed(startup) => assert_eq!(startup.state, b"state"),
+                _ => panic!("the upgrade should fail"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn startup_period_completes_the_upgrade() {
+        let (cmd_tx, mut cmd_rx) = channel(8);
+        let (startup, reply_rx) = startup(Duration::ZERO);
+        let drive = async move {
+            assert_eq!(reply_rx.await.unwrap(), UpgradeResult::Upgraded);
+            // The program doesn't accept upgrades once started.
+            let (cmd, reply_rx) = upgrade_cmd();
+            assert!(cmd_tx.send(cmd).await.is_ok());
+            assert_eq!(reply_rx.await.unwrap(), UpgradeResult::Unsupported);
+            assert!(cmd_tx.send(Command::Stop).await.is_ok());
+        };
+        let (end, _) = tokio::join!(run(start(Some(0)), &mut cmd_rx, Some(startup)), drive);
+        assert!(matches!(end, InstanceEnd::Exited(ExitReason::Stopped)));
+    }
+
+    #[tokio::test]
+    async fn upgrade_in_startup_is_rejected() {
+        let (cmd_tx, mut cmd_rx) = channel(8);
+        let (startup, _reply_rx) = startup(UPGRADE_STARTUP_PERIOD);
+        let drive = async move {
+            let (cmd, reply_rx) = upgrade_cmd();
+            assert!(cmd_tx.send(cmd).await.is_ok());
+            assert_eq!(reply_rx.await.unwrap(), UpgradeResult::InProgress);
+            drop(cmd_tx);
+        };
+        let (end, _) = tokio::join!(run(start(Some(0)), &mut cmd_rx, Some(startup)), drive);
+        assert!(matches!(end, InstanceEnd::Exited(ExitReason::InputClosed)));
+    }
+}
@@ -6,14 +6,18 @@ use std::task::{Context, Poll};
 
 use futures::ready;
 use once_cell::sync::Lazy;
-use sidevm_env::tls::TlsServerConfig;
-use sidevm_env::OcallError;
 use rustls_pemfile::Item;
+use sidevm_env::tls::{TlsClientConfig, TlsServerConfig};
+use sidevm_env::OcallError;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
 use tokio_rustls::{
     client::TlsStream as ClientTlsStream,
-    rustls::{self, ClientConfig, ServerConfig, ServerName},
+    rustls::{
+        self,
+        server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+        ClientConfig, ServerConfig, ServerName,
+    },
     server::TlsStream as ServerTlsStream,
     Accept, Connect, TlsAcceptor, TlsConnector,
 };
@@ -38,16 +42,20 @@ impl From<ServerTlsStream<TcpStream>> for TlsStream {
     }
 }
 
+fn add_builtin_roots(root_store: &mut rustls::RootCertStore) {
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+}
+
 fn default_client_config() -> Arc<ClientConfig> {
     static CLIENT_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
         let mut root_store = rustls::RootCertStore::empty();
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
+        add_builtin_roots(&mut root_store);
 
         let config = ClientConfig::builder()
             .with_safe_defaults()
@@ -64,8 +72,11 @@ impl TlsStream {
         TlsStream::ServerHandshaking(accept)
     }
 
-    pub(crate) fn connect(domain: ServerName, stream: TcpStream) -> TlsStream {
-        let client_config = default_client_config();
+    pub(crate) fn connect(
+        domain: ServerName,
+        stream: TcpStream,
+        client_config: Arc<ClientConfig>,
+    ) -> TlsStream {
         let connector = TlsConnector::from(client_config);
         TlsStream::ClientHandshaking(connector.connect(domain, stream))
     }
@@ -212,18 +223,102 @@ impl AsyncWrite for TlsStream {
 }
 
 pub(crate) fn load_tls_config(config: TlsServerConfig) -> Result<ServerConfig, OcallError> {
-    let (cert_pem, key_pem) = match &config {
-        TlsServerConfig::V0 { cert, key } => (cert, key),
+    let (cert_pem, key_pem, alpn_protocols, client_auth) = match config {
+        TlsServerConfig::V0 { cert, key } => (cert, key, vec![], None),
+        TlsServerConfig::V1 {
+            cert,
+            key,
+            alpn_protocols,
+            client_auth,
+        } => (cert, key, alpn_protocols, client_auth),
     };
 
-    let certs = load_certs(cert_pem)?;
-    let key = load_private_key(key_pem)?;
+    let certs = load_certs(&cert_pem)?;
+    let key = load_private_key(&key_pem)?;
 
-    tokio_rustls::rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
+    let builder = tokio_rustls::rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match client_auth {
+        None => builder.with_no_client_auth(),
+        Some(auth) => {
+            let roots = load_root_store(&auth.root_certs)?;
+            let verifier = if auth.mandatory {
+                AllowAnyAuthenticatedClient::new(roots)
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+    let mut config = builder
         .with_single_cert(certs, key)
-        .or(Err(OcallError::InvalidParameter))
+        .or(Err(OcallError::InvalidParameter))?;
+    config.alpn_protocols = alpn_protocols;
+    Ok(config)
+}
+
+/// Builds the client config, returning it with the server name override if any.
+pub(crate) fn load_client_config(
+    config: TlsClientConfig,
+) -> Result<(Arc<ClientConfig>, Option<String>), OcallError> {
+    let (root_certs, builtin_roots, client_cert, alpn_protocols, server_name) = match config {
+        TlsClientConfig::V0 => return Ok((default_client_config(), None)),
+        TlsClientConfig::V1 {
+            root_certs,
+            builtin_roots,
+            client_cert,
+            alpn_protocols,
+            server_name,
+        } => (
+            root_certs,
+            builtin_roots,
+            client_cert,
+            alpn_protocols,
+            server_name,
+        ),
+    };
+
+    if let Some(name) = &server_name {
+        if name.is_empty() || name.len() > 253 {
+            return Err(OcallError::InvalidParameter);
+        }
+    }
+    let mut root_store = match &root_certs {
+        Some(pem) => load_root_store(pem)?,
+        // Nothing to verify the server against.
+        None if !builtin_roots => return Err(OcallError::InvalidParameter),
+        None => rustls::RootCertStore::empty(),
+    };
+    if builtin_roots {
+        add_builtin_roots(&mut root_store);
+    }
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store);
+    let mut config = match client_cert {
+        None => builder.with_no_client_auth(),
+        Some(client_cert) => {
+            let certs = load_certs(&client_cert.cert)?;
+            let key = load_private_key(&client_cert.key)?;
+            builder
+                .with_single_cert(certs, key)
+                .or(Err(OcallError::InvalidParameter))?
+        }
+    };
+    config.alpn_protocols = alpn_protocols;
+    Ok((Arc::new(config), server_name))
+}
+
+fn load_root_store(pem_str: &str) -> Result<rustls::RootCertStore, OcallError> {
+    let mut root_store = rustls::RootCertStore::empty();
+    for cert in load_certs(pem_str)? {
+        root_store
+            .add(&cert)
+            .or(Err(OcallError::InvalidParameter))?;
+    }
+    if root_store.is_empty() {
+        return Err(OcallError::InvalidParameter);
+    }
+    Ok(root_store)
 }
 
 fn load_certs(pem_str: &str) -> Result<Vec<rustls::Certificate>, OcallError> {
@@ -241,3 +336,124 @@ fn load_private_key(pem_str: &str) -> Result<rustls::PrivateKey, OcallError> {
     };
     Ok(rustls::PrivateKey(key.clone()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sidevm_env::tls::{TlsClientAuth, TlsClientCert};
+
+    const CERT: &str = include_str!("fixtures/tls-cert.pem");
+    const KEY: &str = include_str!("fixtures/tls-key.pem");
+
+    fn client_v1(root_certs: Option<&str>, builtin_roots: bool) -> TlsClientConfig {
+        TlsClientConfig::V1 {
+            root_certs: root_certs.map(Into::into),
+            builtin_roots,
+            client_cert: None,
+            alpn_protocols: vec![b"h2".to_vec()],
+            server_name: None,
+        }
+    }
+
+    fn is_invalid<T>(result: Result<T, OcallError>) -> bool {
+        matches!(result, Err(OcallError::InvalidParameter))
+    }
+
+    fn load_client(config: TlsClientConfig) -> Result<Option<String>, OcallError> {
+        load_client_config(config).map(|(_, server_name)| server_name)
+    }
+
+    #[test]
+    fn client_config_needs_roots() {
+        assert!(load_client(client_v1(None, true)).is_ok());
+        assert!(load_client(client_v1(Some(CERT), false)).is_ok());
+        assert!(load_client(client_v1(Some(CERT), true)).is_ok());
+        assert!(is_invalid(load_client(client_v1(None, false))));
+        assert!(is_invalid(load_client(client_v1(Some("not a pem"), true))));
+    }
+
+    #[test]
+    fn client_config_applies_the_options() {
+        let (config, server_name) = load_client_config(TlsClientConfig::V1 {
+            root_certs: Some(CERT.into()),
+            builtin_roots: false,
+            client_cert: Some(TlsClientCert {
+                cert: CERT.into(),
+                key: KEY.into(),
+            }),
+            alpn_protocols: vec![b"h2".to_vec()],
+            server_name: Some("localhost".into()),
+        })
+        .unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"h2".to_vec()]);
+        assert_eq!(server_name.as_deref(), Some("localhost"));
+
+        let bad_key = TlsClientConfig::V1 {
+            root_certs: None,
+            builtin_roots: true,
+            client_cert: Some(TlsClientCert {
+                cert: CERT.into(),
+                key: CERT.into(),
+            }),
+            alpn_protocols: vec![],
+            server_name: None,
+        };
+        assert!(is_invalid(load_client(bad_key)));
+    }
+
+    #[test]
+    fn client_config_limits_the_server_name() {
+        let with_name = |name: String| TlsClientConfig::V1 {
+            root_certs: None,
+            builtin_roots: true,
+            client_cert: None,
+            alpn_protocols: vec![],
+            server_name: Some(name),
+        };
+        assert!(load_client(with_name("a".repeat(253))).is_ok());
+        assert!(is_invalid(load_client(with_name("a".repeat(254)))));
+        assert!(is_invalid(load_client(with_name(String::new()))));
+    }
+
+    #[test]
+    fn server_config_is_loaded() {
+        let config = load_tls_config(TlsServerConfig::V1 {
+            cert: CERT.into(),
+            key: KEY.into(),
+            alpn_protocols: vec![b"http/1.1".to_vec()],
+            client_auth: Some(TlsClientAuth {
+                root_certs: CERT.into(),
+                mandatory: true,
+            }),
+        })
+        .unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+
+        let v0 = TlsServerConfig::V0 {
+            cert: CERT.into(),
+            key: KEY.into(),
+        };
+        assert!(load_tls_config(v0).is_ok());
+    }
+
+    #[test]
+    fn server_config_rejects_bad_input() {
+        let no_key = TlsServerConfig::V1 {
+            cert: CERT.into(),
+            key: String::new(),
+            alpn_protocols: vec![],
+            client_auth: None,
+        };
+        assert!(is_invalid(load_tls_config(no_key)));
+        let no_client_roots = TlsServerConfig::V1 {
+            cert: CERT.into(),
+            key: KEY.into(),
+            alpn_protocols: vec![],
+            client_auth: Some(TlsClientAuth {
+                root_certs: String::new(),
+                mandatory: false,
+            }),
+        };
+        assert!(is_invalid(load_tls_config(no_client_roots)));
+    }
+}
@@ -1,8 +1,10 @@
-use sidevm_host_runtime::{CacheOps, DynCacheOps, OcallError};
+use sidevm_host_runtime::{CacheOps, DynCacheOps, DynPinkOps, OcallError, PinkOps, VmId};
 
 use clap::{AppSettings, Parser};
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::RwLock;
 
 mod web_api;
@@ -51,6 +53,36 @@ fn simple_cache() -> DynCacheOps {
             let value = cache.remove(key);
             Ok(value)
         }
+
+        fn size(&self, _contract: &[u8]) -> OpResult<u64> {
+            let cache = CACHE.read().unwrap();
+            Ok(cache.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum())
+        }
+    }
+    &Ops
+}
+
+/// There is no pink contract in the demo host, so all the calls fail with `NotFound`.
+fn no_pink() -> DynPinkOps {
+    struct Ops;
+    impl PinkOps for Ops {
+        fn query(
+            &self,
+            _origin: VmId,
+            _contract: VmId,
+            _payload: Vec<u8>,
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, OcallError>> + Send>> {
+            Box::pin(async { Err(OcallError::NotFound) })
+        }
+
+        fn push_command(
+            &self,
+            _origin: VmId,
+            _nonce: Vec<u8>,
+            _message: Vec<u8>,
+        ) -> Result<(), OcallError> {
+            Err(OcallError::NotFound)
+        }
     }
     &Ops
 }
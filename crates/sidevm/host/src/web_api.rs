@@ -67,7 +67,12 @@ impl App {
                 vmid,
                 inner.args.gas_per_breath,
                 crate::simple_cache(),
+                crate::no_pink(),
                 weight,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
             )
             .unwrap();
         inner.instances.insert(id, sender);
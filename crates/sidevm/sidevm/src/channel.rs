@@ -1,6 +1,6 @@
 //! Multi-producer, single-consumer channel implementation.
 use sidevm_env::{
-    messages::{AccountId, QueryRequest, SystemMessage},
+    messages::{self, AccountId, QueryRequest, SystemMessage},
     InputChannel, OcallError,
 };
 
@@ -27,6 +27,23 @@ pub struct Query {
 /// A message from ink! to the side VM.
 pub type GeneralMessage = Vec<u8>;
 
+/// A request from the host to hand off the state of the instance to the upgraded code.
+///
+/// See [`crate::upgrade`] for details.
+pub struct UpgradeRequest {
+    reply_tx: OneshotSender,
+}
+
+impl UpgradeRequest {
+    /// Hand off the serialized state to the upgraded code.
+    ///
+    /// The instance is dropped right after the state is handed off, so it should stop mutating
+    /// the state before calling this.
+    pub fn handoff(self, state: &[u8]) -> Result<(), OcallError> {
+        self.reply_tx.send(state)
+    }
+}
+
 /// Sender end of a oneshot channel connected to host-side.
 pub struct OneshotSender {
     res_id: ResourceId,
@@ -120,6 +137,25 @@ impl Future for Next<'_, Query> {
     }
 }
 
+impl Future for Next<'_, UpgradeRequest> {
+    type Output = Option<UpgradeRequest>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waker_id = crate::env::tasks::intern_waker(cx.waker().clone());
+        match ocall::poll(waker_id, self.ch.res_id.0) {
+            Ok(msg) => {
+                let request = messages::UpgradeRequest::decode(&mut &msg[..])
+                    .expect("Failed to decode UpgradeRequest");
+                let reply_tx = OneshotSender::new(ResourceId(request.reply_tx));
+                Poll::Ready(Some(UpgradeRequest { reply_tx }))
+            }
+            Err(OcallError::EndOfFile) => Poll::Ready(None), // The tx dropped
+            Err(OcallError::Pending) => Poll::Pending,
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+}
+
 macro_rules! singleton_channel {
     ($ch: ident) => {{
         lazy_static! {
@@ -151,3 +187,10 @@ pub fn incoming_system_messages() -> &'static Receiver<SystemMessage> {
 pub fn incoming_queries() -> &'static Receiver<Query> {
     singleton_channel!(Query)
 }
+
+/// Requests to hand off the state for a code upgrade.
+///
+/// The host only upgrades the instance if it has subscribed to this channel.
+pub fn incoming_upgrade_requests() -> &'static Receiver<UpgradeRequest> {
+    singleton_channel!(UpgradeRequest)
+}
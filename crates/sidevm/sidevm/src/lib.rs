@@ -14,7 +14,9 @@ pub use env::tasks as task;
 
 pub mod channel;
 pub mod net;
+pub mod pink;
 pub mod time;
 pub mod exec;
+pub mod upgrade;
 
 mod res_id;
@@ -6,7 +6,7 @@ use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use env::tls::TlsServerConfig;
+use env::tls::{TlsClientConfig, TlsServerConfig};
 
 use crate::env::{self, tasks, Result};
 use crate::{ocall, ResourceId};
@@ -108,6 +108,13 @@ impl TcpStream {
         let res = res.map(ResourceId);
         TcpConnector { res }
     }
+
+    /// Initiate a TLS connection to a remote host with custom configuration, e.g. private root
+    /// certificates or a client certificate.
+    pub fn connect_tls(host: &str, port: u16, config: TlsClientConfig) -> TcpConnector {
+        let res = ocall::tcp_connect_tls(host.into(), port, config).map(ResourceId);
+        TcpConnector { res }
+    }
 }
 
 #[cfg(feature = "hyper")]
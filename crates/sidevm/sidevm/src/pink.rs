@@ -0,0 +1,57 @@
+//! Calling back into the pink contracts in the same cluster.
+
+use sidevm_env::{messages::AccountId, OcallError};
+
+use super::{ocall, ResourceId};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The future of a pink contract query.
+pub struct Query {
+    res_id: ResourceId,
+}
+
+/// Query the pink contract `contract` with a SCALE-encoded ink message.
+///
+/// The query is read-only and runs on a snapshot of the cluster storage, with the contract owning
+/// this sidevm instance as the origin. Only contracts in the same cluster can be queried. Resolves
+/// to the SCALE-encoded output of the message.
+///
+/// # Example
+/// ```ignore
+/// let output = sidevm::pink::query(&contract, &message)?.await?;
+/// ```
+pub fn query(contract: &AccountId, payload: &[u8]) -> Result<Query, OcallError> {
+    let res_id = ocall::pink_query(&contract[..], payload)?;
+    Ok(Query {
+        res_id: ResourceId(res_id),
+    })
+}
+
+/// Queue a command to the pink contract owning this sidevm instance.
+///
+/// The SCALE-encoded ink message is sent on chain by every worker running this sidevm and then
+/// executed by the contract as a transaction, with the contract itself as the caller. The contract
+/// only executes the first copy of each `nonce`, so the program should derive the nonce from the
+/// command deterministically and never reuse it. The nonce is at most 32 bytes, reported along
+/// with the output of the message.
+///
+/// Fails with `ResourceLimited` if the program queues commands too frequently.
+pub fn push_command(nonce: &[u8], message: &[u8]) -> Result<(), OcallError> {
+    ocall::pink_push_command(nonce, message)
+}
+
+impl Future for Query {
+    type Output = Result<Vec<u8>, OcallError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waker_id = crate::env::tasks::intern_waker(cx.waker().clone());
+        match ocall::poll(waker_id, self.res_id.0) {
+            Err(OcallError::Pending) => Poll::Pending,
+            rv => Poll::Ready(rv),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Hot code upgrade of the sidevm instance.
+//!
+//! When the code of a running instance is upgraded, the host sends an [`UpgradeRequest`] to the
+//! instance via [`incoming_upgrade_requests`]. The instance hands off its serialized state in
+//! reply and is then dropped. The new code is started with the state, which can be taken with
+//! [`take_state`]. Messages and queries sent during the upgrade are delivered to the new
+//! instance.
+//!
+//! If the new instance crashes within a short startup period, the host rolls back to the previous
+//! code with the same state. So the previous code should take the state on startup as well.
+//!
+//! # Example
+//! ```ignore
+//! let mut counter: u64 = match sidevm::upgrade::take_state() {
+//!     Some(state) => Decode::decode(&mut &state[..]).unwrap_or_default(),
+//!     None => 0,
+//! };
+//! let upgrade_requests = sidevm::upgrade::incoming_upgrade_requests();
+//! loop {
+//!     tokio::select! {
+//!         request = upgrade_requests.next() => {
+//!             if let Some(request) = request {
+//!                 let _ = request.handoff(&counter.encode());
+//!             }
+//!             break;
+//!         }
+//!         message = sidevm::channel::input_messages().next() => {
+//!             counter += 1;
+//!         }
+//!     }
+//! }
+//! ```
+
+use super::ocall;
+
+pub use crate::channel::{incoming_upgrade_requests, UpgradeRequest};
+
+/// Take the state handed off by the previous instance.
+///
+/// Returns `None` if the instance wasn't started by an upgrade or the state has been taken.
+pub fn take_state() -> Option<Vec<u8>> {
+    ocall::upgrade_take_state().expect("Failed to take the upgrade state")
+}
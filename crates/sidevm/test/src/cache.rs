@@ -0,0 +1,75 @@
+use sidevm_host_runtime::{CacheOps, OcallError};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+type Result<T, E = OcallError> = std::result::Result<T, E>;
+
+struct Entry {
+    value: Vec<u8>,
+    expire_at: Option<Instant>,
+}
+
+/// An in-memory local cache, with the expiration following the tokio time.
+#[derive(Default)]
+pub(crate) struct MemCache {
+    entries: Mutex<BTreeMap<(Vec<u8>, Vec<u8>), Entry>>,
+}
+
+impl CacheOps for MemCache {
+    fn get(&self, contract: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry_key = (contract.to_vec(), key.to_vec());
+        let expired = match entries.get(&entry_key) {
+            None => return Ok(None),
+            Some(entry) => entry.expire_at.map_or(false, |at| at <= Instant::now()),
+        };
+        if expired {
+            entries.remove(&entry_key);
+            return Ok(None);
+        }
+        Ok(entries.get(&entry_key).map(|entry| entry.value.clone()))
+    }
+
+    fn set(&self, contract: &[u8], key: &[u8], value: &[u8]) -> Result<()> {
+        let entry = Entry {
+            value: value.to_vec(),
+            expire_at: None,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert((contract.to_vec(), key.to_vec()), entry);
+        Ok(())
+    }
+
+    fn set_expiration(&self, contract: &[u8], key: &[u8], expire_after_secs: u64) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&(contract.to_vec(), key.to_vec())) {
+            entry.expire_at = Some(Instant::now() + Duration::from_secs(expire_after_secs));
+        }
+        Ok(())
+    }
+
+    fn remove(&self, contract: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let removed = self
+            .entries
+            .lock()
+            .unwrap()
+            .remove(&(contract.to_vec(), key.to_vec()));
+        Ok(removed.map(|entry| entry.value))
+    }
+
+    fn size(&self, contract: &[u8]) -> Result<u64> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .iter()
+            .filter(|((id, _), entry)| {
+                id == contract && entry.expire_at.map_or(true, |at| at > now)
+            })
+            .map(|((_, key), entry)| (key.len() + entry.value.len()) as u64)
+            .sum())
+    }
+}
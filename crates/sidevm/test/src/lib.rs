@@ -0,0 +1,289 @@
+//! A deterministic test harness for sidevm programs.
+//!
+//! The [`Harness`] loads a wasm module into a [`WasmRun`] on a single threaded tokio runtime with
+//! the time paused. Timers of the program fire in virtual time as soon as the runtime is idle, and
+//! the WASI clocks read the same virtual time. The local cache lives in memory, and the pink
+//! contracts are mocked by the test.
+//!
+//! # Example
+//! ```ignore
+//! let wasm = std::fs::read("target/wasm32-wasi/release/echo.wasm")?;
+//! let mut vm = sidevm_test::Harness::new(&wasm, Default::default())?;
+//! vm.run_for(Duration::from_secs(1));
+//! vm.push_message(b"hello".to_vec())?;
+//! assert_eq!(vm.query(None, b"last".to_vec())?, b"hello");
+//! assert!(vm.take_ocall_traces().iter().any(|t| t.func_name == "local_cache_set"));
+//! assert!(vm.take_gas_used() > 0);
+//! ```
+
+use anyhow::{anyhow, bail, Result};
+use futures::future::poll_fn;
+use phala_scheduler::TaskScheduler;
+use sidevm_env::messages::AccountId;
+use sidevm_host_runtime::{
+    service::SystemMessage, CacheOps, Env, FsConfig, GasMeter, OcallError, OcallTrace,
+    OcallTraceRecorder, ResourceLimits, ResourceUsage, ResourceUsageStats, VirtualClock, VmId,
+    WasmRun,
+};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+pub use pink::PushedCommand;
+
+mod cache;
+mod pink;
+
+/// Max time in virtual time to wait for the reply of a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Configuration of the program under test.
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub vm_id: VmId,
+    pub max_memory_pages: u32,
+    pub gas_per_breath: u64,
+    pub weight: u32,
+    /// The realtime of the virtual clock when the program starts, since the unix epoch.
+    pub unix_start: Duration,
+    pub limits: ResourceLimits,
+    pub fs_config: FsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            vm_id: [1; 32],
+            max_memory_pages: 1024,
+            gas_per_breath: 50_000_000_000,
+            weight: 1,
+            // 2022-01-01T00:00:00Z
+            unix_start: Duration::from_secs(1_640_995_200),
+            limits: Default::default(),
+            fs_config: Default::default(),
+        }
+    }
+}
+
+/// Runs a sidevm program under the control of the test.
+///
+/// The program only runs while the harness is driving it, e.g. in `run_for`, `push_message` or
+/// `query`.
+pub struct Harness {
+    wasm_run: WasmRun,
+    env: Env,
+    vm_id: VmId,
+    exit: Option<Result<i32, String>>,
+    gas_meter: GasMeter,
+    usage: ResourceUsage,
+    traces: OcallTraceRecorder,
+    cache: &'static cache::MemCache,
+    pink: &'static pink::MockPink,
+    // Dropped after the instance, which may hold resources of the runtime.
+    runtime: Runtime,
+}
+
+impl Harness {
+    /// Load the wasm module and instantiate the program.
+    pub fn new(wasm: &[u8], config: Config) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()?;
+        let _guard = runtime.enter();
+        // The ops are required to be 'static. Leaking them is fine in tests.
+        let cache: &'static cache::MemCache = Box::leak(Default::default());
+        let pink: &'static pink::MockPink = Box::leak(Default::default());
+        let gas_meter = GasMeter::default();
+        let usage = ResourceUsage::default();
+        let (wasm_run, env) = WasmRun::run(
+            wasm,
+            config.max_memory_pages,
+            config.vm_id,
+            config.gas_per_breath,
+            cache,
+            pink,
+            TaskScheduler::new(1),
+            config.weight,
+            gas_meter.clone(),
+            config.fs_config,
+            config.limits,
+            usage.clone(),
+        )?;
+        let traces = OcallTraceRecorder::default();
+        env.set_ocall_trace_recorder(traces.clone());
+        env.set_virtual_clock(VirtualClock::new(config.unix_start));
+        drop(_guard);
+        Ok(Self {
+            wasm_run,
+            env,
+            vm_id: config.vm_id,
+            exit: None,
+            gas_meter,
+            usage,
+            traces,
+            cache,
+            pink,
+            runtime,
+        })
+    }
+
+    /// Drive the program together with `fut` until `fut` completes.
+    ///
+    /// The program is polled before `fut` in each round to keep the runs reproducible. Returns
+    /// `None` if the program has exited and `fut` can not complete anymore.
+    fn drive<T>(&mut self, fut: impl Future<Output = T>) -> Option<T> {
+        let Self {
+            runtime,
+            wasm_run,
+            exit,
+            ..
+        } = self;
+        let mut fut = Box::pin(fut);
+        runtime.block_on(poll_fn(|cx| {
+            if exit.is_none() {
+                if let Poll::Ready(rv) = Pin::new(&mut *wasm_run).poll(cx) {
+                    *exit = Some(rv.map_err(|err| err.to_string()));
+                }
+            }
+            match fut.as_mut().poll(cx) {
+                Poll::Ready(output) => Poll::Ready(Some(output)),
+                Poll::Pending if exit.is_some() => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            }
+        }))
+    }
+
+    /// Run the program for `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) {
+        self.drive(tokio::time::sleep(duration));
+    }
+
+    /// Run the program until it exits, or fail after `timeout` of virtual time.
+    pub fn run_until_exit(&mut self, timeout: Duration) -> Result<i32> {
+        self.run_for(timeout);
+        match &self.exit {
+            Some(Ok(code)) => Ok(*code),
+            Some(Err(err)) => bail!("The program terminated with error: {err}"),
+            None => bail!("The program didn't exit in {timeout:?}"),
+        }
+    }
+
+    /// How the program exited, or `None` if it is still running.
+    pub fn exit_status(&self) -> Option<&Result<i32, String>> {
+        self.exit.as_ref()
+    }
+
+    /// Push a message to the program, as if it was pushed by the owner contract.
+    pub fn push_message(&mut self, message: Vec<u8>) -> Result<()> {
+        let push = self
+            .env
+            .push_message(message)
+            .ok_or_else(|| anyhow!("The program doesn't accept messages"))?;
+        self.drive(push)
+            .ok_or_else(|| anyhow!("The program exited"))?
+            .or(Err(anyhow!("The message channel is closed")))
+    }
+
+    /// Push a system message to the program.
+    pub fn push_system_message(&mut self, message: SystemMessage) -> Result<()> {
+        let push = self
+            .env
+            .push_system_message(message)
+            .ok_or_else(|| anyhow!("The program doesn't accept system messages"))?;
+        self.drive(push)
+            .ok_or_else(|| anyhow!("The program exited"))?
+            .or(Err(anyhow!("The system message channel is closed")))
+    }
+
+    /// Send a query to the program and run it until the reply arrives.
+    pub fn query(&mut self, origin: Option<AccountId>, payload: Vec<u8>) -> Result<Vec<u8>> {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        let push = self
+            .env
+            .push_query(origin, payload, reply_tx)
+            .ok_or_else(|| anyhow!("The program doesn't accept queries"))?;
+        let reply = async move {
+            push.await?;
+            match tokio::time::timeout(QUERY_TIMEOUT, reply_rx).await {
+                Ok(Ok(reply)) => Ok(reply),
+                Ok(Err(_)) => bail!("The query was dropped without reply"),
+                Err(_) => bail!("The query timed out"),
+            }
+        };
+        self.drive(reply)
+            .ok_or_else(|| anyhow!("The program exited"))?
+    }
+
+    /// Start a mocked TCP peer on the loopback interface, and return its address for the program
+    /// to connect to.
+    ///
+    /// Each incoming connection is served by `handler`, which runs while the program is driven.
+    pub fn mock_peer<F, Fut>(&self, handler: F) -> Result<SocketAddr>
+    where
+        F: Fn(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = self.runtime.block_on(TcpListener::bind("127.0.0.1:0"))?;
+        let addr = listener.local_addr()?;
+        self.runtime.spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handler(stream));
+            }
+        });
+        Ok(addr)
+    }
+
+    /// Answer the pink queries of the program with `handler`, which gets the target contract and
+    /// the SCALE-encoded ink message. Queries fail with `NotFound` if no handler is set.
+    pub fn on_pink_query(
+        &self,
+        handler: impl Fn(AccountId, Vec<u8>) -> Result<Vec<u8>, OcallError> + Send + Sync + 'static,
+    ) {
+        *self.pink.query_handler.lock().unwrap() = Some(Box::new(handler));
+    }
+
+    /// Take the commands pushed by the program to its owner contract since the last take.
+    pub fn take_pink_commands(&self) -> Vec<PushedCommand> {
+        std::mem::take(&mut *self.pink.commands.lock().unwrap())
+    }
+
+    /// Get a value from the local cache of the program.
+    pub fn cache_get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let _guard = self.runtime.enter();
+        self.cache.get(&self.vm_id, key).ok().flatten()
+    }
+
+    /// Set a value to the local cache of the program.
+    pub fn cache_set(&self, key: &[u8], value: &[u8]) {
+        let _guard = self.runtime.enter();
+        let _ = self.cache.set(&self.vm_id, key, value);
+    }
+
+    /// Trace the ocalls of the program, as if it called `enable_ocall_trace`.
+    pub fn enable_ocall_trace(&self) {
+        self.env.set_ocall_trace_enabled(true);
+    }
+
+    /// Take the ocalls traced since the last take.
+    ///
+    /// The ocalls are only traced after the trace is enabled, by the program itself or by
+    /// `enable_ocall_trace`.
+    pub fn take_ocall_traces(&self) -> Vec<OcallTrace> {
+        self.traces.take()
+    }
+
+    /// Take the gas consumed by the program since the last take.
+    pub fn take_gas_used(&self) -> u64 {
+        self.gas_meter.take()
+    }
+
+    /// The resources currently held by the program.
+    pub fn resource_usage(&self) -> ResourceUsageStats {
+        self.usage.stats()
+    }
+}
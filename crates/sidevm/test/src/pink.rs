@@ -0,0 +1,45 @@
+use futures::future::BoxFuture;
+use sidevm_env::messages::AccountId;
+use sidevm_host_runtime::{OcallError, PinkOps, VmId};
+use std::sync::Mutex;
+
+type Result<T, E = OcallError> = std::result::Result<T, E>;
+
+pub(crate) type QueryHandler = dyn Fn(AccountId, Vec<u8>) -> Result<Vec<u8>> + Send + Sync;
+
+/// A command pushed by the program to its owner contract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushedCommand {
+    pub nonce: Vec<u8>,
+    pub message: Vec<u8>,
+}
+
+/// Answers the pink queries with a handler set by the test, and records the pushed commands.
+#[derive(Default)]
+pub(crate) struct MockPink {
+    pub(crate) query_handler: Mutex<Option<Box<QueryHandler>>>,
+    pub(crate) commands: Mutex<Vec<PushedCommand>>,
+}
+
+impl PinkOps for MockPink {
+    fn query(
+        &self,
+        _origin: VmId,
+        contract: AccountId,
+        payload: Vec<u8>,
+    ) -> BoxFuture<'static, Result<Vec<u8>>> {
+        let result = match &*self.query_handler.lock().unwrap() {
+            Some(handler) => handler(contract, payload),
+            None => Err(OcallError::NotFound),
+        };
+        Box::pin(async move { result })
+    }
+
+    fn push_command(&self, _origin: VmId, nonce: Vec<u8>, message: Vec<u8>) -> Result<()> {
+        self.commands
+            .lock()
+            .unwrap()
+            .push(PushedCommand { nonce, message });
+        Ok(())
+    }
+}
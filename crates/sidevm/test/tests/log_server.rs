@@ -0,0 +1,61 @@
+//! Runs the prebuilt log server program of the e2e tests in the harness.
+
+use sidevm_host_runtime::service::SystemMessage;
+use sidevm_test::{Config, Harness};
+use std::time::Duration;
+
+const LOG_SERVER: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../../../e2e/res/log_server.sidevm.wasm"
+);
+
+fn start() -> Harness {
+    let wasm = std::fs::read(LOG_SERVER).unwrap();
+    let mut vm = Harness::new(&wasm, Config::default()).unwrap();
+    vm.enable_ocall_trace();
+    vm.run_for(Duration::from_secs(1));
+    vm
+}
+
+fn pink_log(message: &str) -> SystemMessage {
+    SystemMessage::PinkLog {
+        block_number: 1,
+        contract: [2; 32],
+        in_query: false,
+        timestamp_ms: 1_640_995_200_000,
+        level: 3,
+        message: message.into(),
+    }
+}
+
+#[test]
+fn query_reply_traces_and_gas() {
+    let mut vm = start();
+    assert!(vm.exit_status().is_none());
+    let traces = vm.take_ocall_traces();
+    assert!(traces
+        .iter()
+        .any(|trace| trace.func_name == "create_input_channel"));
+    assert!(vm.take_gas_used() > 0);
+
+    vm.push_system_message(pink_log("hello sidevm")).unwrap();
+    let reply = vm
+        .query(None, br#"{"action": "GetLog", "from": 0}"#.to_vec())
+        .unwrap();
+    let reply = String::from_utf8(reply).unwrap();
+    assert!(reply.starts_with(r#"{"records":["#), "{reply}");
+    assert!(reply.contains("hello sidevm"), "{reply}");
+    assert!(reply.ends_with(r#""next":1}"#), "{reply}");
+
+    let traces = vm.take_ocall_traces();
+    assert!(traces.iter().any(|trace| trace.func_name == "oneshot_send"));
+    assert!(vm.take_gas_used() > 0);
+    assert_eq!(vm.take_gas_used(), 0);
+}
+
+#[test]
+fn invalid_query_is_rejected() {
+    let mut vm = start();
+    let reply = vm.query(None, b"not json".to_vec()).unwrap();
+    assert_eq!(reply, br#"{"error": "Invalid input"}"#);
+}
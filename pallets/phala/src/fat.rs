@@ -16,7 +16,7 @@ pub mod pallet {
 		contract::{
 			messaging::{
 				ClusterEvent, ClusterOperation, ContractOperation, ResourceType,
-				WorkerClusterReport,
+				WorkerClusterReport, MAX_CONTRACT_USAGES_PER_REPORT,
 			},
 			ClusterInfo, ClusterPermission, CodeIndex, ContractClusterId, ContractId, ContractInfo,
 		},
@@ -50,7 +50,7 @@ pub mod pallet {
 		type SidevmCodeSizeLimit: Get<u32>;
 	}
 
-	const STORAGE_VERSION: StorageVersion = StorageVersion::new(5);
+	const STORAGE_VERSION: StorageVersion = StorageVersion::new(6);
 
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
@@ -127,6 +127,10 @@ pub mod pallet {
 		ClusterDestroyed {
 			cluster: ContractClusterId,
 		},
+		ContractUsageReported {
+			cluster: ContractClusterId,
+			worker: WorkerPublicKey,
+		},
 	}
 
 	#[pallet::error]
@@ -142,6 +146,7 @@ pub mod pallet {
 		WorkerNotFound,
 		PayloadTooLarge,
 		NoPinkSystemCode,
+		WorkerNotInCluster,
 	}
 
 	type CodeHash<T> = <T as frame_system::Config>::Hash;
@@ -388,6 +393,39 @@ pub mod pallet {
 						worker: worker_pubkey,
 					});
 				}
+				WorkerClusterReport::ContractUsageReport { cluster, mut usages } => {
+					let workers = ClusterWorkers::<T>::get(cluster);
+					ensure!(
+						workers.contains(&worker_pubkey),
+						Error::<T>::WorkerNotInCluster
+					);
+					ensure!(
+						usages.len() <= MAX_CONTRACT_USAGES_PER_REPORT,
+						Error::<T>::PayloadTooLarge
+					);
+					// Every worker in the cluster runs the same sidevm instances, so only the sidevm
+					// gas reported by the first worker of the cluster is charged.
+					if workers.first() != Some(&worker_pubkey) {
+						usages = usages
+							.into_iter()
+							.map(|(id, usage)| (id, usage.without_sidevm_gas()))
+							.filter(|(_, usage)| !usage.is_empty())
+							.collect();
+						if usages.is_empty() {
+							return Ok(());
+						}
+					}
+					let cluster_info =
+						Clusters::<T>::get(cluster).ok_or(Error::<T>::ClusterNotFound)?;
+					// Forward to the tokenomic driver via the system contract
+					let selector: u32 = 0xf227dbc8; // ContractUsageReport::report_usage()
+					let message = (selector.to_be_bytes(), usages).encode();
+					Self::push_ink_message(cluster_info.system_contract, message);
+					Self::deposit_event(Event::ContractUsageReported {
+						cluster,
+						worker: worker_pubkey,
+					});
+				}
 			}
 			Ok(())
 		}
@@ -26,7 +26,7 @@ pub mod pallet {
 		type Currency: Currency<Self::AccountId>;
 	}
 
-	const STORAGE_VERSION: StorageVersion = StorageVersion::new(5);
+	const STORAGE_VERSION: StorageVersion = StorageVersion::new(6);
 
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
@@ -62,3 +62,95 @@ fn set_unified_version<T: PhalaPallets>(version: u16) {
 	StorageVersion::new(version).put::<stakepool::Pallet<T>>();
 	StorageVersion::new(version).put::<fat_tokenomic::Pallet<T>>();
 }
+
+#[allow(dead_code)]
+type StakePoolBalanceOf<T> = <<T as stakepool::Config>::Currency as Currency<
+	<T as frame_system::Config>::AccountId,
+>>::Balance;
+
+/// Adds the `auto_compound` preference (off by default) to all the stakers in the stake pools
+pub mod v6 {
+	use super::*;
+	use frame_support::traits::OnRuntimeUpgrade;
+	use codec::{Decode, Encode};
+
+	/// The `UserStakeInfo` before the `auto_compound` preference is added
+	#[derive(Encode, Decode)]
+	pub(crate) struct OldUserStakeInfo<AccountId, Balance> {
+		pub user: AccountId,
+		pub locked: Balance,
+		pub shares: Balance,
+		pub available_rewards: Balance,
+		pub reward_debt: Balance,
+	}
+
+	pub struct Migration<T>(sp_std::marker::PhantomData<T>);
+
+	impl<T: PhalaPallets> OnRuntimeUpgrade for Migration<T> {
+		fn on_runtime_upgrade() -> Weight {
+			let mut w = T::DbWeight::get().reads(6);
+			if get_versions::<T>() != unified_versions::<T>(5) {
+				log::info!("phala_pallet: skip the v6 migration");
+				return w;
+			}
+			log::info!("phala_pallet: migrating to v6");
+			stakepool::PoolStakers::<T>::translate(
+				|_key, old: OldUserStakeInfo<T::AccountId, StakePoolBalanceOf<T>>| {
+					w += T::DbWeight::get().reads_writes(1, 1);
+					Some(stakepool::UserStakeInfo {
+						user: old.user,
+						locked: old.locked,
+						shares: old.shares,
+						available_rewards: old.available_rewards,
+						reward_debt: old.reward_debt,
+						auto_compound: false,
+					})
+				},
+			);
+			set_unified_version::<T>(6);
+			w + T::DbWeight::get().writes(6)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock::{new_test_ext, Test};
+	use frame_support::traits::OnRuntimeUpgrade;
+	use codec::Encode;
+
+	#[test]
+	fn v6_adds_auto_compound_to_stakers() {
+		new_test_ext().execute_with(|| {
+			set_unified_version::<Test>(5);
+			let old = v6::OldUserStakeInfo::<u64, u128> {
+				user: 1,
+				locked: 100,
+				shares: 90,
+				available_rewards: 10,
+				reward_debt: 5,
+			};
+			let key = stakepool::PoolStakers::<Test>::hashed_key_for((0, 1));
+			frame_support::storage::unhashed::put_raw(&key, &old.encode());
+
+			v6::Migration::<Test>::on_runtime_upgrade();
+			assert_eq!(
+				stakepool::PoolStakers::<Test>::get((0, 1)),
+				Some(stakepool::UserStakeInfo {
+					user: 1,
+					locked: 100,
+					shares: 90,
+					available_rewards: 10,
+					reward_debt: 5,
+					auto_compound: false,
+				})
+			);
+			assert!(get_versions::<Test>() == unified_versions::<Test>(6));
+
+			// Running it again doesn't touch the migrated data
+			v6::Migration::<Test>::on_runtime_upgrade();
+			assert!(stakepool::PoolStakers::<Test>::get((0, 1)).is_some());
+		});
+	}
+}
@@ -207,7 +207,7 @@ pub mod pallet {
 		type UpdateTokenomicOrigin: EnsureOrigin<Self::RuntimeOrigin>;
 	}
 
-	const STORAGE_VERSION: StorageVersion = StorageVersion::new(5);
+	const STORAGE_VERSION: StorageVersion = StorageVersion::new(6);
 
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
@@ -26,7 +26,7 @@ pub mod pallet {
 		type CallMatcher: CallMatcher<Self>;
 	}
 
-	const STORAGE_VERSION: StorageVersion = StorageVersion::new(5);
+	const STORAGE_VERSION: StorageVersion = StorageVersion::new(6);
 
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
@@ -31,8 +31,8 @@ pub mod pallet {
 		dispatch::DispatchResult,
 		pallet_prelude::*,
 		traits::{
-			Currency, Imbalance, LockIdentifier, LockableCurrency, OnUnbalanced, StorageVersion,
-			UnixTime, WithdrawReasons,
+			Currency, ExistenceRequirement, Imbalance, LockIdentifier, LockableCurrency,
+			OnUnbalanced, StorageVersion, UnixTime, WithdrawReasons,
 		},
 	};
 	use frame_system::pallet_prelude::*;
@@ -49,6 +49,17 @@ pub mod pallet {
 
 	const MAX_WHITELIST_LEN: u32 = 100;
 
+	#[cfg(not(test))]
+	const MAX_AUTO_COMPOUND_STAKERS: u32 = 100;
+	#[cfg(test)]
+	const MAX_AUTO_COMPOUND_STAKERS: u32 = 3;
+
+	/// Max number of stakers to compound on each reward distribution of a pool
+	#[cfg(not(test))]
+	const MAX_COMPOUNDS_PER_REWARD: usize = 8;
+	#[cfg(test)]
+	const MAX_COMPOUNDS_PER_REWARD: usize = 2;
+
 	pub struct DescMaxLen;
 
 	impl Get<u32> for DescMaxLen {
@@ -101,7 +112,7 @@ pub mod pallet {
 		type BackfillOrigin: EnsureOrigin<Self::RuntimeOrigin>;
 	}
 
-	const STORAGE_VERSION: StorageVersion = StorageVersion::new(5);
+	const STORAGE_VERSION: StorageVersion = StorageVersion::new(6);
 
 	#[pallet::pallet]
 	#[pallet::generate_store(pub(super) trait Store)]
@@ -180,6 +191,29 @@ pub mod pallet {
 	pub type PoolDescriptions<T: Config> =
 		StorageMap<_, Twox64Concat, u64, BoundedVec<u8, super::DescMaxLen>>;
 
+	/// Mapping from the position NFT id to the staking position (pid, staker) it represents
+	#[pallet::storage]
+	#[pallet::getter(fn position_nfts)]
+	pub type PositionNfts<T: Config> = StorageMap<_, Twox64Concat, u64, (u64, T::AccountId)>;
+
+	/// Mapping from a staking position (pid, staker) to its NFT id
+	#[pallet::storage]
+	pub type PositionNftIds<T: Config> = StorageMap<_, Twox64Concat, (u64, T::AccountId), u64>;
+
+	/// The id of the next minted position NFT
+	#[pallet::storage]
+	pub type NextPositionNftId<T> = StorageValue<_, u64, ValueQuery>;
+
+	/// Mapping from pool id to the stakers opted in to auto-compound their rewards
+	#[pallet::storage]
+	#[pallet::getter(fn auto_compound_stakers)]
+	pub type AutoCompoundStakers<T: Config> =
+		StorageMap<_, Twox64Concat, u64, Vec<T::AccountId>, ValueQuery>;
+
+	/// Mapping from pool id to the index in [`AutoCompoundStakers`] to compound next
+	#[pallet::storage]
+	pub type AutoCompoundCursor<T> = StorageMap<_, Twox64Concat, u64, u32, ValueQuery>;
+
 	#[pallet::event]
 	#[pallet::generate_deposit(pub(super) fn deposit_event)]
 	pub enum Event<T: Config> {
@@ -344,7 +378,72 @@ pub mod pallet {
 			pid: u64,
 			worker: WorkerPublicKey,
 			amount: BalanceOf<T>,
-		}
+		},
+		/// Some shares are transferred from a staker to another account
+		///
+		/// The stake backing the shares is unlocked from the sender, transferred and locked to
+		/// the receiver.
+		///
+		/// Affected states:
+		/// - the user staking accounts of both parties at [`PoolStakers`]
+		/// - the locking ledgers of both parties at [`StakeLedger`]
+		/// - the withdraw request of the sender in [`StakePools`] is reduced if it exceeds the
+		///   remaining shares
+		SharesTransferred {
+			pid: u64,
+			from: T::AccountId,
+			to: T::AccountId,
+			amount: BalanceOf<T>,
+			shares: BalanceOf<T>,
+		},
+		/// A staking position is represented by a newly minted NFT
+		///
+		/// Affected states:
+		/// - a new entry in [`PositionNfts`] and [`PositionNftIds`]
+		PositionNftMinted {
+			pid: u64,
+			user: T::AccountId,
+			nft_id: u64,
+		},
+		/// A position NFT is transferred together with all the shares of the position
+		///
+		/// Affected states:
+		/// - the position NFT is pointed to the position of the receiver in [`PositionNfts`]
+		///   and [`PositionNftIds`]
+		PositionNftTransferred {
+			nft_id: u64,
+			from: T::AccountId,
+			to: T::AccountId,
+		},
+		/// A position NFT is burned. The position itself is not affected.
+		///
+		/// Affected states:
+		/// - the entry in [`PositionNfts`] and [`PositionNftIds`] is removed
+		PositionNftBurned { nft_id: u64 },
+		/// The auto-compound preference of a staker is updated
+		///
+		/// Affected states:
+		/// - the `auto_compound` field of the user staking account at [`PoolStakers`]
+		/// - the staker is added to or removed from [`AutoCompoundStakers`]
+		AutoCompoundSet {
+			pid: u64,
+			user: T::AccountId,
+			enabled: bool,
+		},
+		/// Some rewards of a staker are converted to shares in place
+		///
+		/// The rewards are moved from the subsidy pool to the staker account and locked.
+		///
+		/// Affected states:
+		/// - the stake related fields in [`StakePools`]
+		/// - the user staking account at [`PoolStakers`]
+		/// - the locking ledger of the staker at [`StakeLedger`]
+		RewardsCompounded {
+			pid: u64,
+			user: T::AccountId,
+			amount: BalanceOf<T>,
+			shares: BalanceOf<T>,
+		},
 	}
 
 	#[pallet::error]
@@ -411,6 +510,20 @@ pub mod pallet {
 		NoWhitelistCreated,
 		/// Too long for pool description length
 		ExceedMaxDescriptionLen,
+		/// The shares to transfer are dust or exceed the shares of the sender
+		InvalidTransferAmount,
+		/// Cannot transfer shares or a position NFT to the sender itself
+		TransferToSelf,
+		/// The staking position is already represented by an NFT
+		PositionNftExists,
+		/// The specified position NFT doesn't exist
+		PositionNftNotFound,
+		/// The caller is not the holder of the position NFT
+		UnauthorizedPositionNftHolder,
+		/// Too many stakers opted in to auto-compound in the pool
+		ExceedAutoCompoundMaxLen,
+		/// The stake is less than the minimal contribution to opt in to auto-compound
+		InsufficientAutoCompoundStake,
 	}
 
 	#[pallet::hooks]
@@ -899,6 +1012,7 @@ pub mod pallet {
 					shares: Zero::zero(),
 					available_rewards: Zero::zero(),
 					reward_debt: Zero::zero(),
+					auto_compound: false,
 				},
 			};
 			let shares = pool_info.add_stake(&mut user_info, a);
@@ -954,6 +1068,7 @@ pub mod pallet {
 
 			let mut pool_info = Self::ensure_pool(pid)?;
 			Self::try_withdraw(&mut pool_info, &mut user_info, shares);
+			Self::maybe_prune_auto_compound(pid, &mut user_info);
 
 			PoolStakers::<T>::insert(&info_key, &user_info);
 			StakePools::<T>::insert(&pid, &pool_info);
@@ -961,6 +1076,190 @@ pub mod pallet {
 			Ok(())
 		}
 
+		/// Transfers some shares of a pool, together with the stake backing them, to `to`
+		///
+		/// It allows a staker to exit the position without waiting for the withdraw queue. The
+		/// pending rewards of both parties are settled before the transfer.
+		///
+		/// Requires:
+		/// 1. The pool exists and is not bankrupt
+		/// 2. The sender has at least `shares` in the pool
+		/// 3. If the pool has a contribution whitelist, `to` is in the whitelist or is the owner
+		#[pallet::weight(0)]
+		#[frame_support::transactional]
+		pub fn transfer_shares(
+			origin: OriginFor<T>,
+			pid: u64,
+			to: T::AccountId,
+			shares: BalanceOf<T>,
+		) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+			let mut pool_info = Self::ensure_pool(pid)?;
+			Self::do_transfer_shares(&mut pool_info, who, to, shares)?;
+			StakePools::<T>::insert(&pid, &pool_info);
+			Ok(())
+		}
+
+		/// Mints an NFT to represent the sender's staking position in a pool
+		///
+		/// Transferring the NFT moves all the shares of the position to the receiver.
+		///
+		/// Requires:
+		/// 1. The sender has stake in the pool
+		/// 2. The position is not represented by an NFT yet
+		#[pallet::weight(0)]
+		pub fn mint_position_nft(origin: OriginFor<T>, pid: u64) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+			let info_key = (pid, who.clone());
+			let user_info = Self::pool_stakers(&info_key).ok_or(Error::<T>::PoolStakeNotFound)?;
+			ensure!(
+				is_nondust_balance(user_info.shares),
+				Error::<T>::PoolStakeNotFound
+			);
+			ensure!(
+				!PositionNftIds::<T>::contains_key(&info_key),
+				Error::<T>::PositionNftExists
+			);
+			let nft_id = NextPositionNftId::<T>::get();
+			NextPositionNftId::<T>::put(nft_id + 1);
+			PositionNfts::<T>::insert(nft_id, &info_key);
+			PositionNftIds::<T>::insert(&info_key, nft_id);
+			Self::deposit_event(Event::<T>::PositionNftMinted {
+				pid,
+				user: who,
+				nft_id,
+			});
+			Ok(())
+		}
+
+		/// Transfers a position NFT, together with all the shares of the position, to `to`
+		///
+		/// Requires:
+		/// 1. The sender holds the NFT
+		/// 2. The position of `to` in the same pool is not represented by another NFT
+		/// 3. The same requirements as [`transfer_shares`](Pallet::transfer_shares)
+		#[pallet::weight(0)]
+		#[frame_support::transactional]
+		pub fn transfer_position_nft(
+			origin: OriginFor<T>,
+			nft_id: u64,
+			to: T::AccountId,
+		) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+			let (pid, holder) =
+				PositionNfts::<T>::get(nft_id).ok_or(Error::<T>::PositionNftNotFound)?;
+			ensure!(holder == who, Error::<T>::UnauthorizedPositionNftHolder);
+			ensure!(
+				!PositionNftIds::<T>::contains_key((pid, to.clone())),
+				Error::<T>::PositionNftExists
+			);
+			let mut pool_info = Self::ensure_pool(pid)?;
+			let shares = Self::pool_stakers((pid, who.clone()))
+				.map(|user_info| user_info.shares)
+				.unwrap_or_default();
+			if is_nondust_balance(shares) {
+				Self::do_transfer_shares(&mut pool_info, who.clone(), to.clone(), shares)?;
+				StakePools::<T>::insert(&pid, &pool_info);
+			}
+			PositionNftIds::<T>::remove((pid, who.clone()));
+			PositionNftIds::<T>::insert((pid, to.clone()), nft_id);
+			PositionNfts::<T>::insert(nft_id, (pid, to.clone()));
+			Self::deposit_event(Event::<T>::PositionNftTransferred {
+				nft_id,
+				from: who,
+				to,
+			});
+			Ok(())
+		}
+
+		/// Burns a position NFT held by the sender. The position itself is kept untouched.
+		#[pallet::weight(0)]
+		pub fn burn_position_nft(origin: OriginFor<T>, nft_id: u64) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+			let (pid, holder) =
+				PositionNfts::<T>::get(nft_id).ok_or(Error::<T>::PositionNftNotFound)?;
+			ensure!(holder == who, Error::<T>::UnauthorizedPositionNftHolder);
+			PositionNfts::<T>::remove(nft_id);
+			PositionNftIds::<T>::remove((pid, who));
+			Self::deposit_event(Event::<T>::PositionNftBurned { nft_id });
+			Ok(())
+		}
+
+		/// Turns on or off the auto-compounding of the sender's rewards in a pool
+		///
+		/// When enabled, the rewards distributed to the sender are converted to shares in place,
+		/// as long as the pool cap and contribution whitelist allow.
+		///
+		/// The staker is opted out automatically once the stake falls below the minimal
+		/// contribution. When all the slots of the pool are taken, the staker with the least
+		/// stake is opted out to make room for a staker with more stake.
+		///
+		/// Requires:
+		/// 1. The sender has stake in the pool
+		/// 2. To enable, the stake of the sender is no less than the minimal contribution
+		/// 3. To enable in a full pool, the stake of the sender is more than the least stake of
+		///    the opted in stakers
+		#[pallet::weight(0)]
+		pub fn set_auto_compound(origin: OriginFor<T>, pid: u64, enable: bool) -> DispatchResult {
+			let who = ensure_signed(origin)?;
+			let info_key = (pid, who.clone());
+			let mut user_info =
+				Self::pool_stakers(&info_key).ok_or(Error::<T>::PoolStakeNotFound)?;
+			let mut stakers = AutoCompoundStakers::<T>::get(pid);
+			if enable {
+				ensure!(
+					user_info.locked >= T::MinContribution::get(),
+					Error::<T>::InsufficientAutoCompoundStake
+				);
+				if !stakers.contains(&who) {
+					if stakers.len() as u32 >= MAX_AUTO_COMPOUND_STAKERS {
+						let (index, least) = stakers
+							.iter()
+							.enumerate()
+							.map(|(i, staker)| {
+								let locked = Self::pool_stakers(&(pid, staker.clone()))
+									.map(|info| info.locked)
+									.unwrap_or_default();
+								(i, locked)
+							})
+							.min_by_key(|(_, locked)| *locked)
+							.ok_or(Error::<T>::ExceedAutoCompoundMaxLen)?;
+						ensure!(
+							user_info.locked > least,
+							Error::<T>::ExceedAutoCompoundMaxLen
+						);
+						let evicted = stakers.remove(index);
+						let evicted_key = (pid, evicted.clone());
+						if let Some(mut evicted_info) = Self::pool_stakers(&evicted_key) {
+							evicted_info.auto_compound = false;
+							PoolStakers::<T>::insert(&evicted_key, &evicted_info);
+						}
+						Self::deposit_event(Event::<T>::AutoCompoundSet {
+							pid,
+							user: evicted,
+							enabled: false,
+						});
+					}
+					stakers.push(who.clone());
+				}
+			} else {
+				stakers.retain(|staker| staker != &who);
+			}
+			if stakers.is_empty() {
+				AutoCompoundStakers::<T>::remove(pid);
+			} else {
+				AutoCompoundStakers::<T>::insert(pid, &stakers);
+			}
+			user_info.auto_compound = enable;
+			PoolStakers::<T>::insert(&info_key, &user_info);
+			Self::deposit_event(Event::<T>::AutoCompoundSet {
+				pid,
+				user: who,
+				enabled: enable,
+			});
+			Ok(())
+		}
+
 		/// Starts a miner on behalf of the stake pool
 		///
 		/// Requires:
@@ -1175,6 +1474,118 @@ pub mod pallet {
 						to_stakers: to_distribute,
 					});
 				}
+				if distributed {
+					Self::compound_rewards(pool_info);
+				}
+			}
+		}
+
+		/// Opts the staker out of auto-compound if the stake falls below the minimal contribution
+		///
+		/// It keeps the dust positions from occupying [`AutoCompoundStakers`]. It's up to the
+		/// caller to persist `user_info`.
+		fn maybe_prune_auto_compound(
+			pid: u64,
+			user_info: &mut UserStakeInfo<T::AccountId, BalanceOf<T>>,
+		) {
+			if !user_info.auto_compound || user_info.locked >= T::MinContribution::get() {
+				return;
+			}
+			user_info.auto_compound = false;
+			Self::remove_auto_compound_staker(pid, &user_info.user);
+		}
+
+		/// Removes the staker from [`AutoCompoundStakers`]
+		fn remove_auto_compound_staker(pid: u64, who: &T::AccountId) {
+			AutoCompoundStakers::<T>::mutate_exists(pid, |stakers| {
+				if let Some(list) = stakers {
+					list.retain(|staker| staker != who);
+					if list.is_empty() {
+						*stakers = None;
+					}
+				}
+			});
+			Self::deposit_event(Event::<T>::AutoCompoundSet {
+				pid,
+				user: who.clone(),
+				enabled: false,
+			});
+		}
+
+		/// Converts the rewards of the auto-compounding stakers to shares
+		///
+		/// Only up to `MAX_COMPOUNDS_PER_REWARD` stakers are visited on each distribution, taking
+		/// turns by [`AutoCompoundCursor`], to bound the work done in the reward hook. The
+		/// rewards keep accumulating for the stakers not visited.
+		///
+		/// A staker is skipped if it's no longer allowed by the contribution whitelist. The
+		/// compounded amount is capped by the pool capacity, and the rewards smaller than the
+		/// minimal contribution are left to accumulate.
+		fn compound_rewards(pool_info: &mut PoolInfo<T::AccountId, BalanceOf<T>>) {
+			let pid = pool_info.pid;
+			let stakers = AutoCompoundStakers::<T>::get(pid);
+			if stakers.is_empty() {
+				return;
+			}
+			// Adding stake to a bankrupt pool is not allowed. See `contribute`.
+			if pool_info.total_stake == Zero::zero() {
+				return;
+			}
+			let start = AutoCompoundCursor::<T>::get(pid) as usize % stakers.len();
+			let count = stakers.len().min(MAX_COMPOUNDS_PER_REWARD);
+			AutoCompoundCursor::<T>::insert(pid, ((start + count) % stakers.len()) as u32);
+			let batch: Vec<_> = stakers.into_iter().cycle().skip(start).take(count).collect();
+			let whitelist = PoolContributionWhitelists::<T>::get(&pid);
+			let mut compounded = false;
+			for staker in batch {
+				if let Some(whitelist) = &whitelist {
+					if !whitelist.contains(&staker) && pool_info.owner != staker {
+						continue;
+					}
+				}
+				let info_key = (pid, staker.clone());
+				let mut user_info = match Self::pool_stakers(&info_key) {
+					Some(user_info) if user_info.locked >= T::MinContribution::get() => user_info,
+					Some(mut user_info) => {
+						user_info.auto_compound = false;
+						Self::remove_auto_compound_staker(pid, &staker);
+						PoolStakers::<T>::insert(&info_key, &user_info);
+						continue;
+					}
+					None => {
+						Self::remove_auto_compound_staker(pid, &staker);
+						continue;
+					}
+				};
+				pool_info.settle_user_pending_reward(&mut user_info);
+				let mut amount = user_info.available_rewards;
+				if let Some(cap) = pool_info.cap {
+					amount = amount.min(cap.saturating_sub(pool_info.total_stake));
+				}
+				// The settlement is only kept in memory when nothing is compounded. It's redone
+				// from the reward debt on the next interaction, so there's no need to write it.
+				if amount < T::MinContribution::get() {
+					continue;
+				}
+				if mining::Pallet::<T>::withdraw_subsidy_pool(&staker, amount).is_err() {
+					continue;
+				}
+				Self::maybe_settle_slash(pool_info, &mut user_info);
+				user_info.available_rewards -= amount;
+				let shares = pool_info.add_stake(&mut user_info, amount);
+				Self::ledger_accrue(&staker, amount);
+				PoolStakers::<T>::insert(&info_key, &user_info);
+				compounded = true;
+				Self::deposit_event(Event::<T>::RewardsCompounded {
+					pid,
+					user: staker,
+					amount,
+					shares,
+				});
+			}
+			// We have new free stake now, try to handle the waiting withdraw queue
+			if compounded {
+				Self::try_process_withdraw_queue(pool_info);
 			}
 		}
 
@@ -1284,6 +1695,86 @@ pub mod pallet {
 			pool_info.reset_pending_reward(user_info);
 		}
 
+		/// Moves `shares` from the position of `from` to the position of `to` in the pool
+		///
+		/// The stake backing the shares is unlocked, transferred to `to` and locked again. The
+		/// withdraw request of `from` is capped to the remaining shares. The caller must persist
+		/// `pool_info`.
+		fn do_transfer_shares(
+			pool_info: &mut PoolInfo<T::AccountId, BalanceOf<T>>,
+			from: T::AccountId,
+			to: T::AccountId,
+			shares: BalanceOf<T>,
+		) -> DispatchResult {
+			ensure!(from != to, Error::<T>::TransferToSelf);
+			let pid = pool_info.pid;
+			if let Some(whitelist) = PoolContributionWhitelists::<T>::get(&pid) {
+				ensure!(
+					whitelist.contains(&to) || pool_info.owner == to,
+					Error::<T>::NotInContributeWhitelist
+				);
+			}
+			// Same as `contribute`, we don't want the bankrupt pool to accept new stakers
+			ensure!(
+				pool_info.total_stake > Zero::zero(),
+				Error::<T>::PoolBankrupt
+			);
+			let from_key = (pid, from.clone());
+			let to_key = (pid, to.clone());
+			let mut from_info =
+				Self::pool_stakers(&from_key).ok_or(Error::<T>::PoolStakeNotFound)?;
+			ensure!(
+				is_nondust_balance(shares) && shares <= from_info.shares,
+				Error::<T>::InvalidTransferAmount
+			);
+			let mut to_info = Self::pool_stakers(&to_key).unwrap_or(UserStakeInfo {
+				user: to.clone(),
+				locked: Zero::zero(),
+				shares: Zero::zero(),
+				available_rewards: Zero::zero(),
+				reward_debt: Zero::zero(),
+				auto_compound: false,
+			});
+			// Clear the pending rewards and slash of both parties before touching the shares
+			pool_info.settle_user_pending_reward(&mut from_info);
+			Self::maybe_settle_slash(pool_info, &mut from_info);
+			pool_info.settle_user_pending_reward(&mut to_info);
+			Self::maybe_settle_slash(pool_info, &mut to_info);
+			let (amount, moved_shares) = pool_info
+				.transfer_shares(&mut from_info, &mut to_info, shares)
+				.ok_or(Error::<T>::InvalidTransferAmount)?;
+			// Move the locked funds
+			Self::ledger_reduce(&from, amount, Zero::zero());
+			<T as Config>::Currency::transfer(
+				&from,
+				&to,
+				amount,
+				ExistenceRequirement::KeepAlive,
+			)?;
+			Self::ledger_accrue(&to, amount);
+			// The queued withdrawal can't exceed the remaining shares
+			for request in pool_info.withdraw_queue.iter_mut() {
+				if request.user == from {
+					request.shares = request.shares.min(from_info.shares);
+				}
+			}
+			pool_info
+				.withdraw_queue
+				.retain(|request| is_nondust_balance(request.shares));
+			Self::maybe_prune_auto_compound(pid, &mut from_info);
+
+			PoolStakers::<T>::insert(&from_key, &from_info);
+			PoolStakers::<T>::insert(&to_key, &to_info);
+			Self::deposit_event(Event::<T>::SharesTransferred {
+				pid,
+				from,
+				to,
+				amount,
+				shares: moved_shares,
+			});
+			Ok(())
+		}
+
 		/// Tries to fulfill the withdraw queue with the newly freed stake
 		fn try_process_withdraw_queue(pool_info: &mut PoolInfo<T::AccountId, BalanceOf<T>>) {
 			// The share price shouldn't change at any point in this function. So we can calculate
@@ -1338,6 +1829,7 @@ pub mod pallet {
 					});
 					// Update the pending reward after changing the staked amount
 					pool_info.reset_pending_reward(&mut user_info);
+					Self::maybe_prune_auto_compound(pool_info.pid, &mut user_info);
 					PoolStakers::<T>::insert(&info_key, &user_info);
 					// Update if the withdraw is partially fulfilled, otherwise pop it out of the
 					// queue
@@ -1751,6 +2243,47 @@ pub mod pallet {
 			Some((amount, user_dust, removed_shares))
 		}
 
+		/// Moves some shares and the stake backing them from one user to another.
+		///
+		/// Requires no dirty slash or pending reward on both users. The pool totals are not
+		/// changed, so the invariant `pool.total_shares == sum(pool_user.shares)` holds. When only
+		/// dust is left to the sender, the dust moves together.
+		///
+		/// Returns the amount of the moved stake and the moved shares, or None if `shares`
+		/// exceeds the sender's shares.
+		fn transfer_shares(
+			&self,
+			from: &mut UserStakeInfo<AccountId, Balance>,
+			to: &mut UserStakeInfo<AccountId, Balance>,
+			shares: Balance,
+		) -> Option<(Balance, Balance)> {
+			debug_assert!(is_nondust_balance(shares));
+			self.assert_slash_clean(from);
+			self.assert_slash_clean(to);
+			self.assert_reward_clean(from);
+			self.assert_reward_clean(to);
+
+			let price = self.share_price()?;
+			let remaining_shares = from.shares.checked_sub(&shares)?;
+			let (remaining_shares, shares_dust) = extract_dust(remaining_shares);
+			let moved_shares = shares + shares_dust;
+			let amount = if remaining_shares == Zero::zero() {
+				from.locked
+			} else {
+				bmul(shares, &price).min(from.locked)
+			};
+			let (remaining_locked, locked_dust) = extract_dust(from.locked - amount);
+			let amount = amount + locked_dust;
+			// Apply updates
+			from.shares = remaining_shares;
+			from.locked = remaining_locked;
+			to.shares.saturating_accrue(moved_shares);
+			to.locked.saturating_accrue(amount);
+			self.reset_pending_reward(from);
+			self.reset_pending_reward(to);
+			Some((amount, moved_shares))
+		}
+
 		/// Slashes the pool with dust removed.
 		fn slash(&mut self, amount: Balance) {
 			debug_assert!(
@@ -1917,6 +2450,8 @@ pub mod pallet {
 		///
 		/// It's subject to the pool reward [accumulator](crate::utils::accumulator).
 		pub reward_debt: Balance,
+		/// Whether the rewards are converted to shares in place once distributed
+		pub auto_compound: bool,
 	}
 
 	/// A withdraw request, usually stored in the withdrawal queue
@@ -1942,7 +2477,7 @@ pub mod pallet {
 		use crate::mock::{
 			ecdh_pubkey, elapse_cool_down, elapse_seconds, new_test_ext, set_block_1,
 			setup_workers, setup_workers_linked_operators, take_events, teleport_to_block,
-			worker_pubkey, Balance, BlockNumber, RuntimeEvent as TestEvent, RuntimeOrigin as Origin, Test, DOLLARS,
+			worker_pubkey, Balance, BlockNumber, RuntimeEvent as TestEvent, RuntimeOrigin as Origin, Test, CENTS, DOLLARS,
 		};
 		// Pallets
 		use crate::mock::{
@@ -2342,6 +2877,319 @@ pub mod pallet {
 			});
 		}
 
+		#[test]
+		fn test_transfer_shares() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(1);
+				setup_pool_with_workers(1, &[1]); // pid = 0
+				assert_ok!(PhalaStakePool::contribute(
+					Origin::signed(2),
+					0,
+					400 * DOLLARS
+				));
+				assert_ok!(PhalaStakePool::contribute(
+					Origin::signed(3),
+					0,
+					100 * DOLLARS
+				));
+				// Each share gets 1 PHA reward
+				assert_ok!(PhalaStakePool::force_assign_reward(
+					Origin::root(),
+					vec![(0, 500 * DOLLARS)]
+				));
+				// Bad cases
+				assert_noop!(
+					PhalaStakePool::transfer_shares(Origin::signed(2), 0, 3, 401 * DOLLARS),
+					Error::<Test>::InvalidTransferAmount
+				);
+				assert_noop!(
+					PhalaStakePool::transfer_shares(Origin::signed(2), 0, 2, 100 * DOLLARS),
+					Error::<Test>::TransferToSelf
+				);
+				assert_noop!(
+					PhalaStakePool::transfer_shares(Origin::signed(1), 0, 2, 100 * DOLLARS),
+					Error::<Test>::PoolStakeNotFound
+				);
+				// Happy path
+				assert_ok!(PhalaStakePool::transfer_shares(
+					Origin::signed(2),
+					0,
+					3,
+					100 * DOLLARS
+				));
+				let staker2 = PhalaStakePool::pool_stakers((0, 2)).unwrap();
+				let staker3 = PhalaStakePool::pool_stakers((0, 3)).unwrap();
+				assert_eq!(staker2.shares, 300 * DOLLARS);
+				assert_eq!(staker2.locked, 300 * DOLLARS);
+				assert_eq!(staker3.shares, 200 * DOLLARS);
+				assert_eq!(staker3.locked, 200 * DOLLARS);
+				// Rewards are settled before the transfer
+				assert_eq!(staker2.available_rewards, 400 * DOLLARS);
+				assert_eq!(staker3.available_rewards, 100 * DOLLARS);
+				let pool = PhalaStakePool::stake_pools(0).unwrap();
+				assert_eq!(pool.total_shares, 500 * DOLLARS);
+				assert_eq!(pool.total_stake, 500 * DOLLARS);
+				// The stake is moved between the ledgers
+				assert_eq!(PhalaStakePool::stake_ledger(2), Some(300 * DOLLARS));
+				assert_eq!(PhalaStakePool::stake_ledger(3), Some(200 * DOLLARS));
+				assert_eq!(Balances::locks(2), vec![the_lock(300 * DOLLARS)]);
+				assert_eq!(Balances::locks(3), vec![the_lock(200 * DOLLARS)]);
+				assert_eq!(Balances::free_balance(2), 1900 * DOLLARS);
+				assert_eq!(Balances::free_balance(3), 1100 * DOLLARS);
+				// The receiver must be in the whitelist if there's one
+				assert_ok!(PhalaStakePool::add_staker_to_whitelist(
+					Origin::signed(1),
+					0,
+					3,
+				));
+				assert_noop!(
+					PhalaStakePool::transfer_shares(Origin::signed(2), 0, 99, 100 * DOLLARS),
+					Error::<Test>::NotInContributeWhitelist
+				);
+				assert_ok!(PhalaStakePool::transfer_shares(
+					Origin::signed(2),
+					0,
+					1,
+					300 * DOLLARS
+				));
+				let staker2 = PhalaStakePool::pool_stakers((0, 2)).unwrap();
+				assert_eq!(staker2.shares, 0);
+				assert_eq!(staker2.locked, 0);
+				assert_eq!(PhalaStakePool::stake_ledger(2), Some(0));
+				assert!(Balances::locks(2).is_empty());
+			});
+		}
+
+		#[test]
+		fn test_position_nft() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(1);
+				setup_pool_with_workers(1, &[1]); // pid = 0
+				assert_ok!(PhalaStakePool::contribute(
+					Origin::signed(2),
+					0,
+					400 * DOLLARS
+				));
+				// Mint
+				assert_noop!(
+					PhalaStakePool::mint_position_nft(Origin::signed(3), 0),
+					Error::<Test>::PoolStakeNotFound
+				);
+				assert_ok!(PhalaStakePool::mint_position_nft(Origin::signed(2), 0));
+				assert_noop!(
+					PhalaStakePool::mint_position_nft(Origin::signed(2), 0),
+					Error::<Test>::PositionNftExists
+				);
+				assert_eq!(PhalaStakePool::position_nfts(0), Some((0, 2)));
+				// Transfer
+				assert_noop!(
+					PhalaStakePool::transfer_position_nft(Origin::signed(3), 0, 3),
+					Error::<Test>::UnauthorizedPositionNftHolder
+				);
+				assert_ok!(PhalaStakePool::transfer_position_nft(
+					Origin::signed(2),
+					0,
+					3
+				));
+				assert_eq!(PhalaStakePool::position_nfts(0), Some((0, 3)));
+				assert_eq!(PositionNftIds::<Test>::get((0, 2)), None);
+				assert_eq!(PositionNftIds::<Test>::get((0, 3)), Some(0));
+				let staker2 = PhalaStakePool::pool_stakers((0, 2)).unwrap();
+				let staker3 = PhalaStakePool::pool_stakers((0, 3)).unwrap();
+				assert_eq!(staker2.shares, 0);
+				assert_eq!(staker3.shares, 400 * DOLLARS);
+				assert_eq!(PhalaStakePool::stake_ledger(3), Some(400 * DOLLARS));
+				// Burn
+				assert_ok!(PhalaStakePool::burn_position_nft(Origin::signed(3), 0));
+				assert_eq!(PhalaStakePool::position_nfts(0), None);
+				assert_eq!(PositionNftIds::<Test>::get((0, 3)), None);
+				assert_noop!(
+					PhalaStakePool::burn_position_nft(Origin::signed(3), 0),
+					Error::<Test>::PositionNftNotFound
+				);
+			});
+		}
+
+		#[test]
+		fn test_auto_compound() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(1);
+				setup_pool_with_workers(1, &[1]); // pid = 0
+				assert_ok!(PhalaStakePool::contribute(
+					Origin::signed(2),
+					0,
+					400 * DOLLARS
+				));
+				assert_ok!(PhalaStakePool::contribute(
+					Origin::signed(3),
+					0,
+					100 * DOLLARS
+				));
+				assert_noop!(
+					PhalaStakePool::set_auto_compound(Origin::signed(99), 0, true),
+					Error::<Test>::PoolStakeNotFound
+				);
+				assert_ok!(PhalaStakePool::set_auto_compound(Origin::signed(2), 0, true));
+				assert!(PhalaStakePool::pool_stakers((0, 2)).unwrap().auto_compound);
+				assert_eq!(PhalaStakePool::auto_compound_stakers(0), vec![2]);
+				// Each share gets 1 PHA reward. Staker 2 compounds the reward in place.
+				assert_ok!(PhalaStakePool::force_assign_reward(
+					Origin::root(),
+					vec![(0, 500 * DOLLARS)]
+				));
+				let staker2 = PhalaStakePool::pool_stakers((0, 2)).unwrap();
+				assert_eq!(staker2.shares, 800 * DOLLARS);
+				assert_eq!(staker2.locked, 800 * DOLLARS);
+				assert_eq!(staker2.available_rewards, 0);
+				assert_eq!(PhalaStakePool::stake_ledger(2), Some(800 * DOLLARS));
+				assert_eq!(Balances::free_balance(2), 2400 * DOLLARS);
+				let staker3 = PhalaStakePool::pool_stakers((0, 3)).unwrap();
+				assert_eq!(staker3.shares, 100 * DOLLARS);
+				let pool = PhalaStakePool::stake_pools(0).unwrap();
+				assert_eq!(pool.total_stake, 900 * DOLLARS);
+				assert_eq!(pool.total_shares, 900 * DOLLARS);
+				// The compounded amount is limited by the cap
+				assert_ok!(PhalaStakePool::set_cap(Origin::signed(1), 0, 1000 * DOLLARS));
+				assert_ok!(PhalaStakePool::force_assign_reward(
+					Origin::root(),
+					vec![(0, 900 * DOLLARS)]
+				));
+				let staker2 = PhalaStakePool::pool_stakers((0, 2)).unwrap();
+				assert_eq!(staker2.shares, 900 * DOLLARS);
+				assert_eq!(staker2.available_rewards, 700 * DOLLARS);
+				let pool = PhalaStakePool::stake_pools(0).unwrap();
+				assert_eq!(pool.total_stake, 1000 * DOLLARS);
+				// Opt out
+				assert_ok!(PhalaStakePool::set_auto_compound(Origin::signed(2), 0, false));
+				assert!(!PhalaStakePool::pool_stakers((0, 2)).unwrap().auto_compound);
+				assert!(PhalaStakePool::auto_compound_stakers(0).is_empty());
+			});
+		}
+
+		#[test]
+		fn test_auto_compound_pruning() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(1);
+				setup_pool_with_workers(1, &[1]); // pid = 0
+				for staker in [2, 3, 4] {
+					assert_ok!(PhalaStakePool::contribute(
+						Origin::signed(staker),
+						0,
+						100 * DOLLARS
+					));
+					assert_ok!(PhalaStakePool::set_auto_compound(
+						Origin::signed(staker),
+						0,
+						true
+					));
+				}
+				assert_eq!(PhalaStakePool::auto_compound_stakers(0), vec![2, 3, 4]);
+				// Withdrawing all the stake opts out
+				assert_ok!(PhalaStakePool::withdraw(
+					Origin::signed(2),
+					0,
+					100 * DOLLARS
+				));
+				assert!(!PhalaStakePool::pool_stakers((0, 2)).unwrap().auto_compound);
+				assert_eq!(PhalaStakePool::auto_compound_stakers(0), vec![3, 4]);
+				// Transferring all the shares opts out
+				assert_ok!(PhalaStakePool::transfer_shares(
+					Origin::signed(3),
+					0,
+					5,
+					100 * DOLLARS
+				));
+				assert!(!PhalaStakePool::pool_stakers((0, 3)).unwrap().auto_compound);
+				assert_eq!(PhalaStakePool::auto_compound_stakers(0), vec![4]);
+				// Leaving a dust position opts out
+				assert_ok!(PhalaStakePool::withdraw(
+					Origin::signed(4),
+					0,
+					100 * DOLLARS - CENTS / 2
+				));
+				assert!(PhalaStakePool::auto_compound_stakers(0).is_empty());
+				// A dust position can't opt in
+				assert_noop!(
+					PhalaStakePool::set_auto_compound(Origin::signed(4), 0, true),
+					Error::<Test>::InsufficientAutoCompoundStake
+				);
+			});
+		}
+
+		#[test]
+		fn test_auto_compound_slots() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(1);
+				setup_pool_with_workers(1, &[1]); // pid = 0
+				for (staker, amount) in [(2, 400), (3, 100), (99, 300), (1, 200)] {
+					assert_ok!(PhalaStakePool::contribute(
+						Origin::signed(staker),
+						0,
+						amount * DOLLARS
+					));
+				}
+				for staker in [2, 3, 99] {
+					assert_ok!(PhalaStakePool::set_auto_compound(
+						Origin::signed(staker),
+						0,
+						true
+					));
+				}
+				// The pool is full, the staker with the least stake makes room for a larger one
+				assert_ok!(PhalaStakePool::set_auto_compound(Origin::signed(1), 0, true));
+				assert_eq!(PhalaStakePool::auto_compound_stakers(0), vec![2, 99, 1]);
+				assert!(!PhalaStakePool::pool_stakers((0, 3)).unwrap().auto_compound);
+				// But not for a smaller one
+				assert_noop!(
+					PhalaStakePool::set_auto_compound(Origin::signed(3), 0, true),
+					Error::<Test>::ExceedAutoCompoundMaxLen
+				);
+			});
+		}
+
+		#[test]
+		fn test_auto_compound_takes_turns() {
+			new_test_ext().execute_with(|| {
+				set_block_1();
+				setup_workers(1);
+				setup_pool_with_workers(1, &[1]); // pid = 0
+				for staker in [2, 3, 99] {
+					assert_ok!(PhalaStakePool::contribute(
+						Origin::signed(staker),
+						0,
+						100 * DOLLARS
+					));
+					assert_ok!(PhalaStakePool::set_auto_compound(
+						Origin::signed(staker),
+						0,
+						true
+					));
+				}
+				let locked = |staker| PhalaStakePool::pool_stakers((0, staker)).unwrap().locked;
+				// Each share gets 1 PHA reward. Only two stakers are compounded at a time.
+				assert_ok!(PhalaStakePool::force_assign_reward(
+					Origin::root(),
+					vec![(0, 300 * DOLLARS)]
+				));
+				assert_eq!(locked(2), 200 * DOLLARS);
+				assert_eq!(locked(3), 200 * DOLLARS);
+				assert_eq!(locked(99), 100 * DOLLARS);
+				// The next distribution starts from the skipped staker
+				assert_ok!(PhalaStakePool::force_assign_reward(
+					Origin::root(),
+					vec![(0, 500 * DOLLARS)]
+				));
+				assert_eq!(locked(99), 300 * DOLLARS);
+				assert_eq!(locked(2), 400 * DOLLARS);
+				assert_eq!(locked(3), 200 * DOLLARS);
+			});
+		}
+
 		#[test]
 		#[ignore]
 		fn test_slash() {
@@ -0,0 +1,444 @@
+//! Encoding ink! contract calls according to the contract metadata json.
+
+use anyhow::{anyhow, bail, Context, Result};
+use codec::{Compact, Encode};
+use serde_json::Value;
+use std::convert::TryFrom;
+
+pub struct Metadata {
+    spec: Value,
+    types: Vec<Value>,
+}
+
+impl Metadata {
+    /// Loads the metadata generated by cargo-contract. Both the `V3` and the versioned layout are
+    /// supported.
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read(path).context("Failed to read the metadata file")?;
+        let metadata: Value =
+            serde_json::from_slice(&content).context("Failed to parse the metadata")?;
+        let metadata = metadata.get("V3").unwrap_or(&metadata);
+        let spec = metadata
+            .get("spec")
+            .cloned()
+            .ok_or_else(|| anyhow!("No spec in the metadata"))?;
+        let types = metadata
+            .get("types")
+            .and_then(Value::as_array)
+            .cloned()
+            .ok_or_else(|| anyhow!("No types in the metadata"))?;
+        Ok(Self { spec, types })
+    }
+
+    /// Encodes a call to the constructor with given label or selector.
+    pub fn encode_constructor(&self, name: &str, args: &[String]) -> Result<Vec<u8>> {
+        self.encode_call("constructors", name, args)
+    }
+
+    /// Encodes a call to the message with given label or selector.
+    pub fn encode_message(&self, name: &str, args: &[String]) -> Result<Vec<u8>> {
+        self.encode_call("messages", name, args)
+    }
+
+    fn encode_call(&self, kind: &str, name: &str, args: &[String]) -> Result<Vec<u8>> {
+        let entries = self
+            .spec
+            .get(kind)
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow!("No {} in the metadata", kind))?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry_label(entry) == Some(name) || entry["selector"] == name)
+            .ok_or_else(|| {
+                let labels: Vec<_> = entries.iter().filter_map(entry_label).collect();
+                anyhow!("No {name} in {kind}, available: {}", labels.join(", "))
+            })?;
+        let selector = entry["selector"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing selector of {}", name))?;
+        let mut buf = super::try_decode_hex(selector)?;
+        let params = entry["args"].as_array().cloned().unwrap_or_default();
+        if params.len() != args.len() {
+            bail!(
+                "{name} takes {} arguments, but {} given",
+                params.len(),
+                args.len()
+            );
+        }
+        for (param, arg) in params.iter().zip(args) {
+            let type_id = param["type"]["type"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("Bad type of argument {}", param["label"]))?;
+            let value = self.parse_arg(type_id, arg)?;
+            self.encode_value(type_id, &value, &mut buf)
+                .with_context(|| format!("Failed to encode argument {}", param["label"]))?;
+        }
+        Ok(buf)
+    }
+
+    /// Parses an argument given in the command line as a value of type `type_id`.
+    ///
+    /// Arguments of the primitive types other than `bool` are taken as is, without the json
+    /// quotes, so are the ones of `Option<String>` with `null` for `None`. Other arguments are
+    /// parsed as json, falling back to a plain string for convenience, e.g. for the hex encoded
+    /// bytes or the variant names.
+    fn parse_arg(&self, type_id: u64, arg: &str) -> Result<Value> {
+        match self.type_def(type_id)?["primitive"].as_str() {
+            Some("bool") | None => {}
+            Some(_) => return Ok(Value::String(arg.into())),
+        }
+        if let Some(inner) = self.option_inner(type_id)? {
+            if self.is_str(inner)? {
+                return Ok(match arg {
+                    "null" => Value::Null,
+                    _ => Value::String(arg.into()),
+                });
+            }
+        }
+        Ok(serde_json::from_str(arg).unwrap_or_else(|_| Value::String(arg.into())))
+    }
+
+    fn is_str(&self, type_id: u64) -> Result<bool> {
+        Ok(self.type_def(type_id)?["primitive"] == "str")
+    }
+
+    /// Returns the type of `T` if `type_id` is an `Option<T>`.
+    fn option_inner(&self, type_id: u64) -> Result<Option<u64>> {
+        let variants = match self.type_def(type_id)?["variant"]["variants"].as_array() {
+            Some(variants) if is_option(variants) => variants,
+            _ => return Ok(None),
+        };
+        let some = variants
+            .iter()
+            .find(|v| v["name"] == "Some")
+            .expect("Checked by is_option");
+        match some["fields"].as_array().map(Vec::as_slice) {
+            Some([field]) => Ok(Some(type_id_of(field)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn type_def(&self, type_id: u64) -> Result<&Value> {
+        self.types
+            .iter()
+            .find(|ty| ty["id"] == type_id)
+            .map(|ty| &ty["type"]["def"])
+            .ok_or_else(|| anyhow!("Type {} not found", type_id))
+    }
+
+    fn encode_value(&self, type_id: u64, value: &Value, buf: &mut Vec<u8>) -> Result<()> {
+        let def = self.type_def(type_id)?;
+        if let Some(primitive) = def.get("primitive").and_then(Value::as_str) {
+            return encode_primitive(primitive, value, buf);
+        }
+        if let Some(composite) = def.get("composite") {
+            let fields = composite["fields"].as_array().cloned().unwrap_or_default();
+            return self.encode_fields(&fields, value, buf);
+        }
+        if let Some(seq) = def.get("sequence") {
+            let elem = type_id_of(seq)?;
+            let items = self.sequence_items(elem, value)?;
+            Compact(items.len() as u32).encode_to(buf);
+            return self.encode_items(elem, &items, buf);
+        }
+        if let Some(array) = def.get("array") {
+            let elem = type_id_of(array)?;
+            let items = self.sequence_items(elem, value)?;
+            if Some(items.len() as u64) != array["len"].as_u64() {
+                bail!("Expect an array of length {}", array["len"]);
+            }
+            return self.encode_items(elem, &items, buf);
+        }
+        if let Some(tuple) = def.get("tuple").and_then(Value::as_array) {
+            if tuple.is_empty() {
+                return Ok(());
+            }
+            let items = value
+                .as_array()
+                .ok_or_else(|| anyhow!("Expect an array for tuple"))?;
+            if items.len() != tuple.len() {
+                bail!("Expect a tuple of length {}", tuple.len());
+            }
+            for (ty, item) in tuple.iter().zip(items) {
+                let ty = ty.as_u64().ok_or_else(|| anyhow!("Bad tuple type"))?;
+                self.encode_value(ty, item, buf)?;
+            }
+            return Ok(());
+        }
+        if let Some(variant) = def.get("variant") {
+            return self.encode_variant(variant, value, buf);
+        }
+        if let Some(compact) = def.get("compact") {
+            let n = as_u128(value)?;
+            match self.type_def(type_id_of(compact)?)?["primitive"].as_str() {
+                Some("u8" | "u16" | "u32") => Compact(n as u32).encode_to(buf),
+                Some("u64") => Compact(n as u64).encode_to(buf),
+                _ => Compact(n).encode_to(buf),
+            }
+            return Ok(());
+        }
+        bail!("Unsupported type {}", def)
+    }
+
+    fn encode_fields(&self, fields: &[Value], value: &Value, buf: &mut Vec<u8>) -> Result<()> {
+        match fields {
+            [] => Ok(()),
+            // Newtypes such as AccountId are given as their inner value.
+            [field] if field.get("name").is_none() => {
+                self.encode_value(type_id_of(field)?, value, buf)
+            }
+            _ => {
+                for (i, field) in fields.iter().enumerate() {
+                    let item = match field["name"].as_str() {
+                        Some(name) => value.get(name),
+                        None => value.get(i),
+                    }
+                    .ok_or_else(|| anyhow!("Missing field {}", field["name"]))?;
+                    self.encode_value(type_id_of(field)?, item, buf)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn encode_variant(&self, variant: &Value, value: &Value, buf: &mut Vec<u8>) -> Result<()> {
+        let variants = variant["variants"].as_array().cloned().unwrap_or_default();
+        let is_variant = |name: &str| variants.iter().any(|v| v["name"] == name);
+        // Accepts `"Name"` and `{"Name": fields}`. An `Option` also accepts `null` for `None` and
+        // the inner value for `Some`.
+        let (name, fields_value) = match value {
+            Value::Null if is_option(&variants) => ("None", Value::Null),
+            Value::Object(map) if map.len() == 1 && is_variant(map.keys().next().unwrap()) => {
+                let (name, value) = map.iter().next().expect("Checked length");
+                (name.as_str(), value.clone())
+            }
+            _ if is_option(&variants) => ("Some", value.clone()),
+            Value::String(name) => (name.as_str(), Value::Null),
+            _ => bail!("Expect a variant, got {}", value),
+        };
+        let (index, variant) = variants
+            .iter()
+            .enumerate()
+            .find(|(_, v)| v["name"] == name)
+            .ok_or_else(|| anyhow!("No variant {}", name))?;
+        let index = variant["index"].as_u64().unwrap_or(index as u64);
+        buf.push(index as u8);
+        let fields = variant["fields"].as_array().cloned().unwrap_or_default();
+        self.encode_fields(&fields, &fields_value, buf)
+    }
+
+    /// Gets the items of a sequence, allowing hex strings for byte sequences.
+    fn sequence_items(&self, elem: u64, value: &Value) -> Result<Vec<Value>> {
+        match value {
+            Value::Array(items) => Ok(items.clone()),
+            Value::String(s) if self.type_def(elem)?["primitive"] == "u8" => {
+                let bytes = super::try_decode_hex(s)?;
+                Ok(bytes.into_iter().map(Value::from).collect())
+            }
+            _ => bail!("Expect an array, got {}", value),
+        }
+    }
+
+    fn encode_items(&self, elem: u64, items: &[Value], buf: &mut Vec<u8>) -> Result<()> {
+        for item in items {
+            self.encode_value(elem, item, buf)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_option(variants: &[Value]) -> bool {
+    let mut names: Vec<_> = variants.iter().filter_map(|v| v["name"].as_str()).collect();
+    names.sort_unstable();
+    names == ["None", "Some"]
+}
+
+fn entry_label(entry: &Value) -> Option<&str> {
+    match &entry["label"] {
+        Value::String(label) => Some(label),
+        // The label is split into segments in the V1 metadata.
+        Value::Array(segments) => segments.last().and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+fn type_id_of(value: &Value) -> Result<u64> {
+    value["type"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("Bad type reference {}", value))
+}
+
+fn as_u128(value: &Value) -> Result<u128> {
+    match value {
+        Value::Number(n) => n.as_u64().map(Into::into),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("Expect an unsigned integer, got {}", value))
+}
+
+fn as_i128(value: &Value) -> Result<i128> {
+    match value {
+        Value::Number(n) => n.as_i64().map(Into::into),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("Expect an integer, got {}", value))
+}
+
+fn encode_primitive(primitive: &str, value: &Value, buf: &mut Vec<u8>) -> Result<()> {
+    macro_rules! encode_int {
+        ($t: ty, $parse: ident) => {
+            <$t>::try_from($parse(value)?)
+                .map_err(|_| anyhow!("{value} out of range of {}", primitive))?
+                .encode_to(buf)
+        };
+    }
+    match primitive {
+        "bool" => value
+            .as_bool()
+            .ok_or_else(|| anyhow!("Expect a bool, got {}", value))?
+            .encode_to(buf),
+        "str" => value
+            .as_str()
+            .ok_or_else(|| anyhow!("Expect a string, got {}", value))?
+            .encode_to(buf),
+        "char" => {
+            let s = value.as_str().unwrap_or_default();
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => (c as u32).encode_to(buf),
+                _ => bail!("Expect a char, got {}", value),
+            }
+        }
+        "u8" => encode_int!(u8, as_u128),
+        "u16" => encode_int!(u16, as_u128),
+        "u32" => encode_int!(u32, as_u128),
+        "u64" => encode_int!(u64, as_u128),
+        "u128" => as_u128(value)?.encode_to(buf),
+        "i8" => encode_int!(i8, as_i128),
+        "i16" => encode_int!(i16, as_i128),
+        "i32" => encode_int!(i32, as_i128),
+        "i64" => encode_int!(i64, as_i128),
+        "i128" => as_i128(value)?.encode_to(buf),
+        _ => bail!("Unsupported primitive type {}", primitive),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::Decode;
+
+    fn metadata() -> Metadata {
+        Metadata::load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/fixtures/metadata.json"
+        ))
+        .unwrap()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Checks the call is encoded as `selector` followed by `expected`, and decodes back.
+    fn assert_call<T: Encode + Decode + PartialEq + std::fmt::Debug>(
+        encoded: Vec<u8>,
+        selector: [u8; 4],
+        expected: T,
+    ) {
+        assert_eq!(encoded[..4], selector);
+        assert_eq!(encoded[4..], expected.encode()[..]);
+        assert_eq!(T::decode(&mut &encoded[4..]).unwrap(), expected);
+    }
+
+    #[test]
+    fn encode_constructor_and_message() {
+        let metadata = metadata();
+        let account = format!("0x{}", "01".repeat(32));
+        let encoded = metadata
+            .encode_constructor("new", &args(&[&account]))
+            .unwrap();
+        assert_call(encoded, [0x9b, 0xae, 0x9d, 0x5e], [1u8; 32]);
+
+        let encoded = metadata
+            .encode_message("transfer", &args(&[&account, "1000000000000000000000"]))
+            .unwrap();
+        let expected = ([1u8; 32], 1_000_000_000_000_000_000_000_u128);
+        assert_call(encoded, [0x84, 0xa1, 0x5d, 0xa1], expected);
+
+        // Selectors can be used in place of the labels.
+        let by_selector = metadata
+            .encode_message("0x84a15da1", &args(&[&account, "1"]))
+            .unwrap();
+        assert_call(by_selector, [0x84, 0xa1, 0x5d, 0xa1], ([1u8; 32], 1u128));
+    }
+
+    #[test]
+    fn strings_are_taken_as_is() {
+        let metadata = metadata();
+        let selector = [0, 0, 0, 1];
+        let encoded = metadata
+            .encode_message("set_name", &args(&["123", "hello"]))
+            .unwrap();
+        let expected = ("123".to_string(), Some("hello".to_string()));
+        assert_call(encoded, selector, expected);
+
+        let encoded = metadata
+            .encode_message("set_name", &args(&["true", "None"]))
+            .unwrap();
+        let expected = ("true".to_string(), Some("None".to_string()));
+        assert_call(encoded, selector, expected);
+
+        let encoded = metadata
+            .encode_message("set_name", &args(&["\"quoted\"", "null"]))
+            .unwrap();
+        let expected = ("\"quoted\"".to_string(), None::<String>);
+        assert_call(encoded, selector, expected);
+    }
+
+    #[test]
+    fn encode_variants_and_options() {
+        // `Mode::Off` and `Mode::Limited(n)` are encoded as `0u8` and `(1u8, n)`.
+        let metadata = metadata();
+        let selector = [0, 0, 0, 2];
+        let encoded = metadata
+            .encode_message("set_mode", &args(&["Off", "7", "0x0102", "true"]))
+            .unwrap();
+        let expected = (0u8, Some(7u32), vec![1u8, 2], true);
+        assert_call(encoded, selector, expected);
+
+        let encoded = metadata
+            .encode_message(
+                "set_mode",
+                &args(&[r#"{"Limited": 5}"#, "null", "[3]", "false"]),
+            )
+            .unwrap();
+        let expected = ((1u8, 5u32), None::<u32>, vec![3u8], false);
+        assert_call(encoded, selector, expected);
+
+        let encoded = metadata
+            .encode_message("set_mode", &args(&["Off", r#"{"Some": 9}"#, "[]", "false"]))
+            .unwrap();
+        let expected = (0u8, Some(9u32), Vec::<u8>::new(), false);
+        assert_call(encoded, selector, expected);
+    }
+
+    #[test]
+    fn bad_calls_are_rejected() {
+        let metadata = metadata();
+        assert!(metadata.encode_message("unknown", &[]).is_err());
+        assert!(metadata.encode_message("set_name", &args(&["a"])).is_err());
+        assert!(metadata
+            .encode_message("set_mode", &args(&["On", "null", "[]", "true"]))
+            .is_err());
+        assert!(metadata
+            .encode_message("set_mode", &args(&["Off", "-1", "[]", "true"]))
+            .is_err());
+        assert!(metadata
+            .encode_message("transfer", &args(&["0x01", "1"]))
+            .is_err());
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::{anyhow, Context, Result};
+use phaxt::subxt::{self, tx::StaticTxPayload, utils::Encoded};
+use sp_core::{crypto::Pair as _, sr25519};
+use sp_runtime::AccountId32;
+
+type SrSigner = subxt::tx::PairSigner<phaxt::Config, sr25519::Pair>;
+
+pub struct ChainClient {
+    api: phaxt::ChainApi,
+    signer: SrSigner,
+}
+
+impl ChainClient {
+    pub async fn connect(url: &str, suri: &str) -> Result<Self> {
+        let api = phaxt::connect(url).await?;
+        let pair =
+            sr25519::Pair::from_string(suri, None).map_err(|err| anyhow!("Bad suri: {:?}", err))?;
+        Ok(Self {
+            api,
+            signer: SrSigner::new(pair),
+        })
+    }
+
+    pub fn account(&self) -> AccountId32 {
+        self.signer.account_id().clone()
+    }
+
+    /// Signs and submits the extrinsic, and waits for it to be included in a block.
+    pub async fn submit(&self, tx: StaticTxPayload<Encoded>) -> Result<()> {
+        let events = self
+            .api
+            .tx()
+            .sign_and_submit_then_watch_default(&tx, &self.signer)
+            .await
+            .context("Failed to submit the extrinsic")?
+            .wait_for_in_block()
+            .await?
+            .wait_for_success()
+            .await
+            .context("The extrinsic failed")?;
+        println!("Included in block 0x{}", hex::encode(events.block_hash()));
+        Ok(())
+    }
+}
@@ -1,8 +1,10 @@
+mod abi;
+mod chain;
 mod query;
 
-use clap::{AppSettings, Parser, Subcommand};
+use clap::{AppSettings, Args, Parser, Subcommand};
 use codec::{Decode, Encode};
-use phala_types::contract::ContractId;
+use phala_types::contract::{ContractClusterId, ContractId};
 use std::convert::TryInto;
 use std::fmt::Debug;
 
@@ -72,20 +74,100 @@ enum RpcCommand {
     GetInfo,
 }
 
+#[derive(Debug, Args)]
+struct ChainArgs {
+    #[clap(long, default_value = "ws://localhost:9944")]
+    node_url: String,
+    /// The secret URI of the signer account
+    #[clap(long, default_value = "//Alice")]
+    suri: String,
+}
+
 #[derive(Debug, Subcommand)]
 enum PinkCommand {
+    /// Query the contract. With `--metadata`, the message is the label or selector of the ink
+    /// message followed by its arguments in json, except that strings and numbers are given
+    /// without quotes. Otherwise it's the hex encoded payload.
     Query {
         #[clap(long, default_value = "http://localhost:8000")]
         url: String,
         #[clap(long)]
         sidevm: bool,
+        /// Path to the ink metadata json of the contract
+        #[clap(long)]
+        metadata: Option<String>,
         id: String,
         message: String,
+        args: Vec<String>,
     },
     Command {
         id: String,
         message: String,
     },
+    /// Upload an ink or sidevm code to the cluster
+    Upload {
+        #[clap(flatten)]
+        chain: ChainArgs,
+        #[clap(long)]
+        cluster: String,
+        #[clap(long)]
+        sidevm: bool,
+        file: String,
+    },
+    /// Instantiate an uploaded ink code
+    Instantiate {
+        #[clap(flatten)]
+        chain: ChainArgs,
+        #[clap(long)]
+        cluster: String,
+        /// Hex encoded salt
+        #[clap(long, default_value = "")]
+        salt: String,
+        #[clap(long)]
+        metadata: Option<String>,
+        code_hash: String,
+        constructor: String,
+        args: Vec<String>,
+    },
+    /// Send a signed command to the contract via `push_message`
+    Send {
+        #[clap(flatten)]
+        chain: ChainArgs,
+        #[clap(long)]
+        metadata: Option<String>,
+        id: String,
+        message: String,
+        args: Vec<String>,
+    },
+    /// Fetch the records of a contract (or all contracts) from the log server
+    Log {
+        #[clap(long, default_value = "http://localhost:8000")]
+        url: String,
+        /// Hex encoded contract id to get the logs of
+        #[clap(long)]
+        contract: Option<String>,
+        #[clap(long, default_value = "0")]
+        from: u64,
+        #[clap(long, default_value = "100")]
+        count: u64,
+        /// Record types to select: Log, Event or MessageOutput. Can be given multiple times
+        #[clap(long = "type")]
+        types: Vec<String>,
+        /// Select logs with level less or equal to this value (1=Error, ..., 5=Trace)
+        #[clap(long)]
+        max_level: Option<u8>,
+        #[clap(long)]
+        from_block: Option<u32>,
+        #[clap(long)]
+        to_block: Option<u32>,
+        /// Start of the time range in milliseconds since the unix epoch
+        #[clap(long)]
+        from_time: Option<u64>,
+        /// End of the time range in milliseconds since the unix epoch
+        #[clap(long)]
+        to_time: Option<u64>,
+        log_server: String,
+    },
 }
 
 #[tokio::main]
@@ -231,16 +313,22 @@ async fn handle_pink_command(command: PinkCommand) {
         SidevmNotFound,
     }
 
+    #[derive(Encode)]
+    enum Payload<T> {
+        Plain(T),
+    }
+
     match command {
         PinkCommand::Query {
             url,
             sidevm,
+            metadata,
             id,
             message,
+            args,
         } => {
-            let id = decode_hex(&id);
-            let id = ContractId::decode(&mut &id[..]).expect("Bad contract id");
-            let message = decode_hex(&message);
+            let id = decode_contract_id(&id);
+            let message = encode_message(metadata.as_deref(), &message, &args);
             let query = if sidevm {
                 Query::SidevmQuery(message)
             } else {
@@ -265,13 +353,7 @@ async fn handle_pink_command(command: PinkCommand) {
             }
         }
         PinkCommand::Command { id, message } => {
-            #[derive(Encode)]
-            enum Payload<T> {
-                Plain(T),
-            }
-
-            let id = decode_hex(&id);
-            let id = ContractId::decode(&mut &id[..]).expect("Bad contract id");
+            let id = decode_contract_id(&id);
             let message = decode_hex(&message);
             let nonce = vec![];
             let command = Command::InkMessage { nonce, message };
@@ -282,9 +364,165 @@ async fn handle_pink_command(command: PinkCommand) {
             );
             println!("command: (0x{})", hex::encode(mq_payload.encode()));
         }
+        PinkCommand::Upload {
+            chain,
+            cluster,
+            sidevm,
+            file,
+        } => {
+            use phala_types::contract::messaging::ResourceType;
+
+            let cluster = decode_cluster_id(&cluster);
+            let code = std::fs::read(&file).expect("Failed to read the code");
+            let resource_type = if sidevm {
+                ResourceType::SidevmCode
+            } else {
+                ResourceType::InkCode
+            };
+            println!("code hash: 0x{}", hex::encode(sp_core::blake2_256(&code)));
+            let tx = phaxt::dynamic::tx::cluster_upload_resource(cluster, resource_type, code);
+            connect_chain(&chain)
+                .await
+                .submit(tx)
+                .await
+                .expect("Failed to upload the code");
+        }
+        PinkCommand::Instantiate {
+            chain,
+            cluster,
+            salt,
+            metadata,
+            code_hash,
+            constructor,
+            args,
+        } => {
+            use phala_types::contract::{CodeIndex, ContractInfo};
+
+            let cluster_id = decode_cluster_id(&cluster);
+            let code_hash = decode_hex(&code_hash);
+            if code_hash.len() != 32 {
+                panic!("Bad code hash");
+            }
+            let code_index = CodeIndex::WasmCode(phaxt::Hash::from_slice(&code_hash));
+            let salt = decode_hex(&salt);
+            let data = match metadata {
+                Some(metadata) => abi::Metadata::load(&metadata)
+                    .and_then(|metadata| metadata.encode_constructor(&constructor, &args))
+                    .expect("Failed to encode the constructor call"),
+                None => decode_hex(&constructor),
+            };
+            let client = connect_chain(&chain).await;
+            let contract_info = ContractInfo {
+                deployer: client.account(),
+                code_index: code_index.clone(),
+                salt: salt.clone(),
+                cluster_id,
+                instantiate_data: data.clone(),
+            };
+            let id = contract_info.contract_id(sp_core::blake2_256);
+            println!("contract id: 0x{}", hex::encode(id));
+            let tx = phaxt::dynamic::tx::instantiate_contract(code_index, data, salt, cluster_id);
+            client
+                .submit(tx)
+                .await
+                .expect("Failed to instantiate the contract");
+        }
+        PinkCommand::Send {
+            chain,
+            metadata,
+            id,
+            message,
+            args,
+        } => {
+            let id = decode_contract_id(&id);
+            let message = encode_message(metadata.as_deref(), &message, &args);
+            // The nonce only needs to be unique among the commands from the same sender.
+            let nonce = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_nanos()
+                .encode();
+            let command = Command::InkMessage { nonce, message };
+            let topic = phala_types::contract::command_topic(id);
+            let tx = phaxt::dynamic::tx::push_message(topic, Payload::Plain(command).encode());
+            connect_chain(&chain)
+                .await
+                .submit(tx)
+                .await
+                .expect("Failed to send the command");
+        }
+        PinkCommand::Log {
+            url,
+            contract,
+            from,
+            count,
+            types,
+            max_level,
+            from_block,
+            to_block,
+            from_time,
+            to_time,
+            log_server,
+        } => {
+            let id = decode_contract_id(&log_server);
+            let mut request = serde_json::json!({
+                "action": "GetLog",
+                "from": from,
+                "count": count,
+                "types": types,
+                "max_level": max_level,
+                "from_block": from_block,
+                "to_block": to_block,
+                "from_time": from_time,
+                "to_time": to_time,
+            });
+            if let Some(contract) = contract {
+                request["contract"] = contract.into();
+            }
+            let query = Query::SidevmQuery(request.to_string().into_bytes());
+            let result: Result<Response, QueryError> =
+                query::query(url, id, query).await.expect("Query failed");
+            match result {
+                Ok(Response::Payload(response)) => {
+                    println!("{}", String::from_utf8_lossy(&response));
+                }
+                Err(err) => println!("Error: {:?}", err),
+            }
+        }
+    }
+}
+
+async fn connect_chain(args: &ChainArgs) -> chain::ChainClient {
+    chain::ChainClient::connect(&args.node_url, &args.suri)
+        .await
+        .expect("Failed to connect to the chain")
+}
+
+/// Encodes the ink message call with the metadata if given, or decodes the hex encoded message.
+fn encode_message(metadata: Option<&str>, message: &str, args: &[String]) -> Vec<u8> {
+    match metadata {
+        Some(metadata) => abi::Metadata::load(metadata)
+            .and_then(|metadata| metadata.encode_message(message, args))
+            .expect("Failed to encode the message"),
+        None => {
+            if !args.is_empty() {
+                panic!("Arguments are only supported with --metadata");
+            }
+            decode_hex(message)
+        }
     }
 }
 
+fn decode_contract_id(hex_str: &str) -> ContractId {
+    let id = decode_hex(hex_str);
+    ContractId::decode(&mut &id[..]).expect("Bad contract id")
+}
+
+fn decode_cluster_id(hex_str: &str) -> ContractClusterId {
+    let id = decode_hex(hex_str);
+    ContractClusterId::decode(&mut &id[..]).expect("Bad cluster id")
+}
+
 fn try_decode_hex(hex_str: &str) -> Result<Vec<u8>, hex::FromHexError> {
     hex::decode(hex_str.strip_prefix("0x").unwrap_or(hex_str))
 }
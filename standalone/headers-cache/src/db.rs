@@ -1,7 +1,7 @@
 use crate::BlockNumber;
 
 use anyhow::Result;
-use rocksdb::DB;
+use rocksdb::{Direction, IteratorMode, DB};
 use std::mem::size_of;
 
 use serde::{Deserialize, Serialize};
@@ -70,6 +70,21 @@ impl CacheDB {
         self.0.get(mk_key(prefix, block)).ok().flatten()
     }
 
+    /// Returns the lowest block number stored with given prefix.
+    fn lowest(&self, prefix: u8) -> Option<BlockNumber> {
+        let start = mk_key(prefix, 0);
+        let (key, _) = self
+            .0
+            .iterator(IteratorMode::From(&start, Direction::Forward))
+            .next()?;
+        if key.len() != start.len() || key[0] != prefix {
+            return None;
+        }
+        let mut number = [0u8; size_of::<BlockNumber>()];
+        number.copy_from_slice(&key[1..]);
+        Some(BlockNumber::from_be_bytes(number))
+    }
+
     fn put(&self, prefix: u8, block: BlockNumber, value: &[u8]) -> Result<()> {
         self.0.put(mk_key(prefix, block), value)?;
         Ok(())
@@ -83,6 +98,10 @@ impl CacheDB {
         self.put(b'h', block, value)
     }
 
+    pub fn lowest_header(&self) -> Option<BlockNumber> {
+        self.lowest(b'h')
+    }
+
     pub fn get_para_header(&self, block: BlockNumber) -> Option<Vec<u8>> {
         self.get(b'p', block)
     }
@@ -91,6 +110,10 @@ impl CacheDB {
         self.put(b'p', block, value)
     }
 
+    pub fn lowest_para_header(&self) -> Option<BlockNumber> {
+        self.lowest(b'p')
+    }
+
     pub fn get_storage_changes(&self, block: BlockNumber) -> Option<Vec<u8>> {
         self.get(b'c', block)
     }
@@ -99,6 +122,10 @@ impl CacheDB {
         self.put(b'c', block, value)
     }
 
+    pub fn lowest_storage_changes(&self) -> Option<BlockNumber> {
+        self.lowest(b'c')
+    }
+
     pub fn get_genesis(&self, block: BlockNumber) -> Option<Vec<u8>> {
         self.get(b'g', block)
     }
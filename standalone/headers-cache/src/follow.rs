@@ -0,0 +1,214 @@
+//! Live-follow mode, importing the newly finalized blocks into the cache database.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use futures::StreamExt;
+use log::{error, info};
+use pherry::headers_cache as cache;
+use scale::Encode;
+use tokio::time::sleep;
+
+use crate::db::{CacheDB, Metadata};
+use crate::BlockNumber;
+
+/// Number of blocks between two metadata updates while catching up.
+const METADATA_UPDATE_INTERVAL: BlockNumber = 1000;
+
+pub(crate) struct FollowArgs {
+    pub node_uri: String,
+    pub para_node_uri: String,
+    pub justification_interval: BlockNumber,
+    pub batch_size: BlockNumber,
+}
+
+/// Checks that the database has the ranges to continue from, so following doesn't start from the
+/// genesis of the chains.
+pub(crate) fn check_start(db: &CacheDB) -> Result<()> {
+    let metadata = db.get_metadata()?.unwrap_or_default();
+    next_header(&metadata)?;
+    next_para_header(&metadata)?;
+    next_storage_changes(&metadata)?;
+    Ok(())
+}
+
+/// Keeps importing finalized relaychain headers, parachain headers and storage changes, restarting
+/// on errors.
+pub(crate) async fn follow(db: Arc<CacheDB>, args: FollowArgs) {
+    loop {
+        if let Err(err) = follow_chain(&db, &args).await {
+            error!("Following the chain failed: {err:?}");
+        }
+        sleep(Duration::from_secs(10)).await;
+        info!("Restarting following the chain...");
+    }
+}
+
+async fn follow_chain(db: &CacheDB, args: &FollowArgs) -> Result<()> {
+    let api = pherry::subxt_connect(&args.node_uri).await?;
+    let para_api = pherry::subxt_connect(&args.para_node_uri).await?;
+    let mut relay_heads = api.rpc().subscribe_finalized_blocks().await?;
+    let mut para_heads = para_api.rpc().subscribe_finalized_blocks().await?;
+    let mut skip_justification = args.justification_interval;
+    info!(
+        "Following the chain at {} and {}",
+        args.node_uri, args.para_node_uri
+    );
+
+    loop {
+        tokio::select! {
+            head = relay_heads.next() => {
+                let head = head.ok_or_else(|| anyhow!("Relaychain subscription closed"))??;
+                let mut metadata = db.get_metadata()?.unwrap_or_default();
+                let next = next_header(&metadata)?;
+                if head.number < next {
+                    continue;
+                }
+                let count = cache::grab_headers(
+                    &api,
+                    &para_api,
+                    next,
+                    head.number - next + 1,
+                    args.justification_interval,
+                    &mut skip_justification,
+                    |info| {
+                        if info.justification.is_some() {
+                            info!("Got justification at {}", info.header.number);
+                        }
+                        db.put_header(info.header.number, &info.encode())?;
+                        imported(db, &mut metadata, info.header.number, Metadata::update_header)
+                    },
+                )
+                .await?;
+                db.put_metadata(metadata)?;
+                info!("Imported {count} headers");
+            }
+            head = para_heads.next() => {
+                let head = head.ok_or_else(|| anyhow!("Parachain subscription closed"))??;
+                let mut metadata = db.get_metadata()?.unwrap_or_default();
+                let next = next_para_header(&metadata)?;
+                if head.number >= next {
+                    let count = cache::grab_para_headers(
+                        &para_api,
+                        next,
+                        head.number - next + 1,
+                        |header| {
+                            db.put_para_header(header.number, &header.encode())?;
+                            imported(db, &mut metadata, header.number, Metadata::update_para_header)
+                        },
+                    )
+                    .await?;
+                    info!("Imported {count} parachain headers");
+                }
+                let next = next_storage_changes(&metadata)?;
+                if head.number >= next {
+                    let count = cache::grab_storage_changes(
+                        &para_api,
+                        next,
+                        head.number - next + 1,
+                        args.batch_size,
+                        |changes| {
+                            let number = changes.block_header.number;
+                            db.put_storage_changes(number, &changes.encode())?;
+                            imported(db, &mut metadata, number, Metadata::update_storage_changes)
+                        },
+                    )
+                    .await?;
+                    info!("Imported {count} storage changes");
+                }
+                db.put_metadata(metadata)?;
+            }
+        }
+    }
+}
+
+/// The next relaychain header to import, following the highest imported header or genesis.
+fn next_header(metadata: &Metadata) -> Result<BlockNumber> {
+    let highest = match metadata.higest.header {
+        Some(number) => number,
+        None => {
+            let genesis = metadata.genesis.iter().max().copied();
+            genesis.context("The genesis must be imported before following")?
+        }
+    };
+    next_of(highest)
+}
+
+/// The next parachain header to import. The parachain headers have no genesis in the database, so
+/// an initial range must be imported.
+fn next_para_header(metadata: &Metadata) -> Result<BlockNumber> {
+    let highest = metadata
+        .higest
+        .para_header
+        .context("The parachain headers must be imported before following")?;
+    next_of(highest)
+}
+
+/// The next storage changes to import. An initial range must be imported, as for the parachain
+/// headers.
+fn next_storage_changes(metadata: &Metadata) -> Result<BlockNumber> {
+    let highest = metadata
+        .higest
+        .storage_changes
+        .context("The storage changes must be imported before following")?;
+    next_of(highest)
+}
+
+fn next_of(highest: BlockNumber) -> Result<BlockNumber> {
+    highest
+        .checked_add(1)
+        .ok_or_else(|| anyhow!("Block number overflow"))
+}
+
+fn imported(
+    db: &CacheDB,
+    metadata: &mut Metadata,
+    number: BlockNumber,
+    update: fn(&mut Metadata, BlockNumber),
+) -> Result<()> {
+    update(metadata, number);
+    if number % METADATA_UPDATE_INTERVAL == 0 {
+        info!("Imported to {number}");
+        db.put_metadata(metadata.clone())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_follow_the_highest_or_genesis() {
+        let mut metadata = Metadata::default();
+        assert!(next_header(&metadata).is_err());
+        metadata.put_genesis(100);
+        metadata.put_genesis(50);
+        assert_eq!(next_header(&metadata).unwrap(), 101);
+        metadata.update_header(200);
+        assert_eq!(next_header(&metadata).unwrap(), 201);
+    }
+
+    #[test]
+    fn para_data_requires_an_imported_range() {
+        let mut metadata = Metadata::default();
+        metadata.put_genesis(100);
+        assert!(next_para_header(&metadata).is_err());
+        assert!(next_storage_changes(&metadata).is_err());
+        metadata.update_para_header(30);
+        metadata.update_storage_changes(20);
+        assert_eq!(next_para_header(&metadata).unwrap(), 31);
+        assert_eq!(next_storage_changes(&metadata).unwrap(), 21);
+        // Block 0 is a valid range, not a missing one.
+        metadata.higest.para_header = Some(0);
+        assert_eq!(next_para_header(&metadata).unwrap(), 1);
+    }
+
+    #[test]
+    fn next_block_does_not_overflow() {
+        let mut metadata = Metadata::default();
+        metadata.update_header(BlockNumber::MAX);
+        assert!(next_header(&metadata).is_err());
+    }
+}
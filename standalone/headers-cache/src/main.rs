@@ -1,5 +1,6 @@
 use std::fs::File;
 use std::io::Write;
+use std::sync::Arc;
 
 use anyhow::Context;
 use log::info;
@@ -9,6 +10,8 @@ use clap::{AppSettings, Parser, Subcommand};
 use pherry::headers_cache as cache;
 
 mod db;
+mod follow;
+mod verify;
 mod web_api;
 
 type BlockNumber = u32;
@@ -140,6 +143,21 @@ enum Action {
         /// The database file to use
         #[clap(long, default_value = "cache.db")]
         db: String,
+        /// Keep importing the newly finalized blocks from the chain into the database
+        #[clap(long)]
+        follow: bool,
+        /// The relaychain RPC endpoint, used with --follow
+        #[clap(long, default_value = "ws://localhost:9945")]
+        node_uri: String,
+        /// The parachain RPC endpoint, used with --follow
+        #[clap(long, default_value = "ws://localhost:9944")]
+        para_node_uri: String,
+        /// Prefered minimum number of blocks between justification, used with --follow
+        #[clap(long, default_value_t = 1000)]
+        justification_interval: BlockNumber,
+        /// Number of blocks of storage changes requested in a single RPC, used with --follow
+        #[clap(long, default_value_t = 10)]
+        batch_size: BlockNumber,
     },
     /// Split given grabbed headers file into chunks
     Split {
@@ -155,6 +173,27 @@ enum Action {
         /// The grabbed headers file to read from
         files: Vec<String>,
     },
+    /// Verify the integrity of the cache database, optionally refetching the broken ranges
+    Verify {
+        /// The database file to use
+        #[clap(long, default_value = "cache.db")]
+        db: String,
+        /// Refetch the broken ranges from the chain
+        #[clap(long)]
+        repair: bool,
+        /// The relaychain RPC endpoint, used with --repair
+        #[clap(long, default_value = "ws://localhost:9945")]
+        node_uri: String,
+        /// The parachain RPC endpoint, used with --repair
+        #[clap(long, default_value = "ws://localhost:9944")]
+        para_node_uri: String,
+        /// Prefered minimum number of blocks between justification, used with --repair
+        #[clap(long, default_value_t = 1000)]
+        justification_interval: BlockNumber,
+        /// Number of blocks of storage changes requested in a single RPC, used with --repair
+        #[clap(long, default_value_t = 10)]
+        batch_size: BlockNumber,
+    },
     /// Show imported block number info in the cache database
     InspectDb {
         /// The database file to use
@@ -316,8 +355,26 @@ async fn main() -> anyhow::Result<()> {
             }
             cache.flush()?;
         }
-        Action::Serve { db } => {
-            web_api::serve(&db).await?;
+        Action::Serve {
+            db,
+            follow,
+            node_uri,
+            para_node_uri,
+            justification_interval,
+            batch_size,
+        } => {
+            let db = Arc::new(db::CacheDB::open(&db)?);
+            if follow {
+                follow::check_start(&db)?;
+                let args = follow::FollowArgs {
+                    node_uri,
+                    para_node_uri,
+                    justification_interval,
+                    batch_size,
+                };
+                tokio::spawn(follow::follow(db.clone(), args));
+            }
+            web_api::serve(db).await?;
         }
         Action::ShowSetId { uri, block } => {
             let api = pherry::subxt_connect(&uri).await?;
@@ -400,6 +457,48 @@ async fn main() -> anyhow::Result<()> {
                 }
             }
         }
+        Action::Verify {
+            db,
+            repair,
+            node_uri,
+            para_node_uri,
+            justification_interval,
+            batch_size,
+        } => {
+            let cache = db::CacheDB::open(&db)?;
+            let verify::Verification { broken, unverified } = verify::verify(&cache)?;
+            if broken.is_empty() && unverified.is_empty() {
+                println!("No problem found");
+                return Ok(());
+            }
+            for range in &unverified {
+                println!(
+                    "Justifications of headers {}-{} not verified",
+                    range.start(),
+                    range.end()
+                );
+            }
+            if broken.is_empty() {
+                println!("No broken range found, but some justifications were not verified");
+                std::process::exit(1);
+            }
+            for b in &broken {
+                println!("{b}");
+            }
+            println!("{} broken ranges found", broken.len());
+            if repair {
+                let args = verify::RepairArgs {
+                    node_uri,
+                    para_node_uri,
+                    justification_interval,
+                    batch_size,
+                };
+                verify::repair(&cache, broken, &args).await?;
+                println!("Repaired, please verify again");
+            } else {
+                std::process::exit(1);
+            }
+        }
         Action::InspectDb { db } => {
             let cache = db::CacheDB::open(&db)?;
             let metadata = cache.get_metadata()?.unwrap_or_default();
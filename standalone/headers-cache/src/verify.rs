@@ -0,0 +1,493 @@
+//! Integrity verification and repair of the cache database.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use phactory::light_validation::RelaychainValidation;
+use pherry::headers_cache as cache;
+use pherry::types::Header;
+use scale::{Decode, Encode};
+
+use crate::db::{CacheDB, Metadata};
+use crate::BlockNumber;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Header,
+    ParaHeader,
+    StorageChanges,
+}
+
+/// A range of blocks found broken in the database.
+#[derive(Debug, Clone)]
+pub(crate) struct BrokenRange {
+    pub kind: Kind,
+    pub range: RangeInclusive<BlockNumber>,
+    pub reason: String,
+}
+
+impl fmt::Display for BrokenRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {}-{}: {}",
+            self.kind,
+            self.range.start(),
+            self.range.end(),
+            self.reason
+        )
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    broken: Vec<BrokenRange>,
+    unverified: Vec<RangeInclusive<BlockNumber>>,
+}
+
+impl Report {
+    fn broken(&mut self, kind: Kind, range: RangeInclusive<BlockNumber>, reason: String) {
+        let broken = BrokenRange {
+            kind,
+            range,
+            reason,
+        };
+        warn!("Broken: {broken}");
+        self.broken.push(broken);
+    }
+
+    fn unverified(&mut self, range: RangeInclusive<BlockNumber>) {
+        warn!(
+            "Justifications of headers {}-{} not verified",
+            range.start(),
+            range.end()
+        );
+        self.unverified.push(range);
+    }
+}
+
+/// The result of a database verification.
+pub(crate) struct Verification {
+    /// The ranges found broken, which can be repaired by refetching them.
+    pub broken: Vec<BrokenRange>,
+    /// The relaychain headers whose justifications could not be verified, because there was no
+    /// genesis to start the light client from, or the light client stopped at a broken range.
+    pub unverified: Vec<RangeInclusive<BlockNumber>>,
+}
+
+/// Walks through the database and returns the problems found.
+pub(crate) fn verify(db: &CacheDB) -> Result<Verification> {
+    let metadata = db.get_metadata()?.unwrap_or_default();
+    let mut report = Report::default();
+    verify_headers(db, &metadata, &mut report)?;
+    verify_para_headers(db, &metadata, &mut report);
+    verify_storage_changes(db, &metadata, &mut report);
+    Ok(Verification {
+        broken: report.broken,
+        unverified: report.unverified,
+    })
+}
+
+type Validator = (RelaychainValidation, u64);
+
+fn init_validator(db: &CacheDB, genesis: BlockNumber) -> Result<Validator> {
+    let data = db
+        .get_genesis(genesis)
+        .context("Genesis missing in the database")?;
+    let info =
+        cache::GenesisBlockInfo::decode(&mut &data[..]).context("Failed to decode the genesis")?;
+    let mut light_client = RelaychainValidation::new();
+    let bridge_id =
+        light_client.initialize_bridge(info.block_header, info.authority_set, info.proof)?;
+    Ok((light_client, bridge_id))
+}
+
+/// Stops the justification validation at a broken range. The blocks from `from` are unverified
+/// until the light client is restarted from a genesis.
+fn stop_validation(
+    validator: &mut Option<Validator>,
+    unverified_from: &mut Option<BlockNumber>,
+    from: BlockNumber,
+) {
+    if validator.take().is_some() {
+        *unverified_from = Some(from);
+    }
+}
+
+/// Checks the parent hash continuity and the justifications of the relaychain headers.
+///
+/// The justifications are validated by a light client started from the lowest genesis. As the
+/// light client can't get over a broken batch, it is restarted from the next genesis after it,
+/// and the headers in between are reported as unverified.
+fn verify_headers(db: &CacheDB, metadata: &Metadata, report: &mut Report) -> Result<()> {
+    let highest = match metadata.higest.header {
+        Some(highest) => highest,
+        None => return Ok(()),
+    };
+    let start = match metadata.genesis.iter().min() {
+        Some(&genesis) => genesis + 1,
+        None => match db.lowest_header() {
+            Some(lowest) => lowest,
+            None => return Ok(()),
+        },
+    };
+    info!("Verifying headers {start}-{highest}");
+
+    let mut validator: Option<Validator> = None;
+    let mut unverified_from = Some(start);
+    let mut parent: Option<Header> = None;
+    let mut batch: Vec<cache::BlockInfo> = vec![];
+    let mut batch_start = start;
+    for number in start..=highest {
+        let genesis = number
+            .checked_sub(1)
+            .filter(|prev| validator.is_none() && metadata.genesis.contains(prev));
+        if let Some(genesis) = genesis {
+            validator = Some(init_validator(db, genesis)?);
+            if let Some(from) = unverified_from.take() {
+                if from < number {
+                    report.unverified(from..=number - 1);
+                }
+            }
+            // The headers before the genesis can't be validated by the new light client.
+            batch.clear();
+        }
+        let info = match db.get_header(number) {
+            Some(data) => match cache::BlockInfo::decode(&mut &data[..]) {
+                Ok(info) if info.header.number == number => info,
+                Ok(_) => {
+                    report.broken(Kind::Header, number..=number, "number mismatch".into());
+                    stop_validation(&mut validator, &mut unverified_from, number);
+                    parent = None;
+                    continue;
+                }
+                Err(err) => {
+                    report.broken(Kind::Header, number..=number, format!("{err}"));
+                    stop_validation(&mut validator, &mut unverified_from, number);
+                    parent = None;
+                    continue;
+                }
+            },
+            None => {
+                report.broken(Kind::Header, number..=number, "missing".into());
+                stop_validation(&mut validator, &mut unverified_from, number);
+                parent = None;
+                continue;
+            }
+        };
+        if let Some(parent) = &parent {
+            if parent.hash() != info.header.parent_hash {
+                report.broken(
+                    Kind::Header,
+                    parent.number..=number,
+                    "parent hash mismatch".into(),
+                );
+                stop_validation(&mut validator, &mut unverified_from, number);
+            }
+        }
+        parent = Some(info.header.clone());
+        if batch.is_empty() {
+            batch_start = number;
+        }
+        let justified = info.justification.is_some();
+        batch.push(info);
+        if !justified {
+            continue;
+        }
+        if let Some((light_client, bridge_id)) = &mut validator {
+            let mut last = batch.pop().expect("The batch is not empty");
+            let mut ancestry_proof: Vec<_> = batch.drain(..).map(|info| info.header).collect();
+            ancestry_proof.reverse();
+            let result = light_client.submit_finalized_headers(
+                *bridge_id,
+                last.header,
+                ancestry_proof,
+                last.justification.take().expect("Justified"),
+                last.authority_set_change,
+            );
+            if let Err(err) = result {
+                report.broken(Kind::Header, batch_start..=number, format!("{err}"));
+                stop_validation(&mut validator, &mut unverified_from, batch_start);
+            }
+        }
+        batch.clear();
+    }
+    if let Some(from) = unverified_from {
+        if from <= highest {
+            report.unverified(from..=highest);
+        }
+    }
+    if !batch.is_empty() {
+        info!(
+            "Headers {}-{highest} are not justified yet",
+            batch[0].header.number
+        );
+    }
+    Ok(())
+}
+
+fn verify_para_headers(db: &CacheDB, metadata: &Metadata, report: &mut Report) {
+    let (lowest, highest) = match (db.lowest_para_header(), metadata.higest.para_header) {
+        (Some(lowest), Some(highest)) => (lowest, highest),
+        _ => return,
+    };
+    info!("Verifying parachain headers {lowest}-{highest}");
+    let mut parent: Option<Header> = None;
+    for number in lowest..=highest {
+        let header = match db
+            .get_para_header(number)
+            .map(|data| Header::decode(&mut &data[..]))
+        {
+            Some(Ok(header)) if header.number == number => header,
+            Some(Ok(_)) => {
+                report.broken(Kind::ParaHeader, number..=number, "number mismatch".into());
+                parent = None;
+                continue;
+            }
+            Some(Err(err)) => {
+                report.broken(Kind::ParaHeader, number..=number, format!("{err}"));
+                parent = None;
+                continue;
+            }
+            None => {
+                report.broken(Kind::ParaHeader, number..=number, "missing".into());
+                parent = None;
+                continue;
+            }
+        };
+        if let Some(parent) = &parent {
+            if parent.hash() != header.parent_hash {
+                report.broken(
+                    Kind::ParaHeader,
+                    parent.number..=number,
+                    "parent hash mismatch".into(),
+                );
+            }
+        }
+        parent = Some(header);
+    }
+}
+
+/// Checks that the storage changes belong to the stored parachain headers.
+fn verify_storage_changes(db: &CacheDB, metadata: &Metadata, report: &mut Report) {
+    let (lowest, highest) = match (db.lowest_storage_changes(), metadata.higest.storage_changes) {
+        (Some(lowest), Some(highest)) => (lowest, highest),
+        _ => return,
+    };
+    info!("Verifying storage changes {lowest}-{highest}");
+    for number in lowest..=highest {
+        let changes = db
+            .get_storage_changes(number)
+            .map(|data| cache::BlockHeaderWithChanges::decode(&mut &data[..]));
+        let reason = match changes {
+            Some(Ok(changes)) => {
+                // A missing or broken parachain header is reported by verify_para_headers.
+                let para_header = db
+                    .get_para_header(number)
+                    .and_then(|data| Header::decode(&mut &data[..]).ok());
+                match header_mismatch(number, &changes.block_header, para_header.as_ref()) {
+                    Some(reason) => reason.into(),
+                    None => continue,
+                }
+            }
+            Some(Err(err)) => format!("{err}"),
+            None => "missing".into(),
+        };
+        report.broken(Kind::StorageChanges, number..=number, reason);
+    }
+}
+
+/// Checks the header of the storage changes at `number` against the parachain header stored at
+/// the same height, if any.
+fn header_mismatch(
+    number: BlockNumber,
+    header: &Header,
+    para_header: Option<&Header>,
+) -> Option<&'static str> {
+    if header.number != number {
+        return Some("number mismatch");
+    }
+    match para_header {
+        Some(para_header) if para_header.hash() != header.hash() => Some("header hash mismatch"),
+        _ => None,
+    }
+}
+
+/// Merges the adjacent or overlapping ranges of the same kind.
+fn merge_ranges(mut broken: Vec<BrokenRange>) -> Vec<BrokenRange> {
+    broken.sort_by_key(|b| (b.kind as u8, *b.range.start()));
+    let mut merged: Vec<BrokenRange> = vec![];
+    for b in broken {
+        if let Some(last) = merged.last_mut() {
+            if last.kind == b.kind && *b.range.start() <= last.range.end().saturating_add(1) {
+                let end = (*last.range.end()).max(*b.range.end());
+                last.range = *last.range.start()..=end;
+                continue;
+            }
+        }
+        merged.push(b);
+    }
+    merged
+}
+
+pub(crate) struct RepairArgs {
+    pub node_uri: String,
+    pub para_node_uri: String,
+    pub justification_interval: BlockNumber,
+    pub batch_size: BlockNumber,
+}
+
+/// Refetches the broken ranges from the chain.
+pub(crate) async fn repair(
+    db: &CacheDB,
+    broken: Vec<BrokenRange>,
+    args: &RepairArgs,
+) -> Result<()> {
+    let api = pherry::subxt_connect(&args.node_uri).await?;
+    let para_api = pherry::subxt_connect(&args.para_node_uri).await?;
+    for b in merge_ranges(broken) {
+        let start = *b.range.start();
+        let count = b.range.end() - start + 1;
+        info!("Repairing {:?} {start}-{}", b.kind, b.range.end());
+        let repaired = match b.kind {
+            Kind::Header => {
+                let mut skip_justification = 0;
+                let mut repaired = cache::grab_headers(
+                    &api,
+                    &para_api,
+                    start,
+                    count - 1,
+                    args.justification_interval,
+                    &mut skip_justification,
+                    |info| db.put_header(info.header.number, &info.encode()),
+                )
+                .await?;
+                // Always get the justification at the end of the range if any, the next batch
+                // might depend on it. The skip counter may have been reset by an authority set
+                // change in the range, so fetch the last block on its own.
+                let mut skip_justification = 0;
+                repaired += cache::grab_headers(
+                    &api,
+                    &para_api,
+                    *b.range.end(),
+                    1,
+                    args.justification_interval,
+                    &mut skip_justification,
+                    |info| db.put_header(info.header.number, &info.encode()),
+                )
+                .await?;
+                repaired
+            }
+            Kind::ParaHeader => {
+                cache::grab_para_headers(&para_api, start, count, |header| {
+                    db.put_para_header(header.number, &header.encode())
+                })
+                .await?
+            }
+            Kind::StorageChanges => {
+                cache::grab_storage_changes(&para_api, start, count, args.batch_size, |changes| {
+                    db.put_storage_changes(changes.block_header.number, &changes.encode())
+                })
+                .await?
+            }
+        };
+        info!("Repaired {repaired} blocks");
+    }
+    db.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broken(kind: Kind, range: RangeInclusive<BlockNumber>) -> BrokenRange {
+        BrokenRange {
+            kind,
+            range,
+            reason: String::new(),
+        }
+    }
+
+    fn ranges(merged: &[BrokenRange]) -> Vec<(Kind, RangeInclusive<BlockNumber>)> {
+        merged.iter().map(|b| (b.kind, b.range.clone())).collect()
+    }
+
+    fn header(number: BlockNumber, state_root: u8) -> Header {
+        Header {
+            parent_hash: Default::default(),
+            number,
+            state_root: [state_root; 32].into(),
+            extrinsics_root: Default::default(),
+            digest: Default::default(),
+        }
+    }
+
+    #[test]
+    fn storage_changes_must_match_the_para_header() {
+        let changes_header = header(5, 1);
+        assert_eq!(header_mismatch(5, &changes_header, None), None);
+        assert_eq!(
+            header_mismatch(5, &changes_header, Some(&header(5, 1))),
+            None
+        );
+        assert_eq!(
+            header_mismatch(5, &changes_header, Some(&header(5, 2))),
+            Some("header hash mismatch")
+        );
+        assert_eq!(
+            header_mismatch(6, &changes_header, Some(&header(6, 1))),
+            Some("number mismatch")
+        );
+    }
+
+    #[test]
+    fn merge_ranges_joins_adjacent_and_overlapping() {
+        let merged = merge_ranges(vec![
+            broken(Kind::Header, 10..=12),
+            broken(Kind::Header, 1..=3),
+            broken(Kind::Header, 4..=5),
+            broken(Kind::Header, 11..=20),
+            broken(Kind::Header, 12..=15),
+            broken(Kind::Header, 22..=22),
+        ]);
+        assert_eq!(
+            ranges(&merged),
+            vec![
+                (Kind::Header, 1..=5),
+                (Kind::Header, 10..=20),
+                (Kind::Header, 22..=22),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_ranges_keeps_kinds_apart() {
+        let merged = merge_ranges(vec![
+            broken(Kind::StorageChanges, 2..=2),
+            broken(Kind::Header, 1..=1),
+            broken(Kind::ParaHeader, 2..=3),
+            broken(Kind::Header, 2..=2),
+            broken(Kind::StorageChanges, 1..=1),
+        ]);
+        assert_eq!(
+            ranges(&merged),
+            vec![
+                (Kind::Header, 1..=2),
+                (Kind::ParaHeader, 2..=3),
+                (Kind::StorageChanges, 1..=2),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_ranges_handles_the_max_block() {
+        let max = BlockNumber::MAX;
+        let merged = merge_ranges(vec![
+            broken(Kind::Header, max..=max),
+            broken(Kind::Header, max - 1..=max),
+        ]);
+        assert_eq!(ranges(&merged), vec![(Kind::Header, max - 1..=max)]);
+    }
+}
@@ -1,38 +1,156 @@
-use rocket::response::status::NotFound;
-use rocket::State;
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+
+use flate2::{write::GzEncoder, Compression};
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder};
 use rocket::{get, routes};
+use rocket::{Request, Response, State};
+use sha2::{Digest, Sha256};
 
 use scale::{Decode, Encode};
 
 use crate::db::CacheDB;
 use crate::BlockNumber;
 
+/// Max number of blocks can be requested in a single range request.
+const MAX_RANGE_COUNT: BlockNumber = 10000;
+/// Bodies smaller than this are sent without compression.
+const MIN_COMPRESS_SIZE: usize = 512;
+const ZSTD_LEVEL: i32 = 3;
+
 struct App {
-    db: CacheDB,
+    db: Arc<CacheDB>,
+}
+
+#[derive(rocket::Responder, Debug, PartialEq)]
+enum Error {
+    #[response(status = 400)]
+    BadRequest(String),
+    #[response(status = 404)]
+    NotFound(String),
+}
+
+/// A SCALE encoded response body.
+///
+/// The responses are cacheable by a CDN for a while, with an ETag computed from the content for
+/// revalidation. They are not marked immutable since broken blocks can be repaired in place. The
+/// body is compressed with zstd or gzip if the client accepts it.
+struct Blob(Vec<u8>);
+
+impl Blob {
+    fn etag(&self) -> String {
+        // Weak since the same content is served in different encodings.
+        let digest = Sha256::digest(&self.0);
+        format!("W/\"{}\"", hex::encode(&digest[..16]))
+    }
+}
+
+/// Encodings listed in the `Accept-Encoding` header values, except the ones with `q=0`.
+fn accepted_encodings<'a>(values: impl Iterator<Item = &'a str>) -> impl Iterator<Item = &'a str> {
+    values
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let encoding = parts.next()?.trim();
+            let disabled = parts.any(|param| {
+                let quality = param.trim().strip_prefix("q=");
+                quality.and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+            (!disabled).then(|| encoding)
+        })
+}
+
+fn compress(encoding: &str, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        "zstd" => zstd::encode_all(data, ZSTD_LEVEL),
+        _ => {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Blob {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let etag = self.etag();
+        let mut builder = Response::build();
+        builder
+            .header(ContentType::Binary)
+            .raw_header("ETag", etag.clone())
+            .raw_header("Cache-Control", "public, max-age=3600")
+            .raw_header("Vary", "Accept-Encoding");
+        let not_modified = req
+            .headers()
+            .get("If-None-Match")
+            .flat_map(|value| value.split(','))
+            .any(|tag| tag.trim() == etag || tag.trim() == "*");
+        if not_modified {
+            return builder.status(Status::NotModified).ok();
+        }
+        let mut body = self.0;
+        if body.len() >= MIN_COMPRESS_SIZE {
+            let encoding = {
+                let accepted: Vec<_> =
+                    accepted_encodings(req.headers().get("Accept-Encoding")).collect();
+                ["zstd", "gzip"]
+                    .into_iter()
+                    .find(|encoding| accepted.contains(encoding))
+            };
+            if let Some(encoding) = encoding {
+                match compress(encoding, &body) {
+                    Ok(compressed) => {
+                        body = compressed;
+                        builder.raw_header("Content-Encoding", encoding);
+                    }
+                    Err(err) => log::error!("Failed to compress the response: {err}"),
+                }
+            }
+        }
+        builder.sized_body(body.len(), Cursor::new(body)).ok()
+    }
+}
+
+fn block_range(
+    start: BlockNumber,
+    count: BlockNumber,
+) -> Result<std::ops::Range<BlockNumber>, Error> {
+    if count > MAX_RANGE_COUNT {
+        return Err(Error::BadRequest(format!(
+            "too many blocks requested, max {MAX_RANGE_COUNT}"
+        )));
+    }
+    let end = start
+        .checked_add(count)
+        .ok_or_else(|| Error::BadRequest("block number overflow".into()))?;
+    Ok(start..end)
 }
 
 #[get("/genesis/<block_number>")]
-fn get_genesis(app: &State<App>, block_number: BlockNumber) -> Result<Vec<u8>, NotFound<String>> {
+fn get_genesis(app: &State<App>, block_number: BlockNumber) -> Result<Blob, Error> {
     app.db
         .get_genesis(block_number)
-        .ok_or_else(|| NotFound("genesis not found".into()))
+        .map(Blob)
+        .ok_or_else(|| Error::NotFound("genesis not found".into()))
 }
 
 #[get("/header/<block_number>")]
-fn get_header(app: &State<App>, block_number: BlockNumber) -> Result<Vec<u8>, NotFound<String>> {
+fn get_header(app: &State<App>, block_number: BlockNumber) -> Result<Blob, Error> {
     app.db
         .get_header(block_number)
-        .ok_or_else(|| NotFound("header not found".into()))
+        .map(Blob)
+        .ok_or_else(|| Error::NotFound("header not found".into()))
 }
 
 #[get("/headers/<start>")]
-fn get_headers(app: &State<App>, start: BlockNumber) -> Result<Vec<u8>, NotFound<String>> {
+fn get_headers(app: &State<App>, start: BlockNumber) -> Result<Blob, Error> {
     let mut headers = vec![];
-    for block in start..start + 10000 {
+    for block in block_range(start, MAX_RANGE_COUNT)? {
         match app.db.get_header(block) {
             Some(data) => {
                 let info = crate::cache::BlockInfo::decode(&mut &data[..])
-                    .map_err(|_| NotFound("Codec error".into()))?;
+                    .map_err(|_| Error::NotFound("Codec error".into()))?;
                 let end = info.justification.is_some();
                 headers.push(info);
                 if end {
@@ -41,12 +159,12 @@ fn get_headers(app: &State<App>, start: BlockNumber) -> Result<Vec<u8>, NotFound
             }
             None => {
                 log::warn!("{} not found", block);
-                return Err(NotFound("header not found".into()));
+                return Err(Error::NotFound("header not found".into()));
             }
         }
     }
     log::info!("Got {} headers", headers.len());
-    Ok(headers.encode())
+    Ok(Blob(headers.encode()))
 }
 
 #[get("/parachain-headers/<start>/<count>")]
@@ -54,24 +172,24 @@ fn get_parachain_headers(
     app: &State<App>,
     start: BlockNumber,
     count: BlockNumber,
-) -> Result<Vec<u8>, NotFound<String>> {
+) -> Result<Blob, Error> {
     let mut headers = vec![];
-    for block in start..start + count {
+    for block in block_range(start, count)? {
         match app.db.get_para_header(block) {
             Some(data) => {
                 use pherry::types::Header;
-                let header =
-                    Header::decode(&mut &data[..]).map_err(|_| NotFound("Codec error".into()))?;
+                let header = Header::decode(&mut &data[..])
+                    .map_err(|_| Error::NotFound("Codec error".into()))?;
                 headers.push(header);
             }
             None => {
                 log::warn!("header at {} not found", block);
-                return Err(NotFound("header not found".into()));
+                return Err(Error::NotFound("header not found".into()));
             }
         }
     }
     log::info!("Got {} parachain headers", headers.len());
-    Ok(headers.encode())
+    Ok(Blob(headers.encode()))
 }
 
 #[get("/storage-changes/<start>/<count>")]
@@ -79,30 +197,28 @@ fn get_storage_changes(
     app: &State<App>,
     start: BlockNumber,
     count: BlockNumber,
-) -> Result<Vec<u8>, NotFound<String>> {
+) -> Result<Blob, Error> {
     let mut changes = vec![];
-    for block in start..start + count {
+    for block in block_range(start, count)? {
         match app.db.get_storage_changes(block) {
             Some(data) => {
                 let header = crate::cache::BlockHeaderWithChanges::decode(&mut &data[..])
-                    .map_err(|_| NotFound("Codec error".into()))?;
+                    .map_err(|_| Error::NotFound("Codec error".into()))?;
                 changes.push(header);
             }
             None => {
                 log::warn!("changes at {} not found", block);
-                return Err(NotFound("header not found".into()));
+                return Err(Error::NotFound("header not found".into()));
             }
         }
     }
     log::info!("Got {} storage changes", changes.len());
-    Ok(changes.encode())
+    Ok(Blob(changes.encode()))
 }
 
-pub(crate) async fn serve(db: &str) -> anyhow::Result<()> {
+pub(crate) async fn serve(db: Arc<CacheDB>) -> anyhow::Result<()> {
     let _rocket = rocket::build()
-        .manage(App {
-            db: CacheDB::open(db)?,
-        })
+        .manage(App { db })
         .mount(
             "/",
             routes![
@@ -118,3 +234,34 @@ pub(crate) async fn serve(db: &str) -> anyhow::Result<()> {
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted(values: &[&'static str]) -> Vec<&'static str> {
+        accepted_encodings(values.iter().copied()).collect()
+    }
+
+    #[test]
+    fn accepted_encodings_skips_disabled() {
+        assert_eq!(accepted(&["gzip, zstd"]), vec!["gzip", "zstd"]);
+        assert_eq!(accepted(&["gzip;q=0.5", "zstd;q=0"]), vec!["gzip"]);
+        assert_eq!(accepted(&["zstd; q=0.0, br"]), vec!["br"]);
+        assert!(accepted(&[]).is_empty());
+    }
+
+    #[test]
+    fn block_range_rejects_bad_ranges() {
+        assert_eq!(block_range(5, 3), Ok(5..8));
+        assert_eq!(block_range(5, MAX_RANGE_COUNT), Ok(5..5 + MAX_RANGE_COUNT));
+        assert!(matches!(
+            block_range(5, MAX_RANGE_COUNT + 1),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(matches!(
+            block_range(BlockNumber::MAX, 1),
+            Err(Error::BadRequest(_))
+        ));
+    }
+}
@@ -281,13 +281,20 @@ pub fn new_partial(
 					shared_voter_state: shared_voter_state.clone(),
 					shared_authority_set: shared_authority_set.clone(),
 					justification_stream: justification_stream.clone(),
-					subscription_executor,
+					subscription_executor: subscription_executor.clone(),
 					finality_provider: finality_proof_provider.clone(),
 				},
 			};
 
 			let mut io = node_rpc::create_full(deps, rpc_backend.clone())?;
-			phala_node_rpc_ext::extend_rpc(&mut io, client.clone(), rpc_backend.clone(), is_archive_mode, pool.clone());
+			phala_node_rpc_ext::extend_rpc(
+				&mut io,
+				client.clone(),
+				rpc_backend.clone(),
+				is_archive_mode,
+				pool.clone(),
+				subscription_executor,
+			);
 			Ok(io)
 		};
 
@@ -10,6 +10,7 @@ pub enum Error {
     FailedToCallRegisterWorker,
     ParachainIdNotFound,
     ParachainValidationDataNotFound,
+    NodeUnhealthy,
 }
 
 impl fmt::Display for Error {
@@ -25,6 +26,7 @@ impl fmt::Display for Error {
             Error::ParachainValidationDataNotFound => {
                 write!(f, "parachain validation data not found")
             }
+            Error::NodeUnhealthy => write!(f, "substrate node unhealthy"),
         }
     }
 }
@@ -0,0 +1,348 @@
+//! Failover across multiple substrate nodes.
+//!
+//! Pherry can be given a list of nodes for the relaychain and the parachain. Before a node is
+//! used, it is checked to be on the expected chain (same genesis hash as the node first connected)
+//! and to agree with the most advanced node on the finalized blocks. While syncing, the node in use is
+//! periodically compared to the others, and the bridge is restarted on another node when it
+//! disconnects or falls behind.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use log::{info, warn};
+use phaxt::subxt::{self, rpc::NumberOrHex};
+use phaxt::{BlockNumber, ChainApi, Hash};
+
+use crate::error::Error;
+
+/// Timeout of a single probe of a node.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connected node.
+pub struct Node {
+    pub endpoint: String,
+    pub api: ChainApi,
+}
+
+struct NodeStatus {
+    endpoint: String,
+    api: ChainApi,
+    head: ChainHead,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ChainHead {
+    genesis_hash: Hash,
+    finalized_number: BlockNumber,
+    finalized_hash: Hash,
+}
+
+async fn finalized_head(api: &ChainApi) -> Result<(BlockNumber, Hash)> {
+    let hash = api.rpc().finalized_head().await?;
+    let header = api
+        .rpc()
+        .header(Some(hash))
+        .await?
+        .ok_or(Error::BlockNotFound)?;
+    Ok((header.number, hash))
+}
+
+async fn probe(endpoint: &str) -> Result<NodeStatus> {
+    let fut = async {
+        let api = crate::subxt_connect(endpoint).await?;
+        let (finalized_number, finalized_hash) = finalized_head(&api).await?;
+        Ok(NodeStatus {
+            endpoint: endpoint.to_string(),
+            head: ChainHead {
+                genesis_hash: api.genesis_hash(),
+                finalized_number,
+                finalized_hash,
+            },
+            api,
+        })
+    };
+    tokio::time::timeout(PROBE_TIMEOUT, fut)
+        .await
+        .map_err(|_| anyhow!("Timed out"))?
+}
+
+/// Returns whether the node at `endpoint` is reachable and responds to rpc requests.
+pub async fn is_reachable(endpoint: &str) -> bool {
+    probe(endpoint).await.is_ok()
+}
+
+/// Connects to the first node in `endpoints` that is on the chain of `genesis_hash`, agrees with
+/// the most advanced node on the finalized blocks, and doesn't fall behind it by more than
+/// `max_lag` blocks.
+///
+/// The chain of the first reachable node is taken if `genesis_hash` is `None`. The caller is
+/// expected to pin the genesis hash of the returned node for the later selections.
+pub async fn select_node(
+    endpoints: &[String],
+    max_lag: BlockNumber,
+    genesis_hash: Option<Hash>,
+) -> Result<Node> {
+    if let [endpoint] = endpoints {
+        // Nothing to choose from, connect directly to report the connection errors as is.
+        let api = crate::subxt_connect(endpoint).await?;
+        if let Some(expected) = genesis_hash {
+            if api.genesis_hash() != expected {
+                return Err(anyhow!(
+                    "Node {endpoint} has a different genesis hash {:?}, expected {:?}",
+                    api.genesis_hash(),
+                    expected
+                ));
+            }
+        }
+        return Ok(Node {
+            endpoint: endpoint.clone(),
+            api,
+        });
+    }
+    let mut nodes = vec![];
+    for (endpoint, status) in endpoints
+        .iter()
+        .zip(join_all(endpoints.iter().map(|endpoint| probe(endpoint))).await)
+    {
+        match status {
+            Ok(status) => nodes.push(status),
+            Err(err) => warn!("Node {endpoint} is unavailable: {err:?}"),
+        }
+    }
+    let heads: Vec<_> = nodes
+        .iter()
+        .map(|node| (node.endpoint.as_str(), node.head))
+        .collect();
+    let (best, candidates) = match candidates(&heads, max_lag, genesis_hash) {
+        Some(v) => v,
+        None => return Err(anyhow!("No available node in {endpoints:?}")),
+    };
+    let best = &nodes[best];
+    for node in candidates.into_iter().map(|i| &nodes[i]) {
+        let head = &node.head;
+        let number =
+            subxt::rpc::BlockNumber::from(NumberOrHex::Number(head.finalized_number.into()));
+        match best.api.rpc().block_hash(Some(number)).await {
+            Ok(Some(hash)) if hash == head.finalized_hash => {}
+            other => {
+                warn!(
+                    "Node {} disagrees with {} on the finalized block {}: {:?} vs {:?}",
+                    node.endpoint, best.endpoint, head.finalized_number, head.finalized_hash, other
+                );
+                continue;
+            }
+        }
+        return Ok(Node {
+            endpoint: node.endpoint.clone(),
+            api: node.api.clone(),
+        });
+    }
+    Err(anyhow!("No healthy node in {endpoints:?}"))
+}
+
+/// Returns the index of the most advanced node in `nodes`, and the indices of the candidate nodes
+/// in the given order: the ones on the chain of `genesis_hash`, or of the first node if not given,
+/// that don't fall behind the most advanced one by more than `max_lag` blocks. Returns `None` if
+/// no node is on the chain.
+fn candidates(
+    nodes: &[(&str, ChainHead)],
+    max_lag: BlockNumber,
+    genesis_hash: Option<Hash>,
+) -> Option<(usize, Vec<usize>)> {
+    let genesis_hash = match genesis_hash {
+        Some(hash) => hash,
+        None => nodes.first()?.1.genesis_hash,
+    };
+    let same_chain: Vec<usize> = (0..nodes.len())
+        .filter(|&i| {
+            let (endpoint, head) = &nodes[i];
+            let same_chain = head.genesis_hash == genesis_hash;
+            if !same_chain {
+                warn!(
+                    "Node {} has a different genesis hash {:?}, expected {:?}",
+                    endpoint, head.genesis_hash, genesis_hash
+                );
+            }
+            same_chain
+        })
+        .collect();
+    let best = same_chain
+        .iter()
+        .copied()
+        .max_by_key(|&i| nodes[i].1.finalized_number)?;
+    let best_number = nodes[best].1.finalized_number;
+    let candidates = same_chain
+        .into_iter()
+        .filter(|&i| {
+            let (endpoint, head) = &nodes[i];
+            let lagging = head.finalized_number.saturating_add(max_lag) < best_number;
+            if lagging {
+                warn!(
+                    "Node {} lags behind: finalized at {}, best {}",
+                    endpoint, head.finalized_number, best_number
+                );
+            }
+            !lagging
+        })
+        .collect();
+    Some((best, candidates))
+}
+
+/// Periodically checks the node in use against the other nodes.
+pub struct HealthChecker {
+    endpoint: String,
+    others: Vec<(String, Option<ChainApi>)>,
+    max_lag: BlockNumber,
+    interval: Duration,
+    last_check: Instant,
+}
+
+impl HealthChecker {
+    pub fn new(
+        endpoints: &[String],
+        endpoint: &str,
+        max_lag: BlockNumber,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+            others: endpoints
+                .iter()
+                .filter(|other| *other != endpoint)
+                .map(|other| (other.clone(), None))
+                .collect(),
+            max_lag,
+            interval,
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Returns `Error::NodeUnhealthy` if the node in use doesn't respond or falls behind any of
+    /// the other nodes by more than `max_lag` finalized blocks.
+    pub async fn check(&mut self, api: &ChainApi) -> Result<()> {
+        if self.others.is_empty() || self.last_check.elapsed() < self.interval {
+            return Ok(());
+        }
+        self.last_check = Instant::now();
+
+        let finalized_number = match tokio::time::timeout(PROBE_TIMEOUT, finalized_head(api)).await
+        {
+            Ok(Ok((number, _))) => number,
+            Ok(Err(err)) => {
+                warn!("Node {} failed to respond: {err:?}", self.endpoint);
+                return Err(Error::NodeUnhealthy.into());
+            }
+            Err(_) => {
+                warn!("Node {} timed out", self.endpoint);
+                return Err(Error::NodeUnhealthy.into());
+            }
+        };
+        let genesis_hash = api.genesis_hash();
+        // Probe the other nodes concurrently, so a check takes at most two probe timeouts no
+        // matter how many nodes are down.
+        let heads =
+            join_all(self.others.iter_mut().map(|(endpoint, other_api)| {
+                finalized_number_of(endpoint, other_api, genesis_hash)
+            }))
+            .await;
+        for ((endpoint, _), other_number) in self.others.iter().zip(heads) {
+            let other_number = match other_number {
+                Some(number) => number,
+                None => continue,
+            };
+            if finalized_number.saturating_add(self.max_lag) < other_number {
+                warn!(
+                    "Node {} lags behind {endpoint}: finalized at {finalized_number}, {other_number} on the other",
+                    self.endpoint
+                );
+                return Err(Error::NodeUnhealthy.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the finalized block number of the node at `endpoint` if it is on the chain of
+/// `genesis_hash`, connecting to it first if `api` is `None`.
+async fn finalized_number_of(
+    endpoint: &str,
+    api: &mut Option<ChainApi>,
+    genesis_hash: Hash,
+) -> Option<BlockNumber> {
+    if api.is_none() {
+        match probe(endpoint).await {
+            Ok(status) => *api = Some(status.api),
+            Err(err) => {
+                info!("Node {endpoint} is unavailable: {err:?}");
+                return None;
+            }
+        }
+    }
+    let other = api.as_ref().expect("Connected above");
+    if other.genesis_hash() != genesis_hash {
+        return None;
+    }
+    match tokio::time::timeout(PROBE_TIMEOUT, finalized_head(other)).await {
+        Ok(Ok((number, _))) => Some(number),
+        _ => {
+            // Reconnect in the next check.
+            *api = None;
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(genesis: u8, number: BlockNumber) -> ChainHead {
+        ChainHead {
+            genesis_hash: Hash::repeat_byte(genesis),
+            finalized_number: number,
+            finalized_hash: Hash::repeat_byte(number as u8),
+        }
+    }
+
+    #[test]
+    fn no_candidates_without_nodes() {
+        assert!(candidates(&[], 10, None).is_none());
+        assert!(candidates(&[], 10, Some(Hash::repeat_byte(1))).is_none());
+    }
+
+    #[test]
+    fn candidates_follow_the_chain_of_the_first_node() {
+        let nodes = [
+            ("a", head(1, 100)),
+            ("b", head(2, 200)),
+            ("c", head(1, 105)),
+        ];
+        assert_eq!(candidates(&nodes, 10, None), Some((2, vec![0, 2])));
+    }
+
+    #[test]
+    fn candidates_follow_the_pinned_chain() {
+        let nodes = [
+            ("a", head(1, 100)),
+            ("b", head(2, 200)),
+            ("c", head(2, 195)),
+        ];
+        let pinned = Some(Hash::repeat_byte(2));
+        assert_eq!(candidates(&nodes, 10, pinned), Some((1, vec![1, 2])));
+        assert_eq!(candidates(&nodes, 10, Some(Hash::repeat_byte(3))), None);
+    }
+
+    #[test]
+    fn lagging_nodes_are_not_candidates() {
+        let nodes = [
+            ("a", head(1, 100)),
+            ("b", head(1, 120)),
+            ("c", head(1, 110)),
+            ("d", head(1, 109)),
+        ];
+        assert_eq!(candidates(&nodes, 10, None), Some((1, vec![1, 2])));
+        assert_eq!(candidates(&nodes, 20, None), Some((1, vec![0, 1, 2, 3])));
+        assert_eq!(candidates(&nodes, 0, None), Some((1, vec![1])));
+    }
+}
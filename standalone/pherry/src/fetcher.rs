@@ -0,0 +1,366 @@
+//! Block data fetching shared by the workers driven by the same pherry process.
+//!
+//! In multi-worker mode, the workers usually sync the same range of blocks. Requests for the same
+//! block or the same range of storage changes are merged into a single RPC request, and the
+//! results are kept for a while for the workers falling behind. The storage changes are synced in
+//! batches aligned by `aligned_batch_end` so that the workers starting from different blocks
+//! request the same ranges.
+//!
+//! The fetcher also works as a prefetch pipeline: the relaychain blocks with justifications from the
+//! node or from the headers cache, and the storage changes are requested `prefetch_depth` batches
+//! ahead, with at most `concurrency` requests in flight.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use phactory_api::blocks::BlockHeaderWithChanges;
+use phaxt::{BlockNumber, RpcClient};
+use tokio::sync::Semaphore;
+
+use crate::headers_cache::BlockInfo;
+use crate::types::{Block, RelaychainApi};
+use crate::CacheClient;
+
+/// Max number of relaychain blocks kept in the shared cache.
+const MAX_CACHED_BLOCKS: usize = 4096;
+/// Max number of storage changes batches kept in the shared cache.
+const MAX_CACHED_STORAGE_CHANGES: usize = 64;
+/// Max number of header batches from the headers cache kept in the shared cache.
+const MAX_CACHED_HEADERS: usize = 64;
+
+type SharedResult<T> = Shared<BoxFuture<'static, Result<T, Arc<anyhow::Error>>>>;
+
+#[derive(Default)]
+struct Inner {
+    blocks: BTreeMap<BlockNumber, SharedResult<Block>>,
+    storage_changes:
+        BTreeMap<(BlockNumber, BlockNumber), SharedResult<Vec<BlockHeaderWithChanges>>>,
+    cached_headers: BTreeMap<BlockNumber, SharedResult<Vec<BlockInfo>>>,
+}
+
+#[derive(Clone)]
+pub struct SharedFetcher {
+    inner: Arc<Mutex<Inner>>,
+    permits: Arc<Semaphore>,
+    prefetch_depth: u32,
+}
+
+impl SharedFetcher {
+    pub fn new(prefetch_depth: u32, concurrency: usize) -> Self {
+        Self {
+            inner: Default::default(),
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            prefetch_depth,
+        }
+    }
+
+    /// Number of batches to prefetch ahead of the requested one.
+    pub fn prefetch_depth(&self) -> u32 {
+        self.prefetch_depth
+    }
+
+    /// Gets the relaychain block at given height, without storage changes.
+    pub async fn get_block(&self, api: &RelaychainApi, number: BlockNumber) -> Result<Block> {
+        self.block_request(api, number)
+            .await
+            .map_err(|err| anyhow!("{err:?}"))
+    }
+
+    /// Starts fetching the relaychain blocks in `from..=to` in background.
+    pub fn prefetch_blocks(&self, api: &RelaychainApi, from: BlockNumber, to: BlockNumber) {
+        for number in from..=to {
+            let _ = self.block_request(api, number);
+        }
+    }
+
+    fn block_request(&self, api: &RelaychainApi, number: BlockNumber) -> SharedResult<Block> {
+        let mut inner = self.inner.lock().unwrap();
+        get_or_spawn(
+            &mut inner.blocks,
+            number,
+            MAX_CACHED_BLOCKS,
+            &self.permits,
+            || {
+                let api = api.clone();
+                async move { crate::get_block_without_storage_changes(&api, Some(number)).await }
+            },
+        )
+    }
+
+    /// The relaychain blocks to prefetch after the batch ending at `batch_end`: up to
+    /// `prefetch_depth` batches of `batch_size` blocks, not beyond `limit`.
+    pub fn blocks_to_prefetch(
+        &self,
+        batch_end: BlockNumber,
+        batch_size: BlockNumber,
+        limit: BlockNumber,
+    ) -> Option<(BlockNumber, BlockNumber)> {
+        prefetch_range(batch_end, batch_size, self.prefetch_depth, limit)
+    }
+
+    /// Gets the relaychain headers starting from `from` from the headers cache, and prefetches the
+    /// following batches.
+    pub async fn get_cached_headers(
+        &self,
+        cache: &CacheClient,
+        from: BlockNumber,
+    ) -> Result<Vec<BlockInfo>> {
+        let headers = self
+            .cached_headers_request(cache, from)
+            .await
+            .map_err(|err| anyhow!("{err:?}"))?;
+        if let Some(last) = headers.last() {
+            self.prefetch_cached_headers(cache, last.header.number + 1, self.prefetch_depth);
+        }
+        Ok(headers)
+    }
+
+    fn prefetch_cached_headers(&self, cache: &CacheClient, from: BlockNumber, depth: u32) {
+        if depth == 0 {
+            return;
+        }
+        // The start of the next batch is only known after the current one is fetched.
+        let this = self.clone();
+        let cache = cache.clone();
+        tokio::spawn(async move {
+            if let Ok(headers) = this.cached_headers_request(&cache, from).await {
+                if let Some(last) = headers.last() {
+                    this.prefetch_cached_headers(&cache, last.header.number + 1, depth - 1);
+                }
+            }
+        });
+    }
+
+    fn cached_headers_request(
+        &self,
+        cache: &CacheClient,
+        from: BlockNumber,
+    ) -> SharedResult<Vec<BlockInfo>> {
+        let mut inner = self.inner.lock().unwrap();
+        get_or_spawn(
+            &mut inner.cached_headers,
+            from,
+            MAX_CACHED_HEADERS,
+            &self.permits,
+            || {
+                let cache = cache.clone();
+                async move {
+                    let headers = cache.get_headers(from).await?;
+                    // Don't keep the empty result, the headers might be cached later.
+                    if headers.is_empty() {
+                        return Err(anyhow!("Headers at {from} not cached"));
+                    }
+                    Ok(headers)
+                }
+            },
+        )
+    }
+
+    /// Fetches the storage changes of the blocks in `from..=to`.
+    pub async fn fetch_storage_changes(
+        &self,
+        client: &RpcClient,
+        cache: Option<&CacheClient>,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> Result<Vec<BlockHeaderWithChanges>> {
+        self.storage_changes_request(client, cache, from, to)
+            .await
+            .map_err(|err| anyhow!("{err:?}"))
+    }
+
+    /// Starts fetching the storage changes of the blocks in `from..=to` in background.
+    pub fn prefetch_storage_changes(
+        &self,
+        client: &RpcClient,
+        cache: Option<&CacheClient>,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) {
+        let _ = self.storage_changes_request(client, cache, from, to);
+    }
+
+    fn storage_changes_request(
+        &self,
+        client: &RpcClient,
+        cache: Option<&CacheClient>,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> SharedResult<Vec<BlockHeaderWithChanges>> {
+        let mut inner = self.inner.lock().unwrap();
+        get_or_spawn(
+            &mut inner.storage_changes,
+            (from, to),
+            MAX_CACHED_STORAGE_CHANGES,
+            &self.permits,
+            || {
+                let client = client.clone();
+                let cache = cache.cloned();
+                async move { crate::fetch_storage_changes(&client, cache.as_ref(), from, to).await }
+            },
+        )
+    }
+}
+
+/// The last block of the batch containing `from`, given that the batches start at the multiples of
+/// `batch_size`. Not greater than `limit`.
+pub fn aligned_batch_end(
+    from: BlockNumber,
+    batch_size: BlockNumber,
+    limit: BlockNumber,
+) -> BlockNumber {
+    let batch_size = batch_size.max(1);
+    let batch_start = from - from % batch_size;
+    batch_start.saturating_add(batch_size - 1).min(limit)
+}
+
+/// The blocks following `batch_end` to prefetch, `depth` batches of `batch_size` blocks at most.
+/// The range is kept within half of the block cache, so the prefetched blocks are not evicted by
+/// the ones requested after them.
+fn prefetch_range(
+    batch_end: BlockNumber,
+    batch_size: BlockNumber,
+    depth: u32,
+    limit: BlockNumber,
+) -> Option<(BlockNumber, BlockNumber)> {
+    let window = batch_size
+        .saturating_mul(depth)
+        .min(MAX_CACHED_BLOCKS as BlockNumber / 2);
+    if window == 0 || batch_end >= limit {
+        return None;
+    }
+    let from = batch_end + 1;
+    Some((from, from.saturating_add(window - 1).min(limit)))
+}
+
+/// Returns the in-flight or finished request for `key`, or spawns a new one if there isn't any
+/// or the previous one failed.
+fn get_or_spawn<K, T, F, Fut>(
+    map: &mut BTreeMap<K, SharedResult<T>>,
+    key: K,
+    capacity: usize,
+    permits: &Arc<Semaphore>,
+    make_fut: F,
+) -> SharedResult<T>
+where
+    K: Ord + Copy,
+    T: Clone + Send + Sync + 'static,
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = Result<T>> + Send + 'static,
+{
+    if let Some(fut) = map.get(&key) {
+        if !matches!(fut.peek(), Some(Err(_))) {
+            return fut.clone();
+        }
+    }
+    let permits = permits.clone();
+    let request = make_fut();
+    let handle = tokio::spawn(async move {
+        let _permit = permits.acquire_owned().await?;
+        request.await
+    });
+    let fut = async move {
+        match handle.await {
+            Ok(result) => result.map_err(Arc::new),
+            Err(err) => Err(Arc::new(anyhow!("Fetching task failed: {err:?}"))),
+        }
+    }
+    .boxed()
+    .shared();
+    map.insert(key, fut.clone());
+    while map.len() > capacity {
+        // Evict the lowest blocks, which are the most likely to be synced by all the workers.
+        let lowest = *map.keys().next().expect("The map is not empty");
+        map.remove(&lowest);
+    }
+    fut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn batches_are_aligned() {
+        assert_eq!(aligned_batch_end(0, 100, 1000), 99);
+        assert_eq!(aligned_batch_end(105, 100, 1000), 199);
+        assert_eq!(aligned_batch_end(199, 100, 1000), 199);
+        assert_eq!(aligned_batch_end(200, 100, 1000), 299);
+        assert_eq!(aligned_batch_end(105, 100, 150), 150);
+        assert_eq!(aligned_batch_end(7, 1, 1000), 7);
+        assert_eq!(aligned_batch_end(7, 0, 1000), 7);
+        assert_eq!(
+            aligned_batch_end(BlockNumber::MAX, 100, BlockNumber::MAX),
+            BlockNumber::MAX
+        );
+    }
+
+    #[test]
+    fn block_prefetches_are_limited() {
+        assert_eq!(prefetch_range(99, 100, 2, 1000), Some((100, 299)));
+        assert_eq!(prefetch_range(99, 100, 2, 150), Some((100, 150)));
+        assert_eq!(prefetch_range(150, 100, 2, 150), None);
+        assert_eq!(prefetch_range(99, 100, 0, 1000), None);
+        assert_eq!(prefetch_range(99, 0, 2, 1000), None);
+        assert_eq!(
+            prefetch_range(0, 1000, 100, BlockNumber::MAX),
+            Some((1, MAX_CACHED_BLOCKS as BlockNumber / 2))
+        );
+    }
+
+    fn spawn_counted(
+        map: &mut BTreeMap<BlockNumber, SharedResult<u32>>,
+        key: BlockNumber,
+        capacity: usize,
+        spawned: &Arc<AtomicUsize>,
+        result: Result<u32, &'static str>,
+    ) -> SharedResult<u32> {
+        let permits = Arc::new(Semaphore::new(1));
+        get_or_spawn(map, key, capacity, &permits, || {
+            spawned.fetch_add(1, Ordering::SeqCst);
+            async move { result.map_err(|err| anyhow!(err)) }
+        })
+    }
+
+    #[tokio::test]
+    async fn get_or_spawn_merges_requests() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let mut map = BTreeMap::new();
+        let first = spawn_counted(&mut map, 1, 8, &spawned, Ok(10));
+        let second = spawn_counted(&mut map, 1, 8, &spawned, Ok(20));
+        assert_eq!(first.await.unwrap(), 10);
+        assert_eq!(second.await.unwrap(), 10);
+        // Finished requests are reused too.
+        let third = spawn_counted(&mut map, 1, 8, &spawned, Ok(30));
+        assert_eq!(third.await.unwrap(), 10);
+        assert_eq!(spawned.load(Ordering::SeqCst), 1);
+
+        let other = spawn_counted(&mut map, 2, 8, &spawned, Ok(40));
+        assert_eq!(other.await.unwrap(), 40);
+        assert_eq!(spawned.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn get_or_spawn_retries_failures() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let mut map = BTreeMap::new();
+        let failed = spawn_counted(&mut map, 1, 8, &spawned, Err("failed"));
+        assert!(failed.await.is_err());
+        let retried = spawn_counted(&mut map, 1, 8, &spawned, Ok(10));
+        assert_eq!(retried.await.unwrap(), 10);
+        assert_eq!(spawned.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn get_or_spawn_evicts_the_lowest() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let mut map = BTreeMap::new();
+        for key in [3, 1, 2] {
+            spawn_counted(&mut map, key, 2, &spawned, Ok(key));
+        }
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), vec![2, 3]);
+    }
+}
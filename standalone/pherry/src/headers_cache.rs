@@ -11,7 +11,7 @@ use log::{error, info, warn};
 
 pub use phactory_api::blocks::{AuthoritySetChange, BlockHeaderWithChanges, GenesisBlockInfo};
 
-#[derive(Decode, Encode, Debug)]
+#[derive(Decode, Encode, Debug, Clone)]
 pub struct BlockInfo {
     pub header: Header,
     pub justification: Option<Vec<u8>>,
@@ -19,7 +19,7 @@ pub struct BlockInfo {
     pub authority_set_change: Option<AuthoritySetChange>,
 }
 
-#[derive(Decode, Encode, Debug)]
+#[derive(Decode, Encode, Debug, Clone)]
 pub struct ParaHeader {
     /// Finalized parachain header number
     pub fin_header_num: BlockNumber,
@@ -101,12 +101,14 @@ pub async fn grap_headers_to_file(
     justification_interval: BlockNumber,
     mut output: impl Write,
 ) -> Result<BlockNumber> {
+    let mut skip_justification = justification_interval;
     grab_headers(
         api,
         para_api,
         start_at,
         count,
         justification_interval,
+        &mut skip_justification,
         |info| {
             if info.justification.is_some() {
                 info!("Got justification at {}", info.header.number);
@@ -162,12 +164,17 @@ pub async fn get_set_id(api: &RelaychainApi, block: BlockNumber) -> Result<(u64,
     Ok((set_id, block.justifications.is_some()))
 }
 
-async fn grab_headers(
+/// Grab headers from the chain and feed them to `f`.
+///
+/// `skip_justification` is the number of blocks to skip before looking for the next justification.
+/// It is updated as the headers are grabbed, so that the caller can resume grabbing later.
+pub async fn grab_headers(
     api: &RelaychainApi,
     para_api: &ParachainApi,
     start_at: BlockNumber,
     count: BlockNumber,
     justification_interval: u32,
+    skip_justification: &mut BlockNumber,
     mut f: impl FnMut(BlockInfo) -> Result<()>,
 ) -> Result<BlockNumber> {
     if start_at == 0 {
@@ -179,7 +186,6 @@ async fn grab_headers(
 
     let header_hash = crate::get_header_hash(api, Some(start_at - 1)).await?;
     let mut last_set = api.current_set_id(Some(header_hash)).await?;
-    let mut skip_justitication = justification_interval;
     let mut grabbed = 0;
 
     let para_id = para_api.get_paraid(None).await?;
@@ -189,7 +195,7 @@ async fn grab_headers(
         let header;
         let justifications;
         let hash;
-        if skip_justitication == 0 {
+        if *skip_justification == 0 {
             let (block, header_hash) = match crate::get_block_at(api, Some(block_number)).await {
                 Ok(x) => x,
                 Err(e) => {
@@ -240,13 +246,13 @@ async fn grab_headers(
 
         let justification = justifications.and_then(|v| v.into_justification(GRANDPA_ENGINE_ID));
 
-        skip_justitication = skip_justitication.saturating_sub(1);
+        *skip_justification = skip_justification.saturating_sub(1);
         last_set = set_id;
 
         let para_header = if justification.is_none() {
             None
         } else {
-            skip_justitication = justification_interval;
+            *skip_justification = justification_interval;
             crate::get_finalized_header_with_paraid(api, para_id, hash).await?
         };
 
@@ -269,7 +275,8 @@ async fn grab_headers(
     Ok(grabbed)
 }
 
-async fn grab_para_headers(
+/// Grab parachain headers from the chain and feed them to `f`.
+pub async fn grab_para_headers(
     api: &ParachainApi,
     start_at: BlockNumber,
     count: BlockNumber,
@@ -297,7 +304,8 @@ async fn grab_para_headers(
     Ok(grabbed)
 }
 
-async fn grab_storage_changes(
+/// Grab storage changes from the chain and feed them to `f`.
+pub async fn grab_storage_changes(
     api: &ParachainApi,
     start_at: BlockNumber,
     count: BlockNumber,
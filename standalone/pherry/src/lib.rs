@@ -3,11 +3,14 @@ use log::{debug, error, info, warn};
 use sp_core::crypto::AccountId32;
 use sp_runtime::generic::Era;
 use std::cmp;
+use std::collections::BTreeMap;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
 
 use codec::Decode;
+use futures::StreamExt as _;
 use phaxt::rpc::ExtraRpcExt as _;
 use phaxt::{subxt, RpcClient};
 use sp_core::{crypto::Pair, sr25519};
@@ -15,6 +18,9 @@ use sp_finality_grandpa::{AuthorityList, SetId, VersionedAuthorityList, GRANDPA_
 
 mod endpoint;
 mod error;
+mod failover;
+mod fetcher;
+mod metrics;
 mod msg_sync;
 mod notify_client;
 mod prefetcher;
@@ -24,6 +30,7 @@ pub mod headers_cache;
 pub mod types;
 
 use crate::error::Error;
+use crate::fetcher::SharedFetcher;
 use crate::types::{
     Block, BlockNumber, Hash, Header, NotifyReq, NumberOrHex, ParachainApi, PrClient,
     RelaychainApi, SignedBlock, SrSigner,
@@ -43,7 +50,7 @@ use phala_types::AttestationProvider;
 
 pub use phaxt::connect as subxt_connect;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(
     about = "Sync messages between pruntime and the blockchain.",
     version,
@@ -102,6 +109,34 @@ pub struct Args {
     )]
     collator_ws_endpoint: String,
 
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "Comma separated relaychain rpc websocket endpoints to fail over between, in the order of preference. Overrides --substrate-ws-endpoint"
+    )]
+    substrate_ws_endpoints: Vec<String>,
+
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "Comma separated parachain rpc websocket endpoints to fail over between, in the order of preference. Overrides --collator-ws-endpoint"
+    )]
+    collator_ws_endpoints: Vec<String>,
+
+    #[clap(
+        default_value = "5",
+        long,
+        help = "Switch to another node if the one in use falls behind by more finalized blocks than this"
+    )]
+    max_node_lag: BlockNumber,
+
+    #[clap(
+        default_value = "60",
+        long,
+        help = "Interval in seconds to check the nodes in use against the others"
+    )]
+    node_health_check_interval: u64,
+
     #[clap(
         default_value = "http://localhost:8000",
         long,
@@ -109,6 +144,13 @@ pub struct Args {
     )]
     pruntime_endpoint: String,
 
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "Comma separated pRuntime http endpoints to drive in one process. Overrides --pruntime-endpoint"
+    )]
+    pruntime_endpoints: Vec<String>,
+
     #[clap(
         long,
         help = "pRuntime http endpoint to handover the key. The handover will only happen when the old pRuntime is synced."
@@ -126,6 +168,13 @@ pub struct Args {
     )]
     mnemonic: String,
 
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "Comma separated controller mnemonics for each of --pruntime-endpoints, default to --mnemonic"
+    )]
+    worker_mnemonics: Vec<String>,
+
     #[clap(
         default_value = "1000",
         long = "fetch-blocks",
@@ -140,6 +189,20 @@ pub struct Args {
     )]
     sync_blocks: BlockNumber,
 
+    #[clap(
+        default_value = "1",
+        long,
+        help = "Number of header and storage changes batches to prefetch ahead of the one being synced."
+    )]
+    prefetch_depth: u32,
+
+    #[clap(
+        default_value = "4",
+        long,
+        help = "Max number of concurrent requests to fetch blocks from Substrate or the headers cache."
+    )]
+    prefetch_concurrency: usize,
+
     #[clap(
         long = "operator",
         help = "The operator account to set the miner for the worker."
@@ -191,7 +254,7 @@ pub struct Args {
     #[clap(
         default_value = "10",
         long,
-        help = "Max auto restart retries if it continiously failing. Only used with --auto-restart"
+        help = "Max auto restart retries if it continiously failing. Only used with --auto-restart, or when switching between multiple substrate nodes"
     )]
     max_restart_retries: u32,
 
@@ -199,6 +262,12 @@ pub struct Args {
     restart_on_rpc_error_threshold: Option<u64>,
 
 
+    #[clap(
+        long,
+        help = "Listen address of the Prometheus metrics endpoint, e.g. 0.0.0.0:9616. Disabled if not set"
+    )]
+    metrics_addr: Option<std::net::SocketAddr>,
+
     #[clap(long, help = "URI to fetch cached headers from")]
     #[clap(default_value = "")]
     headers_cache_uri: String,
@@ -219,10 +288,19 @@ pub struct Args {
     pub attestation_provider: String,
 }
 
+/// Serializes the extrinsic submissions of the workers sharing the same controller account.
+type SignerLock = Arc<tokio::sync::Mutex<()>>;
+
 struct RunningFlags {
     worker_registered: bool,
     endpoint_registered: bool,
     restart_failure_count: u32,
+    /// The substrate nodes the bridge is connected to.
+    connected_nodes: Vec<String>,
+    /// Genesis hashes of the relaychain and the parachain first connected, which the nodes
+    /// switched to later must agree with.
+    relay_genesis_hash: Option<Hash>,
+    para_genesis_hash: Option<Hash>,
 }
 
 struct BlockSyncState {
@@ -328,6 +406,7 @@ pub async fn batch_sync_storage_changes(
     pr: &PrClient,
     api: &ParachainApi,
     cache: Option<&CacheClient>,
+    fetcher: &SharedFetcher,
     from: BlockNumber,
     to: BlockNumber,
     batch_size: BlockNumber,
@@ -337,13 +416,21 @@ pub async fn batch_sync_storage_changes(
         to as i64 - from as i64 + 1
     );
 
-    let mut fetcher = prefetcher::PrefetchClient::new();
+    let mut prefetcher = prefetcher::PrefetchClient::new(fetcher.clone(), to, batch_size);
 
-    for from in (from..=to).step_by(batch_size as _) {
-        let to = to.min(from.saturating_add(batch_size - 1));
-        let storage_changes = fetcher.fetch_storage_changes(api, cache, from, to).await?;
+    // Align the batches so that the workers share the fetched storage changes.
+    let mut next = from;
+    while next <= to {
+        let batch_to = fetcher::aligned_batch_end(next, batch_size, to);
+        let storage_changes = prefetcher
+            .fetch_storage_changes(api, cache, next, batch_to)
+            .await?;
         let r = req_dispatch_block(pr, storage_changes).await?;
         log::debug!("  ..dispatch_block: {:?}", r);
+        if batch_to == BlockNumber::MAX {
+            break;
+        }
+        next = batch_to + 1;
     }
     Ok(())
 }
@@ -456,6 +543,7 @@ async fn batch_sync_block(
     api: &RelaychainApi,
     paraclient: &ParachainApi,
     cache: Option<&CacheClient>,
+    fetcher: &SharedFetcher,
     pr: &PrClient,
     sync_state: &mut BlockSyncState,
     batch_window: BlockNumber,
@@ -480,8 +568,16 @@ async fn batch_sync_block(
     macro_rules! sync_blocks_to {
         ($to: expr) => {
             if next_blocknum <= $to {
-                batch_sync_storage_changes(pr, paraclient, cache, next_blocknum, $to, batch_window)
-                    .await?;
+                batch_sync_storage_changes(
+                    pr,
+                    paraclient,
+                    cache,
+                    fetcher,
+                    next_blocknum,
+                    $to,
+                    batch_window,
+                )
+                .await?;
                 synced_blocks += $to - next_blocknum + 1;
                 next_blocknum = $to + 1;
             };
@@ -665,6 +761,7 @@ async fn maybe_sync_waiting_parablocks(
     api: &RelaychainApi,
     para_api: &ParachainApi,
     cache_client: &Option<CacheClient>,
+    fetcher: &SharedFetcher,
     info: &PhactoryInfo,
     batch_window: BlockNumber,
 ) -> Result<()> {
@@ -719,6 +816,7 @@ async fn maybe_sync_waiting_parablocks(
                 pr,
                 para_api,
                 cache_client.as_ref(),
+                fetcher,
                 info.blocknum,
                 hdr_synced_to,
                 batch_window,
@@ -946,21 +1044,46 @@ async fn bridge(
     args: &Args,
     flags: &mut RunningFlags,
     err_report: Sender<MsgSyncError>,
+    fetcher: &SharedFetcher,
+    signer_lock: &SignerLock,
 ) -> Result<()> {
     // Connect to substrate
 
-    let api: RelaychainApi = subxt_connect(&args.substrate_ws_endpoint).await?;
-    info!("Connected to relaychain at: {}", args.substrate_ws_endpoint);
-
-    let para_uri: &str = if args.parachain {
-        &args.collator_ws_endpoint
+    let relay_endpoints = relaychain_endpoints(args);
+    let relay_node = failover::select_node(
+        &relay_endpoints,
+        args.max_node_lag,
+        flags.relay_genesis_hash,
+    )
+    .await?;
+    flags.relay_genesis_hash = Some(relay_node.api.genesis_hash());
+    let api: RelaychainApi = relay_node.api;
+    info!("Connected to relaychain at: {}", relay_node.endpoint);
+
+    let para_endpoints = if args.parachain {
+        parachain_endpoints(args)
     } else {
-        &args.substrate_ws_endpoint
+        relay_endpoints.clone()
     };
-    let para_api: ParachainApi = subxt_connect(para_uri).await?;
-    info!(
-        "Connected to parachain node at: {}",
-        args.collator_ws_endpoint
+    let para_node =
+        failover::select_node(&para_endpoints, args.max_node_lag, flags.para_genesis_hash).await?;
+    flags.para_genesis_hash = Some(para_node.api.genesis_hash());
+    let para_api: ParachainApi = para_node.api;
+    info!("Connected to parachain node at: {}", para_node.endpoint);
+
+    flags.connected_nodes = vec![relay_node.endpoint.clone(), para_node.endpoint.clone()];
+    let check_interval = Duration::from_secs(args.node_health_check_interval);
+    let mut relay_health = failover::HealthChecker::new(
+        &relay_endpoints,
+        &relay_node.endpoint,
+        args.max_node_lag,
+        check_interval,
+    );
+    let mut para_health = failover::HealthChecker::new(
+        &para_endpoints,
+        &para_node.endpoint,
+        args.max_node_lag,
+        check_interval,
     );
 
     if !args.no_wait {
@@ -1049,6 +1172,7 @@ async fn bridge(
     }
 
     if args.no_sync {
+        let _guard = signer_lock.lock().await;
         if !args.no_register {
             try_register_worker(&pr, &para_api, &mut signer, operator, args).await?;
             flags.worker_registered = true;
@@ -1076,9 +1200,22 @@ async fn bridge(
     };
 
     for round in 0u64.. {
+        relay_health.check(&api).await?;
+        para_health.check(&para_api).await?;
+
         // update the latest pRuntime state
         let info = pr.get_info(()).await?;
         info!("pRuntime get_info response: {:#?}", info);
+        let worker = args.pruntime_endpoint.as_str();
+        metrics::RELAY_SYNCED_HEIGHT
+            .with_label_values(&[worker])
+            .set(info.headernum as i64 - 1);
+        metrics::PARA_SYNCED_HEIGHT
+            .with_label_values(&[worker])
+            .set(info.para_headernum as i64 - 1);
+        metrics::DISPATCHED_HEIGHT
+            .with_label_values(&[worker])
+            .set(info.blocknum as i64 - 1);
         if info.blocknum >= args.to_block {
             info!("Reached target block: {}", args.to_block);
             return Ok(());
@@ -1107,6 +1244,7 @@ async fn bridge(
                 &pr,
                 &para_api,
                 cache_client.as_ref(),
+                fetcher,
                 info.blocknum,
                 next_headernum - 1,
                 args.sync_blocks,
@@ -1123,6 +1261,7 @@ async fn bridge(
                 &api,
                 &para_api,
                 &cache_client,
+                fetcher,
                 &info,
                 args.sync_blocks,
             )
@@ -1132,7 +1271,10 @@ async fn bridge(
         // Sync the relaychain and parachain data from the cache service as much as possible
         if let (true, Some(cache)) = (args.parachain, &cache_client) {
             info!("Fetching headers at {} from cache...", info.headernum);
-            let cached_headers = cache.get_headers(info.headernum).await.unwrap_or_default();
+            let cached_headers = fetcher
+                .get_cached_headers(cache, info.headernum)
+                .await
+                .unwrap_or_default();
             if cached_headers.is_empty() {
                 info!("Header cache missing at {}", info.headernum);
             } else {
@@ -1147,6 +1289,7 @@ async fn bridge(
                     &pr,
                     &para_api,
                     cache_client.as_ref(),
+                    fetcher,
                     info.blocknum,
                     info.para_headernum,
                     cached_headers,
@@ -1158,6 +1301,9 @@ async fn bridge(
         }
 
         let latest_block = get_block_at(&api, None).await?.0.block;
+        metrics::BLOCKS_BEHIND_FINALIZED
+            .with_label_values(&[worker])
+            .set(latest_block.header.number as i64 + 1 - info.headernum as i64);
         // remove the blocks not needed in the buffer. info.blocknum is the next required block
         while let Some(b) = sync_state.blocks.first() {
             if b.block.header.number >= info.blocknum {
@@ -1198,13 +1344,22 @@ async fn bridge(
             }
         };
 
-        for b in next_block..=batch_end {
-            let block = get_block_without_storage_changes(&api, Some(b)).await?;
-
+        let mut blocks = futures::stream::iter(next_block..=batch_end)
+            .map(|b| fetcher.get_block(&api, b))
+            .buffered(args.prefetch_concurrency.max(1));
+        while let Some(block) = blocks.next().await {
+            let block = block?;
             if block.justifications.is_some() {
                 debug!("block with justification at: {}", block.block.header.number);
             }
-            sync_state.blocks.push(block.clone());
+            sync_state.blocks.push(block);
+        }
+        // Fetch the next batches, with their justifications, while this one is synced to pRuntime.
+        // They are requested only after the current batch is fetched to not delay it.
+        if let Some((from, to)) =
+            fetcher.blocks_to_prefetch(batch_end, args.fetch_blocks, latest_block.header.number)
+        {
+            fetcher.prefetch_blocks(&api, from, to);
         }
 
         // send the blocks to pRuntime in batch
@@ -1212,6 +1367,7 @@ async fn bridge(
             &api,
             &para_api,
             cache_client.as_ref(),
+            fetcher,
             &pr,
             &mut sync_state,
             args.sync_blocks,
@@ -1219,9 +1375,13 @@ async fn bridge(
             args.parachain,
         )
         .await?;
+        update_sync_state_metrics(worker, &sync_state);
 
         // check if pRuntime has already reached the chain tip.
         if synced_blocks == 0 && !more_blocks {
+            // Workers sharing the same controller account must not submit extrinsics concurrently,
+            // otherwise they would race on the account nonce.
+            let guard = signer_lock.lock().await;
             if !initial_sync_finished && !args.no_register && !flags.worker_registered {
                 try_register_worker(&pr, &para_api, &mut signer, operator.clone(), args).await?;
                 flags.worker_registered = true;
@@ -1265,6 +1425,7 @@ async fn bridge(
                 )
                 .await?;
             }
+            drop(guard);
             flags.restart_failure_count = 0;
             info!("Waiting for new blocks");
 
@@ -1283,6 +1444,96 @@ async fn bridge(
     Ok(())
 }
 
+fn update_sync_state_metrics(worker: &str, sync_state: &BlockSyncState) {
+    metrics::SYNC_STATE_BUFFERED_BLOCKS
+        .with_label_values(&[worker])
+        .set(sync_state.blocks.len() as i64);
+    if let Some((number, set_id)) = sync_state.authory_set_state {
+        metrics::SYNC_STATE_AUTHORITY_SET_HEIGHT
+            .with_label_values(&[worker])
+            .set(number as i64);
+        metrics::SYNC_STATE_AUTHORITY_SET_ID
+            .with_label_values(&[worker])
+            .set(set_id as i64);
+    }
+}
+
+fn relaychain_endpoints(args: &Args) -> Vec<String> {
+    if args.substrate_ws_endpoints.is_empty() {
+        vec![args.substrate_ws_endpoint.clone()]
+    } else {
+        args.substrate_ws_endpoints.clone()
+    }
+}
+
+fn parachain_endpoints(args: &Args) -> Vec<String> {
+    if args.collator_ws_endpoints.is_empty() {
+        vec![args.collator_ws_endpoint.clone()]
+    } else {
+        args.collator_ws_endpoints.clone()
+    }
+}
+
+/// Returns the connected nodes that no longer respond.
+async fn unreachable_nodes(flags: &RunningFlags) -> Vec<String> {
+    let reachable = futures::future::join_all(
+        flags
+            .connected_nodes
+            .iter()
+            .map(|endpoint| failover::is_reachable(endpoint)),
+    )
+    .await;
+    let mut unreachable = vec![];
+    for (endpoint, reachable) in flags.connected_nodes.iter().zip(reachable) {
+        if !reachable {
+            warn!("Node {endpoint} is unreachable");
+            unreachable.push(endpoint.clone());
+        }
+    }
+    unreachable
+}
+
+/// Returns whether the bridge failed because of the substrate nodes it was connected to, in which
+/// case it can be restarted on the other nodes.
+fn is_node_failure(args: &Args, unreachable: &[String], err: &anyhow::Error) -> bool {
+    if relaychain_endpoints(args).len() <= 1 && parachain_endpoints(args).len() <= 1 {
+        return false;
+    }
+    matches!(err.downcast_ref::<Error>(), Some(Error::NodeUnhealthy)) || !unreachable.is_empty()
+}
+
+/// Counts the error that stopped the bridge against the endpoint it came from.
+///
+/// A node error that can't be pinned to an unreachable node is counted against all the
+/// connected nodes, joined with commas.
+fn record_rpc_error(
+    args: &Args,
+    flags: &RunningFlags,
+    unreachable: &[String],
+    err: &anyhow::Error,
+) {
+    let worker = args.pruntime_endpoint.as_str();
+    if err.chain().any(|e| e.is::<prpc::client::Error>()) {
+        metrics::RPC_ERRORS
+            .with_label_values(&[worker, worker])
+            .inc();
+    }
+    if !unreachable.is_empty() {
+        for endpoint in unreachable {
+            metrics::RPC_ERRORS
+                .with_label_values(&[worker, endpoint])
+                .inc();
+        }
+    } else if err.chain().any(|e| e.is::<subxt::Error>())
+        || matches!(err.downcast_ref::<Error>(), Some(Error::NodeUnhealthy))
+    {
+        let endpoint = flags.connected_nodes.join(",");
+        metrics::RPC_ERRORS
+            .with_label_values(&[worker, &endpoint])
+            .inc();
+    }
+}
+
 fn preprocess_args(args: &mut Args) {
     if args.dev {
         args.use_dev_key = true;
@@ -1378,31 +1629,125 @@ pub async fn pherry_main() {
     let mut args = Args::parse();
     preprocess_args(&mut args);
 
+    if let Some(addr) = args.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(addr).await {
+                error!("Metrics server exited with error: {:?}", err);
+            }
+        });
+    }
+
+    let fetcher = SharedFetcher::new(args.prefetch_depth, args.prefetch_concurrency);
+    if args.pruntime_endpoints.is_empty() {
+        let code = run_worker(&args, &fetcher, &Default::default()).await;
+        if code != 0 {
+            std::process::exit(code);
+        }
+        return;
+    }
+
+    assert!(
+        args.next_pruntime_endpoint.is_none(),
+        "Option --next-pruntime-endpoint can not be used with --pruntime-endpoints."
+    );
+    assert!(
+        args.worker_mnemonics.is_empty()
+            || args.worker_mnemonics.len() == args.pruntime_endpoints.len(),
+        "Option --worker-mnemonics must have the same length as --pruntime-endpoints."
+    );
+    assert!(
+        !args.use_dev_key && args.inject_key.is_empty(),
+        "Options --dev, --use-dev-key and --inject-key can not be used with --pruntime-endpoints, \
+         the workers would share the same identity key."
+    );
+    let mut signer_locks: BTreeMap<String, SignerLock> = BTreeMap::new();
+    let workers: Vec<_> = args
+        .pruntime_endpoints
+        .iter()
+        .enumerate()
+        .map(|(i, endpoint)| {
+            let mut worker_args = args.clone();
+            worker_args.pruntime_endpoint = endpoint.clone();
+            if let Some(mnemonic) = args.worker_mnemonics.get(i) {
+                worker_args.mnemonic = mnemonic.clone();
+            }
+            let signer_lock = signer_locks
+                .entry(worker_args.mnemonic.clone())
+                .or_default()
+                .clone();
+            (worker_args, signer_lock)
+        })
+        .collect();
+    info!("Driving {} workers", workers.len());
+    let fetcher = &fetcher;
+    let mut running: futures::stream::FuturesUnordered<_> = workers
+        .iter()
+        .map(|(args, signer_lock)| async move {
+            (
+                &args.pruntime_endpoint,
+                run_worker(args, fetcher, signer_lock).await,
+            )
+        })
+        .collect();
+    // Exit on the first failed worker, as the single-worker mode does.
+    while let Some((endpoint, code)) = running.next().await {
+        if code != 0 {
+            error!("Worker {endpoint} exited with code {code}");
+            std::process::exit(code);
+        }
+        info!("Worker {endpoint} finished");
+    }
+}
+
+/// Keeps syncing a pRuntime, restarting the bridge on errors if required.
+///
+/// Returns the process exit code: 0 if the bridge finished normally, 1 if it failed after the
+/// worker had been registered, 2 otherwise.
+async fn run_worker(args: &Args, fetcher: &SharedFetcher, signer_lock: &SignerLock) -> i32 {
     let mut flags = RunningFlags {
         worker_registered: false,
         endpoint_registered: false,
         restart_failure_count: 0,
+        connected_nodes: vec![],
+        relay_genesis_hash: None,
+        para_genesis_hash: None,
     };
 
     loop {
         let (sender, receiver) = msg_sync::create_report_channel();
         let threshold = args.restart_on_rpc_error_threshold;
-        tokio::select! {
-            res = bridge(&args, &mut flags, sender) => {
+        let bridge_error = tokio::select! {
+            res = bridge(args, &mut flags, sender, fetcher, signer_lock) => {
                 if let Err(err) = res {
-                    info!("bridge() exited with error: {:?}", err);
+                    info!("[{}] bridge() exited with error: {:?}", args.pruntime_endpoint, err);
+                    Some(err)
                 } else {
-                    break;
+                    return 0;
                 }
             }
-            () = collect_async_errors(threshold, receiver) => ()
+            () = collect_async_errors(threshold, receiver) => None
         };
+        if let Some(err) = bridge_error {
+            let unreachable = unreachable_nodes(&flags).await;
+            record_rpc_error(args, &flags, &unreachable, &err);
+            if is_node_failure(args, &unreachable, &err) {
+                // Switching nodes counts as a restart, so a bridge that fails on every node
+                // doesn't loop forever.
+                if flags.restart_failure_count > args.max_restart_retries {
+                    return if flags.worker_registered { 1 } else { 2 };
+                }
+                flags.restart_failure_count += 1;
+                info!("[{}] Switching substrate nodes...", args.pruntime_endpoint);
+                sleep(Duration::from_secs(2)).await;
+                continue;
+            }
+        }
         if !args.auto_restart || flags.restart_failure_count > args.max_restart_retries {
-            std::process::exit(if flags.worker_registered { 1 } else { 2 });
+            return if flags.worker_registered { 1 } else { 2 };
         }
         flags.restart_failure_count += 1;
         sleep(Duration::from_secs(2)).await;
-        info!("Restarting...");
+        info!("[{}] Restarting...", args.pruntime_endpoint);
     }
 }
 
@@ -1410,6 +1755,7 @@ async fn sync_with_cached_headers(
     pr: &PrClient,
     para_api: &ParachainApi,
     cache: Option<&CacheClient>,
+    fetcher: &SharedFetcher,
     next_blocknum: BlockNumber,
     next_para_headernum: BlockNumber,
     mut headers: Vec<headers_cache::BlockInfo>,
@@ -1447,6 +1793,7 @@ async fn sync_with_cached_headers(
                 pr,
                 para_api,
                 cache,
+                fetcher,
                 next_blocknum,
                 hdr_synced_to,
                 batch_window,
@@ -0,0 +1,163 @@
+//! Prometheus metrics of pherry.
+//!
+//! The per-worker metrics are labeled with the pRuntime endpoint, and the egress metrics with the
+//! message sender, which is unique to each worker.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+pub static RELAY_SYNCED_HEIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pherry_relaychain_synced_height",
+        "The latest relaychain header synced to pRuntime",
+        &["worker"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static PARA_SYNCED_HEIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pherry_parachain_synced_height",
+        "The latest parachain header synced to pRuntime",
+        &["worker"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static DISPATCHED_HEIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pherry_dispatched_height",
+        "The latest block dispatched to pRuntime",
+        &["worker"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static BLOCKS_BEHIND_FINALIZED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pherry_blocks_behind_finalized",
+        "Number of finalized relaychain blocks not synced to pRuntime yet",
+        &["worker"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static SYNC_STATE_BUFFERED_BLOCKS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pherry_sync_state_buffered_blocks",
+        "Number of relaychain blocks buffered in the block sync state",
+        &["worker"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static SYNC_STATE_AUTHORITY_SET_ID: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pherry_sync_state_authority_set_id",
+        "The latest known authority set id in the block sync state",
+        &["worker"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static SYNC_STATE_AUTHORITY_SET_HEIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pherry_sync_state_authority_set_height",
+        "The block at which the latest authority set id is known in the block sync state",
+        &["worker"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static EGRESS_PENDING: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pherry_egress_messages_pending",
+        "Number of egress messages waiting to be submitted to the chain",
+        &["sender"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static EGRESS_SUBMITTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pherry_egress_messages_submitted_total",
+        "Number of egress messages accepted into the transaction pool of the chain",
+        &["sender"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static SUBMISSION_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pherry_extrinsic_submission_errors_total",
+        "Number of egress message extrinsics failed to be submitted to the transaction pool",
+        &["sender", "kind"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static SUBMISSION_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "pherry_extrinsic_submission_seconds",
+        "Time taken to submit an extrinsic to the transaction pool",
+        &["sender"],
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]
+    )
+    .expect("Failed to register metric")
+});
+
+pub static RPC_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pherry_rpc_errors_total",
+        "Number of failed RPCs to a substrate node or pRuntime that interrupted the sync",
+        &["worker", "endpoint"]
+    )
+    .expect("Failed to register metric")
+});
+
+pub fn observe_submission(sender: &str, elapsed: Duration) {
+    SUBMISSION_LATENCY
+        .with_label_values(&[sender])
+        .observe(elapsed.as_secs_f64());
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut not_found = Response::new(Body::empty());
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(not_found);
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("Failed to encode metrics: {err:?}");
+        let mut internal_error = Response::new(Body::empty());
+        *internal_error.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(internal_error);
+    }
+    let response = Response::builder()
+        .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .expect("Valid response");
+    Ok(response)
+}
+
+/// Serves the metrics at `http://<addr>/metrics`.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    info!("Serving metrics at http://{addr}/metrics");
+    server.await?;
+    Ok(())
+}
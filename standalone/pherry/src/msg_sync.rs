@@ -1,6 +1,6 @@
 use anyhow::Result;
 use log::{error, info};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::{
     chain_client::{mq_next_sequence, update_signer_nonce},
@@ -47,6 +47,14 @@ pub async fn maybe_sync_mq_egress(
         let min_seq = mq_next_sequence(api, &sender).await?;
 
         info!("Next seq for {} is {}", sender, min_seq);
+        let sender_label = sender.to_string();
+        let pending = messages
+            .iter()
+            .filter(|message| message.sequence >= min_seq)
+            .count();
+        crate::metrics::EGRESS_PENDING
+            .with_label_values(&[&sender_label])
+            .set(pending as i64);
 
         for message in messages {
             if message.sequence < min_seq {
@@ -71,13 +79,19 @@ pub async fn maybe_sync_mq_egress(
                     let api = api.clone();
                     let err_report = err_report.clone();
                     let extrinsic = crate::subxt::utils::Encoded(extrinsic.encoded().to_vec());
+                    let sender_label = sender_label.clone();
                     tokio::spawn(async move {
                         const TIMEOUT: u64 = 120;
+                        let start = Instant::now();
                         let fut = api.rpc().submit_extrinsic(extrinsic);
                         let result = tokio::time::timeout(Duration::from_secs(TIMEOUT), fut).await;
+                        crate::metrics::observe_submission(&sender_label, start.elapsed());
                         match result {
                             Err(_) => {
                                 error!("Submit message timed out: {}", msg_info);
+                                crate::metrics::SUBMISSION_ERRORS
+                                    .with_label_values(&[&sender_label, "timeout"])
+                                    .inc();
                                 let _ = err_report.send(Error::OtherRpcError).await;
                             }
                             Ok(Err(err)) => {
@@ -93,10 +107,20 @@ pub async fn maybe_sync_mq_egress(
                                     }
                                     _ => Error::OtherRpcError,
                                 };
+                                let kind = match report {
+                                    Error::BadSignature => "bad_signature",
+                                    Error::OtherRpcError => "other",
+                                };
+                                crate::metrics::SUBMISSION_ERRORS
+                                    .with_label_values(&[&sender_label, kind])
+                                    .inc();
                                 let _ = err_report.send(report).await;
                             }
                             Ok(Ok(hash)) => {
                                 info!("Message submited: {} xt-hash={:?}", msg_info, hash);
+                                crate::metrics::EGRESS_SUBMITTED
+                                    .with_label_values(&[&sender_label])
+                                    .inc();
                             }
                         }
                     });
@@ -1,22 +1,26 @@
 use anyhow::Result;
 use phactory_api::blocks::BlockHeaderWithChanges;
 use phaxt::{BlockNumber, RpcClient};
-use tokio::task::JoinHandle;
 
-struct StoragePrefetchState {
-    from: BlockNumber,
-    to: BlockNumber,
-    handle: JoinHandle<Result<Vec<BlockHeaderWithChanges>>>,
-}
+use crate::fetcher::{aligned_batch_end, SharedFetcher};
 
 pub struct PrefetchClient {
-    prefetching_storage_changes: Option<StoragePrefetchState>,
+    fetcher: SharedFetcher,
+    /// The last block to prefetch.
+    limit: BlockNumber,
+    /// Size of the aligned batches to prefetch.
+    batch_size: BlockNumber,
+    /// The batches requested in background, in ascending order.
+    prefetching_storage_changes: Vec<(BlockNumber, BlockNumber)>,
 }
 
 impl PrefetchClient {
-    pub fn new() -> Self {
+    pub fn new(fetcher: SharedFetcher, limit: BlockNumber, batch_size: BlockNumber) -> Self {
         Self {
-            prefetching_storage_changes: None,
+            fetcher,
+            limit,
+            batch_size,
+            prefetching_storage_changes: vec![],
         }
     }
 
@@ -27,41 +31,37 @@ impl PrefetchClient {
         from: BlockNumber,
         to: BlockNumber,
     ) -> Result<Vec<BlockHeaderWithChanges>> {
-        let count = to + 1 - from;
-        let result = if let Some(state) = self.prefetching_storage_changes.take() {
-            if state.from == from && state.to == to {
-                log::info!("use prefetched storage changes ({from}-{to})",);
-                state.handle.await?.ok()
-            } else {
-                log::info!(
-                    "cancelling the prefetch ({}-{}), requesting ({from}-{to})",
-                    state.from,
-                    state.to,
-                );
-                state.handle.abort();
-                None
-            }
-        } else {
-            None
-        };
+        if self.prefetching_storage_changes.first() == Some(&(from, to)) {
+            log::info!("use prefetched storage changes ({from}-{to})");
+        } else if !self.prefetching_storage_changes.is_empty() {
+            // The prefetched batches are left in the shared fetcher for the other workers.
+            log::info!("skipping the prefetches, requesting ({from}-{to})");
+            self.prefetching_storage_changes.clear();
+        }
+        self.prefetching_storage_changes
+            .retain(|&(pre_from, _)| pre_from > to);
+        // Start the requested batch first to make it go ahead of the prefetches.
+        self.fetcher
+            .prefetch_storage_changes(client, cache, from, to);
 
-        let result = if let Some(result) = result {
-            result
-        } else {
-            crate::fetch_storage_changes(client, cache, from, to).await?
+        // Keep the pipeline filled with `prefetch_depth` batches following the requested one.
+        let mut next_from = match self.prefetching_storage_changes.last() {
+            Some(&(_, pre_to)) => pre_to + 1,
+            None => to + 1,
         };
-        let next_from = from + count;
-        let next_to = next_from + count - 1;
-        let client = client.clone();
-        let cache = cache.cloned();
-        self.prefetching_storage_changes = Some(StoragePrefetchState {
-            from: next_from,
-            to: next_to,
-            handle: tokio::spawn(async move {
-                log::info!("prefetching ({next_from}-{next_to})");
-                crate::fetch_storage_changes(&client, cache.as_ref(), next_from, next_to).await
-            }),
-        });
-        Ok(result)
+        while (self.prefetching_storage_changes.len() as u32) < self.fetcher.prefetch_depth()
+            && next_from <= self.limit
+        {
+            let next_to = aligned_batch_end(next_from, self.batch_size, self.limit);
+            log::info!("prefetching ({next_from}-{next_to})");
+            self.fetcher
+                .prefetch_storage_changes(client, cache, next_from, next_to);
+            self.prefetching_storage_changes.push((next_from, next_to));
+            next_from = next_to + 1;
+        }
+
+        self.fetcher
+            .fetch_storage_changes(client, cache, from, to)
+            .await
     }
 }
@@ -7,8 +7,8 @@ use rocket::http::Method;
 use rocket::http::Status;
 use rocket::response::status::Custom;
 use rocket::serde::json::{json, Json, Value as JsonValue};
-use rocket::Phase;
 use rocket::{get, post, routes};
+use rocket::{Build, Phase};
 use rocket_cors::{AllowedHeaders, AllowedMethods, AllowedOrigins, CorsOptions};
 
 use colored::Colorize as _;
@@ -19,7 +19,7 @@ use serde_json::{Map, Value};
 use phactory_api::{actions, prpc};
 use phala_rocket_middleware::ResponseSigner;
 
-use crate::runtime;
+use crate::{runtime, sidevm_gateway};
 
 #[derive(Serialize, Deserialize)]
 struct ContractInput {
@@ -79,13 +79,13 @@ macro_rules! proxy {
     };
 }
 
-enum ReadData {
+pub(crate) enum ReadData {
     Ok(Vec<u8>),
     IoError,
     PayloadTooLarge,
 }
 
-async fn read_data(data: Data<'_>, limit: ByteUnit) -> ReadData {
+pub(crate) async fn read_data(data: Data<'_>, limit: ByteUnit) -> ReadData {
     let stream = data.open(limit);
     let data = match stream.into_bytes().await {
         Ok(data) => data,
@@ -307,6 +307,13 @@ fn print_rpc_methods(prefix: &str, methods: &[&str]) {
     }
 }
 
+fn mount_sidevm_gateway(server: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
+    info!("Sidevm gateway mounted at {}", sidevm_gateway::MOUNT_POINT);
+    server
+        .mount(sidevm_gateway::MOUNT_POINT, sidevm_gateway::routes())
+        .mount("/", sidevm_gateway::host_routes())
+}
+
 pub(super) fn rocket(args: &super::Args) -> rocket::Rocket<impl Phase> {
     let mut server = rocket::build()
         .mount(
@@ -346,6 +353,7 @@ pub(super) fn rocket(args: &super::Args) -> rocket::Rocket<impl Phase> {
     }
 
     server = server.mount("/prpc", routes![prpc_proxy]);
+    server = mount_sidevm_gateway(server);
     print_rpc_methods("/prpc", prpc::phactory_api_server::supported_methods());
 
     if args.allow_cors {
@@ -382,6 +390,7 @@ pub(super) fn rocket_acl(args: &super::Args) -> Option<rocket::Rocket<impl Phase
         rocket::custom(figment).mount("/", routes![getinfo, get_contract_info, get_cluster_info]);
 
     server_acl = server_acl.mount("/prpc", routes![prpc_proxy_acl]);
+    server_acl = mount_sidevm_gateway(server_acl);
 
     if args.allow_cors {
         info!("Allow CORS");
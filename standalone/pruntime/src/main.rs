@@ -2,6 +2,7 @@ mod api_server;
 mod pal_gramine;
 mod ias;
 mod runtime;
+mod sidevm_gateway;
 
 use std::{env, thread};
 
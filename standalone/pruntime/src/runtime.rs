@@ -4,6 +4,7 @@ use anyhow::Result;
 use core::sync::atomic::{AtomicU32, Ordering};
 use log::info;
 use phactory::{benchmark, Phactory, RpcService};
+use std::net::SocketAddr;
 
 lazy_static::lazy_static! {
     static ref APPLICATION: RpcService<GraminePlatform> = RpcService::new(GraminePlatform);
@@ -45,6 +46,18 @@ pub fn ecall_get_cluster_info() -> String {
     serialize_result(result.map(|it| it.clusters))
 }
 
+pub fn ecall_sidevm_gateway_by_contract(contract_id: &str) -> Option<SocketAddr> {
+    APPLICATION
+        .lock_phactory()
+        .sidevm_gateway_by_contract(contract_id)
+}
+
+pub fn ecall_sidevm_gateway_by_host(hostname: &str) -> Option<SocketAddr> {
+    // Catches every request not served by the other routes, only lock the phactory for claimed hosts.
+    phactory::http_gateway_contract_of(hostname)?;
+    APPLICATION.lock_phactory().sidevm_gateway_by_host(hostname)
+}
+
 pub fn ecall_sign_http_response(data: &[u8]) -> Option<String> {
     APPLICATION.lock_phactory().sign_http_response(data)
 }
@@ -0,0 +1,303 @@
+//! Inbound HTTP gateway forwarding external requests to the listeners inside sidevm instances.
+//!
+//! Contracts choose the listener port, and optionally a hostname, with the `SetHttpGateway` pink
+//! event. Requests to `/sidevm/<contract_id>/<path>` are forwarded to `/<path>` of the listener of
+//! the contract's sidevm. Requests to any other path are forwarded by the `Host` header if it
+//! matches a hostname claimed by a contract. Hosts not claimed by any contract are passed on to the
+//! other routes without touching the phactory.
+
+use std::io::Cursor;
+use std::net::SocketAddr;
+
+use log::{debug, error};
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{Method, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::route::{Handler, Outcome, Route};
+use rocket::Request;
+
+use crate::api_server::{read_data, ReadData};
+use crate::runtime;
+
+/// Where the contract id prefixed routes are mounted.
+pub(crate) const MOUNT_POINT: &str = "/sidevm";
+
+/// Rank of the hostname routes, lower than any other route so that they only catch the leftovers.
+const HOST_ROUTE_RANK: isize = 100;
+
+const METHODS: [Method; 7] = [
+    Method::Get,
+    Method::Put,
+    Method::Post,
+    Method::Delete,
+    Method::Options,
+    Method::Head,
+    Method::Patch,
+];
+
+/// Headers only meaningful for a single connection, not forwarded in either direction.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+/// Max size of a response body from a sidevm listener.
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .no_proxy()
+        .build()
+        .expect("Failed to create the gateway http client");
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Whether the request header is forwarded to the sidevm. The `X-Forwarded-For` from the client
+/// is dropped, the gateway sets its own.
+fn is_forwarded_request_header(name: &str) -> bool {
+    !is_hop_by_hop(name) && !name.eq_ignore_ascii_case("x-forwarded-for")
+}
+
+/// Drops the hop-by-hop headers, keeping the ones to be forwarded.
+fn end_to_end_headers<'a>(
+    headers: impl Iterator<Item = (&'a str, &'a str)> + 'a,
+) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+    headers.filter(|(name, _)| !is_hop_by_hop(name))
+}
+
+/// Splits `/sidevm/<contract>/<path>` into the contract id and the path to forward to.
+fn split_contract_path(full_path: &str) -> (&str, &str) {
+    let rest = full_path
+        .strip_prefix(MOUNT_POINT)
+        .unwrap_or(full_path)
+        .trim_start_matches('/');
+    match rest.find('/') {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, "/"),
+    }
+}
+
+/// The lowercase hostname of a `Host` header value, without the port.
+fn hostname_of(host: &str) -> String {
+    host.split(':')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+#[derive(Clone)]
+enum Gateway {
+    ByContract,
+    ByHost,
+}
+
+/// Routes of `/sidevm/<contract_id>/<path..>`, to be mounted at `MOUNT_POINT`.
+pub(crate) fn routes() -> Vec<Route> {
+    METHODS
+        .iter()
+        .map(|method| Route::new(*method, "/<contract>/<path..>", Gateway::ByContract))
+        .collect()
+}
+
+/// Catch-all routes matching the `Host` header, to be mounted at `/`.
+pub(crate) fn host_routes() -> Vec<Route> {
+    METHODS
+        .iter()
+        .map(|method| Route::ranked(HOST_ROUTE_RANK, *method, "/<path..>", Gateway::ByHost))
+        .collect()
+}
+
+#[rocket::async_trait]
+impl Handler for Gateway {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let full_path = req.uri().path().as_str();
+        let (addr, path) = match self {
+            Gateway::ByContract => {
+                let (contract_id, path) = split_contract_path(full_path);
+                (runtime::ecall_sidevm_gateway_by_contract(contract_id), path)
+            }
+            Gateway::ByHost => {
+                let host = req.headers().get_one("Host").unwrap_or_default();
+                (
+                    runtime::ecall_sidevm_gateway_by_host(&hostname_of(host)),
+                    full_path,
+                )
+            }
+        };
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return Outcome::forward(data),
+        };
+        let body = match read_data(data, 16.mebibytes()).await {
+            ReadData::Ok(body) => body,
+            ReadData::IoError => return Outcome::failure(Status::ServiceUnavailable),
+            ReadData::PayloadTooLarge => return Outcome::failure(Status::PayloadTooLarge),
+        };
+        match forward(req, addr, path, body).await {
+            Ok(response) => Outcome::from(req, response),
+            Err(status) => Outcome::failure(status),
+        }
+    }
+}
+
+struct ProxyResponse {
+    status: Status,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl<'r> Responder<'r, 'static> for ProxyResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut builder = Response::build();
+        builder
+            .status(self.status)
+            .sized_body(self.body.len(), Cursor::new(self.body));
+        for (name, value) in self.headers {
+            builder.raw_header_adjoin(name, value);
+        }
+        Ok(builder.finalize())
+    }
+}
+
+async fn forward(
+    req: &Request<'_>,
+    addr: SocketAddr,
+    path: &str,
+    body: Vec<u8>,
+) -> Result<ProxyResponse, Status> {
+    let url = match req.uri().query() {
+        Some(query) => format!("http://{addr}{path}?{query}"),
+        None => format!("http://{addr}{path}"),
+    };
+    debug!("Forwarding {} {} to {url}", req.method(), req.uri());
+    let method = reqwest::Method::from_bytes(req.method().as_str().as_bytes())
+        .or(Err(Status::MethodNotAllowed))?;
+    let mut request = CLIENT.request(method, url).body(body);
+    for header in req.headers().iter() {
+        if !is_forwarded_request_header(header.name().as_str()) {
+            continue;
+        }
+        request = request.header(header.name().as_str(), header.value());
+    }
+    if let Some(ip) = req.client_ip() {
+        request = request.header("X-Forwarded-For", ip.to_string());
+    }
+    let response = request.send().await.map_err(|err| {
+        error!("Failed to forward request to sidevm at {addr}: {err}");
+        Status::BadGateway
+    })?;
+    let status = Status::new(response.status().as_u16());
+    let headers = response
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+    let headers = end_to_end_headers(headers)
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    let body = read_response(response, MAX_RESPONSE_SIZE).await?;
+    Ok(ProxyResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Reads the response body, failing if it's larger than `limit`.
+async fn read_response(mut response: reqwest::Response, limit: usize) -> Result<Vec<u8>, Status> {
+    let too_large = || {
+        error!("Response from sidevm is larger than {limit} bytes");
+        Status::BadGateway
+    };
+    if response.content_length().unwrap_or_default() > limit as u64 {
+        return Err(too_large());
+    }
+    let mut body = vec![];
+    while let Some(chunk) = response.chunk().await.or(Err(Status::BadGateway))? {
+        if body.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    #[test]
+    fn contract_path_is_split() {
+        assert_eq!(split_contract_path("/sidevm/0x01/a/b"), ("0x01", "/a/b"));
+        assert_eq!(split_contract_path("/sidevm/0x01/"), ("0x01", "/"));
+        assert_eq!(split_contract_path("/sidevm/0x01"), ("0x01", "/"));
+    }
+
+    #[test]
+    fn hostname_drops_port_and_case() {
+        assert_eq!(hostname_of("Example.COM:8000"), "example.com");
+        assert_eq!(hostname_of("example.com"), "example.com");
+        assert_eq!(hostname_of(""), "");
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_dropped() {
+        let headers = [
+            ("Content-Type", "text/plain"),
+            ("Connection", "close"),
+            ("Transfer-Encoding", "chunked"),
+            ("content-length", "5"),
+            ("X-Custom", "1"),
+        ];
+        let kept: Vec<_> = end_to_end_headers(headers.into_iter()).collect();
+        assert_eq!(kept, [("Content-Type", "text/plain"), ("X-Custom", "1")]);
+    }
+
+    #[test]
+    fn client_forwarded_for_is_dropped() {
+        assert!(is_forwarded_request_header("Content-Type"));
+        assert!(!is_forwarded_request_header("X-Forwarded-For"));
+        assert!(!is_forwarded_request_header("x-forwarded-for"));
+        assert!(!is_forwarded_request_header("Connection"));
+    }
+
+    #[rocket::get("/ping")]
+    fn ping() -> &'static str {
+        "pong"
+    }
+
+    #[test]
+    fn unclaimed_requests_fall_through() {
+        let server = rocket::build()
+            .mount("/", rocket::routes![ping])
+            .mount(MOUNT_POINT, routes())
+            .mount("/", host_routes());
+        let client = Client::untracked(server).unwrap();
+
+        let response = client
+            .get("/ping")
+            .header(Header::new("Host", "unclaimed.example"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().as_deref(), Some("pong"));
+
+        let response = client
+            .get("/not-found")
+            .header(Header::new("Host", "unclaimed.example"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
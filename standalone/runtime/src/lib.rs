@@ -1434,7 +1434,7 @@ pub type Executive = frame_executive::Executive<
 
 // All migrations executed on runtime upgrade as a nested tuple of types implementing
 // `OnRuntimeUpgrade`.
-type Migrations = ();
+type Migrations = (phala_pallets::migrations::v6::Migration<Runtime>,);
 
 pub struct MqCallMatcher;
 impl pallet_mq::CallMatcher<Runtime> for MqCallMatcher {